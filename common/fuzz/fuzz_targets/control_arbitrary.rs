@@ -8,4 +8,11 @@ fuzz_target!(|data: &[u8]| {
     let _ = common::control::ConfirmPart::deserialize_from_stream(&mut cursor);
     let _ = common::control::DeleteFile::deserialize_from_stream(&mut cursor);
     let _ = common::control::SetFilePriority::deserialize_from_stream(&mut cursor);
+    let _ = common::control::TimeTaggedCommand::deserialize_from_stream(&mut cursor);
+    let _ = common::control::CancelTimeTaggedCommand::deserialize_from_stream(&mut cursor);
+    let _ = common::control::DeleteFilesByFilter::deserialize_from_stream(&mut cursor);
+    let _ = common::control::SetPriorityByFilter::deserialize_from_stream(&mut cursor);
+    let _ = common::control::RequestFileResend::deserialize_from_stream(&mut cursor);
+    let _ = common::control::RequeueArchivedFile::deserialize_from_stream(&mut cursor);
+    let _ = common::control::RequestCatalogedFile::deserialize_from_stream(&mut cursor);
 });
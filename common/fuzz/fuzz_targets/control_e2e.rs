@@ -11,6 +11,15 @@ fuzz_target!(|data: ControlMessage| {
         ControlMessage::ConfirmPart(confirm_part) => check_message(confirm_part),
         ControlMessage::DeleteFile(delete_file) => check_message(delete_file),
         ControlMessage::SetFilePriority(set_priority) => check_message(set_priority),
+        ControlMessage::TimeTagged(time_tagged) => check_message(time_tagged),
+        ControlMessage::CancelTimeTagged(cancel) => check_message(cancel),
+        ControlMessage::ListTimeTagged(list) => check_message(list),
+        ControlMessage::DeleteFilesByFilter(delete) => check_message(delete),
+        ControlMessage::SetPriorityByFilter(set_priority) => check_message(set_priority),
+        ControlMessage::RequestFileResend(msg) => check_message(msg),
+        ControlMessage::RequeueArchivedFile(msg) => check_message(msg),
+        ControlMessage::ListCatalog(msg) => check_message(msg),
+        ControlMessage::RequestCatalogedFile(msg) => check_message(msg),
     }
 });
 
@@ -0,0 +1,232 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    binary_serialize::BinarySerialize,
+    chunks::{is_valid_metadata, metadata_length, read_metadata_fields, write_metadata},
+    validity::ValidityCheck,
+};
+
+// Bundle file structure
+// [magic]          - "LEOFTPBN"
+// [entry count]    - u32
+// [entries]        - The index, a BundleEntry for every file
+// [file contents]  - The contents of every file, in the order of the index
+
+// Small files are packed into bundles by the sender, so that they share a single managed file
+// instead of each having their own header, state and acknowledgements. A bundle is sent like any
+// other file, with the header kind set to bundle, and the receiver unpacks it into the files.
+
+const BUNDLE_MAGIC: &[u8; 8] = b"LEOFTPBN";
+
+/// The file extension of bundles, so that they can be recognized before they're added
+pub const BUNDLE_SUFFIX: &str = ".leoftp-bundle";
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BundleEntry {
+    /// The name of the file, relative to the output folder
+    pub name: String,
+    /// When the file was created, in nanoseconds since the unix epoch
+    pub date: i64,
+    pub size: u64,
+    /// Custom fields, like the metadata in a file header
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl BinarySerialize for BundleEntry {
+    fn serialize_to_stream(&self, writer: &mut impl io::Write) -> io::Result<()> {
+        let name_bytes = self.name.as_bytes();
+        writer.write_all(&(name_bytes.len() as u16).to_le_bytes())?;
+        writer.write_all(name_bytes)?;
+
+        writer.write_all(&self.date.to_le_bytes())?;
+        writer.write_all(&self.size.to_le_bytes())?;
+        write_metadata(writer, &self.metadata)?;
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        2 // name_len
+        + self.name.len() as u32 // name
+        + 8 // date
+        + 8 // size
+        + metadata_length(&self.metadata) // metadata
+    }
+
+    fn deserialize_from_stream(reader: &mut impl io::Read) -> io::Result<Self> {
+        let mut name_len_bytes = [0; 2];
+        reader.read_exact(&mut name_len_bytes)?;
+        let mut name_bytes = vec![0; u16::from_le_bytes(name_len_bytes) as usize];
+        reader.read_exact(&mut name_bytes)?;
+        let name = String::from_utf8(name_bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut date_bytes = [0; 8];
+        reader.read_exact(&mut date_bytes)?;
+        let date = i64::from_le_bytes(date_bytes);
+
+        let mut size_bytes = [0; 8];
+        reader.read_exact(&mut size_bytes)?;
+        let size = u64::from_le_bytes(size_bytes);
+
+        let mut field_count_bytes = [0; 2];
+        reader.read_exact(&mut field_count_bytes)?;
+        let metadata = read_metadata_fields(reader, u16::from_le_bytes(field_count_bytes))?;
+
+        Ok(Self {
+            name,
+            date,
+            size,
+            metadata,
+        })
+    }
+}
+
+impl ValidityCheck for BundleEntry {
+    fn is_valid(&self) -> bool {
+        self.name.len() <= 65535 && is_valid_metadata(&self.metadata)
+    }
+}
+
+/// Write a bundle of the given files. The files themselves are left in place.
+pub fn write_bundle(path: &Path, files: &[(BundleEntry, PathBuf)]) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(&file);
+
+    writer.write_all(BUNDLE_MAGIC)?;
+    writer.write_all(&(files.len() as u32).to_le_bytes())?;
+    for (entry, _) in files {
+        if !entry.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Bundle entry {:?} is too large", entry.name),
+            ));
+        }
+        entry.serialize_to_stream(&mut writer)?;
+    }
+
+    for (entry, source_path) in files {
+        let copied = io::copy(&mut File::open(source_path)?, &mut writer)?;
+        if copied != entry.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("File {:?} changed while it was bundled", source_path),
+            ));
+        }
+    }
+
+    writer.flush()?;
+    drop(writer);
+    file.sync_all()
+}
+
+/// Reads the files of a bundle one by one, in the order of the index
+pub struct BundleReader {
+    reader: BufReader<File>,
+    entries: std::vec::IntoIter<BundleEntry>,
+}
+
+impl BundleReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != BUNDLE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "File isn't a bundle",
+            ));
+        }
+
+        let mut count_bytes = [0; 4];
+        reader.read_exact(&mut count_bytes)?;
+        let count = u32::from_le_bytes(count_bytes);
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            entries.push(BundleEntry::deserialize_from_stream(&mut reader)?);
+        }
+
+        Ok(Self {
+            reader,
+            entries: entries.into_iter(),
+        })
+    }
+
+    /// The entries of the files that weren't read yet
+    pub fn remaining_entries(&self) -> &[BundleEntry] {
+        self.entries.as_slice()
+    }
+
+    /// Copy the contents of the next file into the writer, and return its entry.
+    pub fn next_file(&mut self, writer: &mut impl Write) -> io::Result<Option<BundleEntry>> {
+        let Some(entry) = self.entries.next() else {
+            return Ok(None);
+        };
+
+        let copied = io::copy(&mut (&mut self.reader).take(entry.size), writer)?;
+        if copied != entry.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Bundle ended in the middle of {:?}", entry.name),
+            ));
+        }
+
+        Ok(Some(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDirProvider;
+
+    #[test]
+    fn test_bundle_roundtrip() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+
+        let mut files = Vec::new();
+        for (i, name) in ["a.txt", "camera/b.raw", "empty"].iter().enumerate() {
+            let data = vec![i as u8; i * 100];
+            let path = folder.path().join(format!("{}.bin", i));
+            std::fs::write(&path, &data)?;
+
+            let entry = BundleEntry {
+                name: name.to_string(),
+                date: i as i64,
+                size: data.len() as u64,
+                metadata: BTreeMap::from([("index".to_string(), i.to_string())]),
+            };
+            files.push((entry, path));
+        }
+
+        let bundle_path = folder.path().join(format!("test{}", BUNDLE_SUFFIX));
+        write_bundle(&bundle_path, &files)?;
+
+        let mut reader = BundleReader::open(&bundle_path)?;
+        for (expected_entry, path) in &files {
+            let mut data = Vec::new();
+            let entry = reader.next_file(&mut data)?.unwrap();
+            assert_eq!(&entry, expected_entry);
+            assert_eq!(data, std::fs::read(path)?);
+        }
+        assert!(reader.next_file(&mut Vec::new())?.is_none());
+
+        // A truncated bundle is detected
+        let data = std::fs::read(&bundle_path)?;
+        std::fs::write(&bundle_path, &data[..data.len() - 10])?;
+        let mut reader = BundleReader::open(&bundle_path)?;
+        reader.next_file(&mut Vec::new())?;
+        reader.next_file(&mut Vec::new())?;
+        assert!(reader.next_file(&mut Vec::new()).is_err());
+
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use std::io;
+use std::{collections::BTreeMap, io};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -130,6 +130,94 @@ pub struct HeaderChunk {
     pub part_count: u32,
     pub size: u64,
     pub file_part_size: u32,
+    /// The SHA-256 digest of the whole file, verified by the receiver when assembling it
+    #[serde(default)]
+    pub sha256: Option<[u8; 32]>,
+    /// Custom fields attached by the payload software, e.g. the instrument id or a
+    /// correlation id
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// How the file was compressed by the sender. The size, digest and parts are of the
+    /// compressed file, and the receiver decompresses it after verifying it.
+    #[serde(default)]
+    pub compression: FileCompression,
+    /// Whether the file is a regular file, or a bundle of small files that the receiver
+    /// unpacks
+    #[serde(default)]
+    pub kind: FileKind,
+    /// The file is still growing, e.g. a log that's being written to. New parts are appended
+    /// as it grows, so the part count and size are provisional, and there's no digest yet.
+    /// The sender sends the header again once the file is sealed.
+    #[serde(default)]
+    pub open: bool,
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    #[default]
+    Regular,
+    /// A bundle of small files, see [`crate::bundle`]
+    Bundle,
+}
+
+impl FileKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            FileKind::Regular => 0,
+            FileKind::Bundle => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(FileKind::Regular),
+            1 => Ok(FileKind::Bundle),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown file kind {}", byte),
+            )),
+        }
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileCompression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl FileCompression {
+    fn to_byte(self) -> u8 {
+        match self {
+            FileCompression::None => 0,
+            FileCompression::Zstd => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(FileCompression::None),
+            1 => Ok(FileCompression::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown file compression {}", byte),
+            )),
+        }
+    }
+}
+
+impl HeaderChunk {
+    pub const MAX_METADATA_FIELDS: usize = u16::MAX as usize;
+    pub const MAX_METADATA_KEY_LEN: usize = u8::MAX as usize;
+    pub const MAX_METADATA_VALUE_LEN: usize = u16::MAX as usize;
+    /// The limit of all the metadata fields together when serialized, so that headers stay
+    /// small and always fit in a packet
+    pub const MAX_METADATA_LEN: usize = 4096;
 }
 
 impl BinarySerialize for HeaderChunk {
@@ -147,6 +235,23 @@ impl BinarySerialize for HeaderChunk {
         writer.write_all(&self.size.to_le_bytes())?;
         writer.write_all(&self.file_part_size.to_le_bytes())?;
 
+        match &self.sha256 {
+            Some(digest) => {
+                writer.write_all(&[1])?;
+                writer.write_all(digest)?;
+            }
+            None => {
+                writer.write_all(&[0])?;
+                writer.write_all(&[0; 32])?;
+            }
+        }
+
+        write_metadata(writer, &self.metadata)?;
+
+        writer.write_all(&[self.compression.to_byte()])?;
+        writer.write_all(&[self.kind.to_byte()])?;
+        writer.write_all(&[self.open as u8])?;
+
         Ok(())
     }
 
@@ -158,6 +263,11 @@ impl BinarySerialize for HeaderChunk {
         + 4 // part_count
         + 8 // size
         + 4 // file_part_size
+        + 1 + 32 // sha256
+        + metadata_length(&self.metadata) // metadata
+        + 1 // compression
+        + 1 // kind
+        + 1 // open
     }
 
     fn deserialize_from_stream(reader: &mut impl std::io::Read) -> io::Result<Self> {
@@ -190,6 +300,52 @@ impl BinarySerialize for HeaderChunk {
         reader.read_exact(&mut file_part_size_bytes)?;
         let file_part_size = u32::from_le_bytes(file_part_size_bytes);
 
+        // Headers that were stored before the digest was added end here
+        let mut has_digest_bytes = [0; 1];
+        let sha256 = match reader.read_exact(&mut has_digest_bytes) {
+            Ok(()) => {
+                let mut digest = [0; 32];
+                reader.read_exact(&mut digest)?;
+                (has_digest_bytes[0] != 0).then_some(digest)
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => return Err(e),
+        };
+
+        // Headers that were stored before metadata was added end here
+        let mut field_count_bytes = [0; 2];
+        let field_count = match reader.read_exact(&mut field_count_bytes) {
+            Ok(()) => u16::from_le_bytes(field_count_bytes),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e),
+        };
+
+        let metadata = read_metadata_fields(reader, field_count)?;
+
+        // Headers that were stored before compression was added end here
+        let mut compression_bytes = [0; 1];
+        let compression = match reader.read_exact(&mut compression_bytes) {
+            Ok(()) => FileCompression::from_byte(compression_bytes[0])?,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => FileCompression::None,
+            Err(e) => return Err(e),
+        };
+
+        // Headers that were stored before bundles were added end here
+        let mut kind_bytes = [0; 1];
+        let kind = match reader.read_exact(&mut kind_bytes) {
+            Ok(()) => FileKind::from_byte(kind_bytes[0])?,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => FileKind::Regular,
+            Err(e) => return Err(e),
+        };
+
+        // Headers that were stored before open files were added end here
+        let mut open_bytes = [0; 1];
+        let open = match reader.read_exact(&mut open_bytes) {
+            Ok(()) => open_bytes[0] != 0,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e),
+        };
+
         Ok(Self {
             id,
             name,
@@ -197,13 +353,81 @@ impl BinarySerialize for HeaderChunk {
             part_count,
             size,
             file_part_size,
+            sha256,
+            metadata,
+            compression,
+            kind,
+            open,
         })
     }
 }
 
+/// Metadata is stored as a field count, followed by key-length-value fields
+pub(crate) fn write_metadata(
+    writer: &mut impl io::Write,
+    metadata: &BTreeMap<String, String>,
+) -> io::Result<()> {
+    let field_count = metadata.len() as u16;
+    writer.write_all(&field_count.to_le_bytes())?;
+    for (key, value) in metadata.iter().take(field_count as usize) {
+        writer.write_all(&[key.len() as u8])?;
+        writer.write_all(key.as_bytes())?;
+        writer.write_all(&(value.len() as u16).to_le_bytes())?;
+        writer.write_all(value.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Saturates instead of overflowing, so that a map that's too large fails the validity check
+pub(crate) fn metadata_length(metadata: &BTreeMap<String, String>) -> u32 {
+    let fields_len = metadata
+        .iter()
+        .map(|(key, value)| 1 + key.len() as u64 + 2 + value.len() as u64)
+        .fold(2, u64::saturating_add); // field count
+
+    u32::try_from(fields_len).unwrap_or(u32::MAX)
+}
+
+/// Read the fields that follow the field count written by [`write_metadata`]
+pub(crate) fn read_metadata_fields(
+    reader: &mut impl io::Read,
+    field_count: u16,
+) -> io::Result<BTreeMap<String, String>> {
+    let mut metadata = BTreeMap::new();
+    for _ in 0..field_count {
+        let mut key_len_bytes = [0; 1];
+        reader.read_exact(&mut key_len_bytes)?;
+        let key = read_string(reader, key_len_bytes[0] as usize)?;
+
+        let mut value_len_bytes = [0; 2];
+        reader.read_exact(&mut value_len_bytes)?;
+        let value = read_string(reader, u16::from_le_bytes(value_len_bytes) as usize)?;
+
+        metadata.insert(key, value);
+    }
+
+    Ok(metadata)
+}
+
+pub(crate) fn is_valid_metadata(metadata: &BTreeMap<String, String>) -> bool {
+    metadata.len() <= HeaderChunk::MAX_METADATA_FIELDS
+        && metadata_length(metadata) as usize <= HeaderChunk::MAX_METADATA_LEN
+        && metadata.iter().all(|(key, value)| {
+            key.len() <= HeaderChunk::MAX_METADATA_KEY_LEN
+                && value.len() <= HeaderChunk::MAX_METADATA_VALUE_LEN
+        })
+}
+
+fn read_string(reader: &mut impl io::Read, len: usize) -> io::Result<String> {
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
 impl ValidityCheck for HeaderChunk {
     fn is_valid(&self) -> bool {
-        self.name.len() <= 65535
+        self.name.len() <= 65535 && is_valid_metadata(&self.metadata)
     }
 }
 
@@ -238,6 +462,14 @@ mod tests {
             part_count: 42,
             size: 123456789,
             file_part_size: 1024,
+            sha256: Some([7; 32]),
+            metadata: BTreeMap::from([
+                ("instrument".to_string(), "cam-2".to_string()),
+                ("content_type".to_string(), "image/x-raw".to_string()),
+            ]),
+            compression: FileCompression::Zstd,
+            kind: FileKind::Bundle,
+            open: true,
         };
 
         let mut buffer = Cursor::new(Vec::new());
@@ -247,5 +479,65 @@ mod tests {
         let deserialized_header = HeaderChunk::deserialize_from_stream(&mut buffer).unwrap();
 
         assert_eq!(header, deserialized_header);
+        assert_eq!(
+            buffer.into_inner().len() as u32,
+            header.length_when_serialized()
+        );
+    }
+
+    #[test]
+    fn test_metadata_limit() {
+        let mut header = HeaderChunk {
+            id: Uuid::new_v4(),
+            name: "test".to_string(),
+            date: 123456789,
+            part_count: 42,
+            size: 123456789,
+            file_part_size: 1024,
+            sha256: None,
+            metadata: BTreeMap::from([("notes".to_string(), "a".repeat(4000))]),
+            compression: FileCompression::None,
+            kind: FileKind::Regular,
+            open: false,
+        };
+        assert!(header.is_valid());
+
+        // Each field is within its limits, but not all of them together
+        header
+            .metadata
+            .insert("more_notes".to_string(), "b".repeat(4000));
+        assert!(!header.is_valid());
+    }
+
+    #[test]
+    fn test_legacy_header_without_metadata() {
+        let mut header = HeaderChunk {
+            id: Uuid::new_v4(),
+            name: "test".to_string(),
+            date: 123456789,
+            part_count: 42,
+            size: 123456789,
+            file_part_size: 1024,
+            sha256: Some([7; 32]),
+            metadata: BTreeMap::new(),
+            compression: FileCompression::None,
+            kind: FileKind::Regular,
+            open: false,
+        };
+
+        let mut buffer = Vec::new();
+        header.serialize_to_stream(&mut buffer).unwrap();
+
+        // Drop the open flag, the kind, the compression and the metadata field count, like a
+        // header stored by an older sender
+        buffer.truncate(buffer.len() - 5);
+        let deserialized_header = HeaderChunk::deserialize_from_stream(&mut &buffer[..]).unwrap();
+        assert_eq!(header, deserialized_header);
+
+        // Without the digest too
+        buffer.truncate(buffer.len() - 33);
+        header.sha256 = None;
+        let deserialized_header = HeaderChunk::deserialize_from_stream(&mut &buffer[..]).unwrap();
+        assert_eq!(header, deserialized_header);
     }
 }
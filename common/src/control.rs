@@ -1,7 +1,9 @@
+use std::ops::RangeInclusive;
+
 use uuid::Uuid;
 
 use crate::{
-    binary_serialize::BinarySerialize, file_part_id::FilePartIdRangeInclusive,
+    binary_serialize::BinarySerialize, chunks::HeaderChunk, file_part_id::FilePartIdRangeInclusive,
     validity::ValidityCheck,
 };
 
@@ -11,6 +13,15 @@ pub enum ControlMessage {
     ConfirmPart(ConfirmPart),
     DeleteFile(DeleteFile),
     SetFilePriority(SetFilePriority),
+    TimeTagged(TimeTaggedCommand),
+    CancelTimeTagged(CancelTimeTaggedCommand),
+    ListTimeTagged(ListTimeTaggedCommands),
+    DeleteFilesByFilter(DeleteFilesByFilter),
+    SetPriorityByFilter(SetPriorityByFilter),
+    RequestFileResend(RequestFileResend),
+    RequeueArchivedFile(RequeueArchivedFile),
+    ListCatalog(ListCatalog),
+    RequestCatalogedFile(RequestCatalogedFile),
 }
 
 impl std::fmt::Display for ControlMessage {
@@ -33,6 +44,186 @@ impl std::fmt::Display for ControlMessage {
                 "ControlMessage::SetFilePriority {{ file_id: {}, priority: {} }}",
                 msg.file_id, msg.priority,
             ),
+            ControlMessage::TimeTagged(msg) => write!(
+                f,
+                "ControlMessage::TimeTagged {{ command_id: {}, execute_at: {}, message: {} }}",
+                msg.command_id, msg.execute_at, msg.message,
+            ),
+            ControlMessage::CancelTimeTagged(msg) => write!(
+                f,
+                "ControlMessage::CancelTimeTagged {{ command_id: {} }}",
+                msg.command_id,
+            ),
+            ControlMessage::ListTimeTagged(_) => write!(f, "ControlMessage::ListTimeTagged"),
+            ControlMessage::DeleteFilesByFilter(msg) => write!(
+                f,
+                "ControlMessage::DeleteFilesByFilter {{ filter: {} }}",
+                msg.filter,
+            ),
+            ControlMessage::SetPriorityByFilter(msg) => write!(
+                f,
+                "ControlMessage::SetPriorityByFilter {{ filter: {}, priority: {} }}",
+                msg.filter, msg.priority,
+            ),
+            ControlMessage::RequestFileResend(msg) => write!(
+                f,
+                "ControlMessage::RequestFileResend {{ file_id: {} }}",
+                msg.file_id,
+            ),
+            ControlMessage::RequeueArchivedFile(msg) => write!(
+                f,
+                "ControlMessage::RequeueArchivedFile {{ file_id: {}, priority: {} }}",
+                msg.file_id, msg.priority,
+            ),
+            ControlMessage::ListCatalog(_) => write!(f, "ControlMessage::ListCatalog"),
+            ControlMessage::RequestCatalogedFile(msg) => write!(
+                f,
+                "ControlMessage::RequestCatalogedFile {{ entry_id: {}, priority: {} }}",
+                msg.entry_id, msg.priority,
+            ),
+        }
+    }
+}
+
+impl ControlMessage {
+    /// The lowest type id of a control message. The ids below are for the other transport
+    /// packets.
+    pub const MIN_TYPE_ID: u8 = 128;
+
+    /// The type id of the message, which it's serialized with both as a transport packet and
+    /// when it's nested inside another message
+    fn type_id(&self) -> u8 {
+        match self {
+            ControlMessage::ConfirmPart(_) => 128,
+            ControlMessage::DeleteFile(_) => 129,
+            ControlMessage::SetFilePriority(_) => 130,
+            ControlMessage::TimeTagged(_) => 131,
+            ControlMessage::CancelTimeTagged(_) => 132,
+            ControlMessage::ListTimeTagged(_) => 133,
+            ControlMessage::DeleteFilesByFilter(_) => 134,
+            ControlMessage::SetPriorityByFilter(_) => 135,
+            ControlMessage::RequestFileResend(_) => 136,
+            ControlMessage::RequeueArchivedFile(_) => 137,
+            ControlMessage::ListCatalog(_) => 138,
+            ControlMessage::RequestCatalogedFile(_) => 139,
+        }
+    }
+}
+
+impl BinarySerialize for ControlMessage {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        writer.write_all(&[self.type_id()])?;
+
+        match self {
+            ControlMessage::ConfirmPart(msg) => msg.serialize_to_stream(writer),
+            ControlMessage::DeleteFile(msg) => msg.serialize_to_stream(writer),
+            ControlMessage::SetFilePriority(msg) => msg.serialize_to_stream(writer),
+            ControlMessage::TimeTagged(msg) => msg.serialize_to_stream(writer),
+            ControlMessage::CancelTimeTagged(msg) => msg.serialize_to_stream(writer),
+            ControlMessage::ListTimeTagged(msg) => msg.serialize_to_stream(writer),
+            ControlMessage::DeleteFilesByFilter(msg) => msg.serialize_to_stream(writer),
+            ControlMessage::SetPriorityByFilter(msg) => msg.serialize_to_stream(writer),
+            ControlMessage::RequestFileResend(msg) => msg.serialize_to_stream(writer),
+            ControlMessage::RequeueArchivedFile(msg) => msg.serialize_to_stream(writer),
+            ControlMessage::ListCatalog(msg) => msg.serialize_to_stream(writer),
+            ControlMessage::RequestCatalogedFile(msg) => msg.serialize_to_stream(writer),
+        }
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        let inner = match self {
+            ControlMessage::ConfirmPart(msg) => msg.length_when_serialized(),
+            ControlMessage::DeleteFile(msg) => msg.length_when_serialized(),
+            ControlMessage::SetFilePriority(msg) => msg.length_when_serialized(),
+            ControlMessage::TimeTagged(msg) => msg.length_when_serialized(),
+            ControlMessage::CancelTimeTagged(msg) => msg.length_when_serialized(),
+            ControlMessage::ListTimeTagged(msg) => msg.length_when_serialized(),
+            ControlMessage::DeleteFilesByFilter(msg) => msg.length_when_serialized(),
+            ControlMessage::SetPriorityByFilter(msg) => msg.length_when_serialized(),
+            ControlMessage::RequestFileResend(msg) => msg.length_when_serialized(),
+            ControlMessage::RequeueArchivedFile(msg) => msg.length_when_serialized(),
+            ControlMessage::ListCatalog(msg) => msg.length_when_serialized(),
+            ControlMessage::RequestCatalogedFile(msg) => msg.length_when_serialized(),
+        };
+
+        1 // Type
+        + inner
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut type_buf = [0u8; 1];
+        reader.read_exact(&mut type_buf)?;
+
+        Self::deserialize_with_type(type_buf[0], reader)
+    }
+}
+
+impl ControlMessage {
+    /// Deserialize the message that follows a type id that was already read, e.g. by the
+    /// transport packet parser.
+    pub(crate) fn deserialize_with_type(
+        type_: u8,
+        reader: &mut impl std::io::Read,
+    ) -> std::io::Result<Self> {
+        let message = match type_ {
+            128 => ControlMessage::ConfirmPart(ConfirmPart::deserialize_from_stream(reader)?),
+            129 => ControlMessage::DeleteFile(DeleteFile::deserialize_from_stream(reader)?),
+            130 => {
+                ControlMessage::SetFilePriority(SetFilePriority::deserialize_from_stream(reader)?)
+            }
+            131 => ControlMessage::TimeTagged(TimeTaggedCommand::deserialize_from_stream(reader)?),
+            132 => ControlMessage::CancelTimeTagged(
+                CancelTimeTaggedCommand::deserialize_from_stream(reader)?,
+            ),
+            133 => ControlMessage::ListTimeTagged(ListTimeTaggedCommands::deserialize_from_stream(
+                reader,
+            )?),
+            134 => ControlMessage::DeleteFilesByFilter(
+                DeleteFilesByFilter::deserialize_from_stream(reader)?,
+            ),
+            135 => ControlMessage::SetPriorityByFilter(
+                SetPriorityByFilter::deserialize_from_stream(reader)?,
+            ),
+            136 => ControlMessage::RequestFileResend(RequestFileResend::deserialize_from_stream(
+                reader,
+            )?),
+            137 => ControlMessage::RequeueArchivedFile(
+                RequeueArchivedFile::deserialize_from_stream(reader)?,
+            ),
+            138 => ControlMessage::ListCatalog(ListCatalog::deserialize_from_stream(reader)?),
+            139 => ControlMessage::RequestCatalogedFile(
+                RequestCatalogedFile::deserialize_from_stream(reader)?,
+            ),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid control message type {}", type_),
+                ))
+            }
+        };
+
+        Ok(message)
+    }
+}
+
+impl ValidityCheck for ControlMessage {
+    fn is_valid(&self) -> bool {
+        match self {
+            ControlMessage::ConfirmPart(msg) => msg.is_valid(),
+            ControlMessage::DeleteFile(msg) => msg.is_valid(),
+            ControlMessage::SetFilePriority(msg) => msg.is_valid(),
+            ControlMessage::TimeTagged(msg) => msg.is_valid(),
+            ControlMessage::CancelTimeTagged(msg) => msg.is_valid(),
+            ControlMessage::ListTimeTagged(msg) => msg.is_valid(),
+            ControlMessage::DeleteFilesByFilter(msg) => msg.is_valid(),
+            ControlMessage::SetPriorityByFilter(msg) => msg.is_valid(),
+            ControlMessage::RequestFileResend(msg) => msg.is_valid(),
+            ControlMessage::RequeueArchivedFile(msg) => msg.is_valid(),
+            ControlMessage::ListCatalog(msg) => msg.is_valid(),
+            ControlMessage::RequestCatalogedFile(msg) => msg.is_valid(),
         }
     }
 }
@@ -158,6 +349,564 @@ impl ValidityCheck for SetFilePriority {
     }
 }
 
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Execute a control message once the sender's clock reaches `execute_at`. The command is
+/// persisted by the sender until it's executed or cancelled.
+pub struct TimeTaggedCommand {
+    /// Ground-assigned id, used to cancel the command. Scheduling a command with an id that's
+    /// already pending replaces the pending command.
+    pub command_id: u32,
+    /// Nanoseconds since the unix epoch, in the sender's clock
+    pub execute_at: i64,
+    /// The message to execute. Can't be another time-tagged command.
+    pub message: Box<ControlMessage>,
+}
+
+impl BinarySerialize for TimeTaggedCommand {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        writer.write_all(&self.command_id.to_le_bytes())?;
+        writer.write_all(&self.execute_at.to_le_bytes())?;
+        self.message.serialize_to_stream(writer)?;
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        4 // command_id
+        + 8 // execute_at
+        + self.message.length_when_serialized()
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut command_id_bytes = [0u8; 4];
+        reader.read_exact(&mut command_id_bytes)?;
+        let command_id = u32::from_le_bytes(command_id_bytes);
+
+        let mut execute_at_bytes = [0u8; 8];
+        reader.read_exact(&mut execute_at_bytes)?;
+        let execute_at = i64::from_le_bytes(execute_at_bytes);
+
+        let message = ControlMessage::deserialize_from_stream(reader)?;
+
+        // Nesting isn't allowed, this also stops malicious data from recursing endlessly
+        if let ControlMessage::TimeTagged(_) = message {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Time-tagged commands can't be nested",
+            ));
+        }
+
+        Ok(TimeTaggedCommand {
+            command_id,
+            execute_at,
+            message: Box::new(message),
+        })
+    }
+}
+
+impl ValidityCheck for TimeTaggedCommand {
+    fn is_valid(&self) -> bool {
+        !matches!(*self.message, ControlMessage::TimeTagged(_)) && self.message.is_valid()
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Cancel a pending time-tagged command
+pub struct CancelTimeTaggedCommand {
+    pub command_id: u32,
+}
+
+impl BinarySerialize for CancelTimeTaggedCommand {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        writer.write_all(&self.command_id.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        4
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut command_id_bytes = [0u8; 4];
+        reader.read_exact(&mut command_id_bytes)?;
+        let command_id = u32::from_le_bytes(command_id_bytes);
+
+        Ok(CancelTimeTaggedCommand { command_id })
+    }
+}
+
+impl ValidityCheck for CancelTimeTaggedCommand {
+    fn is_valid(&self) -> bool {
+        true
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Request the list of pending time-tagged commands to be sent on the next downlink
+pub struct ListTimeTaggedCommands;
+
+impl BinarySerialize for ListTimeTaggedCommands {
+    fn serialize_to_stream(&self, _writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        0
+    }
+
+    fn deserialize_from_stream(_reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(ListTimeTaggedCommands)
+    }
+}
+
+impl ValidityCheck for ListTimeTaggedCommands {
+    fn is_valid(&self) -> bool {
+        true
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+/// A filter selecting stored files for bulk operations. A file matches if it matches every
+/// filter field that's set. An empty filter matches every file.
+pub struct FileFilter {
+    /// Glob pattern on the file name, e.g. `*.png`
+    pub name_glob: Option<String>,
+    /// Inclusive range of the header date, in nanoseconds since the unix epoch
+    pub date_range: Option<RangeInclusive<i64>>,
+    /// Inclusive range of the file size, in bytes
+    pub size_range: Option<RangeInclusive<u64>>,
+    /// The input source that the file came from
+    pub source: Option<String>,
+    /// The producer-defined group of the file, set with a sidecar
+    pub group: Option<String>,
+}
+
+impl FileFilter {
+    const FLAG_NAME_GLOB: u8 = 1 << 0;
+    const FLAG_DATE_RANGE: u8 = 1 << 1;
+    const FLAG_SIZE_RANGE: u8 = 1 << 2;
+    const FLAG_SOURCE: u8 = 1 << 3;
+    const FLAG_GROUP: u8 = 1 << 4;
+
+    /// Whether no filter field is set, so that the filter matches every file
+    pub fn is_empty(&self) -> bool {
+        self.name_glob.is_none()
+            && self.date_range.is_none()
+            && self.size_range.is_none()
+            && self.source.is_none()
+            && self.group.is_none()
+    }
+
+    pub fn matches(&self, header: &HeaderChunk, source: Option<&str>, group: Option<&str>) -> bool {
+        if let Some(name_glob) = &self.name_glob {
+            let pattern = match glob::Pattern::new(name_glob) {
+                Ok(pattern) => pattern,
+                Err(err) => {
+                    tracing::warn!("Invalid file filter glob pattern {:?}: {}", name_glob, err);
+                    return false;
+                }
+            };
+
+            if !pattern.matches(&header.name) {
+                return false;
+            }
+        }
+
+        if let Some(date_range) = &self.date_range {
+            if !date_range.contains(&header.date) {
+                return false;
+            }
+        }
+
+        if let Some(size_range) = &self.size_range {
+            if !size_range.contains(&header.size) {
+                return false;
+            }
+        }
+
+        if let Some(filter_source) = &self.source {
+            if source != Some(filter_source.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(filter_group) = &self.group {
+            if group != Some(filter_group.as_str()) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.name_glob.is_some() {
+            flags |= Self::FLAG_NAME_GLOB;
+        }
+        if self.date_range.is_some() {
+            flags |= Self::FLAG_DATE_RANGE;
+        }
+        if self.size_range.is_some() {
+            flags |= Self::FLAG_SIZE_RANGE;
+        }
+        if self.source.is_some() {
+            flags |= Self::FLAG_SOURCE;
+        }
+        if self.group.is_some() {
+            flags |= Self::FLAG_GROUP;
+        }
+        flags
+    }
+}
+
+impl std::fmt::Display for FileFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "FileFilter {{ name_glob: {:?}, date_range: {:?}, size_range: {:?}, source: {:?}, group: {:?} }}",
+            self.name_glob, self.date_range, self.size_range, self.source, self.group,
+        )
+    }
+}
+
+fn write_short_string(writer: &mut impl std::io::Write, value: &str) -> std::io::Result<()> {
+    let bytes = value.as_bytes();
+    writer.write_all(&(bytes.len() as u16).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_short_string(reader: &mut impl std::io::Read) -> std::io::Result<String> {
+    let mut len_bytes = [0u8; 2];
+    reader.read_exact(&mut len_bytes)?;
+    let len = u16::from_le_bytes(len_bytes);
+
+    let mut bytes = vec![0u8; len as usize];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+impl BinarySerialize for FileFilter {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        writer.write_all(&[self.flags()])?;
+
+        if let Some(name_glob) = &self.name_glob {
+            write_short_string(writer, name_glob)?;
+        }
+        if let Some(date_range) = &self.date_range {
+            writer.write_all(&date_range.start().to_le_bytes())?;
+            writer.write_all(&date_range.end().to_le_bytes())?;
+        }
+        if let Some(size_range) = &self.size_range {
+            writer.write_all(&size_range.start().to_le_bytes())?;
+            writer.write_all(&size_range.end().to_le_bytes())?;
+        }
+        if let Some(source) = &self.source {
+            write_short_string(writer, source)?;
+        }
+        if let Some(group) = &self.group {
+            write_short_string(writer, group)?;
+        }
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        1 // flags
+        + self.name_glob.as_ref().map_or(0, |s| 2 + s.len() as u32)
+        + self.date_range.as_ref().map_or(0, |_| 8 + 8)
+        + self.size_range.as_ref().map_or(0, |_| 8 + 8)
+        + self.source.as_ref().map_or(0, |s| 2 + s.len() as u32)
+        + self.group.as_ref().map_or(0, |s| 2 + s.len() as u32)
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut flags_bytes = [0u8; 1];
+        reader.read_exact(&mut flags_bytes)?;
+        let flags = flags_bytes[0];
+
+        let mut filter = FileFilter::default();
+
+        if flags & Self::FLAG_NAME_GLOB != 0 {
+            filter.name_glob = Some(read_short_string(reader)?);
+        }
+        if flags & Self::FLAG_DATE_RANGE != 0 {
+            let mut start_bytes = [0u8; 8];
+            reader.read_exact(&mut start_bytes)?;
+            let mut end_bytes = [0u8; 8];
+            reader.read_exact(&mut end_bytes)?;
+            filter.date_range =
+                Some(i64::from_le_bytes(start_bytes)..=i64::from_le_bytes(end_bytes));
+        }
+        if flags & Self::FLAG_SIZE_RANGE != 0 {
+            let mut start_bytes = [0u8; 8];
+            reader.read_exact(&mut start_bytes)?;
+            let mut end_bytes = [0u8; 8];
+            reader.read_exact(&mut end_bytes)?;
+            filter.size_range =
+                Some(u64::from_le_bytes(start_bytes)..=u64::from_le_bytes(end_bytes));
+        }
+        if flags & Self::FLAG_SOURCE != 0 {
+            filter.source = Some(read_short_string(reader)?);
+        }
+        if flags & Self::FLAG_GROUP != 0 {
+            filter.group = Some(read_short_string(reader)?);
+        }
+
+        Ok(filter)
+    }
+}
+
+impl ValidityCheck for FileFilter {
+    fn is_valid(&self) -> bool {
+        self.name_glob.as_ref().is_none_or(|s| s.len() <= 65535)
+            && self.source.as_ref().is_none_or(|s| s.len() <= 65535)
+            && self.group.as_ref().is_none_or(|s| s.len() <= 65535)
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Delete every file in the ready folder that matches the filter. An empty filter is invalid,
+/// so that a corrupted or mistaken command can't wipe the whole storage.
+pub struct DeleteFilesByFilter {
+    pub filter: FileFilter,
+}
+
+impl BinarySerialize for DeleteFilesByFilter {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        self.filter.serialize_to_stream(writer)
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        self.filter.length_when_serialized()
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let filter = FileFilter::deserialize_from_stream(reader)?;
+
+        Ok(DeleteFilesByFilter { filter })
+    }
+}
+
+impl ValidityCheck for DeleteFilesByFilter {
+    fn is_valid(&self) -> bool {
+        self.filter.is_valid() && !self.filter.is_empty()
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Set the priority of every file (for every part in the file) that matches the filter
+pub struct SetPriorityByFilter {
+    pub filter: FileFilter,
+    pub priority: i16,
+}
+
+impl BinarySerialize for SetPriorityByFilter {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        self.filter.serialize_to_stream(writer)?;
+        writer.write_all(&self.priority.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        self.filter.length_when_serialized() + 2
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let filter = FileFilter::deserialize_from_stream(reader)?;
+
+        let mut priority_bytes = [0u8; 2];
+        reader.read_exact(&mut priority_bytes)?;
+        let priority = i16::from_le_bytes(priority_bytes);
+
+        Ok(SetPriorityByFilter { filter, priority })
+    }
+}
+
+impl ValidityCheck for SetPriorityByFilter {
+    fn is_valid(&self) -> bool {
+        self.filter.is_valid()
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Ask for a file to be sent again in full, e.g. because it failed verification on the ground
+pub struct RequestFileResend {
+    pub file_id: Uuid,
+}
+
+impl BinarySerialize for RequestFileResend {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        writer.write_all(self.file_id.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        16
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut id = [0u8; 16];
+        reader.read_exact(&mut id)?;
+        let file_id = Uuid::from_bytes(id);
+
+        Ok(RequestFileResend { file_id })
+    }
+}
+
+impl ValidityCheck for RequestFileResend {
+    fn is_valid(&self) -> bool {
+        true
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Send a file from the sender's archive again, e.g. because the ground lost it after it was
+/// acknowledged. It's sent as a new file with a new id, as the receiver already finished the
+/// archived one.
+pub struct RequeueArchivedFile {
+    pub file_id: Uuid,
+    pub priority: i16,
+}
+
+impl BinarySerialize for RequeueArchivedFile {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        writer.write_all(self.file_id.as_bytes())?;
+        writer.write_all(&self.priority.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        16 + 2
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut id = [0u8; 16];
+        reader.read_exact(&mut id)?;
+        let file_id = Uuid::from_bytes(id);
+
+        let mut priority_bytes = [0u8; 2];
+        reader.read_exact(&mut priority_bytes)?;
+        let priority = i16::from_le_bytes(priority_bytes);
+
+        Ok(RequeueArchivedFile { file_id, priority })
+    }
+}
+
+impl ValidityCheck for RequeueArchivedFile {
+    fn is_valid(&self) -> bool {
+        true
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Request the whole catalog of files that are only sent on request, to be sent on the next
+/// downlink
+pub struct ListCatalog;
+
+impl BinarySerialize for ListCatalog {
+    fn serialize_to_stream(&self, _writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        0
+    }
+
+    fn deserialize_from_stream(_reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(ListCatalog)
+    }
+}
+
+impl ValidityCheck for ListCatalog {
+    fn is_valid(&self) -> bool {
+        true
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Queue a catalogued file for downlink, with all its parts at the given priority
+pub struct RequestCatalogedFile {
+    pub entry_id: Uuid,
+    pub priority: i16,
+}
+
+impl BinarySerialize for RequestCatalogedFile {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        writer.write_all(self.entry_id.as_bytes())?;
+        writer.write_all(&self.priority.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        16 + 2
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut id = [0u8; 16];
+        reader.read_exact(&mut id)?;
+        let entry_id = Uuid::from_bytes(id);
+
+        let mut priority_bytes = [0u8; 2];
+        reader.read_exact(&mut priority_bytes)?;
+        let priority = i16::from_le_bytes(priority_bytes);
+
+        Ok(RequestCatalogedFile { entry_id, priority })
+    }
+}
+
+impl ValidityCheck for RequestCatalogedFile {
+    fn is_valid(&self) -> bool {
+        true
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
@@ -199,4 +948,129 @@ mod tests {
 
         assert_eq!(msg, deserialized_msg);
     }
+
+    #[test]
+    fn test_time_tagged_serialization() {
+        let msg = TimeTaggedCommand {
+            command_id: 42,
+            execute_at: 1_700_000_000_000_000_000,
+            message: Box::new(ControlMessage::SetFilePriority(SetFilePriority {
+                file_id: Uuid::new_v4(),
+                priority: 5,
+            })),
+        };
+
+        let mut buf = Vec::new();
+        msg.serialize_to_stream(&mut buf).unwrap();
+        assert_eq!(buf.len() as u32, msg.length_when_serialized());
+
+        let mut cursor = Cursor::new(buf);
+        let deserialized_msg = TimeTaggedCommand::deserialize_from_stream(&mut cursor).unwrap();
+
+        assert_eq!(msg, deserialized_msg);
+    }
+
+    #[test]
+    fn test_nested_time_tagged_rejected() {
+        let inner = TimeTaggedCommand {
+            command_id: 1,
+            execute_at: 0,
+            message: Box::new(ControlMessage::ListTimeTagged(ListTimeTaggedCommands)),
+        };
+        let msg = TimeTaggedCommand {
+            command_id: 2,
+            execute_at: 0,
+            message: Box::new(ControlMessage::TimeTagged(inner)),
+        };
+        assert!(!msg.is_valid());
+
+        let mut buf = Vec::new();
+        msg.serialize_to_stream(&mut buf).unwrap();
+
+        let mut cursor = Cursor::new(buf);
+        assert!(TimeTaggedCommand::deserialize_from_stream(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn test_set_priority_by_filter_serialization() {
+        let msg = SetPriorityByFilter {
+            filter: FileFilter {
+                name_glob: Some("*.png".to_string()),
+                date_range: Some(100..=200),
+                size_range: None,
+                source: Some("camera".to_string()),
+                group: Some("capture-42".to_string()),
+            },
+            priority: -3,
+        };
+
+        let mut buf = Vec::new();
+        msg.serialize_to_stream(&mut buf).unwrap();
+        assert_eq!(buf.len() as u32, msg.length_when_serialized());
+
+        let mut cursor = Cursor::new(buf);
+        let deserialized_msg = SetPriorityByFilter::deserialize_from_stream(&mut cursor).unwrap();
+
+        assert_eq!(msg, deserialized_msg);
+    }
+
+    #[test]
+    fn test_delete_files_by_empty_filter_rejected() {
+        let msg = DeleteFilesByFilter {
+            filter: FileFilter::default(),
+        };
+        assert!(!msg.is_valid());
+        assert!(!ControlMessage::DeleteFilesByFilter(msg).is_valid());
+
+        let msg = DeleteFilesByFilter {
+            filter: FileFilter {
+                group: Some("capture-42".to_string()),
+                ..Default::default()
+            },
+        };
+        assert!(msg.is_valid());
+    }
+
+    #[test]
+    fn test_file_filter_matching() {
+        let header = HeaderChunk {
+            id: Uuid::new_v4(),
+            name: "img_001.raw".to_string(),
+            date: 150,
+            part_count: 1,
+            size: 1000,
+            file_part_size: 1024,
+            sha256: None,
+            metadata: Default::default(),
+            compression: Default::default(),
+            kind: Default::default(),
+            open: false,
+        };
+
+        assert!(FileFilter::default().matches(&header, None, None));
+
+        let filter = FileFilter {
+            name_glob: Some("img_*.raw".to_string()),
+            date_range: Some(100..=200),
+            size_range: Some(0..=1000),
+            source: Some("camera".to_string()),
+            group: Some("capture-42".to_string()),
+        };
+        assert!(filter.matches(&header, Some("camera"), Some("capture-42")));
+        assert!(!filter.matches(&header, Some("camera"), Some("capture-43")));
+        assert!(!filter.matches(&header, Some("telemetry"), Some("capture-42")));
+        assert!(!filter.matches(&header, None, None));
+
+        let filter = FileFilter {
+            name_glob: Some("*.png".to_string()),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&header, None, None));
+
+        let filter = FileFilter {
+            size_range: Some(1001..=2000),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&header, None, None));
+    }
 }
@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{binary_serialize::BinarySerialize, validity::ValidityCheck};
 
-#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
 pub enum FilePartId {
     Header,
     Part(u32),
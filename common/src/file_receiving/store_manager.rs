@@ -1,16 +1,18 @@
 use std::{
-    collections::HashMap,
-    fs::File,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{File, OpenOptions},
     io::{self, Write},
     path::{Path, PathBuf},
 };
 
 use crate::{
-    chunks::{Chunk, DataChunk, HeaderChunk},
-    control::{ConfirmPart, ControlMessage},
+    bundle::BundleReader,
+    chunks::{Chunk, DataChunk, FileCompression, FileKind, HeaderChunk},
+    control::{ConfirmPart, ControlMessage, RequestFileResend},
     file_part_id::FilePartId,
 };
 use anyhow::Context;
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 // Received file folder structure
@@ -18,32 +20,74 @@ use uuid::Uuid;
 //     header.json        - The header of the file, if received (can be absent)
 //     [part index].bin   - The received parts of the file, added as they are received
 //     finished           - A file is finished when this file exists. This is for tracking files that were historically completed, but the confirmation was lost.
-
-// A file is finished when all the parts are present
+//     assembled.tmp      - The file while it's being assembled and verified, before it's moved to the output folder
+//     decompressed.tmp   - The decompressed file, for files that were compressed by the sender
+//     bundle-entry.tmp   - A file that's being unpacked from a bundle, before it's moved to the output folder
+//     metadata.tmp       - The custom metadata of the header, before it's moved to the output folder
+
+// A file is finished when all the parts are present. When it's assembled, it's verified against the
+// digest in the header. Files that fail verification are moved to the quarantine folder, their parts
+// are deleted, and a full resend is requested. The header is only confirmed once the file was verified,
+// so that the sender keeps the file until then. Files with custom metadata get a `[name].meta.json`
+// next to them in the output folder, which is written before the file itself.
+
+// Open files are still growing on the sender, and aren't finished until their header arrives again
+// without the open mark. If enabled, the parts of an open file that arrived so far, as far as they're
+// contiguous from the start, are written to `[name].partial` in the output folder. The partial file
+// is removed once the file is finished.
+
+// File names can contain subfolders separated by `/`, e.g. `camera/2026-10-16/img_001.raw`. The same
+// subfolders are created in the output folder. Names are sanitized so that files always end up inside
+// the output folder.
 
 pub struct ReceivingStoreManager {
     workdir_folder: PathBuf,
     result_folder: PathBuf,
+    quarantine_folder: PathBuf,
+
+    /// Write the received start of open files to the output folder
+    partial_output: bool,
 
     confirmed_parts: HashMap<Uuid, Vec<FilePartId>>,
     finished_files: Vec<PathBuf>,
+    /// Control messages other than confirmations, e.g. resend requests
+    pending_control_messages: Vec<ControlMessage>,
+    /// Folders of files that failed to be written to the output folder, e.g. bundles that
+    /// couldn't be unpacked. They're left in the workdir, and only tried again after a restart.
+    failed_file_folders: HashSet<PathBuf>,
 }
 
 impl ReceivingStoreManager {
-    pub fn new(workdir_folder: PathBuf, result_folder: PathBuf) -> anyhow::Result<Self> {
-        // Ensure that both folders exist
+    pub fn new(
+        workdir_folder: PathBuf,
+        result_folder: PathBuf,
+        quarantine_folder: PathBuf,
+    ) -> anyhow::Result<Self> {
+        // Ensure that all the folders exist
         std::fs::create_dir_all(&workdir_folder)?;
         std::fs::create_dir_all(&result_folder)?;
+        std::fs::create_dir_all(&quarantine_folder)?;
 
         Ok(Self {
             workdir_folder,
             result_folder,
+            quarantine_folder,
+
+            partial_output: false,
 
             confirmed_parts: HashMap::new(),
             finished_files: Vec::new(),
+            pending_control_messages: Vec::new(),
+            failed_file_folders: HashSet::new(),
         })
     }
 
+    /// Write the parts of open files that arrived so far to `[name].partial` in the output
+    /// folder, and keep it up to date as more parts arrive. Off by default.
+    pub fn set_partial_output(&mut self, enabled: bool) {
+        self.partial_output = enabled;
+    }
+
     fn get_data_folder_path_for_id(&self, file_id: Uuid) -> PathBuf {
         self.workdir_folder.join(file_id.to_string())
     }
@@ -68,17 +112,14 @@ impl ReceivingStoreManager {
             return Ok(());
         }
 
+        // The parts are only confirmed once the file is verified, see `output_finished_files`.
+        // The sender frees the confirmed parts, so until then it can still resend all of them
+        // if the file fails verification.
         match chunk {
-            Chunk::Header(header_chunk) => {
-                managed_file.receive_header_chunk(header_chunk)?;
-            }
-            Chunk::Data(data_chunk) => {
-                managed_file.receive_data_chunk(data_chunk)?;
-            }
+            Chunk::Header(header_chunk) => managed_file.receive_header_chunk(header_chunk)?,
+            Chunk::Data(data_chunk) => managed_file.receive_data_chunk(data_chunk)?,
         }
 
-        self.add_confirmation_for_file_part(file_id, part_index);
-
         Ok(())
     }
 
@@ -101,18 +142,62 @@ impl ReceivingStoreManager {
         let file_folders = self.get_all_file_folders()?;
 
         for file_folder in file_folders {
-            let managed_file = ManagedReceivingFile::open_or_create(file_folder)?;
-
-            if managed_file.is_finished()? {
+            if self.failed_file_folders.contains(&file_folder) {
                 continue;
             }
 
-            if managed_file.is_file_data_finished()? {
-                let path =
-                    managed_file.write_finished_file_to_output_folder(&self.result_folder)?;
+            // One file that can't be written, e.g. a corrupt bundle, mustn't hold up the others
+            if let Err(err) = self.output_finished_file(file_folder.clone()) {
+                tracing::error!(
+                    "Failed to output file in {:?}, skipping it until a restart: {:?}",
+                    file_folder,
+                    err
+                );
+                self.failed_file_folders.insert(file_folder);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn output_finished_file(&mut self, file_folder: PathBuf) -> anyhow::Result<()> {
+        let managed_file = ManagedReceivingFile::open_or_create(file_folder)?;
+
+        if managed_file.is_finished()? {
+            return Ok(());
+        }
 
-                self.finished_files.push(path);
+        if managed_file.is_file_data_finished()? {
+            let header = managed_file.get_header()?;
+            let file_id = header.id;
+            let result = managed_file.write_finished_file_to_output_folder(
+                &self.result_folder,
+                &self.quarantine_folder,
+            )?;
+
+            match result {
+                AssembledFile::Verified(paths) => {
+                    self.finished_files.extend(paths);
+                    self.add_confirmation_for_file_part(file_id, FilePartId::Header);
+                    for part_id in 0..header.part_count {
+                        self.add_confirmation_for_file_part(file_id, FilePartId::Part(part_id));
+                    }
+                }
+                AssembledFile::Quarantined(path) => {
+                    tracing::error!(
+                        "File {} failed verification, quarantined at {:?}. Requesting a resend.",
+                        file_id,
+                        path
+                    );
+
+                    self.pending_control_messages
+                        .push(ControlMessage::RequestFileResend(RequestFileResend {
+                            file_id,
+                        }));
+                }
             }
+        } else if self.partial_output {
+            managed_file.update_partial_output(&self.result_folder)?;
         }
 
         Ok(())
@@ -121,7 +206,8 @@ impl ReceivingStoreManager {
     pub fn iter_control_messages(&mut self) -> impl Iterator<Item = ControlMessage> + '_ {
         #![allow(clippy::unnecessary_unwrap)]
 
-        self.confirmed_parts
+        let confirmations = self
+            .confirmed_parts
             .drain()
             .flat_map(|(file_id, mut parts)| {
                 // Sort the parts
@@ -155,7 +241,9 @@ impl ReceivingStoreManager {
                         part_range: crate::file_part_id::FilePartIdRangeInclusive::new(from, to),
                     })
                 })
-            })
+            });
+
+        self.pending_control_messages.drain(..).chain(confirmations)
     }
 
     pub fn iter_finished_files(&mut self) -> impl Iterator<Item = PathBuf> + '_ {
@@ -163,6 +251,17 @@ impl ReceivingStoreManager {
     }
 }
 
+/// The result of assembling a finished file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssembledFile {
+    /// The file matched its header, and was written to the output folder. Bundles are
+    /// unpacked into several files.
+    Verified(Vec<PathBuf>),
+
+    /// The file didn't match its header, and was moved to the quarantine folder
+    Quarantined(PathBuf),
+}
+
 pub struct ManagedReceivingFile {
     path: PathBuf,
 }
@@ -188,6 +287,10 @@ impl ManagedReceivingFile {
         }
 
         let header: HeaderChunk = self.get_header()?;
+        if header.open {
+            return Ok(false);
+        }
+
         for part_index in 0..header.part_count {
             let part_path = self.get_bin_path(part_index);
 
@@ -200,6 +303,11 @@ impl ManagedReceivingFile {
     }
 
     pub fn receive_header_chunk(&self, chunk: HeaderChunk) -> anyhow::Result<()> {
+        // A header from while the file was open can arrive after the sealed one
+        if chunk.open && self.get_header_json_path().exists() && !self.get_header()?.open {
+            return Ok(());
+        }
+
         let header_json_tmp_path = self.get_header_json_path().with_extension(".json.tmp");
         let mut header_json_file = File::create(&header_json_tmp_path)
             .context("Failed to create header json file in destination folder")?;
@@ -227,27 +335,83 @@ impl ManagedReceivingFile {
     pub fn write_finished_file_to_output_folder(
         self,
         output_folder: &Path,
-    ) -> anyhow::Result<PathBuf> {
+        quarantine_folder: &Path,
+    ) -> anyhow::Result<AssembledFile> {
         let header: HeaderChunk = self.get_header()?;
 
-        let filename = header.name;
+        // Assemble the file in the workdir first, so that unverified data never shows up in
+        // the output folder
+        let assembled_path = self.get_assembled_file_path();
+        let mut assembled = File::create(&assembled_path)?;
+        let mut hasher = Sha256::new();
+        let mut assembled_size = 0u64;
+        for part_index in 0..header.part_count {
+            let part_path = self.get_bin_path(part_index);
+            let data = std::fs::read(part_path)?;
 
-        // Find a valid filename for the output folder, adding `(n)` to the end if necessary
-        let mut output_path = output_folder.join(&filename);
-        let mut i = 0;
-        while output_path.exists() {
-            i += 1;
-            output_path = output_folder.join(format!("{} ({})", &filename, i));
+            hasher.update(&data);
+            assembled.write_all(&data)?;
+            assembled_size += data.len() as u64;
+        }
+        assembled.sync_all()?;
+        drop(assembled);
+
+        // Files from senders that predate the digest can only be checked by size
+        let digest: [u8; 32] = hasher.finalize().into();
+        let size_matches = assembled_size == header.size;
+        let digest_matches = header.sha256.is_none_or(|expected| expected == digest);
+
+        let relative_path = output_relative_path(&header);
+        let file_name = relative_path.file_name().unwrap().to_string_lossy();
+
+        // The partial output of an open file is replaced by the finished file, or by the
+        // resent parts
+        let partial_path = partial_output_path(output_folder, &header);
+        if partial_path.exists() {
+            std::fs::remove_file(&partial_path)?;
         }
 
-        let mut output = File::create(&output_path)?;
-        for part_index in 0..header.part_count {
-            let part_path = self.get_bin_path(part_index);
-            let mut part_file = File::open(part_path)?;
+        if !size_matches || !digest_matches {
+            let quarantine_path =
+                find_unused_path(quarantine_folder, &format!("{}-{}", header.id, file_name));
+            move_file(&assembled_path, &quarantine_path)?;
 
-            io::copy(&mut part_file, &mut output)?;
+            // Delete the received parts, so that the resent ones are stored from scratch
+            let data_folder = self.get_data_folder_path();
+            std::fs::remove_dir_all(&data_folder)?;
+            std::fs::create_dir_all(&data_folder)?;
+
+            return Ok(AssembledFile::Quarantined(quarantine_path));
         }
 
+        // The digest is of the compressed file, so it's only decompressed once it's verified
+        let output_data_path = match header.compression {
+            FileCompression::None => assembled_path,
+            FileCompression::Zstd => {
+                let decompressed_path = self.get_decompressed_file_path();
+                let decompressed = File::create(&decompressed_path)?;
+                zstd::stream::copy_decode(File::open(&assembled_path)?, &decompressed)
+                    .context("Failed to decompress file")?;
+                decompressed.sync_all()?;
+                std::fs::remove_file(&assembled_path)?;
+                decompressed_path
+            }
+        };
+
+        let output_paths = match header.kind {
+            FileKind::Regular => vec![self.move_to_output_folder(
+                &output_data_path,
+                output_folder,
+                &relative_path,
+                &header.metadata,
+            )?],
+            FileKind::Bundle => {
+                let output_paths = self.unpack_bundle(&output_data_path, output_folder)?;
+                std::fs::remove_file(&output_data_path)?;
+                output_paths
+            }
+        };
+
         // Create the marker file
         let marker_file = self.get_marker_file_path();
         File::create(marker_file)?;
@@ -256,9 +420,110 @@ impl ManagedReceivingFile {
         let data_folder = self.get_data_folder_path();
         std::fs::remove_dir_all(data_folder)?;
 
+        Ok(AssembledFile::Verified(output_paths))
+    }
+
+    /// Append the parts that arrived since the last update to the partial output of an open
+    /// file, as far as they're contiguous from the start.
+    pub fn update_partial_output(&self, output_folder: &Path) -> anyhow::Result<()> {
+        if !self.get_header_json_path().exists() {
+            return Ok(());
+        }
+
+        // The partial output is kept up to date after the file was sealed, until it's finished
+        let header = self.get_header()?;
+        let partial_path = partial_output_path(output_folder, &header);
+        if !header.open && !partial_path.exists() {
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(partial_path.parent().unwrap())?;
+        let mut partial = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&partial_path)?;
+
+        // Every part but the last one is full, so the length tells which parts were written.
+        // A part that was only partly written is written again.
+        let part_size = header.file_part_size as u64;
+        let partial_len = partial.metadata()?.len();
+        let mut part_index = (partial_len / part_size) as u32;
+        if partial_len != part_index as u64 * part_size {
+            partial.set_len(part_index as u64 * part_size)?;
+        }
+
+        while part_index < header.part_count {
+            let part_path = self.get_bin_path(part_index);
+            if !part_path.exists() {
+                break;
+            }
+
+            partial.write_all(&std::fs::read(part_path)?)?;
+            part_index += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Move a finished file to its place in the output folder, with its metadata next to it.
+    fn move_to_output_folder(
+        &self,
+        data_path: &Path,
+        output_folder: &Path,
+        relative_path: &Path,
+        metadata: &BTreeMap<String, String>,
+    ) -> anyhow::Result<PathBuf> {
+        let output_parent = match relative_path.parent() {
+            Some(parent) => output_folder.join(parent),
+            None => output_folder.to_path_buf(),
+        };
+        std::fs::create_dir_all(&output_parent)?;
+
+        let file_name = relative_path.file_name().unwrap().to_string_lossy();
+        let output_path = find_unused_path(&output_parent, &file_name);
+        if !metadata.is_empty() {
+            let metadata_tmp_path = self.get_metadata_file_path();
+            std::fs::write(&metadata_tmp_path, serde_json::to_vec_pretty(metadata)?)?;
+            move_file(&metadata_tmp_path, &metadata_path_for(&output_path))?;
+        }
+        move_file(data_path, &output_path)?;
+
         Ok(output_path)
     }
 
+    /// Write every file in a bundle to the output folder. If this is interrupted, the whole
+    /// bundle gets unpacked again, so the files that were already written show up twice.
+    fn unpack_bundle(
+        &self,
+        bundle_path: &Path,
+        output_folder: &Path,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let mut reader = BundleReader::open(bundle_path).context("Failed to open bundle")?;
+        let entry_path = self.get_bundle_entry_file_path();
+
+        let mut output_paths = Vec::new();
+        loop {
+            let mut entry_file = File::create(&entry_path)?;
+            let Some(entry) = reader.next_file(&mut entry_file)? else {
+                break;
+            };
+            entry_file.sync_all()?;
+            drop(entry_file);
+
+            let relative_path = sanitize_file_name(&entry.name)
+                .unwrap_or_else(|| PathBuf::from(format!("bundle-entry-{}", output_paths.len())));
+            output_paths.push(self.move_to_output_folder(
+                &entry_path,
+                output_folder,
+                &relative_path,
+                &entry.metadata,
+            )?);
+        }
+        std::fs::remove_file(&entry_path)?;
+
+        Ok(output_paths)
+    }
+
     fn get_data_folder_path(&self) -> PathBuf {
         self.path.join("data")
     }
@@ -271,6 +536,22 @@ impl ManagedReceivingFile {
         self.path.join("finished")
     }
 
+    fn get_assembled_file_path(&self) -> PathBuf {
+        self.path.join("assembled.tmp")
+    }
+
+    fn get_decompressed_file_path(&self) -> PathBuf {
+        self.path.join("decompressed.tmp")
+    }
+
+    fn get_bundle_entry_file_path(&self) -> PathBuf {
+        self.path.join("bundle-entry.tmp")
+    }
+
+    fn get_metadata_file_path(&self) -> PathBuf {
+        self.path.join("metadata.tmp")
+    }
+
     fn get_bin_path(&self, part_index: u32) -> PathBuf {
         let data_folder = self.get_data_folder_path();
         let filename = format!("{}.bin", part_index);
@@ -284,3 +565,414 @@ impl ManagedReceivingFile {
         Ok(header)
     }
 }
+
+/// The path of a received file relative to the output folder
+fn output_relative_path(header: &HeaderChunk) -> PathBuf {
+    sanitize_file_name(&header.name).unwrap_or_else(|| PathBuf::from(header.id.to_string()))
+}
+
+/// The suffix of the partial output of an open file
+pub const PARTIAL_SUFFIX: &str = ".partial";
+
+fn partial_output_path(output_folder: &Path, header: &HeaderChunk) -> PathBuf {
+    let path = output_folder.join(output_relative_path(header));
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(PARTIAL_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Turn a file name from a header into a relative path. Empty, `.` and `..` components are
+/// dropped, so that the path can't point outside of the folder it's joined to. Returns `None`
+/// if nothing is left.
+fn sanitize_file_name(name: &str) -> Option<PathBuf> {
+    let path = name
+        .split(['/', '\\'])
+        .filter(|component| !component.is_empty() && *component != "." && *component != "..")
+        .map(|component| component.replace(['\0', ':'], "_"))
+        .collect::<PathBuf>();
+
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// Find a valid filename in the folder, adding `(n)` to the end if necessary
+fn find_unused_path(folder: &Path, filename: &str) -> PathBuf {
+    let mut path = folder.join(filename);
+    let mut i = 0;
+    while path.exists() {
+        i += 1;
+        path = folder.join(format!("{} ({})", filename, i));
+    }
+
+    path
+}
+
+/// The path of the metadata file that's written next to a finished file
+pub fn metadata_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".meta.json");
+    path.with_file_name(name)
+}
+
+/// Move a file, falling back to copying if the destination is on a different filesystem
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    std::fs::copy(from, to)?;
+    std::fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bundle::BundleEntry, file_part_id::FilePartIdRangeInclusive, tempdir::TempDirProvider,
+    };
+
+    fn make_chunks(data: &[u8], part_size: usize) -> (HeaderChunk, Vec<DataChunk>) {
+        let file_id = Uuid::new_v4();
+        let parts = data
+            .chunks(part_size)
+            .enumerate()
+            .map(|(i, part)| DataChunk {
+                file_id,
+                part: i as u32,
+                data: part.to_vec(),
+            })
+            .collect::<Vec<_>>();
+
+        let header = HeaderChunk {
+            id: file_id,
+            name: "test.bin".to_string(),
+            date: 0,
+            part_count: parts.len() as u32,
+            size: data.len() as u64,
+            file_part_size: part_size as u32,
+            sha256: Some(Sha256::digest(data).into()),
+            metadata: Default::default(),
+            compression: Default::default(),
+            kind: Default::default(),
+            open: false,
+        };
+
+        (header, parts)
+    }
+
+    #[test]
+    fn test_file_verification() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut store = ReceivingStoreManager::new(
+            folder.path().join("workdir"),
+            folder.path().join("output"),
+            folder.path().join("quarantine"),
+        )?;
+
+        let data = (0..100u8).collect::<Vec<_>>();
+
+        // A file that arrives intact gets written to the output folder, and only then its
+        // parts are confirmed
+        let (header, parts) = make_chunks(&data, 10);
+        let file_id = header.id;
+        store.receive_chunk(Chunk::Header(header))?;
+        for part in parts {
+            store.receive_chunk(Chunk::Data(part))?;
+        }
+        assert_eq!(store.iter_control_messages().count(), 0);
+        store.output_finished_files()?;
+
+        let finished = store.iter_finished_files().collect::<Vec<_>>();
+        assert_eq!(finished.len(), 1);
+        assert_eq!(std::fs::read(&finished[0])?, data);
+        assert!(!metadata_path_for(&finished[0]).exists());
+        let messages = store.iter_control_messages().collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![ControlMessage::ConfirmPart(ConfirmPart {
+                file_id,
+                part_range: FilePartIdRangeInclusive::new(FilePartId::Header, FilePartId::Part(9)),
+            })]
+        );
+
+        // A file with a corrupt part gets quarantined, and a resend is requested
+        let (header, mut parts) = make_chunks(&data, 10);
+        let file_id = header.id;
+        parts[3].data[0] ^= 0xFF;
+        store.receive_chunk(Chunk::Header(header))?;
+        for part in parts {
+            store.receive_chunk(Chunk::Data(part))?;
+        }
+        store.output_finished_files()?;
+
+        assert_eq!(store.iter_finished_files().count(), 0);
+        assert_eq!(
+            std::fs::read_dir(folder.path().join("quarantine"))?.count(),
+            1
+        );
+
+        // None of its parts are confirmed, so the sender still has all of them to resend
+        let messages = store.iter_control_messages().collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![ControlMessage::RequestFileResend(RequestFileResend {
+                file_id
+            })]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_relative_paths_kept() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let output_folder = folder.path().join("output");
+        let mut store = ReceivingStoreManager::new(
+            folder.path().join("workdir"),
+            output_folder.clone(),
+            folder.path().join("quarantine"),
+        )?;
+
+        let data = (0..30u8).collect::<Vec<_>>();
+        for name in [
+            "camera/2026-10-16/img_001.raw",
+            "../../escape/../img_002.raw",
+        ] {
+            let (mut header, parts) = make_chunks(&data, 10);
+            header.name = name.to_string();
+            store.receive_chunk(Chunk::Header(header))?;
+            for part in parts {
+                store.receive_chunk(Chunk::Data(part))?;
+            }
+        }
+        store.output_finished_files()?;
+
+        let mut finished = store.iter_finished_files().collect::<Vec<_>>();
+        finished.sort();
+        assert_eq!(
+            finished,
+            vec![
+                output_folder.join("camera/2026-10-16/img_001.raw"),
+                output_folder.join("escape/img_002.raw"),
+            ]
+        );
+
+        assert_eq!(sanitize_file_name("/abs/./file"), Some("abs/file".into()));
+        assert_eq!(sanitize_file_name("..\\..\\C:\\x"), Some("C_/x".into()));
+        assert_eq!(sanitize_file_name("../.."), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_file_decompressed() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut store = ReceivingStoreManager::new(
+            folder.path().join("workdir"),
+            folder.path().join("output"),
+            folder.path().join("quarantine"),
+        )?;
+
+        let data = vec![42u8; 500];
+        let compressed = zstd::encode_all(&data[..], 0)?;
+        let (mut header, parts) = make_chunks(&compressed, 8);
+        header.compression = FileCompression::Zstd;
+
+        store.receive_chunk(Chunk::Header(header))?;
+        for part in parts {
+            store.receive_chunk(Chunk::Data(part))?;
+        }
+        store.output_finished_files()?;
+
+        let finished = store.iter_finished_files().collect::<Vec<_>>();
+        assert_eq!(finished.len(), 1);
+        assert_eq!(std::fs::read(&finished[0])?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_metadata_written() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut store = ReceivingStoreManager::new(
+            folder.path().join("workdir"),
+            folder.path().join("output"),
+            folder.path().join("quarantine"),
+        )?;
+
+        let data = (0..50u8).collect::<Vec<_>>();
+        let (mut header, parts) = make_chunks(&data, 10);
+        header.metadata = BTreeMap::from([
+            ("instrument".to_string(), "cam-2".to_string()),
+            (
+                "capture_time".to_string(),
+                "2024-01-01T00:00:00Z".to_string(),
+            ),
+        ]);
+        let metadata = header.metadata.clone();
+
+        store.receive_chunk(Chunk::Header(header))?;
+        for part in parts {
+            store.receive_chunk(Chunk::Data(part))?;
+        }
+        store.output_finished_files()?;
+
+        let finished = store.iter_finished_files().collect::<Vec<_>>();
+        assert_eq!(finished.len(), 1);
+
+        let metadata_path = metadata_path_for(&finished[0]);
+        let written: BTreeMap<String, String> =
+            serde_json::from_slice(&std::fs::read(metadata_path)?)?;
+        assert_eq!(written, metadata);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bundle_unpacked() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut store = ReceivingStoreManager::new(
+            folder.path().join("workdir"),
+            folder.path().join("output"),
+            folder.path().join("quarantine"),
+        )?;
+
+        let mut files = Vec::new();
+        for (i, name) in ["camera/img.raw", "log.txt"].iter().enumerate() {
+            let path = folder.path().join(format!("{}.bin", i));
+            std::fs::write(&path, vec![i as u8; 30])?;
+            let entry = BundleEntry {
+                name: name.to_string(),
+                date: 0,
+                size: 30,
+                metadata: BTreeMap::from([("index".to_string(), i.to_string())]),
+            };
+            files.push((entry, path));
+        }
+        let bundle_path = folder.path().join("test.leoftp-bundle");
+        crate::bundle::write_bundle(&bundle_path, &files)?;
+
+        let (mut header, parts) = make_chunks(&std::fs::read(&bundle_path)?, 16);
+        header.kind = FileKind::Bundle;
+
+        store.receive_chunk(Chunk::Header(header))?;
+        for part in parts {
+            store.receive_chunk(Chunk::Data(part))?;
+        }
+        store.output_finished_files()?;
+
+        let mut finished = store.iter_finished_files().collect::<Vec<_>>();
+        finished.sort();
+        assert_eq!(
+            finished,
+            vec![
+                folder.path().join("output/camera/img.raw"),
+                folder.path().join("output/log.txt"),
+            ]
+        );
+        assert_eq!(std::fs::read(&finished[1])?, vec![1; 30]);
+        assert!(metadata_path_for(&finished[0]).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_broken_file_skipped() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut store = ReceivingStoreManager::new(
+            folder.path().join("workdir"),
+            folder.path().join("output"),
+            folder.path().join("quarantine"),
+        )?;
+
+        // Verified against its header, but not a bundle
+        let (mut broken_header, broken_parts) = make_chunks(&[7; 40], 16);
+        broken_header.kind = FileKind::Bundle;
+        let (mut header, parts) = make_chunks(&[8; 40], 16);
+        header.name = "good.bin".to_string();
+
+        for (header, parts) in [(broken_header.clone(), broken_parts), (header, parts)] {
+            store.receive_chunk(Chunk::Header(header))?;
+            for part in parts {
+                store.receive_chunk(Chunk::Data(part))?;
+            }
+        }
+        store.output_finished_files()?;
+
+        assert_eq!(
+            store.iter_finished_files().collect::<Vec<_>>(),
+            vec![folder.path().join("output/good.bin")]
+        );
+        assert!(folder
+            .path()
+            .join("workdir")
+            .join(broken_header.id.to_string())
+            .is_dir());
+        let confirms_broken_header = store.iter_control_messages().any(|message| {
+            matches!(message, ControlMessage::ConfirmPart(ConfirmPart { file_id, part_range })
+                if file_id == broken_header.id && part_range.from == FilePartId::Header)
+        });
+        assert!(!confirms_broken_header);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_output() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut store = ReceivingStoreManager::new(
+            folder.path().join("workdir"),
+            folder.path().join("output"),
+            folder.path().join("quarantine"),
+        )?;
+        store.set_partial_output(true);
+
+        let data = (0..45u8).collect::<Vec<_>>();
+        let (sealed_header, mut parts) = make_chunks(&data, 10);
+        let mut open_header = sealed_header.clone();
+        open_header.name = "logs/payload.log".to_string();
+        open_header.open = true;
+        open_header.part_count = 2;
+        open_header.size = 20;
+        open_header.sha256 = None;
+        let mut sealed_header = sealed_header;
+        sealed_header.name = open_header.name.clone();
+
+        let partial_path = folder.path().join("output/logs/payload.log.partial");
+        let last_part = parts.pop().unwrap();
+        let mut parts = parts.into_iter();
+
+        store.receive_chunk(Chunk::Header(open_header.clone()))?;
+        store.receive_chunk(Chunk::Data(parts.next().unwrap()))?;
+        let second_part = parts.next().unwrap();
+        store.receive_chunk(Chunk::Data(parts.next().unwrap()))?;
+        store.output_finished_files()?;
+        assert_eq!(std::fs::read(&partial_path)?, &data[..10]);
+
+        store.receive_chunk(Chunk::Data(second_part))?;
+        store.output_finished_files()?;
+        assert_eq!(std::fs::read(&partial_path)?, &data[..20]);
+
+        // The file isn't finished until it's sealed, and an old header doesn't reopen it
+        store.receive_chunk(Chunk::Data(parts.next().unwrap()))?;
+        store.receive_chunk(Chunk::Data(last_part))?;
+        store.output_finished_files()?;
+        assert_eq!(store.iter_finished_files().count(), 0);
+        store.receive_chunk(Chunk::Header(sealed_header))?;
+        store.receive_chunk(Chunk::Header(open_header))?;
+        store.output_finished_files()?;
+
+        let finished = store.iter_finished_files().collect::<Vec<_>>();
+        assert_eq!(
+            finished,
+            vec![folder.path().join("output/logs/payload.log")]
+        );
+        assert_eq!(std::fs::read(&finished[0])?, data);
+        assert!(!partial_path.exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    fs::OpenOptions,
+    io::{Seek, SeekFrom, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::chunks::HeaderChunk;
+
+use super::{
+    entry_folder::{EntryFolder, FolderEntry},
+    managed_sending_file::{hash_file_contents, ManagedFileInfo},
+};
+
+/// The folder inside the archive where the parts of files are collected while they're being
+/// acknowledged
+const PARTIAL_FOLDER: &str = "partial";
+
+/// A folder on the sender that keeps a copy of the files that the ground fully acknowledged, in
+/// case the ground loses them. The copy is reassembled from the parts as they're acknowledged,
+/// before their data is removed from the storage.
+///
+/// Requeued files are moved from the archive into the storage, so the archive must be on the
+/// same filesystem as the storage folder.
+#[derive(Debug, Clone)]
+pub struct ArchiveConfig {
+    /// The folder of the archive on the local filesystem
+    pub path: PathBuf,
+
+    /// Maximum size of the archived files in bytes. The oldest files are removed to make room
+    /// for new ones.
+    pub max_size: Option<u64>,
+
+    /// How long files are kept after they were archived
+    pub retention: Duration,
+}
+
+/// The archived files are kept in an [`EntryFolder`], next to the partial data of the files
+/// that are being acknowledged:
+///
+/// ```plaintext
+/// [archive]/
+/// ├── partial/
+/// │   └── <id>.bin  - The acknowledged parts of a file, at their offset in the file.
+/// └── <id>/
+///     ├── entry.json - The header that the file was sent with, and when it was archived.
+///     └── data.bin   - The file's data as it was sent, e.g. compressed if it was compressed.
+/// ```
+///
+/// When a file is fully acknowledged, its partial data is verified against the digest in its
+/// header before it's moved into the file's folder.
+pub struct SendingArchive {
+    config: ArchiveConfig,
+    files: EntryFolder<ArchivedFile>,
+}
+
+/// A file in the archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedFile {
+    pub header: HeaderChunk,
+    pub info: ManagedFileInfo,
+
+    /// When the file was archived, in nanoseconds since the unix epoch
+    pub archived_at: i64,
+}
+
+impl FolderEntry for ArchivedFile {
+    fn id(&self) -> Uuid {
+        self.header.id
+    }
+
+    fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    fn added_at(&self) -> i64 {
+        self.archived_at
+    }
+}
+
+impl SendingArchive {
+    pub fn load_or_create(config: ArchiveConfig) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(config.path.join(PARTIAL_FOLDER))?;
+        let files = EntryFolder::load_or_create(
+            config.path.clone(),
+            config.max_size,
+            config.retention,
+            "archived",
+            &[PARTIAL_FOLDER],
+        )?;
+
+        Ok(Self { config, files })
+    }
+
+    fn partial_path(&self, file_id: Uuid) -> PathBuf {
+        self.config
+            .path
+            .join(PARTIAL_FOLDER)
+            .join(format!("{}.bin", file_id))
+    }
+
+    pub fn get(&self, file_id: Uuid) -> Option<&ArchivedFile> {
+        self.files.get(file_id)
+    }
+
+    pub fn iter_files(&self) -> impl Iterator<Item = &ArchivedFile> {
+        self.files.iter()
+    }
+
+    /// The path of an archived file's data
+    pub fn data_path(&self, file_id: Uuid) -> PathBuf {
+        self.files.data_path(file_id)
+    }
+
+    /// A path in the archive for a temporary file, e.g. a copy of an archived file that's being
+    /// added to the storage again
+    pub fn temp_path(&self, file_id: Uuid) -> PathBuf {
+        self.partial_path(file_id)
+    }
+
+    pub fn calc_total_size(&self) -> u64 {
+        self.files.calc_total_size()
+    }
+
+    /// Write the data of an acknowledged part into the file's partial data. It's only durable
+    /// once [`Self::sync_partial`] is called.
+    pub fn store_part(&self, header: &HeaderChunk, part: u32, data: &[u8]) -> anyhow::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.partial_path(header.id))?;
+        file.seek(SeekFrom::Start(part as u64 * header.file_part_size as u64))?;
+        file.write_all(data)?;
+
+        Ok(())
+    }
+
+    /// Flush the stored parts of a file to disk. This must be done before the parts' data is
+    /// freed, so that an acknowledgement that survives a power cut doesn't leave a hole in the
+    /// partial data.
+    pub fn sync_partial(&self, file_id: Uuid) -> anyhow::Result<()> {
+        let partial_path = self.partial_path(file_id);
+        if partial_path.is_file() {
+            OpenOptions::new()
+                .write(true)
+                .open(partial_path)?
+                .sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// Archive a fully acknowledged file from its partial data. Files that weren't completely
+    /// collected, e.g. because some of their parts were evicted, fail verification against
+    /// their digest and aren't archived.
+    pub fn finish(
+        &mut self,
+        header: &HeaderChunk,
+        info: &ManagedFileInfo,
+        now: i64,
+    ) -> anyhow::Result<()> {
+        let partial_path = self.partial_path(header.id);
+        if !partial_path.is_file() {
+            return Ok(());
+        }
+
+        let is_complete = match header.sha256 {
+            Some(sha256) => {
+                std::fs::metadata(&partial_path)?.len() == header.size
+                    && hash_file_contents(&partial_path)? == sha256
+            }
+            None => false,
+        };
+        if !is_complete {
+            tracing::warn!(
+                "Not archiving file {}, its acknowledged parts couldn't be verified against its digest",
+                header.id
+            );
+            return self.discard(header.id);
+        }
+
+        // A file that was resent is archived again
+        self.remove(header.id)?;
+
+        if let Some(max_size) = self
+            .config
+            .max_size
+            .filter(|max_size| *max_size < header.size)
+        {
+            tracing::warn!(
+                "Not archiving file {}, its size {} exceeds the maximum archive size {}",
+                header.id,
+                header.size,
+                max_size
+            );
+            return self.discard(header.id);
+        }
+
+        let file = ArchivedFile {
+            header: header.clone(),
+            info: info.clone(),
+            archived_at: now,
+        };
+        self.files.add(file, &partial_path)?;
+        tracing::info!("Archived file {}", header.id);
+
+        Ok(())
+    }
+
+    /// Drop the partial data of a file, e.g. because it was deleted before it was fully
+    /// acknowledged.
+    pub fn discard(&self, file_id: Uuid) -> anyhow::Result<()> {
+        let partial_path = self.partial_path(file_id);
+        if partial_path.is_file() {
+            std::fs::remove_file(partial_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Drop the partial data of all the files that aren't managed anymore, e.g. because they
+    /// were deleted right before a crash.
+    pub fn discard_stale_partials(&self, is_managed: impl Fn(Uuid) -> bool) -> anyhow::Result<()> {
+        for entry in std::fs::read_dir(self.config.path.join(PARTIAL_FOLDER))? {
+            let path = entry?.path();
+            let file_id = path
+                .file_stem()
+                .and_then(|stem| Uuid::parse_str(&stem.to_string_lossy()).ok());
+            if !file_id.is_some_and(&is_managed) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, file_id: Uuid) -> anyhow::Result<()> {
+        self.files.remove(file_id)
+    }
+
+    /// Remove the files that were archived longer than the retention at `now` (nanoseconds
+    /// since the unix epoch).
+    pub fn remove_expired(&mut self, now: i64) -> anyhow::Result<()> {
+        self.files.remove_expired(now)
+    }
+}
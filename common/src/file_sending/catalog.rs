@@ -0,0 +1,195 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    chunks::{FileCompression, FileKind},
+    status::CatalogEntry,
+    validity::ValidityCheck,
+};
+
+use super::{
+    entry_folder::{EntryFolder, FolderEntry},
+    managed_sending_file::file_created_date,
+    storage_manager::NewFileOptions,
+};
+
+/// A folder on the sender for files that are only sent when the ground requests them. Their
+/// catalog entries are sent on the downlink instead, and the ground requests a file with
+/// [`crate::control::RequestCatalogedFile`].
+///
+/// Requested files are moved from the catalog into the storage, so the catalog must be on the
+/// same filesystem as the storage folder.
+#[derive(Debug, Clone)]
+pub struct CatalogConfig {
+    /// The folder of the catalog on the local filesystem
+    pub path: PathBuf,
+
+    /// Maximum size of the catalogued files in bytes. The oldest files are removed to make room
+    /// for new ones.
+    pub max_size: Option<u64>,
+
+    /// How long files are kept after they were catalogued, unless the ground requests them
+    pub retention: Duration,
+}
+
+/// The catalogued files are kept in an [`EntryFolder`], with their summary and the options
+/// they're added to the storage with as their entry.json, and their original data as data.bin.
+pub struct FileCatalog {
+    entries: EntryFolder<CatalogedFile>,
+}
+
+/// A file in the catalog
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogedFile {
+    pub entry_id: Uuid,
+    pub name: String,
+
+    /// When the file was created, in nanoseconds since the unix epoch
+    pub date: i64,
+    pub size: u64,
+    pub metadata: BTreeMap<String, String>,
+
+    pub source: Option<String>,
+    pub group: Option<String>,
+    pub ttl: Option<Duration>,
+    pub chunk_size: Option<u32>,
+    pub compression: FileCompression,
+    pub kind: FileKind,
+
+    /// When the file was catalogued, in nanoseconds since the unix epoch
+    pub cataloged_at: i64,
+}
+
+impl CatalogedFile {
+    /// The entry that's sent on the downlink
+    pub fn entry(&self) -> CatalogEntry {
+        CatalogEntry {
+            entry_id: self.entry_id,
+            name: self.name.clone(),
+            date: self.date,
+            size: self.size,
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    /// The options that the file is added to the storage with once it's requested
+    pub fn new_file_options(&self, priority: i16) -> NewFileOptions {
+        NewFileOptions {
+            name: Some(self.name.clone()),
+            source: self.source.clone(),
+            ttl: self.ttl,
+            metadata: self.metadata.clone(),
+            priority: Some(priority),
+            chunk_size: self.chunk_size,
+            compression: self.compression,
+            group: self.group.clone(),
+            kind: self.kind,
+            catalog_only: false,
+        }
+    }
+}
+
+impl FolderEntry for CatalogedFile {
+    fn id(&self) -> Uuid {
+        self.entry_id
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn added_at(&self) -> i64 {
+        self.cataloged_at
+    }
+}
+
+impl FileCatalog {
+    pub fn load_or_create(config: CatalogConfig) -> anyhow::Result<Self> {
+        let entries = EntryFolder::load_or_create(
+            config.path,
+            config.max_size,
+            config.retention,
+            "catalogued",
+            &[],
+        )?;
+
+        Ok(Self { entries })
+    }
+
+    pub fn get(&self, entry_id: Uuid) -> Option<&CatalogedFile> {
+        self.entries.get(entry_id)
+    }
+
+    /// All the files in the catalog, oldest first
+    pub fn iter_files(&self) -> impl Iterator<Item = &CatalogedFile> {
+        let mut files = self.entries.iter().collect::<Vec<_>>();
+        files.sort_by_key(|file| (file.date, file.entry_id));
+        files.into_iter()
+    }
+
+    /// The path of a catalogued file's data
+    pub fn data_path(&self, entry_id: Uuid) -> PathBuf {
+        self.entries.data_path(entry_id)
+    }
+
+    /// The total size of the catalogued files, in bytes
+    pub fn calc_total_size(&self) -> u64 {
+        self.entries.calc_total_size()
+    }
+
+    /// Move a file into the catalog at `now` (nanoseconds since the unix epoch), removing the
+    /// oldest files if it doesn't fit. The name and options are kept for when it's requested.
+    pub fn add(
+        &mut self,
+        path: &Path,
+        options: NewFileOptions,
+        now: i64,
+    ) -> anyhow::Result<&CatalogedFile> {
+        let name = match options.name {
+            Some(name) => name,
+            None => path
+                .file_name()
+                .context("File path has no file name")?
+                .to_string_lossy()
+                .to_string(),
+        };
+
+        let file = CatalogedFile {
+            entry_id: Uuid::new_v4(),
+            name,
+            date: file_created_date(path)?,
+            size: std::fs::metadata(path)?.len(),
+            metadata: options.metadata,
+            source: options.source,
+            group: options.group,
+            ttl: options.ttl,
+            chunk_size: options.chunk_size,
+            compression: options.compression,
+            kind: options.kind,
+            cataloged_at: now,
+        };
+        anyhow::ensure!(file.entry().is_valid(), "File metadata is too large");
+
+        let file = self.entries.add(file, path)?;
+        tracing::info!("Catalogued file {:?} as entry {}", path, file.entry_id);
+
+        Ok(file)
+    }
+
+    pub fn remove(&mut self, entry_id: Uuid) -> anyhow::Result<()> {
+        self.entries.remove(entry_id)
+    }
+
+    /// Remove the files that were catalogued longer than the retention at `now` (nanoseconds
+    /// since the unix epoch).
+    pub fn remove_expired(&mut self, now: i64) -> anyhow::Result<()> {
+        self.entries.remove_expired(now)
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use uuid::Uuid;
+
+use super::storage_backend::{PosixBackend, StorageBackend};
+
+/// An entry of an [`EntryFolder`], that's stored as its entry.json
+pub trait FolderEntry: Serialize + DeserializeOwned {
+    fn id(&self) -> Uuid;
+
+    /// The size of the entry's data in bytes
+    fn size(&self) -> u64;
+
+    /// When the entry was added, in nanoseconds since the unix epoch
+    fn added_at(&self) -> i64;
+}
+
+/// A folder on the sender that keeps files outside of the storage, bounded by their total size
+/// and how long they're kept. Used for the archive and the catalog.
+///
+/// Folder structure:
+///
+/// ```plaintext
+/// [folder]/
+/// └── <id>/
+///     ├── entry.json - The entry of the file.
+///     └── data.bin   - The file's data.
+/// ```
+///
+/// entry.json is written first, and the file is moved into the entry's folder as data.bin
+/// last. Folders without data.bin are removed when the folder is loaded.
+pub struct EntryFolder<T> {
+    path: PathBuf,
+    max_size: Option<u64>,
+    retention: Duration,
+
+    /// What happens to the files in the folder, e.g. "archived", for the log messages
+    verb: &'static str,
+
+    entries: HashMap<Uuid, T>,
+}
+
+impl<T: FolderEntry> EntryFolder<T> {
+    /// Load the entries from the folder at `path`. The subfolders in `ignored` aren't entries,
+    /// but are used by the owner of the folder.
+    pub fn load_or_create(
+        path: PathBuf,
+        max_size: Option<u64>,
+        retention: Duration,
+        verb: &'static str,
+        ignored: &[&str],
+    ) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&path)?;
+
+        let mut entries = HashMap::new();
+        for entry in std::fs::read_dir(&path)? {
+            let path = entry?.path();
+            if !path.is_dir()
+                || ignored
+                    .iter()
+                    .any(|ignored| path.file_name() == Some(ignored.as_ref()))
+            {
+                continue;
+            }
+
+            if !path.join("data.bin").is_file() {
+                tracing::warn!("Removing file that wasn't completely {}: {:?}", verb, path);
+                std::fs::remove_dir_all(&path)?;
+                continue;
+            }
+
+            let entry = std::fs::read(path.join("entry.json"))?;
+            let entry: T = serde_json::from_slice(&entry)
+                .with_context(|| format!("Failed to read {} file {:?}", verb, path))?;
+            entries.insert(entry.id(), entry);
+        }
+
+        Ok(Self {
+            path,
+            max_size,
+            retention,
+            verb,
+            entries,
+        })
+    }
+
+    fn folder_path(&self, id: Uuid) -> PathBuf {
+        self.path.join(id.to_string())
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<&T> {
+        self.entries.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.values()
+    }
+
+    /// The path of an entry's data
+    pub fn data_path(&self, id: Uuid) -> PathBuf {
+        self.folder_path(id).join("data.bin")
+    }
+
+    /// The total size of the entries' data, in bytes
+    pub fn calc_total_size(&self) -> u64 {
+        self.entries.values().map(|entry| entry.size()).sum()
+    }
+
+    /// Add an entry, moving the file at `data_path` into the folder. The oldest entries are
+    /// removed if it doesn't fit.
+    pub fn add(&mut self, entry: T, data_path: &Path) -> anyhow::Result<&T> {
+        if let Some(max_size) = self.max_size {
+            anyhow::ensure!(
+                entry.size() <= max_size,
+                "File size {} exceeds the maximum size {} of the {} files",
+                entry.size(),
+                max_size,
+                self.verb
+            );
+            self.remove_oldest_until_size_reached(max_size - entry.size())?;
+        }
+
+        let folder_path = self.folder_path(entry.id());
+        std::fs::create_dir_all(&folder_path)?;
+        PosixBackend.write_atomic(
+            &folder_path.join("entry.json"),
+            &serde_json::to_vec(&entry)?,
+        )?;
+        if let Err(err) = std::fs::rename(data_path, folder_path.join("data.bin")) {
+            std::fs::remove_dir_all(&folder_path).unwrap_or_default();
+            return Err(err.into());
+        }
+
+        let id = entry.id();
+        self.entries.insert(id, entry);
+        Ok(&self.entries[&id])
+    }
+
+    pub fn remove(&mut self, id: Uuid) -> anyhow::Result<()> {
+        if self.entries.remove(&id).is_some() {
+            std::fs::remove_dir_all(self.folder_path(id))?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the entries that were added longer than the retention at `now` (nanoseconds
+    /// since the unix epoch).
+    pub fn remove_expired(&mut self, now: i64) -> anyhow::Result<()> {
+        let retention = self.retention.as_nanos() as i64;
+        let expired = self
+            .entries
+            .values()
+            .filter(|entry| entry.added_at().saturating_add(retention) <= now)
+            .map(|entry| entry.id())
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            tracing::info!("Removing expired {} file: {}", self.verb, id);
+            self.remove(id)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_oldest_until_size_reached(&mut self, size: u64) -> anyhow::Result<()> {
+        let mut entries = self
+            .entries
+            .values()
+            .map(|entry| (entry.added_at(), entry.id()))
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by(|a, b| b.cmp(a)); // Oldest last, we will pop items from the end
+
+        let mut total_size = self.calc_total_size();
+        while total_size > size {
+            let Some((_, id)) = entries.pop() else {
+                break;
+            };
+
+            tracing::info!("Removing {} file {} to make room", self.verb, id);
+            total_size -= self.entries[&id].size();
+            self.remove(id)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use crate::tempdir::TempDirProvider;
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    struct TestEntry {
+        id: Uuid,
+        size: u64,
+        added_at: i64,
+    }
+
+    impl FolderEntry for TestEntry {
+        fn id(&self) -> Uuid {
+            self.id
+        }
+
+        fn size(&self) -> u64 {
+            self.size
+        }
+
+        fn added_at(&self) -> i64 {
+            self.added_at
+        }
+    }
+
+    #[test]
+    fn test_entry_folder() -> anyhow::Result<()> {
+        let tempdir = TempDirProvider::new_for_test().create()?;
+        let path = tempdir.path().join("folder");
+        let load = || {
+            EntryFolder::<TestEntry>::load_or_create(
+                path.clone(),
+                Some(10),
+                Duration::from_nanos(100),
+                "kept",
+                &["partial"],
+            )
+        };
+        let mut folder = load()?;
+        std::fs::create_dir_all(path.join("partial"))?;
+
+        let mut ids = Vec::new();
+        for added_at in 0..3 {
+            let data_path = tempdir.path().join("data.bin");
+            std::fs::write(&data_path, [0u8; 4])?;
+            let entry = TestEntry {
+                id: Uuid::new_v4(),
+                size: 4,
+                added_at,
+            };
+            ids.push(entry.id);
+            folder.add(entry, &data_path)?;
+        }
+
+        // The oldest entry was removed to make room
+        assert!(folder.get(ids[0]).is_none());
+        assert_eq!(folder.calc_total_size(), 8);
+        assert!(folder.data_path(ids[1]).is_file());
+
+        let data_path = tempdir.path().join("data.bin");
+        std::fs::write(&data_path, [0u8; 11])?;
+        let too_large = TestEntry {
+            id: Uuid::new_v4(),
+            size: 11,
+            added_at: 3,
+        };
+        assert!(folder.add(too_large, &data_path).is_err());
+
+        // Entries that weren't completely added are removed on load
+        let incomplete = path.join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&incomplete)?;
+        std::fs::write(incomplete.join("entry.json"), b"{}")?;
+        let mut folder = load()?;
+        assert!(!incomplete.exists());
+        assert!(path.join("partial").is_dir());
+        assert_eq!(folder.iter().count(), 2);
+
+        folder.remove_expired(101)?;
+        assert!(folder.get(ids[1]).is_none());
+        assert!(folder.get(ids[2]).is_some());
+
+        Ok(())
+    }
+}
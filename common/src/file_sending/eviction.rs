@@ -0,0 +1,112 @@
+use std::cmp::Ordering;
+
+use super::{
+    managed_sending_file::ManagedSendingFile,
+    storage_manager::{cmp_file_storage_part_for_deletion, StorageFilePart},
+};
+
+/// A stored part that could be evicted, along with the file it belongs to
+pub struct EvictionCandidate<'a> {
+    pub part: StorageFilePart,
+    pub file: &'a ManagedSendingFile,
+}
+
+/// Decides which parts are deleted without being acknowledged when the storage is over its
+/// maximum size.
+pub trait EvictionPolicy: Send + Sync {
+    /// Files that are never evicted, even if the storage stays over its maximum size.
+    fn is_protected(&self, file: &ManagedSendingFile) -> bool;
+
+    /// Evict whole files at once, so that the ground never ends up with half a file.
+    fn whole_files_only(&self) -> bool;
+
+    /// The order in which parts are evicted. Parts that compare as less are evicted first.
+    fn cmp_for_eviction(&self, a: &EvictionCandidate, b: &EvictionCandidate) -> Ordering;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EvictionConfig {
+    pub order: EvictionOrder,
+
+    /// See [`EvictionPolicy::whole_files_only`]
+    pub whole_files_only: bool,
+
+    /// Files that are never evicted
+    pub protected: Vec<ProtectedClass>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionOrder {
+    /// Lowest priority parts first, and the last parts of a file before the first ones
+    #[default]
+    LowestPriorityFirst,
+
+    /// Parts of the file with the oldest creation date first
+    OldestFileFirst,
+
+    /// Parts of the file with the largest size first
+    LargestFileFirst,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtectedClass {
+    /// Files from this input source
+    Source(String),
+
+    /// Files in this producer-defined group
+    Group(String),
+}
+
+impl ProtectedClass {
+    pub fn matches(&self, file: &ManagedSendingFile) -> bool {
+        match self {
+            ProtectedClass::Source(source) => file.info().source.as_ref() == Some(source),
+            ProtectedClass::Group(group) => file.info().group.as_ref() == Some(group),
+        }
+    }
+}
+
+impl EvictionConfig {
+    pub fn build(&self) -> Box<dyn EvictionPolicy> {
+        Box::new(ConfiguredEvictionPolicy {
+            config: self.clone(),
+        })
+    }
+}
+
+/// The eviction policy described by an [`EvictionConfig`]
+pub struct ConfiguredEvictionPolicy {
+    config: EvictionConfig,
+}
+
+impl EvictionPolicy for ConfiguredEvictionPolicy {
+    fn is_protected(&self, file: &ManagedSendingFile) -> bool {
+        self.config
+            .protected
+            .iter()
+            .any(|class| class.matches(file))
+    }
+
+    fn whole_files_only(&self) -> bool {
+        self.config.whole_files_only
+    }
+
+    fn cmp_for_eviction(&self, a: &EvictionCandidate, b: &EvictionCandidate) -> Ordering {
+        let (a_header, b_header) = (a.file.header(), b.file.header());
+
+        // Files are evicted one after the other, unless parts are only ordered by priority
+        let file_order = match self.config.order {
+            EvictionOrder::LowestPriorityFirst => Ordering::Equal,
+            EvictionOrder::OldestFileFirst => a_header
+                .date
+                .cmp(&b_header.date)
+                .then_with(|| a_header.id.cmp(&b_header.id)),
+            EvictionOrder::LargestFileFirst => b_header
+                .size
+                .cmp(&a_header.size)
+                .then_with(|| a_header.id.cmp(&b_header.id)),
+        };
+
+        file_order.then_with(|| cmp_file_storage_part_for_deletion(&a.part, &b.part))
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::file_part_id::{FilePartId, FilePartIdRangeInclusive};
+
+use super::{managed_sending_file::write_file_atomic, storage_backend::StorageBackend};
+
+/// A part that was sent, but not acknowledged yet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InFlightPart {
+    pub file_id: Uuid,
+    pub part_id: FilePartId,
+    /// When the part was last sent, in nanoseconds since the unix epoch
+    pub sent_at: i64,
+    /// The downlink session number that the part was last sent in
+    pub session: u32,
+    /// The priority of the part before it was first sent, restored when the part times out
+    pub original_priority: i16,
+}
+
+/// When unacknowledged parts should have their original priority restored. A part times out
+/// when either of the limits is reached.
+#[derive(Debug, Clone, Default)]
+pub struct InFlightTimeout {
+    /// Time out after the part was sent this many downlink sessions ago
+    pub sessions: Option<u32>,
+
+    /// Time out after the part was sent this long ago
+    pub duration: Option<Duration>,
+}
+
+/// How many records are appended to a log segment before the next one is started. Segments are
+/// written to in place, which some backends handle by rewriting the whole file now and then, so
+/// they are kept short.
+const LOG_SEGMENT_RECORDS: u32 = 256;
+
+/// Tracks the parts that are in flight, i.e. sent but not acknowledged. The state is kept in
+/// memory, and persisted so that every lowered priority that survives a crash has a record to
+/// restore it from. Later sends of a part that's already in flight only update when it was sent,
+/// so losing those on a crash only makes the part time out sooner.
+///
+/// A file can have hundreds of thousands of parts in flight, so the tracker isn't rewritten for
+/// every sent part. Instead, each newly sent part is appended to a log of short segments next to
+/// the snapshot, e.g. `inflight.0.log`, `inflight.1.log`, which are replayed on top of the
+/// snapshot when it's loaded. [`InFlightTracker::save`] writes a new snapshot and removes the
+/// segments. A torn record at the end of a segment is from an interrupted append, and is
+/// ignored.
+pub struct InFlightTracker {
+    backend: Arc<dyn StorageBackend>,
+    path: PathBuf,
+    current_session: u32,
+    parts: HashMap<Uuid, BTreeMap<FilePartId, InFlightPart>>,
+    /// The segment that records are appended to, and how many it has
+    log_segment: u32,
+    log_segment_records: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InFlightTrackerData {
+    current_session: u32,
+    parts: Vec<InFlightPart>,
+}
+
+impl InFlightTracker {
+    pub fn load_or_create(
+        backend: Arc<dyn StorageBackend>,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+
+        let mut tracker = Self {
+            backend,
+            path,
+            current_session: 0,
+            parts: HashMap::new(),
+            log_segment: 0,
+            log_segment_records: 0,
+        };
+
+        if tracker.backend.is_file(&tracker.path) {
+            let data = tracker.backend.read(&tracker.path)?;
+            let data: InFlightTrackerData = bincode::deserialize(&data)?;
+
+            tracker.current_session = data.current_session;
+            for part in data.parts {
+                tracker.insert(part);
+            }
+        }
+
+        // New records go into a new segment, after the ones that are replayed
+        while tracker
+            .backend
+            .is_file(&tracker.segment_path(tracker.log_segment))
+        {
+            let data = tracker
+                .backend
+                .read(&tracker.segment_path(tracker.log_segment))?;
+            let mut reader = &data[..];
+            while !reader.is_empty() {
+                let Ok(part) = bincode::deserialize_from::<_, InFlightPart>(&mut reader) else {
+                    tracing::warn!("Ignoring torn in-flight record");
+                    break;
+                };
+
+                // Starting a session isn't persisted, but the records know which one they're from
+                tracker.current_session = tracker.current_session.max(part.session);
+                tracker.replay(part);
+            }
+
+            tracker.log_segment += 1;
+        }
+
+        Ok(tracker)
+    }
+
+    fn segment_path(&self, segment: u32) -> PathBuf {
+        self.path.with_extension(format!("{}.log", segment))
+    }
+
+    fn insert(&mut self, part: InFlightPart) {
+        self.parts
+            .entry(part.file_id)
+            .or_default()
+            .insert(part.part_id, part);
+    }
+
+    /// Apply a logged record. Like with [`Self::record_sent`], the original priority of a part
+    /// that's already in flight is kept.
+    fn replay(&mut self, record: InFlightPart) {
+        self.parts
+            .entry(record.file_id)
+            .or_default()
+            .entry(record.part_id)
+            .and_modify(|part| {
+                part.sent_at = record.sent_at;
+                part.session = record.session;
+            })
+            .or_insert(record);
+    }
+
+    fn get(&self, file_id: Uuid, part_id: FilePartId) -> Option<&InFlightPart> {
+        self.parts.get(&file_id)?.get(&part_id)
+    }
+
+    pub fn begin_session(&mut self) {
+        self.current_session = self.current_session.wrapping_add(1);
+    }
+
+    /// Record that a part was sent. If the part is already in flight, the original priority
+    /// is kept, so that repeated sends don't lose it.
+    pub fn record_sent(&mut self, file_id: Uuid, part_id: FilePartId, priority: i16, now: i64) {
+        let session = self.current_session;
+        self.parts
+            .entry(file_id)
+            .or_default()
+            .entry(part_id)
+            .and_modify(|part| {
+                part.sent_at = now;
+                part.session = session;
+            })
+            .or_insert(InFlightPart {
+                file_id,
+                part_id,
+                sent_at: now,
+                session,
+                original_priority: priority,
+            });
+    }
+
+    /// Append the record of a part that was just sent for the first time to the log, so that its
+    /// original priority is persisted without rewriting the whole tracker.
+    pub fn append_record(&mut self, file_id: Uuid, part_id: FilePartId) -> anyhow::Result<()> {
+        let Some(part) = self.get(file_id, part_id) else {
+            return Ok(());
+        };
+        let record = bincode::serialize(part)?;
+
+        if self.log_segment_records >= LOG_SEGMENT_RECORDS {
+            self.log_segment += 1;
+            self.log_segment_records = 0;
+        }
+
+        let segment_path = self.segment_path(self.log_segment);
+        if self.log_segment_records == 0 {
+            self.backend.write_atomic(&segment_path, &record)?;
+        } else {
+            let len = self.backend.file_len(&segment_path)?;
+            self.backend.write_at(&segment_path, len, &record)?;
+        }
+        self.log_segment_records += 1;
+
+        Ok(())
+    }
+
+    pub fn remove_acked(&mut self, file_id: Uuid, part_range: &FilePartIdRangeInclusive) {
+        let Some(parts) = self.parts.get_mut(&file_id) else {
+            return;
+        };
+
+        // Split off the acknowledged range, and put back the parts after it
+        let mut acked = parts.split_off(&part_range.from);
+        if let FilePartId::Part(to) = part_range.to {
+            if let Some(next) = to.checked_add(1) {
+                parts.append(&mut acked.split_off(&FilePartId::Part(next)));
+            }
+        } else {
+            parts.append(&mut acked.split_off(&FilePartId::Part(0)));
+        }
+
+        if parts.is_empty() {
+            self.parts.remove(&file_id);
+        }
+    }
+
+    pub fn remove_file(&mut self, file_id: Uuid) {
+        self.parts.remove(&file_id);
+    }
+
+    /// Remove and return all the in-flight parts of a file.
+    pub fn take_file(&mut self, file_id: Uuid) -> Vec<InFlightPart> {
+        self.parts
+            .remove(&file_id)
+            .map(|parts| parts.into_values().collect())
+            .unwrap_or_default()
+    }
+
+    /// The priority of the file was changed, so that should be restored on timeout instead.
+    pub fn set_original_priority_for_file(&mut self, file_id: Uuid, priority: i16) {
+        for part in self
+            .parts
+            .get_mut(&file_id)
+            .into_iter()
+            .flat_map(|p| p.values_mut())
+        {
+            part.original_priority = priority;
+        }
+    }
+
+    pub fn is_in_flight(&self, file_id: Uuid, part_id: FilePartId) -> bool {
+        self.get(file_id, part_id).is_some()
+    }
+
+    /// Remove and return all the parts that timed out at `now` (nanoseconds since the unix epoch).
+    pub fn take_timed_out(&mut self, timeout: &InFlightTimeout, now: i64) -> Vec<InFlightPart> {
+        let current_session = self.current_session;
+        let is_timed_out = |part: &InFlightPart| {
+            let sessions_timed_out = timeout
+                .sessions
+                .is_some_and(|n| current_session.wrapping_sub(part.session) >= n);
+            let duration_timed_out = timeout
+                .duration
+                .is_some_and(|d| now.saturating_sub(part.sent_at) >= d.as_nanos() as i64);
+
+            sessions_timed_out || duration_timed_out
+        };
+
+        let mut timed_out = Vec::new();
+        self.parts.retain(|_, parts| {
+            parts.retain(|_, part| {
+                if is_timed_out(part) {
+                    timed_out.push(part.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+
+            !parts.is_empty()
+        });
+
+        timed_out
+    }
+
+    /// Write a snapshot of all the in-flight parts, and remove the log segments that it
+    /// replaces.
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        let data = InFlightTrackerData {
+            current_session: self.current_session,
+            parts: self
+                .parts
+                .values()
+                .flat_map(|parts| parts.values().cloned())
+                .collect(),
+        };
+
+        write_file_atomic(&*self.backend, &self.path, |file| {
+            Ok(bincode::serialize_into(file, &data)?)
+        })?;
+
+        for segment in 0..=self.log_segment {
+            let segment_path = self.segment_path(segment);
+            if self.backend.is_file(&segment_path) {
+                self.backend.remove_file(&segment_path)?;
+            }
+        }
+        self.log_segment = 0;
+        self.log_segment_records = 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{file_sending::storage_backend::PosixBackend, tempdir::TempDirProvider};
+
+    #[test]
+    fn test_in_flight_timeouts() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let path = folder.path().join("inflight.bin");
+        let file_id = Uuid::new_v4();
+
+        let mut tracker = InFlightTracker::load_or_create(Arc::new(PosixBackend), &path)?;
+        tracker.begin_session();
+        tracker.record_sent(file_id, FilePartId::Header, 5, 0);
+        tracker.record_sent(file_id, FilePartId::Part(0), 5, 0);
+        tracker.record_sent(file_id, FilePartId::Part(1), 5, 0);
+
+        // Re-sending keeps the original priority
+        tracker.record_sent(file_id, FilePartId::Part(1), 4, 100);
+        tracker.remove_acked(
+            file_id,
+            &FilePartIdRangeInclusive::new_single(FilePartId::Header),
+        );
+        tracker.save()?;
+
+        let mut tracker = InFlightTracker::load_or_create(Arc::new(PosixBackend), &path)?;
+        assert!(!tracker.is_in_flight(file_id, FilePartId::Header));
+
+        let timeout = InFlightTimeout {
+            sessions: Some(2),
+            duration: Some(Duration::from_nanos(50)),
+        };
+
+        let timed_out = tracker.take_timed_out(&timeout, 60);
+        assert_eq!(timed_out.len(), 1);
+        assert_eq!(timed_out[0].part_id, FilePartId::Part(0));
+
+        tracker.begin_session();
+        tracker.begin_session();
+        let timed_out = tracker.take_timed_out(&timeout, 60);
+        assert_eq!(timed_out.len(), 1);
+        assert_eq!(timed_out[0].part_id, FilePartId::Part(1));
+        assert_eq!(timed_out[0].original_priority, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_flight_log() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let path = folder.path().join("inflight.bin");
+        let file_id = Uuid::new_v4();
+        let part_count = LOG_SEGMENT_RECORDS + 10;
+
+        let mut tracker = InFlightTracker::load_or_create(Arc::new(PosixBackend), &path)?;
+        tracker.begin_session();
+        for part in 0..part_count {
+            tracker.record_sent(file_id, FilePartId::Part(part), 3, 0);
+            tracker.append_record(file_id, FilePartId::Part(part))?;
+        }
+        assert!(!path.exists());
+        assert!(tracker.segment_path(1).exists());
+
+        // A record that was interrupted while being appended
+        let mut segment = std::fs::OpenOptions::new()
+            .append(true)
+            .open(tracker.segment_path(1))?;
+        std::io::Write::write_all(&mut segment, &[1, 2, 3])?;
+
+        let mut tracker = InFlightTracker::load_or_create(Arc::new(PosixBackend), &path)?;
+        assert_eq!(tracker.current_session, 1);
+        for part in 0..part_count {
+            assert!(tracker.is_in_flight(file_id, FilePartId::Part(part)));
+        }
+
+        tracker.remove_acked(
+            file_id,
+            &FilePartIdRangeInclusive::new(FilePartId::Part(10), FilePartId::Part(19)),
+        );
+        tracker.save()?;
+        assert!(!tracker.segment_path(0).exists());
+        assert!(!tracker.segment_path(1).exists());
+
+        let mut tracker = InFlightTracker::load_or_create(Arc::new(PosixBackend), &path)?;
+        assert!(tracker.is_in_flight(file_id, FilePartId::Part(9)));
+        assert!(!tracker.is_in_flight(file_id, FilePartId::Part(10)));
+        assert!(!tracker.is_in_flight(file_id, FilePartId::Part(19)));
+        assert!(tracker.is_in_flight(file_id, FilePartId::Part(20)));
+        assert_eq!(tracker.take_file(file_id).len(), part_count as usize - 10);
+
+        Ok(())
+    }
+}
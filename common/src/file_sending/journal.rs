@@ -0,0 +1,135 @@
+use std::{path::PathBuf, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::file_part_id::FilePartId;
+
+use super::{
+    managed_sending_file::write_file_atomic, storage_backend::StorageBackend, tiering::StorageTier,
+};
+
+/// A change to the storage that takes several filesystem operations
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JournalEntry {
+    /// Parts of a file are acknowledged: the state is updated, then their data is removed, and
+    /// the file is deleted if nothing is left. Finished on recovery.
+    Acknowledge {
+        file_id: Uuid,
+        from: FilePartId,
+        to: FilePartId,
+    },
+
+    /// A contiguous file is split into a file per part. Finished on recovery.
+    Split { file_id: Uuid },
+
+    /// A file's folder is removed. Finished on recovery, before the files are loaded.
+    Delete { file_id: Uuid },
+
+    /// A new file is created, with its data moved in last. Rolled back on recovery, unless the
+    /// file was complete, in which case only the priority is set again.
+    Add {
+        file_id: Uuid,
+        priority: Option<i16>,
+    },
+
+    /// A file is staged in the hot tier by copying it there, with its state copied last, or
+    /// unstaged by writing its state back into the storage folder and removing the staged copy.
+    /// On recovery, before the files are loaded, an incomplete copy is removed, and an
+    /// unstaging is done again.
+    Move { file_id: Uuid, to: StorageTier },
+}
+
+/// Write-ahead journal of the storage changes that take several filesystem operations. The
+/// change is written before it's started, and cleared once it's done, so that a change that
+/// was interrupted by a crash can be finished or rolled back on startup, instead of guessing
+/// from the files that were left behind.
+///
+/// Changes are made one at a time, so only a single entry is kept. Starting a change while
+/// another is pending replaces it, which is only done when the new change covers the rest of
+/// the pending one, e.g. deleting a file once all its parts were acknowledged.
+pub struct Journal {
+    backend: Arc<dyn StorageBackend>,
+    path: PathBuf,
+    pending: Option<JournalEntry>,
+}
+
+impl Journal {
+    pub fn load_or_create(
+        backend: Arc<dyn StorageBackend>,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+
+        let pending = if backend.is_file(&path) {
+            bincode::deserialize(&backend.read(&path)?)?
+        } else {
+            None
+        };
+
+        Ok(Self {
+            backend,
+            path,
+            pending,
+        })
+    }
+
+    /// The change that was started, but not finished
+    pub fn pending(&self) -> Option<&JournalEntry> {
+        self.pending.as_ref()
+    }
+
+    /// Record a change before starting it.
+    pub fn begin(&mut self, entry: JournalEntry) -> anyhow::Result<()> {
+        self.write(Some(entry))
+    }
+
+    /// Clear the pending change once it's done.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        if self.pending.is_none() {
+            return Ok(());
+        }
+
+        self.write(None)
+    }
+
+    fn write(&mut self, pending: Option<JournalEntry>) -> anyhow::Result<()> {
+        write_file_atomic(&*self.backend, &self.path, |stream| {
+            Ok(bincode::serialize_into(stream, &pending)?)
+        })?;
+        self.pending = pending;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_sending::storage_backend::MemoryBackend;
+
+    use super::*;
+
+    #[test]
+    fn test_journal() -> anyhow::Result<()> {
+        let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+        let path = PathBuf::from("journal.bin");
+
+        let mut journal = Journal::load_or_create(backend.clone(), &path)?;
+        assert_eq!(journal.pending(), None);
+
+        let entry = JournalEntry::Acknowledge {
+            file_id: Uuid::new_v4(),
+            from: FilePartId::Header,
+            to: FilePartId::Part(3),
+        };
+        journal.begin(entry.clone())?;
+        let mut journal = Journal::load_or_create(backend.clone(), &path)?;
+        assert_eq!(journal.pending(), Some(&entry));
+
+        journal.finish()?;
+        let journal = Journal::load_or_create(backend, &path)?;
+        assert_eq!(journal.pending(), None);
+
+        Ok(())
+    }
+}
@@ -1,25 +1,36 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::{self, BufWriter, Seek, Write},
+    fs::File,
+    io,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use crate::{
     binary_serialize::BinarySerialize,
     chunks::{Chunk, DataChunk, HeaderChunk},
     file_part_id::{FilePartId, FilePartIdRangeInclusive},
-    substream::SubstreamReader,
 };
+use anyhow::Context;
 use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
 
+use super::storage_backend::{BackendFileReader, StorageBackend};
+
+pub use self::info::ManagedFileInfo;
 use self::{
+    checksums::PartChecksums,
     mode::ManagedFileMode,
     state::{ManagedFileState, ManagedFileStatePart},
 };
 
+mod checksums;
+mod info;
 mod mode;
 mod state;
 
+/// The header files of an open file are updated every this many appended parts
+const HEADER_WRITE_INTERVAL: u32 = 64;
+
 /// Managed file structure:
 ///
 /// ```plaintext
@@ -27,8 +38,10 @@ mod state;
 /// ├── header.json - The human-readable header file. This is never read.
 /// ├
 /// ├── header.bin  - The machine-readable header file.
+/// ├── info.json   - Sender-only information about the file, e.g. its input source.
+/// ├── checksums.bin - The checksum of each data part, to detect corruption of stored parts.
 /// ├── mode.bin    - The mode of the file, representing either "contiguous" or "split".
-/// ├── state.bin   - The state file. It marks which parts are acknowledged.
+/// ├── state.bin   - The state file. It marks which parts are acknowledged, as a bitmap.
 /// ├
 /// ├── data.bin    - The raw file binary data. This is present in contiguous mode.
 /// └── data/       - The data folder. This is present in split mode.
@@ -37,21 +50,25 @@ mod state;
 ///     └── 2.bin
 /// ```
 ///
-/// There are 2 modes: contiguous and split. In contiguous mode, the data is stored in a single
+/// There are 3 modes: contiguous, split and punched. In contiguous mode, the data is stored in a single
 /// file. In split mode, the data is stored in multiple files. Split mode can save storage space for
 /// files that have been mostly acknolwedged, with only a few parts remaining. But, they require
-/// more disk reads/writes to create.
+/// more disk reads/writes to create. Punched mode saves the same space without copying the data,
+/// see below.
 ///
 /// ## Creation process
 ///
 /// When a managed file is being created, the following steps are made:
 /// 1. Create the folder
 /// 2. Create the header.json files
-/// 3. Create the the header.bin, mode.bin the state.bin file
+/// 3. Create the the header.bin, info.json, checksums.bin, mode.bin the state.bin file
 /// 4. Move in the data.bin file
 ///
 /// The state should be "contiguous".
 ///
+/// If info.json is missing when reading, the default info is used, as the file was likely created
+/// before info.json existed. Likewise, if checksums.bin is missing, the parts aren't verified.
+///
 /// When reading header.bin, if it's "contugous" and data.bin is missing, then the file
 /// is invalid, as the creation process must've been interrupted.
 ///
@@ -94,64 +111,216 @@ mod state;
 /// When a part of a split managed file part is acknowledged, the state files should be updated first,
 /// then the data file should be deleted after. If zero parts are left, then the managed file can
 /// safely be deleted.
-#[derive(Debug, PartialEq, Eq)]
+///
+/// ## Hole punching process
+///
+/// Instead of splitting, the space of the acknowledged parts can be freed in place, by punching
+/// holes into data.bin. Not every filesystem supports that, so the file is split instead if it
+/// doesn't. When holes are punched, the following steps are made:
+/// 1. Punch holes where the acknowledged parts are in data.bin
+/// 2. Modify the state to be "punched"
+///
+/// Punched files are read like contiguous files. If the process was interrupted, then the file is
+/// still contiguous, with some of its acknowledged parts zeroed, which is never read.
+///
+/// ### Acknowledging process
+///
+/// When a part of a punched managed file is acknowledged, the state files should be updated first.
+/// Then data.bin is shrunk like for contiguous files, and a hole is punched where the part was.
+///
+/// ## Open files
+///
+/// Files that are still growing, e.g. logs that are being written to, are created "open". They
+/// start out in split mode without any data parts, and the header is marked as open. When a part
+/// is appended, the following steps are made:
+/// 1. Write the part's file in the data folder
+/// 2. Add the part to the state
+/// 3. Append the part's checksum to checksums.bin in place, and then its count
+/// 4. Every few parts, and after a short part, update the part count and size in the header files
+///
+/// The count in checksums.bin is the number of appended parts. If the state has parts that
+/// checksums.bin doesn't count yet, then appending was interrupted after the state was written,
+/// and their checksums are appended from the parts' files. If the header counts fewer parts than
+/// checksums.bin, then it's brought up to date. The parts it's missing are full, except for the
+/// last one, whose size is taken from its file.
+///
+/// Every part of an open file is full, except for the last one, which can only be appended right
+/// before the file is sealed. Sealing adds the header back to the state, so that it's sent again,
+/// and then clears the open mark in the header files. An open file isn't finished when all of its
+/// parts are acknowledged, as more parts may still be appended.
+///
+/// ## Moving process
+///
+/// Files can be moved to another folder, which may be in another storage backend. When a file is
+/// moved, the following steps are made:
+/// 1. Copy all the files except state.bin into the new folder
+/// 2. Copy state.bin into the new folder
+/// 3. Delete the old folder
+///
+/// If the new folder doesn't have a state.bin, then the move was interrupted before the copy was
+/// complete, and the new folder can be deleted. Otherwise, the old folder can be deleted.
+///
+/// Punched files are copied as contiguous files, and holes are punched again if the new backend
+/// supports it.
+///
+/// All the files are accessed through a [`StorageBackend`], so the "folders" above may only
+/// be path prefixes, depending on the backend.
+#[derive(Debug)]
 pub struct ManagedSendingFile {
+    backend: Arc<dyn StorageBackend>,
     folder_path: PathBuf,
     header: HeaderChunk,
+    info: ManagedFileInfo,
+    checksums: Option<PartChecksums>,
     mode: ManagedFileMode,
     state: ManagedFileState,
 }
 
+/// A stored part didn't match the checksum it had when the file was created
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartChecksumMismatch {
+    pub file_id: uuid::Uuid,
+    pub part: u32,
+}
+
+impl std::fmt::Display for PartChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Checksum mismatch for part {} of file {}",
+            self.part, self.file_id
+        )
+    }
+}
+
+impl std::error::Error for PartChecksumMismatch {}
+
+// The backend isn't compared, a file is the same regardless of which backend handle loaded it
+impl PartialEq for ManagedSendingFile {
+    fn eq(&self, other: &Self) -> bool {
+        self.folder_path == other.folder_path
+            && self.header == other.header
+            && self.info == other.info
+            && self.checksums == other.checksums
+            && self.mode == other.mode
+            && self.state == other.state
+    }
+}
+
+impl Eq for ManagedSendingFile {}
+
 impl ManagedSendingFile {
     /// Please reference the doc comment on [`ManagedFile`] for more information.
     pub fn create_new_from_header(
+        backend: Arc<dyn StorageBackend>,
         managed_file_destination_pat: impl AsRef<Path>,
         data_file_path: impl AsRef<Path>,
         header: HeaderChunk,
+        info: ManagedFileInfo,
     ) -> anyhow::Result<Self> {
         let path = managed_file_destination_pat.as_ref();
         let data_file_path = data_file_path.as_ref();
         let mode = ManagedFileMode::Contiguous;
 
         // 1. Create the folder
-        std::fs::create_dir_all(path)?;
+        backend.create_dir_all(path)?;
 
         // 2. Create the header.json file
-        write_file_atomic(path.join("header.json"), |file| {
+        write_file_atomic(&*backend, path.join("header.json"), |file| {
             Ok(serde_json::to_writer_pretty(file, &header)?)
         })?;
 
-        // 3. Create the the header.bin, mode.bin the state.bin file
-        write_file_atomic(path.join("header.bin"), |file| {
+        // 3. Create the the header.bin, info.json, checksums.bin, mode.bin the state.bin file
+        write_file_atomic(&*backend, path.join("header.bin"), |file| {
             Ok(header.serialize_to_stream(file)?)
         })?;
-        write_file_atomic(path.join("mode.bin"), |file| {
+        write_file_atomic(&*backend, path.join("info.json"), |file| {
+            Ok(serde_json::to_writer(file, &info)?)
+        })?;
+        let checksums = PartChecksums::compute_from_reader(
+            File::open(data_file_path)?,
+            header.file_part_size,
+            header.part_count,
+        )?;
+        write_file_atomic(&*backend, path.join("checksums.bin"), |file| {
+            Ok(checksums.serialize_to_stream(file)?)
+        })?;
+        write_file_atomic(&*backend, path.join("mode.bin"), |file| {
             Ok(mode.serialize_to_stream(file)?)
         })?;
 
         let state_path = path.join("state.bin");
-        let state = ManagedFileState::new_from_part_count(header.part_count, state_path)?;
+        let state =
+            ManagedFileState::new_from_part_count(&*backend, header.part_count, state_path)?;
 
         // 4. Move in the data.bin file
-        std::fs::rename(data_file_path, path.join("data.bin"))?;
+        backend.import_file(data_file_path, &path.join("data.bin"))?;
+
+        Ok(Self {
+            backend,
+            folder_path: path.to_path_buf(),
+            header,
+            info,
+            checksums: Some(checksums),
+            mode,
+            state,
+        })
+    }
+
+    /// Create an open file, that parts are appended to until it's sealed. The header must be
+    /// marked as open, and have no parts yet.
+    pub fn create_open(
+        backend: Arc<dyn StorageBackend>,
+        managed_file_destination_path: impl AsRef<Path>,
+        header: HeaderChunk,
+        info: ManagedFileInfo,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            header.open && header.part_count == 0 && header.size == 0,
+            "Open files must be created without any parts"
+        );
+
+        let path = managed_file_destination_path.as_ref();
+        let mode = ManagedFileMode::Split;
+        let checksums = PartChecksums::default();
+
+        backend.create_dir_all(&path.join("data"))?;
+        write_header_files(&*backend, path, &header)?;
+        write_file_atomic(&*backend, path.join("info.json"), |file| {
+            Ok(serde_json::to_writer(file, &info)?)
+        })?;
+        write_file_atomic(&*backend, path.join("checksums.bin"), |file| {
+            Ok(checksums.serialize_to_stream(file)?)
+        })?;
+        write_file_atomic(&*backend, path.join("mode.bin"), |file| {
+            Ok(mode.serialize_to_stream(file)?)
+        })?;
+
+        let state = ManagedFileState::new_from_part_count(&*backend, 0, path.join("state.bin"))?;
 
         Ok(Self {
+            backend,
             folder_path: path.to_path_buf(),
             header,
+            info,
+            checksums: Some(checksums),
             mode,
             state,
         })
     }
 
     /// Please reference the doc comment on [`ManagedFile`] for more information.
-    pub fn try_read_from_path(path: impl AsRef<Path>) -> anyhow::Result<Option<Self>> {
+    pub fn try_read_from_path(
+        backend: Arc<dyn StorageBackend>,
+        path: impl AsRef<Path>,
+    ) -> anyhow::Result<Option<Self>> {
         // Logging rule: Use `tracing::warn` for recoverable states, and `tracing::error` for
         // unrecoverable states that will result in data loss.
 
         let path = path.as_ref();
 
         let cleanup = || {
-            let result = std::fs::remove_dir_all(path);
+            let result = backend.remove_dir_all(path);
 
             if let Err(e) = result {
                 tracing::error!(
@@ -162,13 +331,13 @@ impl ManagedSendingFile {
         };
 
         // Check if the path exists and is a folder, otherwise return None.
-        if !path.is_dir() {
+        if !backend.is_dir(path) {
             return Ok(None);
         }
 
         // Read header.bin, if it's missing then it's invalid
         let header_path = path.join("header.bin");
-        if !header_path.is_file() {
+        if !backend.is_file(&header_path) {
             tracing::warn!(
                 "Managed file folder was missing header.bin, deleting: {:?}",
                 path
@@ -177,12 +346,29 @@ impl ManagedSendingFile {
             return Ok(None);
         }
 
-        let mut header_file = File::open(header_path)?;
-        let header = HeaderChunk::deserialize_from_stream(&mut header_file)?;
+        let header = HeaderChunk::deserialize_from_stream(&mut &backend.read(&header_path)?[..])?;
+
+        // Read info.json, if it's missing then use the default
+        let info_path = path.join("info.json");
+        let info = if backend.is_file(&info_path) {
+            serde_json::from_slice(&backend.read(&info_path)?)?
+        } else {
+            ManagedFileInfo::default()
+        };
+
+        // Read checksums.bin, if it's missing then the parts can't be verified
+        let checksums_path = path.join("checksums.bin");
+        let checksums = if backend.is_file(&checksums_path) {
+            Some(PartChecksums::deserialize_from_stream(
+                &mut &backend.read(&checksums_path)?[..],
+            )?)
+        } else {
+            None
+        };
 
         // Read state.bin, if it's missing then it's invalid
         let state_path = path.join("state.bin");
-        if !state_path.is_file() {
+        if !backend.is_file(&state_path) {
             tracing::warn!(
                 "Managed file folder was missing state.bin, deleting: {:?}",
                 path
@@ -191,11 +377,11 @@ impl ManagedSendingFile {
             return Ok(None);
         }
 
-        let state: ManagedFileState = ManagedFileState::load_from_file(state_path)?;
+        let state: ManagedFileState = ManagedFileState::load_from_file(&*backend, state_path)?;
 
         // Read mode.bin, if it's missing then it's invalid
         let mode_path = path.join("mode.bin");
-        if !mode_path.is_file() {
+        if !backend.is_file(&mode_path) {
             tracing::warn!(
                 "Managed file folder was missing mode.bin, deleting: {:?}",
                 path
@@ -204,17 +390,17 @@ impl ManagedSendingFile {
             return Ok(None);
         }
 
-        let mut mode_file = File::open(mode_path)?;
-        let mode: ManagedFileMode = BinarySerialize::deserialize_from_stream(&mut mode_file)?;
+        let mode: ManagedFileMode =
+            BinarySerialize::deserialize_from_stream(&mut &backend.read(&mode_path)?[..])?;
 
         let mut should_trigger_splitting = false;
 
         match mode {
-            ManagedFileMode::Contiguous => {
+            ManagedFileMode::Contiguous | ManagedFileMode::Punched => {
                 // If the state is "contugous" and data.bin is missing, then the file
                 // is invalid, as the creation process must've been interrupted.
                 let data_path = path.join("data.bin");
-                if !data_path.is_file() {
+                if !backend.is_file(&data_path) {
                     tracing::warn!(
                         "Managed file folder (contiguous mode) was missing data.bin, deleting: {:?}",
                         path
@@ -226,7 +412,7 @@ impl ManagedSendingFile {
                 // If the state is "contiguous" and the data folder exists, then the splitting process
                 // must've been interrupted. In this case, the splitting process is run again.
                 let data_folder_path = path.join("data");
-                if data_folder_path.is_dir() {
+                if backend.is_dir(&data_folder_path) {
                     tracing::warn!(
                         "Managed file folder (contiguous mode) had a data folder which should only exist in split mode. Splitting: {:?}",
                         path
@@ -238,7 +424,7 @@ impl ManagedSendingFile {
                 // If the state is "split" and the data folder is missing, then the state is invalid and
                 // the file can be deleted.
                 let data_folder_path = path.join("data");
-                if !data_folder_path.is_dir() {
+                if !backend.is_dir(&data_folder_path) {
                     tracing::error!(
                         "Managed file folder (split mode) was missing data folder, deleting: {:?}",
                         path
@@ -250,19 +436,17 @@ impl ManagedSendingFile {
                 // If the state is "split" and the data.bin file exists, then it can be deleted, as the
                 // state changes to "split" only after the file has been fully split.
                 let data_path = path.join("data.bin");
-                if data_path.is_file() {
+                if backend.is_file(&data_path) {
                     tracing::warn!(
                         "Managed file folder (split mode) had a data.bin file which should only exist in contiguous mode. Deleting data.bin in: {:?}",
                         path
                     );
-                    std::fs::remove_file(data_path)?;
+                    backend.remove_file(&data_path)?;
                 }
 
                 // If the state is "split" and there's files in the data folder that aren't in the state,
                 // then they can be safely deleted as they're not needed anymore.
-                let mut files = std::fs::read_dir(data_folder_path)?
-                    .map(|e| e.map(|e| e.path()))
-                    .collect::<Result<Vec<_>, _>>()?;
+                let mut files = backend.read_dir(&data_folder_path)?;
 
                 let mut found_parts = 0;
                 for file in files.iter_mut() {
@@ -275,7 +459,7 @@ impl ManagedSendingFile {
                             "Managed file folder (split mode) had a file in the data folder that didn't end with .bin, deleting: {:?}",
                             file
                         );
-                        std::fs::remove_file(file)?;
+                        backend.remove_file(file)?;
                         continue;
                     }
                     let file_part_name = &file_name[..file_name.len() - 4];
@@ -287,13 +471,13 @@ impl ManagedSendingFile {
                             "Managed file folder (split mode) had a file in the data folder that didn't have a valid part name, deleting: {:?}",
                             file
                         );
-                        std::fs::remove_file(file)?;
+                        backend.remove_file(file)?;
                         continue;
                     };
 
                     // If the part is not in the state, then it can be deleted.
                     if !state.remaining_parts().iter().any(|p| p.part == part) {
-                        std::fs::remove_file(file)?;
+                        backend.remove_file(file)?;
                     }
 
                     found_parts += 1;
@@ -313,8 +497,11 @@ impl ManagedSendingFile {
         };
 
         let mut file = Self {
+            backend,
             folder_path: path.to_path_buf(),
             header,
+            info,
+            checksums,
             mode,
             state,
         };
@@ -323,6 +510,10 @@ impl ManagedSendingFile {
             file.trigger_file_split()?;
         }
 
+        if file.header.open {
+            file.recover_interrupted_append()?;
+        }
+
         Ok(Some(file))
     }
 
@@ -335,8 +526,7 @@ impl ManagedSendingFile {
         match part {
             FilePartId::Header => {
                 let file_path = self.folder_path.join("header.bin");
-                let file = File::open(file_path)?;
-                let _file_len = file.metadata()?.len() as usize;
+                let _file_len = self.backend.file_len(&file_path)? as usize;
                 Ok(Some(Chunk::Header(self.header.clone())))
             }
             FilePartId::Part(part_id) => {
@@ -348,47 +538,91 @@ impl ManagedSendingFile {
                     return Ok(None);
                 }
 
-                match self.mode {
-                    ManagedFileMode::Contiguous => {
-                        let file_path = self.folder_path.join("data.bin");
-                        let mut file = File::open(file_path)?;
+                let data = self.read_part_data(part_id)?;
+                self.verify_part_data(part_id, &data)?;
 
-                        let part_size = self.header.file_part_size as usize;
-                        let offset = part_id as usize * part_size;
-                        file.seek(io::SeekFrom::Start(offset as u64))?;
+                let data_chunk = DataChunk {
+                    data,
+                    file_id: self.header.id,
+                    part: part_id,
+                };
 
-                        let mut file = SubstreamReader::new(file, part_size);
-                        let mut data = Vec::new();
-                        std::io::copy(&mut file, &mut data)?;
+                Ok(Some(Chunk::Data(data_chunk)))
+            }
+        }
+    }
 
-                        let data_chunk = DataChunk {
-                            data,
-                            file_id: self.header.id,
-                            part: part_id,
-                        };
+    /// Read the raw data of a part, without verifying it.
+    fn read_part_data(&self, part_id: u32) -> anyhow::Result<Vec<u8>> {
+        let data = match self.mode {
+            ManagedFileMode::Contiguous | ManagedFileMode::Punched => {
+                let file_path = self.folder_path.join("data.bin");
 
-                        Ok(Some(Chunk::Data(data_chunk)))
-                    }
-                    ManagedFileMode::Split => {
-                        let file_path = self
-                            .folder_path
-                            .join(format!("data/{}.bin", part.as_string()));
-                        let mut file = File::open(file_path)?;
-
-                        let mut data = Vec::new();
-                        std::io::copy(&mut file, &mut data)?;
-
-                        let data_chunk = DataChunk {
-                            data,
-                            file_id: self.header.id,
-                            part: part_id,
-                        };
-
-                        Ok(Some(Chunk::Data(data_chunk)))
-                    }
-                }
+                let part_size = self.header.file_part_size as u64;
+                let offset = part_id as u64 * part_size;
+                self.backend.read_range(&file_path, offset, part_size)?
+            }
+            ManagedFileMode::Split => {
+                let file_path = self.folder_path.join(format!("data/{}.bin", part_id));
+                self.backend.read(&file_path)?
+            }
+        };
+
+        Ok(data)
+    }
+
+    fn verify_part_data(&self, part_id: u32, data: &[u8]) -> Result<(), PartChecksumMismatch> {
+        let Some(checksums) = &self.checksums else {
+            return Ok(());
+        };
+
+        if !checksums.verify(part_id, data) {
+            return Err(PartChecksumMismatch {
+                file_id: self.header.id,
+                part: part_id,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Verify all the remaining data parts, returning the ones that are corrupted.
+    pub fn find_corrupted_parts(&self) -> anyhow::Result<Vec<u32>> {
+        let mut corrupted = Vec::new();
+        if self.checksums.is_none() {
+            return Ok(corrupted);
+        }
+
+        for part in self.state.remaining_parts() {
+            let FilePartId::Part(part_id) = part.part else {
+                continue;
+            };
+
+            let data = self.read_part_data(part_id)?;
+            if self.verify_part_data(part_id, &data).is_err() {
+                corrupted.push(part_id);
             }
         }
+
+        Ok(corrupted)
+    }
+
+    /// Copy a corrupted part into the quarantine folder for later inspection, and remove it
+    /// from the parts to send.
+    pub fn quarantine_part(
+        &mut self,
+        part_id: u32,
+        quarantine_folder: &Path,
+    ) -> anyhow::Result<()> {
+        self.backend.create_dir_all(quarantine_folder)?;
+
+        let data = self.read_part_data(part_id)?;
+        let quarantine_path = quarantine_folder.join(format!("{}-{}.bin", self.header.id, part_id));
+        self.backend.write_atomic(&quarantine_path, &data)?;
+
+        self.acknowledge_file_parts(FilePartIdRangeInclusive::new_single(FilePartId::Part(
+            part_id,
+        )))
     }
 
     pub fn trigger_file_split(&mut self) -> anyhow::Result<()> {
@@ -397,10 +631,12 @@ impl ManagedSendingFile {
         }
 
         // 1. Create the data folder
-        std::fs::create_dir_all(self.folder_path.join("data"))?;
+        self.backend
+            .create_dir_all(&self.folder_path.join("data"))?;
 
-        // Prepare data (open the file, get all the non-header remaining parts sorted in reverse)
-        let mut file = File::open(self.folder_path.join("data.bin"))?;
+        // Prepare data (get all the non-header remaining parts sorted in reverse)
+        let data_path = self.folder_path.join("data.bin");
+        let data_len = self.backend.file_len(&data_path)?;
         let remaining_parts = &self.state.remaining_parts();
         let mut remaining_part_numbers = remaining_parts
             .iter()
@@ -415,46 +651,255 @@ impl ManagedSendingFile {
         // 2. Split the data.bin into the data folder based on remaining parts, starting
         // from the last part and working our way backwards, reducing the file size as we go.
         for part_id in remaining_part_numbers {
-            let part_size = self.header.file_part_size as usize;
-            let offset = part_id as usize * part_size;
+            let part_size = self.header.file_part_size as u64;
+            let offset = part_id as u64 * part_size;
             let max = offset + part_size;
 
-            if max > file.metadata()?.len() as usize {
+            if max > data_len {
                 anyhow::bail!("File size is smaller than expected");
             }
 
-            file.seek(io::SeekFrom::Start(offset as u64))?;
-            let mut reader = SubstreamReader::new(&mut file, part_size);
+            let data = self.backend.read_range(&data_path, offset, part_size)?;
 
             let path = self.folder_path.join(format!("data/{}.bin", part_id));
-            write_file_atomic(path, move |file| {
-                io::copy(&mut reader, file)?;
-                Ok(())
-            })?;
+            self.backend.write_atomic(&path, &data)?;
         }
 
         // 3. Modify the state to be "split"
         self.set_mode(ManagedFileMode::Split)?;
 
         // 4. Delete the data.bin file
-        std::fs::remove_file(self.folder_path.join("data.bin"))?;
+        self.backend.remove_file(&data_path)?;
+
+        Ok(())
+    }
+
+    /// Free the space of the acknowledged parts in place, by punching holes into data.bin,
+    /// instead of splitting the file. Returns false if there was no hole to punch, or if the
+    /// backend or the filesystem can't punch holes, in which case the file is left as it is.
+    pub fn trigger_hole_punching(&mut self) -> anyhow::Result<bool> {
+        if self.mode != ManagedFileMode::Contiguous {
+            anyhow::bail!("File isn't contiguous");
+        }
+
+        // 1. Punch holes where the acknowledged parts are in data.bin. The parts after the last
+        // remaining one were already cut off when they were acknowledged.
+        let part_size = self.header.file_part_size as u64;
+        let mut next_part = 0;
+        let mut punched = false;
+        for part in self.state.remaining_parts() {
+            let FilePartId::Part(part_id) = part.part else {
+                continue;
+            };
+
+            if part_id > next_part {
+                let offset = next_part as u64 * part_size;
+                let len = (part_id - next_part) as u64 * part_size;
+                match self.punch_data_hole(offset, len) {
+                    Ok(()) => punched = true,
+                    Err(err) if err.kind() == io::ErrorKind::Unsupported => return Ok(false),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            next_part = part_id + 1;
+        }
+
+        // Without a hole, it's not known whether later acknowledgements could punch one
+        if !punched {
+            return Ok(false);
+        }
+
+        // 2. Modify the state to be "punched"
+        self.set_mode(ManagedFileMode::Punched)?;
+
+        Ok(true)
+    }
+
+    fn punch_data_hole(&self, offset: u64, len: u64) -> io::Result<()> {
+        let data_path = self.folder_path.join("data.bin");
+        self.backend.punch_hole(&data_path, offset, len)
+    }
+
+    /// Append a part to an open file. Only the last part of a file may be shorter than the part
+    /// size, so after a short part nothing else can be appended.
+    pub fn append_part(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        anyhow::ensure!(self.header.open, "File {} isn't open", self.header.id);
+
+        let part_size = self.header.file_part_size as u64;
+        anyhow::ensure!(
+            !data.is_empty() && data.len() as u64 <= part_size,
+            "Appended part must be between 1 and {} bytes, got {}",
+            part_size,
+            data.len()
+        );
+        anyhow::ensure!(
+            self.header.size == self.header.part_count as u64 * part_size,
+            "File {} already ends with a short part",
+            self.header.id
+        );
+
+        let part_id = self.header.part_count;
+
+        // 1. Write the part's file in the data folder
+        let part_path = self.folder_path.join(format!("data/{}.bin", part_id));
+        self.backend.write_atomic(&part_path, data)?;
+
+        // 2. Add the part to the state
+        self.state.add_part(
+            &*self.backend,
+            ManagedFileStatePart {
+                part: FilePartId::Part(part_id),
+                priority: self.info.append_priority,
+            },
+        )?;
+
+        // 3. Append the part's checksum to checksums.bin
+        self.append_checksum(part_id, data)?;
+
+        // 4. Update the part count and size in the header files, which is only needed every few
+        // parts, as they're recovered from checksums.bin
+        self.header.part_count += 1;
+        self.header.size += data.len() as u64;
+        let is_short = data.len() as u64 != part_size;
+        if is_short || self.header.part_count.is_multiple_of(HEADER_WRITE_INTERVAL) {
+            write_header_files(&*self.backend, &self.folder_path, &self.header)?;
+        }
+
+        Ok(())
+    }
+
+    fn append_checksum(&mut self, part_id: u32, data: &[u8]) -> anyhow::Result<()> {
+        let path = self.folder_path.join("checksums.bin");
+        match &mut self.checksums {
+            Some(checksums) if checksums.part_count() == part_id => {
+                checksums.append_part_in_place(&*self.backend, &path, data)?;
+            }
+            checksums => {
+                let checksums = checksums.get_or_insert_with(PartChecksums::default);
+                checksums.set_part(part_id, data);
+                write_file_atomic(&*self.backend, path, |file| {
+                    Ok(checksums.serialize_to_stream(file)?)
+                })?;
+            }
+        }
 
         Ok(())
     }
 
+    /// Seal an open file, once nothing more gets appended to it. The header is sent again,
+    /// with the final part count and size, and the digest of the whole file if it's known.
+    pub fn seal(&mut self, sha256: Option<[u8; 32]>) -> anyhow::Result<()> {
+        anyhow::ensure!(self.header.open, "File {} isn't open", self.header.id);
+
+        self.state.add_part(
+            &*self.backend,
+            ManagedFileStatePart {
+                part: FilePartId::Header,
+                priority: self.info.append_priority,
+            },
+        )?;
+
+        self.header.open = false;
+        self.header.sha256 = sha256;
+        write_header_files(&*self.backend, &self.folder_path, &self.header)?;
+
+        Ok(())
+    }
+
+    /// Finish the appends of an open file that were interrupted after the part was added to
+    /// the state, and count the appended parts that the header doesn't count yet.
+    fn recover_interrupted_append(&mut self) -> anyhow::Result<()> {
+        let appended_count = self
+            .checksums
+            .as_ref()
+            .map(PartChecksums::part_count)
+            .unwrap_or_default()
+            .max(self.header.part_count);
+        let mut unchecksummed_parts = self
+            .state
+            .remaining_parts()
+            .iter()
+            .filter_map(|part| match part.part {
+                FilePartId::Part(i) if i >= appended_count => Some(i),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        unchecksummed_parts.sort_unstable();
+
+        for part_id in unchecksummed_parts {
+            let part_path = self.folder_path.join(format!("data/{}.bin", part_id));
+            let data = self.backend.read(&part_path)?;
+            self.append_checksum(part_id, &data)?;
+        }
+
+        let checksummed_count = self
+            .checksums
+            .as_ref()
+            .map(PartChecksums::part_count)
+            .unwrap_or_default();
+        if checksummed_count <= self.header.part_count {
+            return Ok(());
+        }
+
+        tracing::info!(
+            "Open managed file {} has {} appended parts that its header is missing, updating it",
+            self.header.id,
+            checksummed_count - self.header.part_count
+        );
+
+        // Acknowledged parts' files are already deleted, but only the last part can be short
+        for part_id in self.header.part_count..checksummed_count {
+            let part_path = self.folder_path.join(format!("data/{}.bin", part_id));
+            self.header.size += match self.backend.is_file(&part_path) {
+                true => self.backend.file_len(&part_path)?,
+                false => self.header.file_part_size as u64,
+            };
+            self.header.part_count += 1;
+        }
+
+        write_header_files(&*self.backend, &self.folder_path, &self.header)
+    }
+
     pub fn decrease_part_priority(&mut self, part: FilePartId) -> anyhow::Result<()> {
-        self.state.modify_part_priority(part, |p| {
+        self.state.modify_part_priority(&*self.backend, part, |p| {
             *p -= 1;
         })?;
 
         Ok(())
     }
 
+    /// Decrease a part's priority in memory only, until the state is flushed.
+    pub fn decrease_part_priority_deferred(&mut self, part: FilePartId) {
+        self.state.modify_part_priority_deferred(part, |p| {
+            *p -= 1;
+        });
+    }
+
+    /// Write the priority changes that were only made in memory.
+    pub fn flush_state(&mut self) -> anyhow::Result<()> {
+        self.state.flush(&*self.backend)
+    }
+
+    pub fn set_part_priority(&mut self, part: FilePartId, priority: i16) -> anyhow::Result<()> {
+        self.state.modify_part_priority(&*self.backend, part, |p| {
+            *p = priority;
+        })?;
+
+        Ok(())
+    }
+
     pub fn set_all_parts_priorities(&mut self, priority: i16) -> anyhow::Result<()> {
-        self.state.modify_all_part_priorities(|p| {
+        self.state.modify_all_part_priorities(&*self.backend, |p| {
             *p = priority;
         })?;
 
+        // Parts that are appended later get the same priority
+        if self.header.open {
+            let mut info = self.info.clone();
+            info.append_priority = priority;
+            self.set_info(info)?;
+        }
+
         Ok(())
     }
 
@@ -464,32 +909,25 @@ impl ManagedSendingFile {
     ) -> anyhow::Result<()> {
         // First, update the state
         self.state
-            .filter_remaining_parts(|p| !part_range.contains(p.part))?;
+            .filter_remaining_parts(&*self.backend, |p| !part_range.contains(p.part))?;
 
         // Then, delete the data accordingly
         match self.mode {
             ManagedFileMode::Contiguous => {
-                // Find the last part number that's unacknowledged
-                let last_unacknowledged_part = self.get_last_unacknowledged_data_chunk_index();
-
-                // Open the file
-                let file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .open(self.folder_path.join("data.bin"))?;
+                self.shrink_data_file()?;
+            }
+            ManagedFileMode::Punched => {
+                let result_len = self.shrink_data_file()?;
 
-                // Determine the resulting file size
-                let result_len = if let Some(last) = last_unacknowledged_part {
+                // Punch a hole where the acknowledged data parts were, if they weren't cut off
+                let (from, to) = (part_range.from.to_index(), part_range.to.to_index());
+                if to > 0 {
                     let part_size = self.header.file_part_size as u64;
-                    (last + 1) as u64 * part_size
-                } else {
-                    0
-                };
-
-                // Shrink the file to the correct size
-                let file_current_len = file.metadata()?.len();
-                if file_current_len > result_len {
-                    file.set_len(result_len)?;
+                    let offset = from.saturating_sub(1) as u64 * part_size;
+                    let end = (to as u64 * part_size).min(result_len);
+                    if end > offset {
+                        self.punch_data_hole(offset, end - offset)?;
+                    }
                 }
             }
             ManagedFileMode::Split => {
@@ -503,12 +941,12 @@ impl ManagedSendingFile {
                     let file_path = self.folder_path.join(format!("data/{}.bin", i));
 
                     // If the file doesn't exist already, do nothing.
-                    if !file_path.is_file() {
+                    if !self.backend.is_file(&file_path) {
                         continue;
                     }
 
                     // Delete the file
-                    let result = std::fs::remove_file(file_path);
+                    let result = self.backend.remove_file(&file_path);
                     if let Err(e) = result {
                         tracing::error!(
                             "Failed to delete file part {} in managed file folder: {}",
@@ -523,19 +961,112 @@ impl ManagedSendingFile {
         Ok(())
     }
 
+    /// Cut off the acknowledged parts at the end of data.bin. Returns the resulting length.
+    fn shrink_data_file(&self) -> anyhow::Result<u64> {
+        // Find the last part number that's unacknowledged
+        let last_unacknowledged_part = self.get_last_unacknowledged_data_chunk_index();
+
+        let data_path = self.folder_path.join("data.bin");
+
+        // Determine the resulting file size
+        let result_len = if let Some(last) = last_unacknowledged_part {
+            let part_size = self.header.file_part_size as u64;
+            (last + 1) as u64 * part_size
+        } else {
+            0
+        };
+
+        // Shrink the file to the correct size
+        let file_current_len = self.backend.file_len(&data_path)?;
+        if file_current_len > result_len {
+            self.backend.set_len(&data_path, result_len)?;
+        }
+
+        Ok(result_len)
+    }
+
     pub fn is_finished(&self) -> bool {
-        self.state.remaining_parts().is_empty()
+        self.state.remaining_parts().is_empty() && !self.header.open
     }
 
     pub fn delete(self) -> anyhow::Result<()> {
-        std::fs::remove_dir_all(self.folder_path)?;
+        self.backend.remove_dir_all(&self.folder_path)?;
+        Ok(())
+    }
+
+    /// Move the file into a folder that may be in another storage backend.
+    pub fn move_to(
+        &mut self,
+        backend: Arc<dyn StorageBackend>,
+        folder_path: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        let moved = self.copy_to(backend, folder_path)?;
+
+        // 3. Delete the old folder
+        let old = std::mem::replace(self, moved);
+        old.delete()
+    }
+
+    /// Copy the file into a folder that may be in another storage backend, and return the copy.
+    /// The original folder is left as it is.
+    pub fn copy_to(
+        &mut self,
+        backend: Arc<dyn StorageBackend>,
+        folder_path: impl AsRef<Path>,
+    ) -> anyhow::Result<Self> {
+        let folder_path = folder_path.as_ref();
+        self.flush_state()?;
+
+        // 1. Copy all the files except state.bin into the new folder
+        backend.create_dir_all(folder_path)?;
+        copy_folder(&*self.backend, &self.folder_path, &*backend, folder_path)?;
+        if self.mode == ManagedFileMode::Punched {
+            write_file_atomic(&*backend, folder_path.join("mode.bin"), |file| {
+                Ok(ManagedFileMode::Contiguous.serialize_to_stream(file)?)
+            })?;
+        }
+
+        // 2. Copy state.bin into the new folder
+        let state = self.backend.read(&self.folder_path.join("state.bin"))?;
+        backend.write_atomic(&folder_path.join("state.bin"), &state)?;
+
+        let mut copy = Self::try_read_from_path(backend, folder_path)?
+            .context("Copied file couldn't be read back")?;
+        if self.mode == ManagedFileMode::Punched {
+            copy.trigger_hole_punching()?;
+        }
+
+        Ok(copy)
+    }
+
+    /// Write the info and the state of the file into another copy of its folder, which still has
+    /// the data of all the parts that are left. state.bin is written last.
+    pub fn write_state_to(
+        &mut self,
+        backend: &dyn StorageBackend,
+        folder_path: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        let folder_path = folder_path.as_ref();
+        self.flush_state()?;
+
+        for name in ["info.json", "state.bin"] {
+            let path = self.folder_path.join(name);
+            if self.backend.is_file(&path) {
+                backend.write_atomic(&folder_path.join(name), &self.backend.read(&path)?)?;
+            }
+        }
+
         Ok(())
     }
 
+    pub fn is_contiguous(&self) -> bool {
+        self.mode == ManagedFileMode::Contiguous
+    }
+
     fn set_mode(&mut self, new_mode: ManagedFileMode) -> anyhow::Result<()> {
         self.mode = new_mode;
 
-        write_file_atomic(self.folder_path.join("mode.bin"), |file| {
+        write_file_atomic(&*self.backend, self.folder_path.join("mode.bin"), |file| {
             Ok(self.mode.serialize_to_stream(file)?)
         })?;
 
@@ -550,6 +1081,19 @@ impl ManagedSendingFile {
         &self.header
     }
 
+    pub fn info(&self) -> &ManagedFileInfo {
+        &self.info
+    }
+
+    pub fn set_info(&mut self, info: ManagedFileInfo) -> anyhow::Result<()> {
+        write_file_atomic(&*self.backend, self.folder_path.join("info.json"), |file| {
+            Ok(serde_json::to_writer(file, &info)?)
+        })?;
+        self.info = info;
+
+        Ok(())
+    }
+
     fn get_last_unacknowledged_data_chunk_index(&self) -> Option<u32> {
         let mut last_unacknowledged_part = None;
         for part in self.state.remaining_parts().iter() {
@@ -591,7 +1135,8 @@ impl ManagedSendingFile {
                     0
                 }
             }
-            ManagedFileMode::Split => {
+            // Only the remaining parts take up space, the acknowledged ones were removed or punched
+            ManagedFileMode::Split | ManagedFileMode::Punched => {
                 let mut result = 0;
 
                 for part in self.state.remaining_parts().iter() {
@@ -609,7 +1154,7 @@ impl ManagedSendingFile {
     }
 
     pub fn chunks_saved_by_splitting(&self) -> u32 {
-        if self.mode == ManagedFileMode::Split {
+        if self.mode != ManagedFileMode::Contiguous {
             return 0;
         }
 
@@ -623,37 +1168,102 @@ impl ManagedSendingFile {
     }
 }
 
-fn write_file_atomic(
+/// Serialize a file in memory, then write it atomically through the backend.
+pub(crate) fn write_file_atomic(
+    backend: &dyn StorageBackend,
     path: impl AsRef<Path>,
-    write: impl FnOnce(&mut BufWriter<&mut File>) -> anyhow::Result<()>,
+    write: impl FnOnce(&mut Vec<u8>) -> anyhow::Result<()>,
 ) -> anyhow::Result<()> {
-    let path = path.as_ref();
-    let tmp_path = path.with_extension("-tmp");
+    let mut data = Vec::new();
+    write(&mut data)?;
+    backend.write_atomic(path.as_ref(), &data)?;
 
-    let mut file = std::fs::File::create(&tmp_path)?;
-    let mut writer = BufWriter::new(&mut file);
-    write(&mut writer)?;
-    writer.flush()?;
-    drop(writer);
+    Ok(())
+}
 
-    file.sync_all()?;
-    drop(file);
+/// Write both the human-readable and the machine-readable header files.
+fn write_header_files(
+    backend: &dyn StorageBackend,
+    folder_path: &Path,
+    header: &HeaderChunk,
+) -> anyhow::Result<()> {
+    write_file_atomic(backend, folder_path.join("header.json"), |file| {
+        Ok(serde_json::to_writer_pretty(file, header)?)
+    })?;
+    write_file_atomic(backend, folder_path.join("header.bin"), |file| {
+        Ok(header.serialize_to_stream(file)?)
+    })?;
 
-    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Copy the files and folders inside a folder into another, except for state.bin.
+fn copy_folder(
+    from_backend: &dyn StorageBackend,
+    from: &Path,
+    to_backend: &dyn StorageBackend,
+    to: &Path,
+) -> anyhow::Result<()> {
+    for path in from_backend.read_dir(from)? {
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        let to_path = to.join(name);
+
+        if from_backend.is_dir(&path) {
+            to_backend.create_dir_all(&to_path)?;
+            copy_folder(from_backend, &path, to_backend, &to_path)?;
+        } else if name != "state.bin" {
+            // Data files can be larger than the memory
+            let len = from_backend.file_len(&path)?;
+            let mut reader = BackendFileReader::new(from_backend, &path);
+            to_backend.write_atomic_from(&to_path, &mut reader, len)?;
+        }
+    }
 
     Ok(())
 }
 
-pub fn generate_file_header_from_path(path: &Path, file_part_size: u32) -> io::Result<HeaderChunk> {
-    let file = File::open(path)?;
+/// Hash the contents of a file with SHA-256.
+pub fn hash_file_contents(path: &Path) -> io::Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
 
-    let file_size = file.metadata()?.len();
+    Ok(hasher.finalize().into())
+}
+
+/// The suffix of the compressed copy of a file, which is written next to it while the file
+/// is being added to the storage.
+pub const COMPRESSING_SUFFIX: &str = ".leoftp-compressing";
+
+/// Write a zstd compressed copy of a file next to it, and return its path.
+pub fn compress_file_zstd(path: &Path) -> io::Result<PathBuf> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(COMPRESSING_SUFFIX);
+    let compressed_path = path.with_file_name(name);
+
+    let compressed = File::create(&compressed_path)?;
+    zstd::stream::copy_encode(File::open(path)?, &compressed, 0)?;
+    compressed.sync_all()?;
 
-    let file_created_date = file.metadata()?.created()?;
+    Ok(compressed_path)
+}
+
+/// When a file was created, in nanoseconds since the unix epoch
+pub fn file_created_date(path: &Path) -> io::Result<i64> {
+    let file_created_date = std::fs::metadata(path)?.created()?;
     // Convert SystemTime to DateTime<Utc>
     let datetime: DateTime<Utc> = file_created_date.into();
     // Format the datetime to number of nanoseconds since the unix epoch
-    let date = datetime.timestamp_nanos_opt().unwrap_or_default();
+    Ok(datetime.timestamp_nanos_opt().unwrap_or_default())
+}
+
+pub fn generate_file_header_from_path(path: &Path, file_part_size: u32) -> io::Result<HeaderChunk> {
+    let file = File::open(path)?;
+
+    let file_size = file.metadata()?.len();
+    let date = file_created_date(path)?;
 
     let part_count = if file_size % file_part_size as u64 == 0 {
         file_size / file_part_size as u64
@@ -668,6 +1278,11 @@ pub fn generate_file_header_from_path(path: &Path, file_part_size: u32) -> io::R
         size: file_size,
         part_count: part_count as u32,
         file_part_size,
+        sha256: Some(hash_file_contents(path)?),
+        metadata: Default::default(),
+        compression: Default::default(),
+        kind: Default::default(),
+        open: false,
     };
 
     Ok(file_header)
@@ -676,7 +1291,10 @@ pub fn generate_file_header_from_path(path: &Path, file_part_size: u32) -> io::R
 #[cfg(test)]
 mod tests {
     use super::{state::ManagedFileState, *};
-    use crate::tempdir::{TempDir, TempDirProvider};
+    use crate::{
+        file_sending::storage_backend::{LogStructuredBackend, MemoryBackend, PosixBackend},
+        tempdir::{TempDir, TempDirProvider},
+    };
 
     struct DummyFile {
         _folder: TempDir,
@@ -708,6 +1326,11 @@ mod tests {
             } else {
                 (file_size / part_size + 1) as u32
             },
+            sha256: None,
+            metadata: Default::default(),
+            compression: Default::default(),
+            kind: Default::default(),
+            open: false,
         }
     }
 
@@ -718,7 +1341,13 @@ mod tests {
     ) -> anyhow::Result<ManagedSendingFile> {
         let header = make_test_header(file_size, part_size);
         let file = make_dummy_file(file_size)?;
-        ManagedSendingFile::create_new_from_header(path, file.path, header)
+        ManagedSendingFile::create_new_from_header(
+            Arc::new(PosixBackend),
+            path,
+            file.path,
+            header,
+            ManagedFileInfo::default(),
+        )
     }
 
     fn assert_file_exists(path: impl AsRef<Path>) {
@@ -736,7 +1365,7 @@ mod tests {
 
     fn read_state(folder_path: impl AsRef<Path>) -> ManagedFileState {
         let state_path = folder_path.as_ref().join("state.bin");
-        ManagedFileState::load_from_file(state_path).unwrap()
+        ManagedFileState::load_from_file(&PosixBackend, state_path).unwrap()
     }
 
     fn read_mode(folder_path: impl AsRef<Path>) -> ManagedFileMode {
@@ -746,7 +1375,7 @@ mod tests {
     }
 
     fn assert_equal_after_parsing(path: impl AsRef<Path>, file: &ManagedSendingFile) {
-        let new_file = ManagedSendingFile::try_read_from_path(path)
+        let new_file = ManagedSendingFile::try_read_from_path(Arc::new(PosixBackend), path)
             .unwrap()
             .unwrap();
         assert_eq!(&new_file, file);
@@ -1020,4 +1649,219 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_hole_punching() -> anyhow::Result<()> {
+        let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+        let source = TempDirProvider::new_for_test().create()?;
+        let source_path = source.path().join("data.bin");
+        let data = (0..100).collect::<Vec<u8>>();
+        std::fs::write(&source_path, &data)?;
+
+        let path = PathBuf::from("file");
+        let data_path = path.join("data.bin");
+        let mut file = ManagedSendingFile::create_new_from_header(
+            backend.clone(),
+            &path,
+            &source_path,
+            make_test_header(100, 10),
+            ManagedFileInfo::default(),
+        )?;
+
+        // Without a gap before the last remaining part there's no hole to punch, so the file
+        // stays contiguous
+        file.acknowledge_file_parts(FilePartIdRangeInclusive::new_single(FilePartId::Part(9)))?;
+        assert!(!file.trigger_hole_punching()?);
+        assert!(file.is_contiguous());
+
+        file.acknowledge_file_parts(FilePartIdRangeInclusive::new(
+            FilePartId::Part(2),
+            FilePartId::Part(4),
+        ))?;
+        assert_eq!(file.chunks_saved_by_splitting(), 3);
+
+        assert!(file.trigger_hole_punching()?);
+        assert!(!file.is_contiguous());
+        assert_eq!(file.chunks_saved_by_splitting(), 0);
+        assert_eq!(file.calc_remaining_data_size(), 60);
+
+        // The acknowledged parts are zeroed in place, the remaining ones are untouched
+        let stored = backend.read(&data_path)?;
+        assert_eq!(stored.len(), 90);
+        assert_eq!(stored[..20], data[..20]);
+        assert_eq!(stored[20..50], [0; 30]);
+        assert_eq!(stored[50..], data[50..90]);
+
+        // Acknowledging a part punches its hole right away
+        file.acknowledge_file_parts(FilePartIdRangeInclusive::new_single(FilePartId::Part(6)))?;
+        assert_eq!(backend.read_range(&data_path, 60, 10)?, [0; 10]);
+        assert_eq!(file.calc_remaining_data_size(), 50);
+        assert!(file.get_file_part(FilePartId::Part(5))?.is_some());
+        assert!(file.get_file_part(FilePartId::Part(6))?.is_none());
+
+        let loaded = ManagedSendingFile::try_read_from_path(backend, &path)?.unwrap();
+        assert_eq!(loaded, file);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_moving_file() -> anyhow::Result<()> {
+        let memory: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+        let folder = TempDirProvider::new_for_test().create()?;
+        let log: Arc<dyn StorageBackend> = Arc::new(LogStructuredBackend::open(
+            folder.path().join("storage.log"),
+        )?);
+        let source_path = folder.path().join("data.bin");
+        std::fs::write(&source_path, (0..100).collect::<Vec<u8>>())?;
+
+        let path = PathBuf::from("file");
+        let mut file = ManagedSendingFile::create_new_from_header(
+            memory.clone(),
+            &path,
+            &source_path,
+            make_test_header(100, 10),
+            ManagedFileInfo::default(),
+        )?;
+        file.acknowledge_file_parts(FilePartIdRangeInclusive::new(
+            FilePartId::Part(2),
+            FilePartId::Part(4),
+        ))?;
+        assert!(file.trigger_hole_punching()?);
+        let part = file.get_file_part(FilePartId::Part(5))?;
+
+        // Holes are punched again in a backend that supports it
+        let other_memory: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+        file.move_to(other_memory.clone(), &path)?;
+        assert!(!memory.is_dir(&path));
+        assert!(!file.is_contiguous());
+        assert_eq!(file.get_file_part(FilePartId::Part(5))?, part);
+
+        // The log can't punch holes, so the file stays contiguous
+        file.move_to(log.clone(), "moved")?;
+        assert!(!other_memory.is_dir(&path));
+        assert!(file.is_contiguous());
+        assert_eq!(file.get_file_part(FilePartId::Part(5))?, part);
+        let loaded = ManagedSendingFile::try_read_from_path(log, "moved")?.unwrap();
+        assert_eq!(loaded, file);
+        assert_eq!(file.remaining_parts().len(), 8);
+
+        Ok(())
+    }
+
+    fn make_open_test_file(path: impl AsRef<Path>, part_size: u32) -> ManagedSendingFile {
+        let mut header = make_test_header(0, part_size as u64);
+        header.open = true;
+        ManagedSendingFile::create_open(
+            Arc::new(PosixBackend),
+            path,
+            header,
+            ManagedFileInfo::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_open_file() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut file = make_open_test_file(folder.path(), 10);
+        assert_eq!(read_mode(folder.path()), ManagedFileMode::Split);
+        assert_eq!(file.remaining_parts().len(), 1);
+
+        file.append_part(&[1; 10])?;
+        file.append_part(&[2; 10])?;
+        assert_eq!(file.header().part_count, 2);
+        assert_eq!(file.header().size, 20);
+        assert_file_exists_with_size(folder.path().join("data/1.bin"), 10);
+        assert_equal_after_parsing(folder.path(), &file);
+
+        // An open file isn't finished when everything was acknowledged
+        file.acknowledge_file_parts(FilePartIdRangeInclusive::new(
+            FilePartId::Header,
+            FilePartId::Part(1),
+        ))?;
+        assert!(!file.is_finished());
+
+        file.set_all_parts_priorities(4)?;
+        file.append_part(&[3; 5])?;
+        assert!(file.append_part(&[4; 10]).is_err());
+        assert_eq!(file.remaining_parts()[0].priority, 4);
+        let Some(Chunk::Data(chunk)) = file.get_file_part(FilePartId::Part(2))? else {
+            panic!("Expected a data chunk");
+        };
+        assert_eq!(chunk.data, vec![3; 5]);
+
+        file.seal(Some([9; 32]))?;
+        assert!(!file.header().open);
+        assert_eq!(file.header().size, 25);
+        assert!(file
+            .remaining_parts()
+            .iter()
+            .any(|p| p.part == FilePartId::Header));
+        assert!(file.seal(None).is_err());
+        assert_equal_after_parsing(folder.path(), &file);
+
+        file.acknowledge_file_parts(FilePartIdRangeInclusive::new(
+            FilePartId::Header,
+            FilePartId::Part(2),
+        ))?;
+        assert!(file.is_finished());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_file_interrupted_append() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut file = make_open_test_file(folder.path(), 10);
+        file.append_part(&[1; 10])?;
+        let checksums_before_append = std::fs::read(folder.path().join("checksums.bin"))?;
+        file.append_part(&[2; 10])?;
+
+        // The append was interrupted after the state was written
+        std::fs::write(folder.path().join("checksums.bin"), checksums_before_append)?;
+
+        let loaded =
+            ManagedSendingFile::try_read_from_path(Arc::new(PosixBackend), folder.path())?.unwrap();
+        assert_eq!(loaded.header().part_count, 2);
+        assert_eq!(loaded.header().size, 20);
+        assert_eq!(&loaded, &file);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_file_header_written_at_intervals() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut file = make_open_test_file(folder.path(), 10);
+        let header_path = folder.path().join("header.bin");
+        let header_before = std::fs::read(&header_path)?;
+
+        // Full parts only append their checksum in place
+        file.append_part(&[1; 10])?;
+        file.append_part(&[2; 10])?;
+        assert_eq!(std::fs::read(&header_path)?, header_before);
+        assert_file_exists_with_size(folder.path().join("checksums.bin"), 4 + 2 * 8);
+
+        // Acknowledged parts are still counted from checksums.bin
+        file.acknowledge_file_parts(FilePartIdRangeInclusive::new(
+            FilePartId::Header,
+            FilePartId::Part(0),
+        ))?;
+        assert_equal_after_parsing(folder.path(), &file);
+
+        for _ in 2..HEADER_WRITE_INTERVAL {
+            file.append_part(&[3; 10])?;
+        }
+        assert_ne!(std::fs::read(&header_path)?, header_before);
+        let header_after_interval = std::fs::read(&header_path)?;
+
+        // A short part ends the file, so the header is written right away
+        file.append_part(&[4; 5])?;
+        assert_ne!(std::fs::read(&header_path)?, header_after_interval);
+        assert_eq!(file.header().size, HEADER_WRITE_INTERVAL as u64 * 10 + 5);
+        assert_equal_after_parsing(folder.path(), &file);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,117 @@
+use std::{
+    hash::Hasher,
+    io::{self, Read},
+    path::Path,
+};
+
+use crate::{binary_serialize::BinarySerialize, file_sending::storage_backend::StorageBackend};
+
+/// Checksums of each data part of a managed file, computed when the file is created. They're
+/// used to detect parts that got corrupted while stored, e.g. by bit flips.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartChecksums {
+    checksums: Vec<u64>,
+}
+
+impl PartChecksums {
+    pub fn compute_from_reader(
+        mut reader: impl Read,
+        part_size: u32,
+        part_count: u32,
+    ) -> io::Result<Self> {
+        let mut checksums = Vec::with_capacity(part_count as usize);
+        let mut buf = vec![0u8; part_size as usize];
+
+        for _ in 0..part_count {
+            // The last part may be shorter than the part size
+            let mut len = 0;
+            while len < buf.len() {
+                let read = reader.read(&mut buf[len..])?;
+                if read == 0 {
+                    break;
+                }
+                len += read;
+            }
+
+            checksums.push(Self::checksum_data(&buf[..len]));
+        }
+
+        Ok(Self { checksums })
+    }
+
+    /// The number of parts that have a checksum
+    pub fn part_count(&self) -> u32 {
+        self.checksums.len() as u32
+    }
+
+    /// Add the checksum of the next part, and write it into the serialized checksums at `path`
+    /// in place, instead of rewriting them. The count is written last, so if this is
+    /// interrupted, the serialized checksums are left as they were.
+    pub fn append_part_in_place(
+        &mut self,
+        backend: &dyn StorageBackend,
+        path: &Path,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let checksum = Self::checksum_data(data);
+        let offset = 4 + self.checksums.len() as u64 * 8;
+        backend.write_at(path, offset, &checksum.to_le_bytes())?;
+        backend.write_at(path, 0, &(self.checksums.len() as u32 + 1).to_le_bytes())?;
+        self.checksums.push(checksum);
+
+        Ok(())
+    }
+
+    /// Set the checksum of a part, dropping the checksums of the parts after it.
+    pub fn set_part(&mut self, part: u32, data: &[u8]) {
+        self.checksums.truncate(part as usize);
+        self.checksums.resize(part as usize, 0);
+        self.checksums.push(Self::checksum_data(data));
+    }
+
+    pub fn checksum_data(data: &[u8]) -> u64 {
+        let mut hasher = twox_hash::XxHash64::with_seed(0);
+        hasher.write(data);
+        hasher.finish()
+    }
+
+    /// Check the data of a part against its checksum. Parts without a checksum fail.
+    pub fn verify(&self, part: u32, data: &[u8]) -> bool {
+        self.checksums
+            .get(part as usize)
+            .is_some_and(|checksum| *checksum == Self::checksum_data(data))
+    }
+}
+
+impl BinarySerialize for PartChecksums {
+    fn serialize_to_stream(&self, writer: &mut impl io::Write) -> io::Result<()> {
+        writer.write_all(&(self.checksums.len() as u32).to_le_bytes())?;
+        for checksum in &self.checksums {
+            writer.write_all(&checksum.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        4 + self.checksums.len() as u32 * 8
+    }
+
+    fn deserialize_from_stream(reader: &mut impl io::Read) -> io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut count_bytes = [0u8; 4];
+        reader.read_exact(&mut count_bytes)?;
+        let count = u32::from_le_bytes(count_bytes);
+
+        let mut checksums = Vec::new();
+        for _ in 0..count {
+            let mut checksum_bytes = [0u8; 8];
+            reader.read_exact(&mut checksum_bytes)?;
+            checksums.push(u64::from_le_bytes(checksum_bytes));
+        }
+
+        Ok(Self { checksums })
+    }
+}
@@ -0,0 +1,33 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Extra information about a managed file that's only relevant to the sender, and isn't sent
+/// to the ground. Every field has a default, so that new fields can be added without breaking
+/// previously stored files.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ManagedFileInfo {
+    /// The input source that the file came from, if any.
+    pub source: Option<String>,
+
+    /// When the file expires and gets deleted even if it's not fully acknowledged, in
+    /// nanoseconds since the unix epoch.
+    pub expires_at: Option<i64>,
+
+    /// The hex encoded SHA-256 hash of the file's contents, used to detect duplicate files.
+    pub content_hash: Option<String>,
+
+    /// The producer-defined group that the file belongs to, if any.
+    pub group: Option<String>,
+
+    /// For open files, the file that's being appended from.
+    pub open_source_path: Option<PathBuf>,
+
+    /// For open files, the priority that appended parts start out with.
+    pub append_priority: i16,
+
+    /// Some of the file's parts were evicted or dropped as corrupted, so it's not archived once
+    /// it's acknowledged.
+    pub has_dropped_parts: bool,
+}
@@ -7,6 +7,8 @@ use num_derive::{FromPrimitive, ToPrimitive};
 pub enum ManagedFileMode {
     Contiguous = 0,
     Split = 1,
+    /// Contiguous, with holes punched where the acknowledged parts were
+    Punched = 2,
 }
 
 impl BinarySerialize for ManagedFileMode {
@@ -55,7 +57,11 @@ mod tests {
 
     #[test]
     fn test_mode_serialization() {
-        let modes = [ManagedFileMode::Contiguous, ManagedFileMode::Split];
+        let modes = [
+            ManagedFileMode::Contiguous,
+            ManagedFileMode::Split,
+            ManagedFileMode::Punched,
+        ];
 
         for mode in modes {
             let mut buf = Vec::new();
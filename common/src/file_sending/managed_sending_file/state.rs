@@ -1,19 +1,60 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom},
+    collections::{BTreeSet, HashMap},
+    io::Read,
     path::PathBuf,
 };
 
-use crate::file_part_id::FilePartId;
+use crate::{file_part_id::FilePartId, file_sending::storage_backend::StorageBackend};
 
 use super::write_file_atomic;
 
+/// Marks a state file in the bitmap format. Files without it are in the legacy format.
+const STATE_MAGIC: &[u8; 4] = b"LFS2";
+
+/// Where the bitmap starts, after the magic, the default priority and the bitmap length
+const BITMAP_OFFSET: u64 = 4 + 2 + 4;
+
+/// The size of a priority override, a u32 part index and an i16 priority
+const OVERRIDE_SIZE: u64 = 6;
+
 /// Represents a managed file's state, as a managed file object. This struct can only exist
 /// when
+///
+/// The state file stores the acknowledgements as a bitmap, so that acknowledging a part only
+/// rewrites the bytes of the bitmap that changed, instead of the whole file. Very large files can
+/// have hundreds of thousands of parts, and the file is on flash.
+///
+/// ```txt
+/// [magic]            - "LFS2"
+/// [default priority] - i16, the priority of the parts without an override
+/// [bitmap length]    - u32, in bytes
+/// [bitmap]           - A bit for each part index (the header is 0, part n is n + 1), set while
+///                      the part is remaining
+/// [override count]   - u32
+/// [overrides]        - (part index u32, priority i16), for the parts with another priority
+/// ```
+///
+/// An override is changed in place, and new overrides are written after the last one before the
+/// count is increased, so an interrupted write only loses that priority change. Overrides of
+/// acknowledged parts are dead, and stay until the file is rewritten, which happens when the
+/// bitmap has to grow, all priorities are changed, or there are more dead overrides than live
+/// ones. The most common priority becomes the default when the file is rewritten, so that the
+/// parts that were all decremented by sending them don't each need an override.
+///
+/// The legacy format is a (part index u32, priority i16) for each remaining part. It's migrated
+/// to the bitmap format when it's loaded.
 #[derive(Debug, PartialEq, Eq)]
 pub struct ManagedFileState {
     inner_file: PathBuf,
     remaining_parts: Vec<ManagedFileStatePart>,
+    default_priority: i16,
+    bitmap: Vec<u8>,
+    /// The slot in the file of each remaining part's priority override
+    override_slots: HashMap<u32, u32>,
+    /// The number of override slots in the file, including the dead ones of acknowledged parts
+    slot_count: u32,
+    /// Parts whose priority was only changed in memory, until the state is flushed
+    unflushed_parts: BTreeSet<u32>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -24,6 +65,7 @@ pub struct ManagedFileStatePart {
 
 impl ManagedFileState {
     pub fn new_from_part_count(
+        backend: &dyn StorageBackend,
         part_count: u32,
         result_path: impl Into<PathBuf>,
     ) -> anyhow::Result<Self> {
@@ -41,95 +83,295 @@ impl ManagedFileState {
             });
         }
 
-        let path = result_path.into();
-        write_file_atomic(&path, |stream| serialize_parts_to_stream(&parts, stream))?;
-
-        Ok(Self {
-            inner_file: path,
+        let mut state = Self {
+            inner_file: result_path.into(),
             remaining_parts: parts,
-        })
+            default_priority: 0,
+            bitmap: Vec::new(),
+            override_slots: HashMap::new(),
+            slot_count: 0,
+            unflushed_parts: BTreeSet::new(),
+        };
+        state.rewrite(backend)?;
+
+        Ok(state)
     }
 
-    pub fn load_from_file(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+    pub fn load_from_file(
+        backend: &dyn StorageBackend,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<Self> {
         let path = path.into();
-        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let data = backend.read(&path)?;
+
+        if data.starts_with(STATE_MAGIC) {
+            return Ok(deserialize_state(path, &data)?);
+        }
 
-        let parts = deserialize_parts_from_file(&mut file)?;
+        let mut parts = deserialize_legacy_parts(&data)?;
+        parts.sort_by_key(|p| p.part.to_index());
+        parts.dedup_by_key(|p| p.part.to_index());
 
-        Ok(Self {
+        tracing::info!(
+            "Migrating legacy state file to the bitmap format: {:?}",
+            path
+        );
+
+        let mut state = Self {
             inner_file: path,
+            default_priority: 0,
             remaining_parts: parts,
+            bitmap: Vec::new(),
+            override_slots: HashMap::new(),
+            slot_count: 0,
+            unflushed_parts: BTreeSet::new(),
+        };
+        state.rewrite(backend)?;
+
+        Ok(state)
+    }
+
+    /// Write the whole state file again, with a bitmap that's large enough for the remaining
+    /// parts and only the overrides that are still needed.
+    fn rewrite(&mut self, backend: &dyn StorageBackend) -> anyhow::Result<()> {
+        let needed_len = self
+            .remaining_parts
+            .last()
+            .map_or(1, |p| p.part.to_index() as usize / 8 + 1);
+        let bitmap_len = self.bitmap.len().max(needed_len);
+        self.bitmap.clear();
+        self.bitmap.resize(bitmap_len, 0);
+        for part in &self.remaining_parts {
+            let index = part.part.to_index() as usize;
+            self.bitmap[index / 8] |= 1 << (index % 8);
+        }
+
+        let mut priority_counts = HashMap::<i16, usize>::new();
+        for part in &self.remaining_parts {
+            *priority_counts.entry(part.priority).or_default() += 1;
+        }
+        if let Some((&priority, _)) = priority_counts
+            .iter()
+            .max_by_key(|(p, count)| (**count, **p))
+        {
+            self.default_priority = priority;
+        }
+
+        let overrides = self
+            .remaining_parts
+            .iter()
+            .filter(|p| p.priority != self.default_priority)
+            .collect::<Vec<_>>();
+        self.override_slots = overrides
+            .iter()
+            .enumerate()
+            .map(|(slot, p)| (p.part.to_index(), slot as u32))
+            .collect();
+        self.slot_count = overrides.len() as u32;
+        self.unflushed_parts.clear();
+
+        write_file_atomic(backend, &self.inner_file, |stream| {
+            stream.extend_from_slice(STATE_MAGIC);
+            stream.extend_from_slice(&self.default_priority.to_le_bytes());
+            stream.extend_from_slice(&(self.bitmap.len() as u32).to_le_bytes());
+            stream.extend_from_slice(&self.bitmap);
+            stream.extend_from_slice(&(overrides.len() as u32).to_le_bytes());
+            for part in &overrides {
+                serialize_override(part.part.to_index(), part.priority, stream);
+            }
+
+            Ok(())
         })
     }
 
-    fn modify_single_part(
-        &mut self,
-        index: usize,
-        data: ManagedFileStatePart,
+    fn overrides_offset(&self) -> u64 {
+        BITMAP_OFFSET + self.bitmap.len() as u64
+    }
+
+    /// Write the bytes of the bitmap from `first` to `last` in place.
+    fn write_bitmap_bytes(
+        &self,
+        backend: &dyn StorageBackend,
+        first: usize,
+        last: usize,
     ) -> anyhow::Result<()> {
-        self.remaining_parts[index] = data.clone();
+        backend.write_at(
+            &self.inner_file,
+            BITMAP_OFFSET + first as u64,
+            &self.bitmap[first..=last],
+        )?;
 
-        let mut file = OpenOptions::new().write(true).open(&self.inner_file)?;
-        file.seek(SeekFrom::Start((index * 6) as u64))?;
-        serialize_part_to_stream(&data, &mut file)?;
+        Ok(())
+    }
+
+    /// Write the priority of a part in place. Parts with the default priority don't need an
+    /// override, unless they had one before.
+    fn write_priority(
+        &mut self,
+        backend: &dyn StorageBackend,
+        index: u32,
+        priority: i16,
+    ) -> anyhow::Result<()> {
+        let overrides_offset = self.overrides_offset();
+
+        if let Some(&slot) = self.override_slots.get(&index) {
+            let offset = overrides_offset + 4 + slot as u64 * OVERRIDE_SIZE + 4;
+            backend.write_at(&self.inner_file, offset, &priority.to_le_bytes())?;
+        } else if priority != self.default_priority {
+            let slot = self.slot_count;
+
+            let mut bytes = Vec::with_capacity(OVERRIDE_SIZE as usize);
+            serialize_override(index, priority, &mut bytes);
+            let offset = overrides_offset + 4 + slot as u64 * OVERRIDE_SIZE;
+            backend.write_at(&self.inner_file, offset, &bytes)?;
+
+            // The override only counts once it's fully written
+            backend.write_at(
+                &self.inner_file,
+                overrides_offset,
+                &(slot + 1).to_le_bytes(),
+            )?;
+            self.override_slots.insert(index, slot);
+            self.slot_count += 1;
+        }
 
         Ok(())
     }
 
+    fn position_of(&self, part: FilePartId) -> Result<usize, usize> {
+        self.remaining_parts
+            .binary_search_by_key(&part.to_index(), |p| p.part.to_index())
+    }
+
     pub fn modify_part_priority(
         &mut self,
+        backend: &dyn StorageBackend,
         part: FilePartId,
         modify: impl FnOnce(&mut i16),
     ) -> anyhow::Result<()> {
-        let index = self.remaining_parts.iter().position(|p| p.part == part);
-        if let Some(index) = index {
+        if let Ok(index) = self.position_of(part) {
             let mut current = self.remaining_parts[index].priority;
             modify(&mut current);
-            self.modify_single_part(
-                index,
-                ManagedFileStatePart {
-                    part,
-                    priority: current,
-                },
-            )?;
+            self.remaining_parts[index].priority = current;
+            self.write_priority(backend, part.to_index(), current)?;
+            self.unflushed_parts.remove(&part.to_index());
+        } else {
+            tracing::info!(
+                "Attempted to modify priority of non-existent part: {:?}",
+                part
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Change a part's priority in memory only. It's written by the next [`Self::flush`], so
+    /// the change is lost if the state isn't flushed before a crash.
+    pub fn modify_part_priority_deferred(
+        &mut self,
+        part: FilePartId,
+        modify: impl FnOnce(&mut i16),
+    ) {
+        if let Ok(index) = self.position_of(part) {
+            modify(&mut self.remaining_parts[index].priority);
+            self.unflushed_parts.insert(part.to_index());
         } else {
             tracing::info!(
                 "Attempted to modify priority of non-existent part: {:?}",
                 part
             );
         }
+    }
+
+    /// Write the priorities that were only changed in memory.
+    pub fn flush(&mut self, backend: &dyn StorageBackend) -> anyhow::Result<()> {
+        while let Some(&index) = self.unflushed_parts.first() {
+            // Acknowledged parts don't need their priority anymore
+            if let Ok(position) = self.position_of(FilePartId::from_index(index)) {
+                let priority = self.remaining_parts[position].priority;
+                self.write_priority(backend, index, priority)?;
+            }
+            self.unflushed_parts.remove(&index);
+        }
 
         Ok(())
     }
 
     pub fn modify_all_part_priorities(
         &mut self,
+        backend: &dyn StorageBackend,
         mut modify: impl FnMut(&mut i16),
     ) -> anyhow::Result<()> {
         for part in self.remaining_parts.iter_mut() {
             modify(&mut part.priority);
         }
 
-        write_file_atomic(&self.inner_file, |stream| {
-            serialize_parts_to_stream(&self.remaining_parts, stream)
-        })?;
-
-        Ok(())
+        // Usually all parts get the same priority, so no overrides are needed
+        self.rewrite(backend)
     }
 
     pub fn filter_remaining_parts(
         &mut self,
+        backend: &dyn StorageBackend,
         predicate: impl Fn(&ManagedFileStatePart) -> bool,
     ) -> anyhow::Result<()> {
-        self.remaining_parts.retain(predicate);
+        // The parts are sorted, so the first changed byte is found first
+        let mut changed_bytes: Option<(usize, usize)> = None;
+        for part in self.remaining_parts.iter().filter(|p| !predicate(p)) {
+            let index = part.part.to_index() as usize;
+            self.bitmap[index / 8] &= !(1 << (index % 8));
+            changed_bytes = Some((
+                changed_bytes.map_or(index / 8, |(first, _)| first),
+                index / 8,
+            ));
+        }
 
-        write_file_atomic(&self.inner_file, |stream| {
-            serialize_parts_to_stream(&self.remaining_parts, stream)
-        })?;
+        let Some((first, last)) = changed_bytes else {
+            return Ok(());
+        };
+
+        self.remaining_parts.retain(predicate);
+        self.write_bitmap_bytes(backend, first, last)?;
+
+        // The overrides of the removed parts are dead
+        let bitmap = &self.bitmap;
+        self.override_slots
+            .retain(|index, _| bitmap[*index as usize / 8] & (1 << (index % 8)) != 0);
+        let dead_slots = self.slot_count - self.override_slots.len() as u32;
+        if dead_slots > self.override_slots.len() as u32 {
+            self.rewrite(backend)?;
+        }
 
         Ok(())
     }
 
+    /// Add a part that isn't remaining yet, e.g. one that was appended to an open file.
+    pub fn add_part(
+        &mut self,
+        backend: &dyn StorageBackend,
+        part: ManagedFileStatePart,
+    ) -> anyhow::Result<()> {
+        let Err(position) = self.position_of(part.part) else {
+            return Ok(());
+        };
+
+        self.remaining_parts.insert(position, part.clone());
+
+        let index = part.part.to_index();
+        self.unflushed_parts.remove(&index);
+        let byte = index as usize / 8;
+        if byte >= self.bitmap.len() {
+            // Growing the bitmap moves the overrides, so it grows ahead to not rewrite the file
+            // for every appended part
+            self.bitmap.resize((byte + 1).max(self.bitmap.len() * 2), 0);
+            return self.rewrite(backend);
+        }
+
+        self.write_priority(backend, index, part.priority)?;
+        self.bitmap[byte] |= 1 << (index % 8);
+        self.write_bitmap_bytes(backend, byte, byte)
+    }
+
     pub fn remaining_parts(&self) -> &[ManagedFileStatePart] {
         &self.remaining_parts
     }
@@ -137,8 +379,8 @@ impl ManagedFileState {
     pub fn remaining_non_header_parts_len(&self) -> usize {
         let contains_header = self
             .remaining_parts
-            .iter()
-            .any(|p| p.part == FilePartId::Header);
+            .first()
+            .is_some_and(|p| p.part == FilePartId::Header);
 
         if contains_header {
             self.remaining_parts.len() - 1
@@ -148,51 +390,90 @@ impl ManagedFileState {
     }
 }
 
-fn serialize_part_to_stream(
-    part: &ManagedFileStatePart,
-    writer: &mut impl std::io::Write,
-) -> anyhow::Result<()> {
-    let part_int = part.part.to_index();
-    writer.write_all(&part_int.to_le_bytes())?;
+fn serialize_override(index: u32, priority: i16, writer: &mut Vec<u8>) {
+    writer.extend_from_slice(&index.to_le_bytes());
+    writer.extend_from_slice(&priority.to_le_bytes());
+}
 
-    writer.write_all(&part.priority.to_le_bytes())?;
+fn read_u32(reader: &mut &[u8]) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
 
-    Ok(())
+fn read_i16(reader: &mut &[u8]) -> std::io::Result<i16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(i16::from_le_bytes(bytes))
 }
 
-fn serialize_parts_to_stream(
-    parts: &[ManagedFileStatePart],
-    writer: &mut impl std::io::Write,
-) -> anyhow::Result<()> {
-    for part in parts {
-        serialize_part_to_stream(part, writer)?;
+fn deserialize_state(inner_file: PathBuf, data: &[u8]) -> std::io::Result<ManagedFileState> {
+    let mut reader = &data[STATE_MAGIC.len()..];
+    let default_priority = read_i16(&mut reader)?;
+
+    let bitmap_len = read_u32(&mut reader)? as usize;
+    if reader.len() < bitmap_len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "State file is shorter than its bitmap",
+        ));
+    }
+    let (bitmap, mut reader) = reader.split_at(bitmap_len);
+
+    // Overrides after the count are from an interrupted write, and are ignored
+    let override_count = read_u32(&mut reader)?;
+    let mut priorities = HashMap::new();
+    let mut override_slots = HashMap::new();
+    for slot in 0..override_count {
+        let index = read_u32(&mut reader)?;
+        let priority = read_i16(&mut reader)?;
+
+        // Overrides of acknowledged parts are dead
+        let remaining = bitmap
+            .get(index as usize / 8)
+            .is_some_and(|byte| byte & (1 << (index % 8)) != 0);
+        if remaining {
+            priorities.insert(index, priority);
+            override_slots.insert(index, slot);
+        }
     }
 
-    Ok(())
-}
+    let mut remaining_parts = Vec::new();
+    for (byte_index, byte) in bitmap.iter().enumerate().filter(|(_, byte)| **byte != 0) {
+        for bit in (0..8).filter(|bit| byte & (1 << bit) != 0) {
+            let index = (byte_index * 8 + bit) as u32;
+            remaining_parts.push(ManagedFileStatePart {
+                part: FilePartId::from_index(index),
+                priority: priorities.get(&index).copied().unwrap_or(default_priority),
+            });
+        }
+    }
 
-fn deserialize_parts_from_file(reader: &mut File) -> std::io::Result<Vec<ManagedFileStatePart>> {
-    let file_len = reader.metadata()?.len();
+    Ok(ManagedFileState {
+        inner_file,
+        remaining_parts,
+        default_priority,
+        bitmap: bitmap.to_vec(),
+        override_slots,
+        slot_count: override_count,
+        unflushed_parts: BTreeSet::new(),
+    })
+}
 
-    if file_len % 6 != 0 {
+fn deserialize_legacy_parts(mut reader: &[u8]) -> std::io::Result<Vec<ManagedFileStatePart>> {
+    if !reader.chunks_exact(6).remainder().is_empty() {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidData,
             "State parts file length is not a multiple of 6",
         ));
     }
 
-    let part_count = file_len / 6;
-    let mut parts = Vec::with_capacity(part_count as usize);
+    let part_count = reader.len() / 6;
+    let mut parts = Vec::with_capacity(part_count);
 
     for _ in 0..part_count {
-        let mut part_bytes = [0u8; 4];
-        reader.read_exact(&mut part_bytes)?;
-        let part_int = u32::from_le_bytes(part_bytes);
-        let part = FilePartId::from_index(part_int);
-
-        let mut priority_bytes = [0u8; 2];
-        reader.read_exact(&mut priority_bytes)?;
-        let priority = i16::from_le_bytes(priority_bytes);
+        let part = FilePartId::from_index(read_u32(&mut reader)?);
+        let priority = read_i16(&mut reader)?;
 
         parts.push(ManagedFileStatePart { part, priority });
     }
@@ -202,7 +483,7 @@ fn deserialize_parts_from_file(reader: &mut File) -> std::io::Result<Vec<Managed
 
 #[cfg(test)]
 mod tests {
-    use crate::tempdir::TempDirProvider;
+    use crate::{file_sending::storage_backend::PosixBackend, tempdir::TempDirProvider};
 
     use super::*;
 
@@ -213,7 +494,8 @@ mod tests {
         let folder = TempDirProvider::new_for_test().create().unwrap();
         let path = folder.path().join("state.bin");
 
-        let mut state = ManagedFileState::new_from_part_count(part_count, path.clone()).unwrap();
+        let mut state =
+            ManagedFileState::new_from_part_count(&PosixBackend, part_count, path.clone()).unwrap();
 
         execute(&mut state).unwrap();
 
@@ -222,7 +504,8 @@ mod tests {
         std::fs::copy(path.clone(), new_state_path.clone()).unwrap();
 
         // Load the new state
-        let new_state = ManagedFileState::load_from_file(new_state_path.clone()).unwrap();
+        let new_state =
+            ManagedFileState::load_from_file(&PosixBackend, new_state_path.clone()).unwrap();
 
         // Check that the new state is the same as the old one
         assert_eq!(state.remaining_parts(), new_state.remaining_parts());
@@ -238,7 +521,7 @@ mod tests {
         test_changes(100, |state| {
             // 51 because the first part is a header
             assert_eq!(state.remaining_parts()[51].priority, 0);
-            state.modify_part_priority(FilePartId::Part(50), |priority| {
+            state.modify_part_priority(&PosixBackend, FilePartId::Part(50), |priority| {
                 *priority = 100;
             })?;
             assert_eq!(state.remaining_parts()[51].priority, 100);
@@ -251,18 +534,203 @@ mod tests {
     fn test_filter() {
         test_changes(100, |state| {
             assert_eq!(state.remaining_parts().len(), 101);
-            state.filter_remaining_parts(|part| part.part != FilePartId::Part(50))?;
+            state
+                .filter_remaining_parts(&PosixBackend, |part| part.part != FilePartId::Part(50))?;
             assert_eq!(state.remaining_parts().len(), 100);
 
             Ok(())
         });
     }
 
+    #[test]
+    fn test_add_part() {
+        test_changes(0, |state| {
+            assert_eq!(state.remaining_parts().len(), 1);
+            let part = ManagedFileStatePart {
+                part: FilePartId::Part(0),
+                priority: 5,
+            };
+            state.add_part(&PosixBackend, part.clone())?;
+            state.add_part(&PosixBackend, part.clone())?;
+            assert_eq!(state.remaining_parts().len(), 2);
+            assert_eq!(state.remaining_parts()[1], part);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_acknowledge_in_place() {
+        let folder = TempDirProvider::new_for_test().create().unwrap();
+        let path = folder.path().join("state.bin");
+
+        // 50 GB in 64 KiB parts
+        let mut state =
+            ManagedFileState::new_from_part_count(&PosixBackend, 800_000, &path).unwrap();
+        let before = std::fs::read(&path).unwrap();
+        assert!(before.len() < 100_100);
+
+        state
+            .filter_remaining_parts(&PosixBackend, |part| part.part != FilePartId::Part(500))
+            .unwrap();
+        state
+            .modify_part_priority(&PosixBackend, FilePartId::Part(10), |p| *p = -1)
+            .unwrap();
+
+        // Only the byte with the part's bit changed, and the override was added at the end
+        let after = std::fs::read(&path).unwrap();
+        assert_eq!(after.len(), before.len() + 6);
+        let changed = (0..before.len())
+            .filter(|i| before[*i] != after[*i])
+            .collect::<Vec<_>>();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[0], BITMAP_OFFSET as usize + 501 / 8);
+        assert_eq!(changed[1], before.len() - 4);
+
+        let loaded = ManagedFileState::load_from_file(&PosixBackend, &path).unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(loaded.remaining_parts().len(), 800_000);
+        assert_eq!(loaded.remaining_parts()[11].priority, -1);
+    }
+
+    #[test]
+    fn test_dead_overrides_compacted() {
+        let folder = TempDirProvider::new_for_test().create().unwrap();
+        let path = folder.path().join("state.bin");
+
+        let mut state = ManagedFileState::new_from_part_count(&PosixBackend, 100, &path).unwrap();
+        let before = std::fs::read(&path).unwrap();
+
+        // Sending every part gives each one an override
+        for part in 0..100 {
+            state
+                .modify_part_priority(&PosixBackend, FilePartId::Part(part), |p| *p -= 1)
+                .unwrap();
+        }
+        let sent_len = std::fs::read(&path).unwrap().len();
+        assert_eq!(sent_len, before.len() + 100 * 6);
+
+        // The overrides of acknowledged parts stay until they outnumber the live ones
+        state
+            .filter_remaining_parts(
+                &PosixBackend,
+                |part| !matches!(part.part, FilePartId::Part(n) if n < 50),
+            )
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap().len(), sent_len);
+        let loaded = ManagedFileState::load_from_file(&PosixBackend, &path).unwrap();
+        assert_eq!(loaded, state);
+
+        state
+            .filter_remaining_parts(&PosixBackend, |part| part.part != FilePartId::Part(50))
+            .unwrap();
+
+        // The sent parts share the default priority now, so only the header has an override
+        assert_eq!(std::fs::read(&path).unwrap().len(), before.len() + 6);
+        let loaded = ManagedFileState::load_from_file(&PosixBackend, &path).unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(loaded.remaining_parts()[0].priority, 0);
+        assert_eq!(loaded.remaining_parts()[1].priority, -1);
+    }
+
+    #[test]
+    fn test_interrupted_override() {
+        let folder = TempDirProvider::new_for_test().create().unwrap();
+        let path = folder.path().join("state.bin");
+
+        let mut state = ManagedFileState::new_from_part_count(&PosixBackend, 10, &path).unwrap();
+        let before = std::fs::read(&path).unwrap();
+
+        // The override was written, but not the count
+        let mut interrupted = before.clone();
+        serialize_override(3, 7, &mut interrupted);
+        std::fs::write(&path, &interrupted).unwrap();
+
+        let mut loaded = ManagedFileState::load_from_file(&PosixBackend, &path).unwrap();
+        assert_eq!(loaded, state);
+        assert!(loaded.remaining_parts().iter().all(|p| p.priority == 0));
+
+        // The next override overwrites it
+        for state in [&mut state, &mut loaded] {
+            state
+                .modify_part_priority(&PosixBackend, FilePartId::Part(5), |p| *p = 2)
+                .unwrap();
+        }
+        let loaded = ManagedFileState::load_from_file(&PosixBackend, &path).unwrap();
+        assert_eq!(loaded, state);
+    }
+
+    #[test]
+    fn test_legacy_migration() {
+        let folder = TempDirProvider::new_for_test().create().unwrap();
+        let path = folder.path().join("state.bin");
+
+        let mut legacy = Vec::new();
+        for (index, priority) in [(0u32, 1i16), (5, 1), (3, 4), (1000, 1)] {
+            legacy.extend_from_slice(&index.to_le_bytes());
+            legacy.extend_from_slice(&priority.to_le_bytes());
+        }
+        std::fs::write(&path, &legacy).unwrap();
+
+        let state = ManagedFileState::load_from_file(&PosixBackend, &path).unwrap();
+        assert_eq!(
+            state.remaining_parts(),
+            &[
+                ManagedFileStatePart {
+                    part: FilePartId::Header,
+                    priority: 1,
+                },
+                ManagedFileStatePart {
+                    part: FilePartId::Part(2),
+                    priority: 4,
+                },
+                ManagedFileStatePart {
+                    part: FilePartId::Part(4),
+                    priority: 1,
+                },
+                ManagedFileStatePart {
+                    part: FilePartId::Part(999),
+                    priority: 1,
+                },
+            ]
+        );
+
+        // The file was rewritten in the new format
+        assert!(std::fs::read(&path).unwrap().starts_with(STATE_MAGIC));
+        let loaded = ManagedFileState::load_from_file(&PosixBackend, &path).unwrap();
+        assert_eq!(loaded, state);
+    }
+
+    #[test]
+    fn test_deferred_priority() {
+        let folder = TempDirProvider::new_for_test().create().unwrap();
+        let path = folder.path().join("state.bin");
+
+        let mut state = ManagedFileState::new_from_part_count(&PosixBackend, 10, &path).unwrap();
+        state.modify_part_priority_deferred(FilePartId::Part(2), |p| *p -= 1);
+        state.modify_part_priority_deferred(FilePartId::Part(5), |p| *p -= 1);
+        state
+            .filter_remaining_parts(&PosixBackend, |part| part.part != FilePartId::Part(5))
+            .unwrap();
+        assert_eq!(state.unflushed_parts.len(), 2);
+
+        // The acknowledgement was written right away, but not the decrement
+        let loaded = ManagedFileState::load_from_file(&PosixBackend, &path).unwrap();
+        assert_eq!(loaded.remaining_parts().len(), 10);
+        assert_eq!(loaded.remaining_parts()[3].priority, 0);
+
+        state.flush(&PosixBackend).unwrap();
+        assert!(state.unflushed_parts.is_empty());
+        let loaded = ManagedFileState::load_from_file(&PosixBackend, &path).unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(loaded.remaining_parts()[3].priority, -1);
+    }
+
     #[test]
     fn test_update_all_priorities() {
         test_changes(100, |state| {
             assert_eq!(state.remaining_parts()[51].priority, 0);
-            state.modify_all_part_priorities(|priority| {
+            state.modify_all_part_priorities(&PosixBackend, |priority| {
                 *priority = 100;
             })?;
             assert_eq!(state.remaining_parts()[51].priority, 100);
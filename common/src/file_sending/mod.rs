@@ -1,2 +1,11 @@
+pub mod archive;
+pub mod catalog;
+pub mod entry_folder;
+pub mod eviction;
+pub mod in_flight;
+pub mod journal;
 pub mod managed_sending_file;
+pub mod storage_backend;
 pub mod storage_manager;
+pub mod tiering;
+pub mod time_tagged_commands;
@@ -0,0 +1,210 @@
+use std::{
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+pub use self::{log_structured::LogStructuredBackend, memory::MemoryBackend, posix::PosixBackend};
+
+mod log_structured;
+mod memory;
+mod posix;
+
+/// The storage that managed files are kept in. Paths are used as keys, so backends that
+/// don't have real folders only need to track which folders were created.
+///
+/// All writes through [`StorageBackend::write_atomic`] must be atomic: after a crash, either
+/// the old or the new contents are visible, never a mix.
+pub trait StorageBackend: std::fmt::Debug + Send + Sync {
+    /// Replace the contents of a file atomically, creating it if it doesn't exist.
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+
+    /// Like [`StorageBackend::write_atomic`], but streams `len` bytes from a reader, so that
+    /// large files don't have to be read into memory.
+    fn write_atomic_from(&self, path: &Path, data: &mut dyn Read, len: u64) -> io::Result<()>;
+
+    /// Overwrite a part of an existing file in place. This isn't atomic.
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Read up to `len` bytes starting at `offset`. Less bytes are returned if the file ends.
+    fn read_range(&self, path: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>>;
+
+    fn file_len(&self, path: &Path) -> io::Result<u64>;
+
+    /// Shrink or extend a file. Extending fills the file with zeros.
+    fn set_len(&self, path: &Path, len: u64) -> io::Result<()>;
+
+    /// Free the space of a range of a file in place, without changing its length. The range
+    /// reads as zeros afterwards. Returns [`io::ErrorKind::Unsupported`] if the backend or the
+    /// filesystem can't do that.
+    fn punch_hole(&self, path: &Path, offset: u64, len: u64) -> io::Result<()>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// List the paths of the files and folders directly inside a folder.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove a folder with everything inside it.
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Move a file from the local filesystem into the storage. The source file is removed.
+    fn import_file(&self, source: &Path, path: &Path) -> io::Result<()>;
+}
+
+/// Which storage backend the sending storage is kept in
+#[derive(Debug, Clone, Default)]
+pub enum StorageBackendConfig {
+    /// A folder per managed file on the local filesystem
+    #[default]
+    Posix,
+
+    /// A single append-only log file, for filesystems where folders and renames are expensive
+    LogStructured { log_path: PathBuf },
+}
+
+impl StorageBackendConfig {
+    pub fn build(&self) -> io::Result<Arc<dyn StorageBackend>> {
+        match self {
+            StorageBackendConfig::Posix => Ok(Arc::new(PosixBackend)),
+            StorageBackendConfig::LogStructured { log_path } => {
+                Ok(Arc::new(LogStructuredBackend::open(log_path.clone())?))
+            }
+        }
+    }
+}
+
+/// Reads a file from a storage backend in blocks, e.g. to stream it into another backend.
+pub struct BackendFileReader<'a> {
+    backend: &'a dyn StorageBackend,
+    path: &'a Path,
+    pos: u64,
+}
+
+impl<'a> BackendFileReader<'a> {
+    const BLOCK_SIZE: u64 = 1024 * 1024;
+
+    pub fn new(backend: &'a dyn StorageBackend, path: &'a Path) -> Self {
+        Self {
+            backend,
+            path,
+            pos: 0,
+        }
+    }
+}
+
+impl Read for BackendFileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(Self::BLOCK_SIZE);
+        let data = self.backend.read_range(self.path, self.pos, len)?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.pos += data.len() as u64;
+
+        Ok(data.len())
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{:?} not found", path))
+}
+
+fn punching_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Punching holes isn't supported by this storage backend",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDirProvider;
+
+    fn check_backend(backend: &dyn StorageBackend, root: &Path) -> anyhow::Result<()> {
+        let folder = root.join("file");
+        backend.create_dir_all(&folder.join("data"))?;
+        assert!(backend.is_dir(&folder));
+
+        let state_path = folder.join("state.bin");
+        backend.write_atomic(&state_path, &[1, 2, 3, 4])?;
+        backend.write_at(&state_path, 2, &[9, 9, 9])?;
+        assert_eq!(backend.read(&state_path)?, vec![1, 2, 9, 9, 9]);
+        assert_eq!(backend.read_range(&state_path, 3, 10)?, vec![9, 9]);
+
+        backend.set_len(&state_path, 2)?;
+        assert_eq!(backend.read(&state_path)?, vec![1, 2]);
+        backend.set_len(&state_path, 3)?;
+        assert_eq!(backend.read(&state_path)?, vec![1, 2, 0]);
+        assert_eq!(backend.file_len(&state_path)?, 3);
+
+        // Holes are only punched where the backend supports it
+        backend.write_atomic(&state_path, &[1; 8])?;
+        match backend.punch_hole(&state_path, 2, 3) {
+            Ok(()) => assert_eq!(backend.read(&state_path)?, vec![1, 1, 0, 0, 0, 1, 1, 1]),
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::Unsupported),
+        }
+
+        let source_folder = TempDirProvider::new_for_test().create()?;
+        let source_path = source_folder.path().join("input.bin");
+        std::fs::write(&source_path, [5u8; 100])?;
+        backend.import_file(&source_path, &folder.join("data.bin"))?;
+        assert!(!source_path.exists());
+        assert_eq!(
+            backend.read_range(&folder.join("data.bin"), 90, 20)?,
+            [5; 10]
+        );
+
+        let data_path = folder.join("data.bin");
+        let mut reader = BackendFileReader::new(backend, &data_path);
+        backend.write_atomic_from(&folder.join("copy.bin"), &mut reader, 100)?;
+        assert_eq!(backend.read(&folder.join("copy.bin"))?, [5; 100]);
+        backend.remove_file(&folder.join("copy.bin"))?;
+
+        backend.write_atomic(&folder.join("data/0.bin"), &[7])?;
+        let mut entries = backend.read_dir(&folder)?;
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                folder.join("data"),
+                folder.join("data.bin"),
+                folder.join("state.bin")
+            ]
+        );
+
+        backend.remove_file(&folder.join("data.bin"))?;
+        assert!(!backend.is_file(&folder.join("data.bin")));
+        assert!(backend.read(&folder.join("data.bin")).is_err());
+
+        backend.remove_dir_all(&folder)?;
+        assert!(!backend.is_dir(&folder));
+        assert!(!backend.is_file(&folder.join("data/0.bin")));
+        assert!(backend.read_dir(root)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backends() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        check_backend(&PosixBackend, folder.path())?;
+
+        let memory = MemoryBackend::new();
+        let root = PathBuf::from("/storage");
+        memory.create_dir_all(&root)?;
+        check_backend(&memory, &root)?;
+
+        let log = LogStructuredBackend::open(folder.path().join("storage.log"))?;
+        log.create_dir_all(&root)?;
+        check_backend(&log, &root)?;
+
+        Ok(())
+    }
+}
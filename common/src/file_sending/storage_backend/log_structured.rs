@@ -0,0 +1,848 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{File, OpenOptions},
+    hash::Hasher,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use num_derive::{FromPrimitive, ToPrimitive};
+
+use super::{not_found, punching_unsupported, StorageBackend};
+
+const MAGIC: &[u8; 8] = b"LEOFTPLG";
+
+/// Marks the start of every record, so that replay can find the next record after a corrupt
+/// header
+const RECORD_SYNC: &[u8; 8] = b"LEOFTPRC";
+
+/// Sync marker, body length, body checksum and header checksum
+const RECORD_HEADER_LEN: u64 = 32;
+
+/// Files written to in place more than this many times are rewritten as a single record, so
+/// that reading them doesn't have to piece together many small writes.
+const MAX_EXTENTS_PER_FILE: usize = 16;
+
+/// The log is compacted when it's larger than this, and less than half of it is still used.
+const COMPACT_MIN_LOG_LEN: u64 = 16 * 1024 * 1024;
+
+const COPY_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Stores everything in a single append-only log file. Every change is appended as a
+/// checksummed record, and never overwrites earlier data, which suits flash storage and
+/// filesystems where folders and renames are expensive. The index of the files is kept in
+/// memory, and rebuilt by replaying the log when it's opened.
+///
+/// Log structure:
+///
+/// ```plaintext
+/// "LEOFTPLG"                    - magic
+/// ["LEOFTPRC"][u64 body length][u64 xxhash of the body][u64 xxhash of the header][body]
+/// ["LEOFTPRC"][u64 body length][u64 xxhash of the body][u64 xxhash of the header][body]
+/// ...
+///
+/// header hash: of the body length and the body hash
+/// body: [u8 op][u16 path length][path][op fields][data]
+/// ```
+///
+/// Both checksums are written last, so a record that was interrupted while being written has
+/// a bad header. A record with a bad body is skipped. After a bad header, the log is scanned for
+/// the next record with a good one, and the records from there on are replayed. If there's none,
+/// the log ends with a torn record, which is dropped along with its change.
+///
+/// Once less than half of the log is still used, the live files are copied into a new log
+/// which replaces the old one.
+#[derive(Debug)]
+pub struct LogStructuredBackend {
+    log_path: PathBuf,
+    inner: Mutex<LogInner>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
+enum RecordOp {
+    /// Replace the contents of a file. Data: the new contents
+    Write = 0,
+    /// Fields: [u64 offset]. Data: the bytes to write at the offset
+    WriteAt = 1,
+    /// Fields: [u64 length]
+    SetLen = 2,
+    RemoveFile = 3,
+    CreateDir = 4,
+    RemoveDirAll = 5,
+}
+
+#[derive(Debug)]
+struct LogInner {
+    log: File,
+    log_len: u64,
+    /// File data in the log that's still used
+    live_bytes: u64,
+    files: BTreeMap<PathBuf, LogFile>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+/// A file in the log, made of the pieces of data written to it. Later extents take
+/// precedence over earlier ones, and anything not covered by an extent is zeros.
+#[derive(Debug, Clone, Default)]
+struct LogFile {
+    len: u64,
+    extents: Vec<Extent>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    file_offset: u64,
+    log_offset: u64,
+    len: u64,
+}
+
+impl LogFile {
+    fn live_bytes(&self) -> u64 {
+        self.extents.iter().map(|extent| extent.len).sum()
+    }
+
+    fn truncate(&mut self, len: u64) {
+        self.extents.retain(|extent| extent.file_offset < len);
+        for extent in self.extents.iter_mut() {
+            extent.len = extent.len.min(len - extent.file_offset);
+        }
+        self.len = len;
+    }
+}
+
+impl LogStructuredBackend {
+    /// Open the log at the path, creating it if it doesn't exist.
+    pub fn open(log_path: PathBuf) -> io::Result<Self> {
+        let inner = LogInner::load(&log_path)?;
+
+        Ok(Self {
+            log_path,
+            inner: Mutex::new(inner),
+        })
+    }
+
+    /// Copy the live files into a new log, dropping everything that was overwritten or removed.
+    pub fn compact(&self) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.compact(&self.log_path)
+    }
+
+    /// Append a change to the log and apply it, compacting the log afterwards if needed.
+    fn append(
+        &self,
+        op: RecordOp,
+        path: &Path,
+        fields: &[u8],
+        data: &mut dyn Read,
+        data_len: u64,
+    ) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.append(op, path, fields, data, data_len)?;
+
+        let fragmented = inner
+            .files
+            .get(path)
+            .is_some_and(|file| file.extents.len() > MAX_EXTENTS_PER_FILE);
+        if fragmented {
+            // Stream the file through its own handle, as appending moves the log's position
+            let file = inner.files[path].clone();
+            let mut log = File::open(&self.log_path)?;
+            let mut reader = LogFileReader {
+                log: &mut log,
+                file: &file,
+                pos: 0,
+            };
+            inner.append(RecordOp::Write, path, &[], &mut reader, file.len)?;
+        }
+
+        if inner.log_len > COMPACT_MIN_LOG_LEN && inner.log_len > inner.live_bytes * 2 {
+            inner.compact(&self.log_path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl LogInner {
+    fn load(log_path: &Path) -> io::Result<Self> {
+        let mut log = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(log_path)?;
+        let file_len = log.metadata()?.len();
+
+        if file_len == 0 {
+            log.write_all(MAGIC)?;
+            log.sync_all()?;
+        } else {
+            let mut magic = [0u8; 8];
+            log.read_exact(&mut magic)?;
+            if &magic != MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Not a leoftp storage log",
+                ));
+            }
+        }
+
+        let mut inner = Self {
+            log,
+            log_len: MAGIC.len() as u64,
+            live_bytes: 0,
+            files: BTreeMap::new(),
+            dirs: BTreeSet::new(),
+        };
+
+        inner.replay(file_len)?;
+
+        Ok(inner)
+    }
+
+    /// Read the header of the record at the position, returning the body length and checksum if
+    /// the header is intact and the body fits in the log.
+    fn read_record_header(&mut self, pos: u64, file_len: u64) -> io::Result<Option<(u64, u64)>> {
+        if pos.saturating_add(RECORD_HEADER_LEN) > file_len {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        self.log.seek(SeekFrom::Start(pos))?;
+        self.log.read_exact(&mut header)?;
+        if &header[0..8] != RECORD_SYNC
+            || header_checksum(&header[8..24]).to_le_bytes() != header[24..32]
+        {
+            return Ok(None);
+        }
+
+        let body_len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let checksum = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let fits = RECORD_HEADER_LEN
+            .checked_add(body_len)
+            .and_then(|record_len| pos.checked_add(record_len))
+            .is_some_and(|record_end| record_end <= file_len);
+
+        Ok(fits.then_some((body_len, checksum)))
+    }
+
+    /// Find the next record with an intact header after the position, by its sync marker.
+    fn find_next_record(&mut self, after: u64, file_len: u64) -> io::Result<Option<u64>> {
+        let marker_len = RECORD_SYNC.len() as u64;
+        let mut block = vec![0u8; COPY_BLOCK_SIZE as usize];
+        let mut block_start = after + 1;
+        while block_start + marker_len <= file_len {
+            let len = (file_len - block_start).min(COPY_BLOCK_SIZE) as usize;
+            self.log.seek(SeekFrom::Start(block_start))?;
+            self.log.read_exact(&mut block[..len])?;
+
+            let candidates = block[..len]
+                .windows(RECORD_SYNC.len())
+                .enumerate()
+                .filter(|(_, window)| *window == RECORD_SYNC)
+                .map(|(i, _)| block_start + i as u64)
+                .collect::<Vec<_>>();
+            for candidate in candidates {
+                if self.read_record_header(candidate, file_len)?.is_some() {
+                    return Ok(Some(candidate));
+                }
+            }
+
+            // Overlap the blocks, so that a marker across their boundary isn't missed
+            block_start += (len as u64).saturating_sub(marker_len - 1).max(1);
+        }
+
+        Ok(None)
+    }
+
+    fn replay(&mut self, file_len: u64) -> io::Result<()> {
+        let mut pos = MAGIC.len() as u64;
+        while pos < file_len {
+            let Some((body_len, checksum)) = self.read_record_header(pos, file_len)? else {
+                match self.find_next_record(pos, file_len)? {
+                    Some(next) => {
+                        tracing::error!(
+                            "Storage log has a corrupt record header at {}, skipping to {}",
+                            pos,
+                            next
+                        );
+                        pos = next;
+                        continue;
+                    }
+                    None => {
+                        tracing::warn!(
+                            "Storage log ends with an incomplete record at {}, dropping it",
+                            pos
+                        );
+                        self.log.set_len(pos)?;
+                        self.log.sync_all()?;
+                        break;
+                    }
+                }
+            };
+            let record_end = pos + RECORD_HEADER_LEN + body_len;
+
+            // Hash the whole body, but only keep the start, as the data may be large
+            let mut hasher = twox_hash::XxHash64::with_seed(0);
+            let mut prefix = Vec::new();
+            let mut block = vec![0u8; COPY_BLOCK_SIZE as usize];
+            let mut remaining = body_len;
+            while remaining > 0 {
+                let len = remaining.min(COPY_BLOCK_SIZE) as usize;
+                self.log.read_exact(&mut block[..len])?;
+                hasher.write(&block[..len]);
+                if prefix.len() < MAX_BODY_PREFIX_LEN {
+                    let keep = len.min(MAX_BODY_PREFIX_LEN - prefix.len());
+                    prefix.extend_from_slice(&block[..keep]);
+                }
+                remaining -= len as u64;
+            }
+
+            let record = (hasher.finish() == checksum)
+                .then(|| parse_body_prefix(&prefix))
+                .flatten();
+            let Some((op, path, fields, prefix_len)) = record else {
+                tracing::error!("Storage log has a corrupt record at {}, skipping it", pos);
+                pos = record_end;
+                continue;
+            };
+
+            let data_offset = pos + RECORD_HEADER_LEN + prefix_len;
+            self.apply(op, &path, &fields, data_offset, record_end - data_offset);
+            pos = record_end;
+        }
+
+        self.log_len = pos;
+
+        Ok(())
+    }
+
+    fn append(
+        &mut self,
+        op: RecordOp,
+        path: &Path,
+        fields: &[u8],
+        data: &mut dyn Read,
+        data_len: u64,
+    ) -> io::Result<()> {
+        let start = self.log_len;
+        let result = write_record(&mut self.log, start, op, path, fields, data, data_len);
+        let record_len = match result {
+            Ok(record_len) => record_len,
+            Err(err) => {
+                // Drop whatever part of the record was written
+                self.log.set_len(start)?;
+                return Err(err);
+            }
+        };
+        self.log.sync_data()?;
+
+        self.log_len = start + record_len;
+        let data_offset = self.log_len - data_len;
+        self.apply(op, path, fields, data_offset, data_len);
+
+        Ok(())
+    }
+
+    fn apply(&mut self, op: RecordOp, path: &Path, fields: &[u8], data_offset: u64, data_len: u64) {
+        let field = || u64::from_le_bytes(fields[0..8].try_into().unwrap());
+
+        let previous = self.files.get(path).map(LogFile::live_bytes).unwrap_or(0);
+        match op {
+            RecordOp::Write => {
+                let file = LogFile {
+                    len: data_len,
+                    extents: vec![Extent {
+                        file_offset: 0,
+                        log_offset: data_offset,
+                        len: data_len,
+                    }],
+                };
+                self.files.insert(path.to_path_buf(), file);
+            }
+            RecordOp::WriteAt => {
+                let offset = field();
+                let file = self.files.entry(path.to_path_buf()).or_default();
+                file.extents.push(Extent {
+                    file_offset: offset,
+                    log_offset: data_offset,
+                    len: data_len,
+                });
+                file.len = file.len.max(offset + data_len);
+            }
+            RecordOp::SetLen => {
+                let len = field();
+                self.files
+                    .entry(path.to_path_buf())
+                    .or_default()
+                    .truncate(len);
+            }
+            RecordOp::RemoveFile => {
+                self.files.remove(path);
+            }
+            RecordOp::CreateDir => {
+                for dir in path.ancestors() {
+                    self.dirs.insert(dir.to_path_buf());
+                }
+            }
+            RecordOp::RemoveDirAll => {
+                self.files.retain(|file, _| !file.starts_with(path));
+                self.dirs.retain(|dir| !dir.starts_with(path));
+            }
+        }
+        let current = self.files.get(path).map(LogFile::live_bytes).unwrap_or(0);
+
+        // Removing a folder can drop many files, so recount
+        if op == RecordOp::RemoveDirAll {
+            self.live_bytes = self.files.values().map(LogFile::live_bytes).sum();
+        } else {
+            self.live_bytes = self.live_bytes - previous + current;
+        }
+    }
+
+    fn read_range(&mut self, path: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let file = self.files.get(path).ok_or_else(|| not_found(path))?;
+        read_file_range(&mut self.log, file, offset, len)
+    }
+
+    fn compact(&mut self, log_path: &Path) -> io::Result<()> {
+        let compact_path = log_path.with_extension("compact");
+        let mut new_log = File::create(&compact_path)?;
+        new_log.write_all(MAGIC)?;
+        let mut new_len = MAGIC.len() as u64;
+
+        for dir in self.dirs.iter() {
+            new_len += write_record(
+                &mut new_log,
+                new_len,
+                RecordOp::CreateDir,
+                dir,
+                &[],
+                &mut io::empty(),
+                0,
+            )?;
+        }
+
+        let mut new_files = BTreeMap::new();
+        for (path, file) in self.files.iter() {
+            let mut reader = LogFileReader {
+                log: &mut self.log,
+                file,
+                pos: 0,
+            };
+            new_len += write_record(
+                &mut new_log,
+                new_len,
+                RecordOp::Write,
+                path,
+                &[],
+                &mut reader,
+                file.len,
+            )?;
+
+            let new_file = LogFile {
+                len: file.len,
+                extents: vec![Extent {
+                    file_offset: 0,
+                    log_offset: new_len - file.len,
+                    len: file.len,
+                }],
+            };
+            new_files.insert(path.clone(), new_file);
+        }
+
+        new_log.sync_all()?;
+        drop(new_log);
+        std::fs::rename(&compact_path, log_path)?;
+
+        tracing::info!(
+            "Compacted storage log from {} to {} bytes",
+            self.log_len,
+            new_len
+        );
+
+        self.log = OpenOptions::new().read(true).write(true).open(log_path)?;
+        self.log_len = new_len;
+        self.files = new_files;
+        self.live_bytes = self.files.values().map(LogFile::live_bytes).sum();
+
+        Ok(())
+    }
+}
+
+/// Op, path length, path and the longest op fields
+const MAX_BODY_PREFIX_LEN: usize = 1 + 2 + u16::MAX as usize + 8;
+
+fn op_fields_len(op: RecordOp) -> usize {
+    match op {
+        RecordOp::WriteAt | RecordOp::SetLen => 8,
+        _ => 0,
+    }
+}
+
+/// Write a record at the position in the log, returning its length.
+fn write_record(
+    log: &mut File,
+    at: u64,
+    op: RecordOp,
+    path: &Path,
+    fields: &[u8],
+    data: &mut dyn Read,
+    data_len: u64,
+) -> io::Result<u64> {
+    let path_bytes = path
+        .to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path isn't valid UTF-8"))?
+        .as_bytes();
+    let path_len: u16 = path_bytes
+        .len()
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path is too long"))?;
+
+    let mut prefix = vec![num_traits::ToPrimitive::to_u8(&op).unwrap()];
+    prefix.extend_from_slice(&path_len.to_le_bytes());
+    prefix.extend_from_slice(path_bytes);
+    prefix.extend_from_slice(fields);
+
+    let body_len = prefix.len() as u64 + data_len;
+
+    log.seek(SeekFrom::Start(at))?;
+    log.write_all(RECORD_SYNC)?;
+    log.write_all(&body_len.to_le_bytes())?;
+    // The checksums are written once the data was streamed through
+    log.write_all(&[0u8; 16])?;
+
+    let mut hasher = twox_hash::XxHash64::with_seed(0);
+    hasher.write(&prefix);
+    log.write_all(&prefix)?;
+
+    let mut block = vec![0u8; COPY_BLOCK_SIZE as usize];
+    let mut remaining = data_len;
+    while remaining > 0 {
+        let len = remaining.min(COPY_BLOCK_SIZE) as usize;
+        data.read_exact(&mut block[..len])?;
+        hasher.write(&block[..len]);
+        log.write_all(&block[..len])?;
+        remaining -= len as u64;
+    }
+
+    let mut checksums = [0u8; 16];
+    checksums[0..8].copy_from_slice(&hasher.finish().to_le_bytes());
+    let mut header = body_len.to_le_bytes().to_vec();
+    header.extend_from_slice(&checksums[0..8]);
+    checksums[8..16].copy_from_slice(&header_checksum(&header).to_le_bytes());
+    log.seek(SeekFrom::Start(at + 16))?;
+    log.write_all(&checksums)?;
+
+    Ok(RECORD_HEADER_LEN + body_len)
+}
+
+/// The checksum of a record's body length and body checksum
+fn header_checksum(fields: &[u8]) -> u64 {
+    let mut hasher = twox_hash::XxHash64::with_seed(0);
+    hasher.write(fields);
+    hasher.finish()
+}
+
+/// Parse the op, path and fields at the start of a record body, returning their length too.
+fn parse_body_prefix(body: &[u8]) -> Option<(RecordOp, PathBuf, Vec<u8>, u64)> {
+    let op: RecordOp = num_traits::FromPrimitive::from_u8(*body.first()?)?;
+    let path_len = u16::from_le_bytes(body.get(1..3)?.try_into().ok()?) as usize;
+    let path = std::str::from_utf8(body.get(3..3 + path_len)?).ok()?;
+
+    let fields_start = 3 + path_len;
+    let fields_end = fields_start + op_fields_len(op);
+    let fields = body.get(fields_start..fields_end)?.to_vec();
+
+    Some((op, PathBuf::from(path), fields, fields_end as u64))
+}
+
+fn read_file_range(log: &mut File, file: &LogFile, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let start = offset.min(file.len);
+    let end = offset.saturating_add(len).min(file.len);
+    let mut data = vec![0u8; (end - start) as usize];
+
+    for extent in file.extents.iter() {
+        let overlap_start = start.max(extent.file_offset);
+        let overlap_end = end.min(extent.file_offset + extent.len);
+        if overlap_start >= overlap_end {
+            continue;
+        }
+
+        log.seek(SeekFrom::Start(
+            extent.log_offset + (overlap_start - extent.file_offset),
+        ))?;
+        log.read_exact(
+            &mut data[(overlap_start - start) as usize..(overlap_end - start) as usize],
+        )?;
+    }
+
+    Ok(data)
+}
+
+/// Reads a file from the log block by block, for copying large files.
+struct LogFileReader<'a> {
+    log: &'a mut File,
+    file: &'a LogFile,
+    pos: u64,
+}
+
+impl Read for LogFileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(COPY_BLOCK_SIZE);
+        let data = read_file_range(self.log, self.file, self.pos, len)?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.pos += data.len() as u64;
+
+        Ok(data.len())
+    }
+}
+
+impl StorageBackend for LogStructuredBackend {
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.append(
+            RecordOp::Write,
+            path,
+            &[],
+            &mut &data[..],
+            data.len() as u64,
+        )
+    }
+
+    fn write_atomic_from(&self, path: &Path, data: &mut dyn Read, len: u64) -> io::Result<()> {
+        // The reader may read from this log, which is locked while appending, so the data is
+        // put next to the log first
+        let staging_path = self.log_path.with_extension("staging");
+        let mut staging = File::create(&staging_path)?;
+        let copied = io::copy(&mut data.take(len), &mut staging)?;
+        drop(staging);
+        if copied != len {
+            std::fs::remove_file(&staging_path)?;
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Expected {} bytes to write to {:?}, got {}",
+                    len, path, copied
+                ),
+            ));
+        }
+
+        self.import_file(&staging_path, path)
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        if !self.is_file(path) {
+            return Err(not_found(path));
+        }
+
+        self.append(
+            RecordOp::WriteAt,
+            path,
+            &offset.to_le_bytes(),
+            &mut &data[..],
+            data.len() as u64,
+        )
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.read_range(path, 0, u64::MAX)
+    }
+
+    fn read_range(&self, path: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        self.inner.lock().unwrap().read_range(path, offset, len)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        let inner = self.inner.lock().unwrap();
+        let file = inner.files.get(path).ok_or_else(|| not_found(path))?;
+        Ok(file.len)
+    }
+
+    fn set_len(&self, path: &Path, len: u64) -> io::Result<()> {
+        if !self.is_file(path) {
+            return Err(not_found(path));
+        }
+
+        self.append(
+            RecordOp::SetLen,
+            path,
+            &len.to_le_bytes(),
+            &mut io::empty(),
+            0,
+        )
+    }
+
+    fn punch_hole(&self, _path: &Path, _offset: u64, _len: u64) -> io::Result<()> {
+        // Overwritten data stays in the log until it's compacted
+        Err(punching_unsupported())
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.inner.lock().unwrap().files.contains_key(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.lock().unwrap().dirs.contains(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.is_dir(path) {
+            return Ok(());
+        }
+
+        self.append(RecordOp::CreateDir, path, &[], &mut io::empty(), 0)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+
+        let children = inner
+            .files
+            .keys()
+            .chain(inner.dirs.iter())
+            .filter(|child| child.parent() == Some(path))
+            .cloned()
+            .collect();
+
+        Ok(children)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        if !self.is_file(path) {
+            return Err(not_found(path));
+        }
+
+        self.append(RecordOp::RemoveFile, path, &[], &mut io::empty(), 0)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        if !self.is_dir(path) {
+            return Err(not_found(path));
+        }
+
+        self.append(RecordOp::RemoveDirAll, path, &[], &mut io::empty(), 0)
+    }
+
+    fn import_file(&self, source: &Path, path: &Path) -> io::Result<()> {
+        let mut file = File::open(source)?;
+        let len = file.metadata()?.len();
+        self.append(RecordOp::Write, path, &[], &mut file, len)?;
+
+        std::fs::remove_file(source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDirProvider;
+
+    #[test]
+    fn test_replay_and_compaction() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let log_path = folder.path().join("storage.log");
+        let root = PathBuf::from("storage");
+
+        let backend = LogStructuredBackend::open(log_path.clone())?;
+        backend.create_dir_all(&root.join("a"))?;
+        backend.write_atomic(&root.join("a/data.bin"), &[1; 1000])?;
+        backend.write_atomic(&root.join("a/state.bin"), &[0; 60])?;
+        for i in 0..40 {
+            backend.write_at(&root.join("a/state.bin"), i, &[i as u8])?;
+        }
+        backend.set_len(&root.join("a/data.bin"), 500)?;
+        backend.write_atomic(&root.join("removed.bin"), &[2; 100])?;
+        backend.remove_file(&root.join("removed.bin"))?;
+        drop(backend);
+
+        let expected_state = (0..40u8).chain([0; 20]).collect::<Vec<_>>();
+        let check = |backend: &LogStructuredBackend| -> anyhow::Result<()> {
+            assert!(backend.is_dir(&root.join("a")));
+            assert_eq!(backend.read(&root.join("a/data.bin"))?, vec![1; 500]);
+            assert_eq!(backend.read(&root.join("a/state.bin"))?, expected_state);
+            assert!(!backend.is_file(&root.join("removed.bin")));
+            Ok(())
+        };
+
+        let backend = LogStructuredBackend::open(log_path.clone())?;
+        check(&backend)?;
+
+        let len_before = std::fs::metadata(&log_path)?.len();
+        backend.compact()?;
+        assert!(std::fs::metadata(&log_path)?.len() < len_before);
+        check(&backend)?;
+        drop(backend);
+
+        // A write that was cut off by a crash is dropped
+        let backend = LogStructuredBackend::open(log_path.clone())?;
+        backend.write_atomic(&root.join("torn.bin"), &[3; 100])?;
+        drop(backend);
+        let len = std::fs::metadata(&log_path)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)?
+            .set_len(len - 10)?;
+
+        let backend = LogStructuredBackend::open(log_path.clone())?;
+        check(&backend)?;
+        assert!(!backend.is_file(&root.join("torn.bin")));
+        backend.write_atomic(&root.join("after.bin"), &[4; 10])?;
+        drop(backend);
+
+        let backend = LogStructuredBackend::open(log_path)?;
+        check(&backend)?;
+        assert_eq!(backend.read(&root.join("after.bin"))?, vec![4; 10]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_skips_corrupt_records() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let log_path = folder.path().join("storage.log");
+
+        let backend = LogStructuredBackend::open(log_path.clone())?;
+        let mut record_starts = Vec::new();
+        for i in 0..4u8 {
+            record_starts.push(std::fs::metadata(&log_path)?.len());
+            backend.write_atomic(&PathBuf::from(format!("{}.bin", i)), &[i; 100])?;
+        }
+        drop(backend);
+
+        let flip_bit = |offset: u64| -> anyhow::Result<()> {
+            let mut log = OpenOptions::new().read(true).write(true).open(&log_path)?;
+            let mut byte = [0u8];
+            log.seek(SeekFrom::Start(offset))?;
+            log.read_exact(&mut byte)?;
+            log.seek(SeekFrom::Start(offset))?;
+            log.write_all(&[byte[0] ^ 0x40])?;
+            Ok(())
+        };
+
+        // A flipped body length only loses that record, not the ones after it
+        flip_bit(record_starts[1] + 8)?;
+        // A flipped byte in the data only loses that record too
+        flip_bit(record_starts[2] + RECORD_HEADER_LEN + 20)?;
+
+        let len_before = std::fs::metadata(&log_path)?.len();
+        let backend = LogStructuredBackend::open(log_path.clone())?;
+        assert_eq!(backend.read(Path::new("0.bin"))?, vec![0; 100]);
+        assert!(!backend.is_file(Path::new("1.bin")));
+        assert!(!backend.is_file(Path::new("2.bin")));
+        assert_eq!(backend.read(Path::new("3.bin"))?, vec![3; 100]);
+        assert_eq!(std::fs::metadata(&log_path)?.len(), len_before);
+
+        // Records appended after the corrupt ones are replayed too
+        backend.write_atomic(Path::new("4.bin"), &[4; 10])?;
+        drop(backend);
+        let backend = LogStructuredBackend::open(log_path)?;
+        assert_eq!(backend.read(Path::new("3.bin"))?, vec![3; 100]);
+        assert_eq!(backend.read(Path::new("4.bin"))?, vec![4; 10]);
+
+        Ok(())
+    }
+}
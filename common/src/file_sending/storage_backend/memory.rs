@@ -0,0 +1,144 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::{not_found, StorageBackend};
+
+/// Keeps everything in memory. Nothing survives the process, so it's only useful for tests.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    inner: Mutex<MemoryBackendInner>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryBackendInner {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_file<T>(&self, path: &Path, f: impl FnOnce(&mut Vec<u8>) -> T) -> io::Result<T> {
+        let mut inner = self.inner.lock().unwrap();
+        let file = inner.files.get_mut(path).ok_or_else(|| not_found(path))?;
+        Ok(f(file))
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.files.insert(path.to_path_buf(), data.to_vec());
+        Ok(())
+    }
+
+    fn write_atomic_from(&self, path: &Path, data: &mut dyn Read, len: u64) -> io::Result<()> {
+        let mut contents = Vec::new();
+        data.take(len).read_to_end(&mut contents)?;
+        if contents.len() as u64 != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        self.write_atomic(path, &contents)
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.with_file(path, |file| {
+            let end = offset as usize + data.len();
+            if file.len() < end {
+                file.resize(end, 0);
+            }
+            file[offset as usize..end].copy_from_slice(data);
+        })
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.with_file(path, |file| file.clone())
+    }
+
+    fn read_range(&self, path: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        self.with_file(path, |file| {
+            let start = (offset as usize).min(file.len());
+            let end = (offset.saturating_add(len) as usize).min(file.len());
+            file[start..end].to_vec()
+        })
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        self.with_file(path, |file| file.len() as u64)
+    }
+
+    fn set_len(&self, path: &Path, len: u64) -> io::Result<()> {
+        self.with_file(path, |file| file.resize(len as usize, 0))
+    }
+
+    fn punch_hole(&self, path: &Path, offset: u64, len: u64) -> io::Result<()> {
+        self.with_file(path, |file| {
+            let start = (offset as usize).min(file.len());
+            let end = (offset.saturating_add(len) as usize).min(file.len());
+            file[start..end].fill(0);
+        })
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.inner.lock().unwrap().files.contains_key(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.lock().unwrap().dirs.contains(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        for dir in path.ancestors() {
+            inner.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+
+        let children = inner
+            .files
+            .keys()
+            .chain(inner.dirs.iter())
+            .filter(|child| child.parent() == Some(path))
+            .cloned()
+            .collect();
+
+        Ok(children)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.files.remove(path).ok_or_else(|| not_found(path))?;
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+
+        inner.files.retain(|file, _| !file.starts_with(path));
+        inner.dirs.retain(|dir| !dir.starts_with(path));
+        Ok(())
+    }
+
+    fn import_file(&self, source: &Path, path: &Path) -> io::Result<()> {
+        let data = std::fs::read(source)?;
+        self.write_atomic(path, &data)?;
+        std::fs::remove_file(source)
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use super::StorageBackend;
+
+/// Stores everything directly on the local filesystem, with real folders.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PosixBackend;
+
+impl StorageBackend for PosixBackend {
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let tmp_path = path.with_extension("-tmp");
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        drop(file);
+
+        std::fs::rename(&tmp_path, path)
+    }
+
+    fn write_atomic_from(&self, path: &Path, data: &mut dyn Read, len: u64) -> io::Result<()> {
+        let tmp_path = path.with_extension("-tmp");
+
+        let mut file = File::create(&tmp_path)?;
+        let copied = io::copy(&mut data.take(len), &mut file)?;
+        if copied != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Expected {} bytes to write to {:?}, got {}",
+                    len, path, copied
+                ),
+            ));
+        }
+        file.sync_all()?;
+        drop(file);
+
+        std::fs::rename(&tmp_path, path)
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn read_range(&self, path: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = Vec::new();
+        file.take(len).read_to_end(&mut data)?;
+
+        Ok(data)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    fn set_len(&self, path: &Path, len: u64) -> io::Result<()> {
+        OpenOptions::new().write(true).open(path)?.set_len(len)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn punch_hole(&self, path: &Path, offset: u64, len: u64) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let file = OpenOptions::new().write(true).open(path)?;
+        // SAFETY: The file descriptor stays open for the duration of the call
+        let result = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+
+        // Filesystems without hole punching fail with EOPNOTSUPP, which is `Unsupported`
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn punch_hole(&self, _path: &Path, _offset: u64, _len: u64) -> io::Result<()> {
+        Err(super::punching_unsupported())
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+
+    fn import_file(&self, source: &Path, path: &Path) -> io::Result<()> {
+        std::fs::rename(source, path)
+    }
+}
@@ -1,67 +1,379 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    control::ControlMessage,
+    chunks::{Chunk, DataChunk, FileCompression, FileKind, HeaderChunk},
+    control::{ControlMessage, FileFilter},
     file_part_id::{FilePartId, FilePartIdRangeInclusive},
+    status::{
+        CatalogEntries, FileRemoved, FileRemovedReason, PartsCorrupted, PartsEvicted, StatusMessage,
+    },
+    validity::ValidityCheck,
 };
 use anyhow::Context;
 use uuid::Uuid;
 
-use super::managed_sending_file::{generate_file_header_from_path, ManagedSendingFile};
+use super::{
+    archive::{ArchiveConfig, SendingArchive},
+    catalog::{CatalogConfig, FileCatalog},
+    eviction::{EvictionCandidate, EvictionConfig, EvictionPolicy},
+    in_flight::{InFlightTimeout, InFlightTracker},
+    journal::{Journal, JournalEntry},
+    managed_sending_file::{
+        compress_file_zstd, file_created_date, generate_file_header_from_path, ManagedFileInfo,
+        ManagedSendingFile,
+    },
+    storage_backend::{PosixBackend, StorageBackend, StorageBackendConfig},
+    tiering::{select_hot_files, HotTierConfig, StorageTier},
+    time_tagged_commands::TimeTaggedCommandStore,
+};
+
+/// The folder inside the storage where corrupted parts are moved to. It's not a managed file,
+/// so it's skipped when loading the storage.
+const QUARANTINE_FOLDER: &str = "quarantine";
 
 #[derive(Debug, Clone)]
 pub struct SendingStorageManagerConfig {
     /// The chunk size to use for new files
     pub new_file_chunk_size: u32,
 
-    /// Maximum size of the storage folder in bytes
+    /// Maximum size of the storage folder in bytes. Files that are staged in the hot tier are
+    /// still kept in the storage folder, so they count towards it too.
     pub max_folder_size: Option<u64>,
 
+    /// Maximum size of the stored data of the files from each input source in bytes, in both
+    /// tiers. Files from other sources aren't evicted to make room for a source within its quota.
+    pub source_quotas: HashMap<String, u64>,
+
     /// Trigger a split if >=n chunks worth of disk space can be saved by splitting
     pub split_file_if_n_chunks_saved: Option<u32>,
+
+    /// When sent but unacknowledged parts get their original priority back
+    pub in_flight_timeout: InFlightTimeout,
+
+    /// Send a status message on the downlink when an expired file is deleted
+    pub announce_expired_files: bool,
+
+    /// What to do with new files that have the same contents as an already managed file
+    pub duplicate_policy: DuplicateFilePolicy,
+
+    /// What to do with stored parts that fail their checksum
+    pub corrupt_part_action: CorruptPartAction,
+
+    /// When the priority decrements of sent parts are written to the storage
+    pub state_flush: StateFlushPolicy,
+
+    /// Free the space of acknowledged parts by punching holes into files in place, instead of
+    /// splitting them into a file per part. Files are split if the filesystem doesn't support it.
+    pub punch_holes: bool,
+
+    /// Which parts are evicted when the storage is over its maximum size
+    pub eviction: EvictionConfig,
+
+    /// A fast folder that the files which are sent next are staged in, on top of the storage
+    /// folder
+    pub hot_tier: Option<HotTierConfig>,
+
+    /// Keep a copy of the files that were fully acknowledged, instead of deleting them
+    pub archive: Option<ArchiveConfig>,
+
+    /// Keep the files that are added with [`NewFileOptions::catalog_only`] until the ground
+    /// requests them
+    pub catalog: Option<CatalogConfig>,
+
+    /// Where the managed files are stored
+    pub backend: StorageBackendConfig,
+}
+
+impl Default for SendingStorageManagerConfig {
+    fn default() -> Self {
+        Self {
+            new_file_chunk_size: 1024 * 64,
+            max_folder_size: None,
+            source_quotas: HashMap::new(),
+            split_file_if_n_chunks_saved: None,
+            in_flight_timeout: InFlightTimeout::default(),
+            announce_expired_files: false,
+            duplicate_policy: DuplicateFilePolicy::default(),
+            corrupt_part_action: CorruptPartAction::default(),
+            state_flush: StateFlushPolicy::default(),
+            punch_holes: false,
+            eviction: EvictionConfig::default(),
+            hot_tier: None,
+            archive: None,
+            catalog: None,
+            backend: StorageBackendConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateFilePolicy {
+    /// Add the duplicate as a separate file
+    #[default]
+    Keep,
+
+    /// Drop the duplicate, leaving the managed file as is
+    Drop,
+
+    /// Drop the duplicate, but merge its options into the managed file. If either file
+    /// expires, the later expiry time is kept.
+    Merge,
+}
+
+/// What to do with stored parts that fail their checksum. They can't be sent anymore, so
+/// they're always reported on the downlink and dropped from the parts to send.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CorruptPartAction {
+    /// Drop the corrupted parts
+    #[default]
+    Report,
+
+    /// Keep a copy of the corrupted parts in the quarantine folder of the storage, for later
+    /// inspection. Once the quarantined parts reach `max_size` bytes, further corrupted parts
+    /// are dropped like with [`CorruptPartAction::Report`].
+    Quarantine { max_size: u64 },
+}
+
+/// When the priority decrements of sent parts are written to the state files. A busy downlink
+/// session sends thousands of parts, so writing each decrement wears out the flash.
+///
+/// Decrements that weren't written are lost on a crash, which only means that the parts are
+/// sent again sooner. Acknowledgements and all other changes are always written right away.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StateFlushPolicy {
+    /// Write every decrement right away
+    #[default]
+    Immediate,
+
+    /// Keep the decrements in memory, and write them at this interval, at the end of every
+    /// downlink session, and on shutdown
+    Batched { interval: Duration },
+}
+
+/// Options for a file being added to the storage
+#[derive(Debug, Clone, Default)]
+pub struct NewFileOptions {
+    /// The name that's sent in the file header, e.g. the path relative to the input folder
+    /// with `/` as the separator. Defaults to the file name.
+    pub name: Option<String>,
+
+    /// The input source that the file came from. Files can be filtered by source in bulk
+    /// control messages.
+    pub source: Option<String>,
+
+    /// How long after its creation date the file expires
+    pub ttl: Option<Duration>,
+
+    /// Custom fields that are sent in the file header
+    pub metadata: BTreeMap<String, String>,
+
+    /// The initial priority of all the parts of the file. Defaults to 0.
+    pub priority: Option<i16>,
+
+    /// The size of the file parts, instead of the configured `new_file_chunk_size`
+    pub chunk_size: Option<u32>,
+
+    pub compression: FileCompression,
+
+    /// A producer-defined group that the file belongs to. Files can be filtered by group in
+    /// bulk control messages.
+    pub group: Option<String>,
+
+    /// Set for bundles of small files, which the receiver unpacks
+    pub kind: FileKind,
+
+    /// Only add the file to the catalog, and send it once the ground requests it
+    pub catalog_only: bool,
 }
 
 pub struct SendingStorageManager {
+    backend: Arc<dyn StorageBackend>,
     path: PathBuf,
     files: HashMap<Uuid, ManagedSendingFile>,
+    /// Managed files by the hash of their contents, to detect duplicates
+    content_index: HashMap<String, Uuid>,
     config: SendingStorageManagerConfig,
+    time_tagged_commands: TimeTaggedCommandStore,
+    in_flight: InFlightTracker,
+    /// Status messages waiting to be sent on the next downlink
+    status_messages: VecDeque<StatusMessage>,
+    /// Files left to verify in the current scrub pass
+    scrub_queue: Vec<Uuid>,
+    /// Sealed files whose header wasn't sent again yet. Until it is, acknowledgements of the
+    /// header are for the one that was sent while the file was open.
+    resealed_headers: HashSet<Uuid>,
+    journal: Journal,
+    last_state_flush: Instant,
+    eviction: Box<dyn EvictionPolicy>,
+    /// The hot tier is always a folder on the local filesystem
+    hot_backend: Arc<dyn StorageBackend>,
+    /// Files with a copy staged in the hot tier, which is the copy that's sent and updated, with
+    /// the size of the data of their copy in the storage folder. That copy isn't changed until
+    /// the file is unstaged, so it still has the data of the parts acknowledged since.
+    hot_files: HashMap<Uuid, u64>,
+    archive: Option<SendingArchive>,
+    catalog: Option<FileCatalog>,
 }
 
 impl SendingStorageManager {
     pub fn new(path: PathBuf, config: SendingStorageManagerConfig) -> anyhow::Result<Self> {
-        let folder_files = std::fs::read_dir(&path).context("Failed to read storage folder")?;
-
-        let mut files = HashMap::new();
-        for file in folder_files {
-            let file = match file {
-                Ok(file) => file,
-                Err(err) => {
-                    tracing::warn!("Failed to read storage folder entry: {}", err);
-                    continue;
+        let backend = config
+            .backend
+            .build()
+            .context("Failed to open storage backend")?;
+
+        // Backends without real folders start out empty
+        backend
+            .create_dir_all(&path)
+            .context("Failed to create storage folder")?;
+
+        Self::new_with_backend(path, config, backend)
+    }
+
+    /// Load the storage from an already opened backend, instead of the configured one. The
+    /// storage folder must already exist in the backend.
+    pub fn new_with_backend(
+        path: PathBuf,
+        config: SendingStorageManagerConfig,
+        backend: Arc<dyn StorageBackend>,
+    ) -> anyhow::Result<Self> {
+        let hot_backend: Arc<dyn StorageBackend> = Arc::new(PosixBackend);
+        let hot_path = config
+            .hot_tier
+            .as_ref()
+            .map(|hot_tier| hot_tier.path.as_path());
+        if let Some(hot_path) = hot_path {
+            hot_backend
+                .create_dir_all(hot_path)
+                .context("Failed to create hot tier folder")?;
+        }
+
+        let journal = Journal::load_or_create(backend.clone(), path.join("journal.bin"))
+            .context("Failed to load storage journal")?;
+        if let Some(entry) = journal.pending() {
+            tracing::warn!("Recovering interrupted storage change: {:?}", entry);
+            let hot_tier = hot_path.map(|hot_path| (&*hot_backend, hot_path));
+            recover_file_folder(&*backend, &path, hot_tier, entry)?;
+        }
+
+        let mut files =
+            load_file_folders(&backend, &path).context("Failed to read storage folder")?;
+
+        // The staged copies are newer than the ones in the storage folder. If the hot tier was
+        // lost, the parts that were acknowledged since the files were staged are sent again.
+        let mut hot_files = HashMap::new();
+        if let Some(hot_path) = hot_path {
+            let hot = load_file_folders(&hot_backend, hot_path)
+                .context("Failed to read hot tier folder")?;
+            for (file_id, file) in hot {
+                if let Some(cold) = files.get_mut(&file_id) {
+                    hot_files.insert(file_id, cold.calc_remaining_data_size());
+                    *cold = file;
+                } else {
+                    tracing::warn!("Removing staged copy of deleted file {}", file_id);
+                    file.delete()?;
                 }
-            };
-            let path = file.path();
+            }
+        }
 
-            if !path.is_dir() {
-                continue;
+        let time_tagged_commands =
+            TimeTaggedCommandStore::load_or_create(backend.clone(), path.join("time_tagged.bin"))
+                .context("Failed to load time-tagged commands")?;
+
+        let in_flight = InFlightTracker::load_or_create(backend.clone(), path.join("inflight.bin"))
+            .context("Failed to load in-flight parts")?;
+
+        let content_index = files
+            .values()
+            .filter_map(|file| {
+                let hash = file.info().content_hash.clone()?;
+                Some((hash, file.header().id))
+            })
+            .collect();
+
+        let eviction = config.eviction.build();
+
+        let archive = match &config.archive {
+            Some(archive_config) => {
+                let archive = SendingArchive::load_or_create(archive_config.clone())
+                    .context("Failed to load archive")?;
+                archive.discard_stale_partials(|file_id| files.contains_key(&file_id))?;
+                Some(archive)
             }
+            None => None,
+        };
 
-            let file = ManagedSendingFile::try_read_from_path(&path)?;
-            let Some(file) = file else {
-                // File was likely ended, but not cleaned up.
-                continue;
-            };
-            files.insert(file.header().id, file);
-        }
+        let catalog = config
+            .catalog
+            .clone()
+            .map(FileCatalog::load_or_create)
+            .transpose()
+            .context("Failed to load catalog")?;
 
-        Ok(Self {
+        let mut storage_manager = Self {
+            backend,
             path,
             files,
+            content_index,
             config,
-        })
+            time_tagged_commands,
+            in_flight,
+            status_messages: VecDeque::new(),
+            scrub_queue: Vec::new(),
+            resealed_headers: HashSet::new(),
+            journal,
+            last_state_flush: Instant::now(),
+            eviction,
+            hot_backend,
+            hot_files,
+            archive,
+            catalog,
+        };
+        storage_manager.finish_interrupted_change()?;
+
+        // The hot tier may be new, or may have been lost if it's a RAM disk
+        storage_manager.rebalance_tiers()?;
+
+        Ok(storage_manager)
+    }
+
+    /// Finish the change that was interrupted by a crash, if it's one that's finished once the
+    /// files are loaded.
+    fn finish_interrupted_change(&mut self) -> anyhow::Result<()> {
+        let Some(entry) = self.journal.pending().cloned() else {
+            return Ok(());
+        };
+
+        match entry {
+            JournalEntry::Acknowledge { file_id, from, to } => {
+                self.acknowledge_parts(file_id, FilePartIdRangeInclusive::new(from, to))?;
+            }
+            JournalEntry::Split { file_id } => {
+                if self
+                    .files
+                    .get(&file_id)
+                    .is_some_and(|file| file.is_contiguous())
+                {
+                    self.split_file(file_id)?;
+                }
+            }
+            JournalEntry::Add {
+                file_id,
+                priority: Some(priority),
+            } => {
+                if let Some(file) = self.files.get_mut(&file_id) {
+                    file.set_all_parts_priorities(priority)?;
+                }
+            }
+            JournalEntry::Add { .. } | JournalEntry::Delete { .. } | JournalEntry::Move { .. } => {}
+        }
+
+        self.journal.finish()
     }
 
     pub fn get_file(&self, file_id: Uuid) -> Option<&ManagedSendingFile> {
@@ -76,21 +388,23 @@ impl SendingStorageManager {
         match control {
             ControlMessage::ConfirmPart(confirm) => {
                 let file_id = confirm.file_id;
-                let part_range = confirm.part_range;
-
-                let file = self.files.get_mut(&file_id);
+                let mut part_range = confirm.part_range;
+
+                if self.resealed_headers.contains(&file_id) && part_range.from == FilePartId::Header
+                {
+                    if part_range.to == FilePartId::Header {
+                        return Ok(());
+                    }
+                    part_range = FilePartIdRangeInclusive::new(FilePartId::Part(0), part_range.to);
+                }
 
-                let Some(file) = file else {
+                if !self.files.contains_key(&file_id) {
                     tracing::info!("Received confirmation for non-existent file: {}", file_id);
                     return Ok(());
-                };
-
-                file.acknowledge_file_parts(part_range)?;
-                if file.is_finished() {
-                    self.delete_file_by_id(file_id)?;
                 }
 
-                Ok(())
+                self.archive_parts(file_id, &part_range);
+                self.acknowledge_parts(file_id, part_range)
             }
             ControlMessage::DeleteFile(delete) => {
                 let file_id = delete.file_id;
@@ -109,348 +423,3510 @@ impl SendingStorageManager {
                 };
 
                 file.set_all_parts_priorities(priority)?;
+                self.in_flight
+                    .set_original_priority_for_file(file_id, priority);
+
+                self.rebalance_tiers()
+            }
+            ControlMessage::TimeTagged(time_tagged) => {
+                if matches!(*time_tagged.message, ControlMessage::TimeTagged(_)) {
+                    anyhow::bail!("Time-tagged commands can't be nested");
+                }
+
+                self.time_tagged_commands.add(time_tagged)
+            }
+            ControlMessage::CancelTimeTagged(cancel) => {
+                let cancelled = self.time_tagged_commands.cancel(cancel.command_id)?;
+                if !cancelled {
+                    tracing::info!(
+                        "Received cancellation for non-existent time-tagged command: {}",
+                        cancel.command_id
+                    );
+                }
 
                 Ok(())
             }
-        }
-    }
+            ControlMessage::DeleteFilesByFilter(delete) => {
+                anyhow::ensure!(
+                    !delete.filter.is_empty(),
+                    "Refusing to delete files by an empty filter, which matches every file"
+                );
 
-    pub fn iter_files(&self) -> impl Iterator<Item = &ManagedSendingFile> {
-        self.files.values()
-    }
+                let file_ids = self.file_ids_matching_filter(&delete.filter);
+                tracing::info!(
+                    "Deleting {} files matching filter: {}",
+                    file_ids.len(),
+                    delete.filter
+                );
 
-    pub fn iter_remaining_storage_file_parts(&self) -> impl '_ + Iterator<Item = StorageFilePart> {
-        let nested_iter = self.files.values().map(|file| {
-            file.remaining_parts().iter().map(|part| StorageFilePart {
-                file_id: file.header().id,
-                part_id: part.part,
-                priority: part.priority,
-            })
-        });
+                for file_id in file_ids {
+                    self.delete_file_by_id(file_id)?;
+                }
 
-        nested_iter.flatten()
+                Ok(())
+            }
+            ControlMessage::SetPriorityByFilter(set_priority) => {
+                let file_ids = self.file_ids_matching_filter(&set_priority.filter);
+                tracing::info!(
+                    "Setting priority {} for {} files matching filter: {}",
+                    set_priority.priority,
+                    file_ids.len(),
+                    set_priority.filter
+                );
+
+                for file_id in file_ids {
+                    if let Some(file) = self.files.get_mut(&file_id) {
+                        file.set_all_parts_priorities(set_priority.priority)?;
+                        self.in_flight
+                            .set_original_priority_for_file(file_id, set_priority.priority);
+                    }
+                }
+
+                self.rebalance_tiers()
+            }
+            ControlMessage::RequestFileResend(resend) => {
+                let file_id = resend.file_id;
+                let file = self.files.get_mut(&file_id);
+
+                let Some(file) = file else {
+                    // The receiver has the file's header, so it's resent under the same id
+                    if self
+                        .archive
+                        .as_ref()
+                        .is_some_and(|a| a.get(file_id).is_some())
+                    {
+                        tracing::info!("Resending file {} from the archive", file_id);
+                        return self.requeue_archived_file(file_id, file_id, None);
+                    }
+
+                    tracing::warn!(
+                        "Received resend request for a file that's no longer stored: {}",
+                        file_id
+                    );
+                    self.status_messages
+                        .push_back(StatusMessage::FileRemoved(FileRemoved {
+                            file_id,
+                            reason: FileRemovedReason::NotResendable,
+                        }));
+                    return Ok(());
+                };
+
+                // The receiver only confirms the parts of a file once it was verified, so all
+                // of them are normally still stored. Parts that were freed anyway, e.g. evicted,
+                // were collected by the archive, so the file is completed there and resent from
+                // it.
+                let stored_part_count = file.remaining_parts().len() as u32;
+                let part_count = file.header().part_count;
+                if stored_part_count < part_count + 1 && self.archive.is_some() {
+                    tracing::info!(
+                        "Resending file {} from the archive, as some of its parts were already freed",
+                        file_id
+                    );
+                    let all_parts = FilePartIdRangeInclusive::new(
+                        FilePartId::Header,
+                        match part_count {
+                            0 => FilePartId::Header,
+                            n => FilePartId::Part(n - 1),
+                        },
+                    );
+                    self.archive_parts(file_id, &all_parts);
+                    self.acknowledge_parts(file_id, all_parts)?;
+                    return self.requeue_archived_file(file_id, file_id, None);
+                }
+
+                // Otherwise the file can't be completed anymore, so the ground is told that it's
+                // gone instead of waiting for it
+                if stored_part_count < part_count + 1 {
+                    tracing::warn!(
+                        "Deleting file {}, a resend was requested but only {} of its parts are still stored",
+                        file_id,
+                        stored_part_count
+                    );
+                    self.delete_file_by_id(file_id)?;
+                    self.status_messages
+                        .push_back(StatusMessage::FileRemoved(FileRemoved {
+                            file_id,
+                            reason: FileRemovedReason::NotResendable,
+                        }));
+                    return Ok(());
+                }
+
+                // The priority of the sent parts is restored, so they get sent as if they're
+                // new
+                for part in self.in_flight.take_file(file_id) {
+                    file.set_part_priority(part.part_id, part.original_priority)?;
+                }
+
+                Ok(())
+            }
+            ControlMessage::ListTimeTagged(_) => {
+                for page in self.time_tagged_commands.list().into_pages() {
+                    self.status_messages
+                        .push_back(StatusMessage::TimeTaggedCommandList(page));
+                }
+
+                Ok(())
+            }
+            ControlMessage::RequeueArchivedFile(requeue) => {
+                self.requeue_archived_file(requeue.file_id, Uuid::new_v4(), Some(requeue.priority))
+            }
+            ControlMessage::ListCatalog(_) => {
+                let Some(catalog) = &self.catalog else {
+                    tracing::warn!("Received catalog list request, but there's no catalog");
+                    return Ok(());
+                };
+
+                let entries = catalog
+                    .iter_files()
+                    .map(|file| file.entry())
+                    .collect::<Vec<_>>();
+                for page in CatalogEntries::pages(entries) {
+                    self.status_messages
+                        .push_back(StatusMessage::CatalogEntries(page));
+                }
+
+                Ok(())
+            }
+            ControlMessage::RequestCatalogedFile(request) => {
+                self.request_cataloged_file(request.entry_id, request.priority)
+            }
+        }
     }
 
-    fn delete_file_by_id(&mut self, file_id: Uuid) -> anyhow::Result<()> {
-        let file = self.files.remove(&file_id);
+    /// Execute all the time-tagged commands that are due at `now` (nanoseconds since the unix epoch).
+    pub fn execute_due_time_tagged_commands(&mut self, now: i64) -> anyhow::Result<()> {
+        let due = self.time_tagged_commands.take_due(now)?;
 
-        let Some(file) = file else {
-            tracing::info!("Received delete request for non-existent file: {}", file_id);
-            return Ok(());
-        };
+        for control in due {
+            tracing::info!("Executing time-tagged command: {}", control);
 
-        file.delete()?;
+            let process_result = self.process_control(control.clone());
+            if let Err(err) = process_result {
+                tracing::error!(
+                    "Error processing time-tagged control message.\nMessage: {:?}\nError: {}",
+                    control,
+                    err
+                );
+            }
+        }
 
         Ok(())
     }
 
-    pub fn delete_parts_until_max_size_reached(&mut self, size: u64) -> anyhow::Result<()> {
-        let mut total_size = 0;
-        for file in self.files.values() {
-            total_size += file.calc_remaining_data_size();
+    /// Delete all the files that expired at `now` (nanoseconds since the unix epoch).
+    pub fn delete_expired_files(&mut self, now: i64) -> anyhow::Result<()> {
+        let expired = self
+            .files
+            .values()
+            .filter(|file| file.info().expires_at.is_some_and(|at| at <= now))
+            .map(|file| file.header().id)
+            .collect::<Vec<_>>();
+
+        for file_id in expired {
+            tracing::info!("Deleting expired file: {}", file_id);
+            self.delete_file_by_id(file_id)?;
+
+            if self.config.announce_expired_files {
+                self.status_messages
+                    .push_back(StatusMessage::FileRemoved(FileRemoved {
+                        file_id,
+                        reason: FileRemovedReason::Expired,
+                    }));
+            }
         }
 
-        let mut items = self.iter_remaining_storage_file_parts().collect::<Vec<_>>();
-        items.sort_unstable_by(|a, b| cmp_file_storage_part_for_deletion(b, a)); // Sort from highest priority to lowest, we will pop items from the end
+        if let Some(archive) = &mut self.archive {
+            archive.remove_expired(now)?;
+        }
+        if let Some(catalog) = &mut self.catalog {
+            catalog.remove_expired(now)?;
+        }
 
-        let mut deleted_parts_count = 0;
+        Ok(())
+    }
 
-        while total_size > size {
-            let item = items.pop();
-            let item = match item {
-                Some(item) => item,
-                None => break,
-            };
+    /// Start tracking sent parts under a new downlink session.
+    pub fn begin_downlink_session(&mut self) {
+        self.in_flight.begin_session();
+    }
 
-            let file = self.files.get_mut(&item.file_id);
-            let file = match file {
-                Some(file) => file,
-                None => continue,
-            };
+    /// Persist the priority decrements and the in-flight parts at the end of a downlink session.
+    pub fn end_downlink_session(&mut self) -> anyhow::Result<()> {
+        self.flush_state()?;
 
-            let file_size = file.calc_remaining_data_size();
+        // Sent parts have a lower priority now, so other files may be sent next
+        self.rebalance_tiers()
+    }
 
-            // Acknowledge the part to remove it from storage
-            file.acknowledge_file_parts(FilePartIdRangeInclusive::new_single(item.part_id))?;
-            deleted_parts_count += 1;
+    /// Write the priority decrements that are only kept in memory, see [`StateFlushPolicy`],
+    /// along with the in-flight parts that they're restored from. This should be called before
+    /// shutting down.
+    pub fn flush_state(&mut self) -> anyhow::Result<()> {
+        self.last_state_flush = Instant::now();
 
-            if file.is_finished() {
-                self.delete_file_by_id(item.file_id)?;
+        // The in-flight parts go first, so that no decrement is written without them
+        self.in_flight.save()?;
+        for file in self.files.values_mut() {
+            file.flush_state()?;
+        }
 
-                // Update the total size with the difference
-                let new_file_size = 0;
-                total_size = total_size - file_size + new_file_size;
-                continue;
-            }
+        Ok(())
+    }
 
-            if let Some(split_if_n) = self.config.split_file_if_n_chunks_saved {
-                if file.chunks_saved_by_splitting() >= split_if_n {
-                    file.trigger_file_split()?;
+    /// Flush the state if the interval of the flush policy passed since the last flush.
+    pub fn flush_state_if_due(&mut self) -> anyhow::Result<()> {
+        match self.config.state_flush {
+            StateFlushPolicy::Immediate => Ok(()),
+            StateFlushPolicy::Batched { interval } => {
+                if self.last_state_flush.elapsed() < interval {
+                    return Ok(());
                 }
+
+                self.flush_state()
             }
+        }
+    }
 
-            // Update the total size with the difference
-            let new_file_size = file.calc_remaining_data_size();
-            total_size = total_size - file_size + new_file_size;
+    /// Restore the original priority of the in-flight parts that timed out at `now`
+    /// (nanoseconds since the unix epoch) without being acknowledged.
+    pub fn restore_timed_out_in_flight_parts(&mut self, now: i64) -> anyhow::Result<()> {
+        let timed_out = self
+            .in_flight
+            .take_timed_out(&self.config.in_flight_timeout, now);
+        if timed_out.is_empty() {
+            return Ok(());
         }
 
-        tracing::warn!(
-            "Deleted {} parts to reach maximum folder size of {}",
-            deleted_parts_count,
-            size
+        tracing::info!(
+            "Restoring priority of {} unacknowledged parts",
+            timed_out.len()
         );
 
+        // The records of the restored parts are dropped by the next flush. Until then, a crash
+        // only brings them back with the priority they already have again.
+        for part in timed_out {
+            if let Some(file) = self.files.get_mut(&part.file_id) {
+                file.set_part_priority(part.part_id, part.original_priority)?;
+            }
+        }
+
         Ok(())
     }
 
-    pub fn add_file_from_path(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
-        let header =
-            generate_file_header_from_path(path.as_ref(), self.config.new_file_chunk_size)?;
-        let destination_path = self.path.join(header.id.to_string());
+    /// Verify the stored parts of the next file against their checksums. Each call checks one
+    /// file, so that scrubbing can be spread out over time.
+    pub fn scrub_next_file(&mut self) -> anyhow::Result<()> {
+        if self.scrub_queue.is_empty() {
+            self.scrub_queue = self.files.keys().copied().collect();
+        }
 
-        if let Some(max_folder_size) = self.config.max_folder_size {
-            if max_folder_size < header.size {
-                anyhow::bail!(
-                    "File size {} exceeds maximum folder size {}",
-                    header.size,
-                    max_folder_size
-                );
+        let Some(file_id) = self.scrub_queue.pop() else {
+            return Ok(());
+        };
+        let Some(file) = self.files.get(&file_id) else {
+            // File was deleted since the pass started
+            return Ok(());
+        };
+
+        let corrupted = file.find_corrupted_parts()?;
+        if !corrupted.is_empty() {
+            self.handle_corrupted_parts(file_id, corrupted)?;
+        }
+
+        Ok(())
+    }
+
+    /// Report parts that failed their checksum, and drop them from the parts to send. A copy is
+    /// kept in quarantine if configured.
+    pub fn handle_corrupted_parts(&mut self, file_id: Uuid, parts: Vec<u32>) -> anyhow::Result<()> {
+        let Some(file) = self.files.get(&file_id) else {
+            return Ok(());
+        };
+
+        // Parts that were already dropped, e.g. found by the scrub and the downlink at once
+        let parts = parts
+            .into_iter()
+            .filter(|part| {
+                file.remaining_parts()
+                    .iter()
+                    .any(|p| p.part == FilePartId::Part(*part))
+            })
+            .collect::<Vec<_>>();
+        if parts.is_empty() {
+            return Ok(());
+        }
+
+        tracing::error!("Corrupted parts in file {}: {:?}", file_id, parts);
+
+        // Corrupted parts stay in the tier of their file
+        let tier = self.file_tier(file_id).unwrap_or(StorageTier::Cold);
+        let (backend, root) = self.tier_root(tier);
+        let quarantine_folder = root.join(QUARANTINE_FOLDER);
+        let quarantine = match self.config.corrupt_part_action {
+            CorruptPartAction::Report => false,
+            CorruptPartAction::Quarantine { max_size } => {
+                let parts_size = parts.len() as u64 * file.header().file_part_size as u64;
+                let quarantine_size = folder_size(&**backend, &quarantine_folder)?;
+                let fits = quarantine_size + parts_size <= max_size;
+                if !fits {
+                    tracing::warn!(
+                        "Quarantine folder is full, dropping the corrupted parts of file {}",
+                        file_id
+                    );
+                }
+
+                fits
             }
+        };
 
-            let remaining_size = max_folder_size - header.size;
+        self.mark_parts_dropped(file_id)?;
+        let Some(file) = self.files.get_mut(&file_id) else {
+            return Ok(());
+        };
+        for part in &parts {
+            if quarantine {
+                file.quarantine_part(*part, &quarantine_folder)?;
+            } else {
+                file.acknowledge_file_parts(FilePartIdRangeInclusive::new_single(
+                    FilePartId::Part(*part),
+                ))?;
+            }
 
-            self.delete_parts_until_max_size_reached(remaining_size)?;
+            let part_range = FilePartIdRangeInclusive::new_single(FilePartId::Part(*part));
+            self.in_flight.remove_acked(file_id, &part_range);
         }
 
-        let file = ManagedSendingFile::create_new_from_header(destination_path, path, header)?;
+        if file.is_finished() {
+            self.delete_file_by_id(file_id)?;
+        }
 
-        self.files.insert(file.header().id, file);
+        self.status_messages
+            .push_back(StatusMessage::PartsCorrupted(PartsCorrupted {
+                file_id,
+                parts,
+                quarantined: quarantine,
+            }));
 
         Ok(())
     }
 
-    /// Confirms that a file has been sent, decreasing its priority. Not to be confused
-    /// with acknowleding files, which deletes their parts.
-    pub fn confirm_file_sent(
-        &mut self,
-        file_id: uuid::Uuid,
-        part_id: FilePartId,
-    ) -> anyhow::Result<()> {
-        let file = self.get_file_mut(file_id);
-
-        let Some(file) = file else {
-            tracing::info!(
-                "Received file send confirmation for non-existent file: {}",
-                file_id
-            );
+    /// Record that some of a file's parts are removed without being sent, so that the file
+    /// isn't archived, as it couldn't be verified anymore.
+    fn mark_parts_dropped(&mut self, file_id: Uuid) -> anyhow::Result<()> {
+        let Some(file) = self.files.get_mut(&file_id) else {
             return Ok(());
         };
+        if file.info().has_dropped_parts {
+            return Ok(());
+        }
 
-        file.decrease_part_priority(part_id)?;
+        let mut info = file.info().clone();
+        info.has_dropped_parts = true;
+        file.set_info(info)?;
+
+        if let Some(archive) = &self.archive {
+            archive.discard(file_id)?;
+        }
 
         Ok(())
     }
 
-    pub fn path(&self) -> &PathBuf {
-        &self.path
+    pub fn in_flight(&self) -> &InFlightTracker {
+        &self.in_flight
     }
-}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct StorageFilePart {
-    pub file_id: Uuid,
+    pub fn archive(&self) -> Option<&SendingArchive> {
+        self.archive.as_ref()
+    }
 
-    pub part_id: FilePartId,
-    pub priority: i16,
-}
+    pub fn catalog(&self) -> Option<&FileCatalog> {
+        self.catalog.as_ref()
+    }
+
+    /// Take the next status message that's waiting to be sent on the downlink.
+    pub fn pop_status_message(&mut self) -> Option<StatusMessage> {
+        self.status_messages.pop_front()
+    }
+
+    fn file_ids_matching_filter(&self, filter: &FileFilter) -> Vec<Uuid> {
+        self.files
+            .values()
+            .filter(|file| {
+                filter.matches(
+                    file.header(),
+                    file.info().source.as_deref(),
+                    file.info().group.as_deref(),
+                )
+            })
+            .map(|file| file.header().id)
+            .collect()
+    }
+
+    pub fn iter_files(&self) -> impl Iterator<Item = &ManagedSendingFile> {
+        self.files.values()
+    }
+
+    pub fn iter_remaining_storage_file_parts(&self) -> impl '_ + Iterator<Item = StorageFilePart> {
+        let nested_iter = self.files.values().map(|file| {
+            file.remaining_parts().iter().map(|part| StorageFilePart {
+                file_id: file.header().id,
+                part_id: part.part,
+                priority: part.priority,
+            })
+        });
+
+        nested_iter.flatten()
+    }
+
+    fn delete_file_by_id(&mut self, file_id: Uuid) -> anyhow::Result<()> {
+        let file = self.files.remove(&file_id);
+
+        let Some(file) = file else {
+            tracing::info!("Received delete request for non-existent file: {}", file_id);
+            return Ok(());
+        };
+
+        if let Some(hash) = &file.info().content_hash {
+            if self.content_index.get(hash) == Some(&file_id) {
+                self.content_index.remove(hash);
+            }
+        }
+
+        self.journal.begin(JournalEntry::Delete { file_id })?;
+        file.delete()?;
+        if self.hot_files.remove(&file_id).is_some() {
+            self.backend
+                .remove_dir_all(&self.path.join(file_id.to_string()))?;
+        }
+        self.in_flight.remove_file(file_id);
+        self.resealed_headers.remove(&file_id);
+        if let Some(archive) = &self.archive {
+            archive.discard(file_id)?;
+        }
+
+        self.journal.finish()
+    }
+
+    /// Acknowledge parts of a file, which removes their data, and delete the file once all its
+    /// parts are acknowledged. The file is archived first if its parts were collected in the
+    /// archive.
+    fn acknowledge_parts(
+        &mut self,
+        file_id: Uuid,
+        part_range: FilePartIdRangeInclusive,
+    ) -> anyhow::Result<()> {
+        let Some(file) = self.files.get_mut(&file_id) else {
+            return Ok(());
+        };
+
+        self.journal.begin(JournalEntry::Acknowledge {
+            file_id,
+            from: part_range.from,
+            to: part_range.to,
+        })?;
+        file.acknowledge_file_parts(part_range.clone())?;
+        self.in_flight.remove_acked(file_id, &part_range);
+        if file.is_finished() {
+            // Files with evicted parts couldn't be verified against their digest
+            let archive = self
+                .archive
+                .as_mut()
+                .filter(|_| !file.info().has_dropped_parts);
+            if let Some(archive) = archive {
+                let now = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+                if let Err(err) = archive.finish(file.header(), file.info(), now) {
+                    tracing::error!("Failed to archive file {}: {:?}", file_id, err);
+                }
+            }
+
+            self.delete_file_by_id(file_id)?;
+        }
+
+        self.journal.finish()
+    }
+
+    /// Copy the data of the confirmed parts that are still stored into the archive, before
+    /// they're removed.
+    fn archive_parts(&self, file_id: Uuid, part_range: &FilePartIdRangeInclusive) {
+        let (Some(archive), Some(file)) = (&self.archive, self.files.get(&file_id)) else {
+            return;
+        };
+        if file.info().has_dropped_parts {
+            return;
+        }
+
+        let parts = file
+            .remaining_parts()
+            .iter()
+            .filter(|part| part_range.contains(part.part));
+        for part in parts {
+            let FilePartId::Part(part_id) = part.part else {
+                continue;
+            };
+
+            let result = file.get_file_part(part.part).and_then(|chunk| match chunk {
+                Some(Chunk::Data(chunk)) => archive.store_part(file.header(), part_id, &chunk.data),
+                _ => Ok(()),
+            });
+            if let Err(err) = result {
+                // The archived copy fails verification without the part, so it's not kept
+                tracing::error!(
+                    "Failed to archive part {} of file {}: {:?}",
+                    part_id,
+                    file_id,
+                    err
+                );
+            }
+        }
+
+        // The parts' data is freed right after, by an acknowledgement that's synced to disk
+        if let Err(err) = archive.sync_partial(file_id) {
+            tracing::error!(
+                "Failed to sync archived parts of file {}: {:?}",
+                file_id,
+                err
+            );
+        }
+    }
+
+    /// Add an archived file to the storage again as `new_file_id`, so that it's sent again. It
+    /// keeps its id when it's resent because the ground lost it, and gets a new one when the
+    /// ground requeues it explicitly.
+    fn requeue_archived_file(
+        &mut self,
+        file_id: Uuid,
+        new_file_id: Uuid,
+        priority: Option<i16>,
+    ) -> anyhow::Result<()> {
+        let Some(archive) = &self.archive else {
+            tracing::warn!(
+                "Received requeue request for file {}, but there's no archive",
+                file_id
+            );
+            return Ok(());
+        };
+        let Some(archived) = archive.get(file_id).cloned() else {
+            tracing::warn!(
+                "Received requeue request for file that's not archived: {}",
+                file_id
+            );
+            return Ok(());
+        };
+
+        let mut header = archived.header;
+        header.id = new_file_id;
+        let info = ManagedFileInfo {
+            source: archived.info.source,
+            content_hash: archived.info.content_hash,
+            group: archived.info.group,
+            ..Default::default()
+        };
+        tracing::info!("Requeuing archived file {} as file {}", file_id, header.id);
+
+        // The archived copy is kept, as the ground may lose the file again
+        let data_path = archive.temp_path(header.id);
+        std::fs::copy(archive.data_path(file_id), &data_path)?;
+
+        let result = self
+            .make_room_for_file(info.source.as_deref(), header.size)
+            .and_then(|()| self.create_file(header, &data_path, info, priority));
+        if result.is_err() {
+            std::fs::remove_file(&data_path).unwrap_or_default();
+        }
+
+        result
+    }
+
+    /// Add a catalogued file to the storage, with all its parts at `priority`. It's removed from
+    /// the catalog once it was added.
+    fn request_cataloged_file(&mut self, entry_id: Uuid, priority: i16) -> anyhow::Result<()> {
+        let Some(catalog) = &self.catalog else {
+            tracing::warn!(
+                "Received request for catalogued file {}, but there's no catalog",
+                entry_id
+            );
+            return Ok(());
+        };
+        let Some(cataloged) = catalog.get(entry_id) else {
+            tracing::warn!(
+                "Received request for file that's not catalogued: {}",
+                entry_id
+            );
+            return Ok(());
+        };
+
+        tracing::info!("Adding requested catalogued file {}", entry_id);
+        let options = cataloged.new_file_options(priority);
+        let data_path = catalog.data_path(entry_id);
+        self.add_file_from_path_with_options(data_path, options)?;
+
+        if let Some(catalog) = &mut self.catalog {
+            catalog.remove(entry_id)?;
+        }
+
+        Ok(())
+    }
+
+    fn split_file(&mut self, file_id: Uuid) -> anyhow::Result<()> {
+        let Some(file) = self.files.get_mut(&file_id) else {
+            return Ok(());
+        };
+
+        self.journal.begin(JournalEntry::Split { file_id })?;
+        let punched = self.config.punch_holes && file.trigger_hole_punching()?;
+        if !punched {
+            if self.config.punch_holes {
+                tracing::info!(
+                    "Couldn't punch holes into file {}, e.g. because the storage doesn't support it, splitting it instead",
+                    file_id
+                );
+            }
+            file.trigger_file_split()?;
+        }
+
+        self.journal.finish()
+    }
+
+    /// Replace the configured eviction policy, e.g. with one that isn't built from the config.
+    pub fn set_eviction_policy(&mut self, policy: Box<dyn EvictionPolicy>) {
+        self.eviction = policy;
+    }
+
+    /// The total size of the file data that's still stored, in bytes
+    pub fn calc_total_data_size(&self) -> u64 {
+        self.files
+            .values()
+            .map(|file| file.calc_remaining_data_size())
+            .sum()
+    }
+
+    /// The size of the file data that's still stored in a tier, in bytes. Staged files count
+    /// towards both tiers.
+    pub fn calc_tier_data_size(&self, tier: StorageTier) -> u64 {
+        self.files
+            .iter()
+            .map(|(file_id, file)| match tier {
+                StorageTier::Cold => self.calc_folder_data_size(*file_id, file),
+                StorageTier::Hot if self.hot_files.contains_key(file_id) => {
+                    file.calc_remaining_data_size()
+                }
+                StorageTier::Hot => 0,
+            })
+            .sum()
+    }
+
+    /// The size of a file's data in the storage folder, which is larger than what's remaining
+    /// if parts were acknowledged while the file was staged.
+    fn calc_folder_data_size(&self, file_id: Uuid, file: &ManagedSendingFile) -> u64 {
+        match self.hot_files.get(&file_id) {
+            Some(&cold_size) => cold_size,
+            None => file.calc_remaining_data_size(),
+        }
+    }
+
+    /// The storage tier that a file is sent from
+    pub fn file_tier(&self, file_id: Uuid) -> Option<StorageTier> {
+        if !self.files.contains_key(&file_id) {
+            return None;
+        }
+
+        match self.hot_files.contains_key(&file_id) {
+            true => Some(StorageTier::Hot),
+            false => Some(StorageTier::Cold),
+        }
+    }
+
+    /// The backend and the folder of a storage tier. Without a hot tier, that's the storage
+    /// folder for both tiers.
+    fn tier_root(&self, tier: StorageTier) -> (&Arc<dyn StorageBackend>, &Path) {
+        match (tier, &self.config.hot_tier) {
+            (StorageTier::Hot, Some(hot_tier)) => (&self.hot_backend, &hot_tier.path),
+            _ => (&self.backend, &self.path),
+        }
+    }
+
+    /// Stage and unstage files, so that the files which are sent next are staged in the hot
+    /// tier, see [`HotTierConfig`].
+    pub fn rebalance_tiers(&mut self) -> anyhow::Result<()> {
+        let Some(hot_tier) = &self.config.hot_tier else {
+            return Ok(());
+        };
+        let hot_max_size = hot_tier.max_size;
+        let selected = select_hot_files(self.files.values(), hot_tier);
+
+        // Make room first
+        let demoted = self
+            .hot_files
+            .keys()
+            .filter(|file_id| !selected.contains(file_id))
+            .copied()
+            .collect::<Vec<_>>();
+        for file_id in demoted {
+            self.unstage_file(file_id)?;
+        }
+
+        let mut hot_size = self.calc_tier_data_size(StorageTier::Hot);
+        let promoted = selected
+            .iter()
+            .filter(|file_id| !self.hot_files.contains_key(file_id))
+            .copied()
+            .collect::<Vec<_>>();
+        for file_id in promoted {
+            let size = self.files[&file_id].calc_remaining_data_size();
+            if hot_size + size > hot_max_size {
+                continue;
+            }
+
+            self.stage_file(file_id)?;
+            hot_size += size;
+        }
+
+        Ok(())
+    }
+
+    /// Copy a file into the hot tier, and send it from there. Its folder in the storage folder is
+    /// kept, in case the hot tier is lost.
+    fn stage_file(&mut self, file_id: Uuid) -> anyhow::Result<()> {
+        let (backend, root) = self.tier_root(StorageTier::Hot);
+        let (backend, folder) = (backend.clone(), root.join(file_id.to_string()));
+        let Some(file) = self.files.get_mut(&file_id) else {
+            return Ok(());
+        };
+
+        tracing::info!("Staging file {} in the hot storage tier", file_id);
+        self.journal.begin(JournalEntry::Move {
+            file_id,
+            to: StorageTier::Hot,
+        })?;
+        let cold_size = file.calc_remaining_data_size();
+        let staged = file.copy_to(backend, folder)?;
+        *file = staged;
+        self.hot_files.insert(file_id, cold_size);
+
+        self.journal.finish()
+    }
+
+    /// Bring the state of a staged file back into the storage folder, and delete its copy in the
+    /// hot tier.
+    fn unstage_file(&mut self, file_id: Uuid) -> anyhow::Result<()> {
+        let folder = self.path.join(file_id.to_string());
+        let Some(file) = self.files.get_mut(&file_id) else {
+            return Ok(());
+        };
+
+        tracing::info!("Unstaging file {} from the hot storage tier", file_id);
+        self.journal.begin(JournalEntry::Move {
+            file_id,
+            to: StorageTier::Cold,
+        })?;
+
+        // The parts that were acknowledged while the file was staged are acknowledged in the
+        // storage folder too, to free their data there. A crash before the state is written back
+        // only leaves some of that data behind.
+        let mut cold = ManagedSendingFile::try_read_from_path(self.backend.clone(), &folder)?
+            .with_context(|| format!("Storage folder copy of file {} is missing", file_id))?;
+        let acked = cold
+            .remaining_parts()
+            .iter()
+            .map(|part| part.part)
+            .filter(|part| {
+                file.remaining_parts()
+                    .binary_search_by_key(&part.to_index(), |p| p.part.to_index())
+                    .is_err()
+            })
+            .collect::<Vec<_>>();
+        for part_range in part_ranges(acked) {
+            cold.acknowledge_file_parts(part_range)?;
+        }
+
+        file.write_state_to(&*self.backend, &folder)?;
+        let unstaged = ManagedSendingFile::try_read_from_path(self.backend.clone(), &folder)?
+            .with_context(|| format!("Unstaged file {} couldn't be read back", file_id))?;
+        std::mem::replace(file, unstaged).delete()?;
+        self.hot_files.remove(&file_id);
+
+        self.journal.finish()
+    }
+
+    /// The size of the file data from an input source that's still stored, in bytes
+    pub fn calc_source_data_size(&self, source: &str) -> u64 {
+        self.files
+            .values()
+            .filter(|file| file.info().source.as_deref() == Some(source))
+            .map(|file| file.calc_remaining_data_size())
+            .sum()
+    }
+
+    /// Evict parts without them being acknowledged until the data in the storage folder is at
+    /// most `size` bytes, in the order of the eviction policy. Evictions are reported on the
+    /// downlink.
+    pub fn delete_parts_until_max_size_reached(&mut self, size: u64) -> anyhow::Result<()> {
+        self.evict_parts(EvictionScope::StorageFolder, size)
+    }
+
+    /// Evict parts of the files from an input source, like
+    /// [`Self::delete_parts_until_max_size_reached`], until the source's data is at most `size`
+    /// bytes.
+    pub fn delete_source_parts_until_quota_reached(
+        &mut self,
+        source: &str,
+        size: u64,
+    ) -> anyhow::Result<()> {
+        self.evict_parts(EvictionScope::Source(source), size)
+    }
+
+    fn in_eviction_scope(&self, scope: &EvictionScope, file: &ManagedSendingFile) -> bool {
+        match scope {
+            // Staged files are kept in the storage folder as well
+            EvictionScope::StorageFolder => true,
+            EvictionScope::Source(source) => file.info().source.as_deref() == Some(*source),
+        }
+    }
+
+    /// The size of a file's data that counts towards the limit of an eviction scope
+    fn calc_scope_data_size(&self, scope: &EvictionScope, file_id: Uuid) -> u64 {
+        let Some(file) = self.files.get(&file_id) else {
+            return 0;
+        };
+
+        match scope {
+            EvictionScope::StorageFolder => self.calc_folder_data_size(file_id, file),
+            EvictionScope::Source(_) => file.calc_remaining_data_size(),
+        }
+    }
+
+    fn evict_parts(&mut self, scope: EvictionScope, size: u64) -> anyhow::Result<()> {
+        let mut total_size = self
+            .files
+            .values()
+            .filter(|file| self.in_eviction_scope(&scope, file))
+            .map(|file| self.calc_scope_data_size(&scope, file.header().id))
+            .sum::<u64>();
+        if total_size <= size {
+            return Ok(());
+        }
+
+        let policy = &self.eviction;
+        let mut items = self
+            .files
+            .values()
+            .filter(|file| self.in_eviction_scope(&scope, file))
+            .filter(|file| !policy.is_protected(file))
+            .flat_map(|file| {
+                file.remaining_parts()
+                    .iter()
+                    .map(move |part| EvictionCandidate {
+                        part: StorageFilePart {
+                            file_id: file.header().id,
+                            part_id: part.part,
+                            priority: part.priority,
+                        },
+                        file,
+                    })
+            })
+            .collect::<Vec<_>>();
+        items.sort_unstable_by(|a, b| policy.cmp_for_eviction(b, a)); // Sort from last to first evicted, we will pop items from the end
+        let mut items = items.into_iter().map(|item| item.part).collect::<Vec<_>>();
+
+        let whole_files_only = self.eviction.whole_files_only();
+        let mut evicted_files = Vec::new();
+        let mut evicted_parts = HashMap::<Uuid, Vec<FilePartId>>::new();
+
+        while total_size > size {
+            let item = items.pop();
+            let item = match item {
+                Some(item) => item,
+                None => break,
+            };
+
+            if !self.files.contains_key(&item.file_id) {
+                continue;
+            }
+            let file_size = self.calc_scope_data_size(&scope, item.file_id);
+
+            if !evicted_parts.contains_key(&item.file_id) {
+                evicted_files.push(item.file_id);
+            }
+
+            if whole_files_only {
+                self.delete_file_by_id(item.file_id)?;
+                evicted_parts.entry(item.file_id).or_default();
+                total_size -= file_size;
+                continue;
+            }
+
+            self.mark_parts_dropped(item.file_id)?;
+
+            // Only the staged copy would be changed, so the data in the storage folder is freed
+            // by bringing the file back there first
+            if matches!(scope, EvictionScope::StorageFolder)
+                && self.hot_files.contains_key(&item.file_id)
+            {
+                self.unstage_file(item.file_id)?;
+            }
+
+            // Acknowledge the part to remove it from storage
+            let part_range = FilePartIdRangeInclusive::new_single(item.part_id);
+            self.acknowledge_parts(item.file_id, part_range)?;
+            evicted_parts
+                .entry(item.file_id)
+                .or_default()
+                .push(item.part_id);
+
+            // The file was deleted if it was finished
+            let Some(file) = self.files.get(&item.file_id) else {
+                // Update the total size with the difference
+                let new_file_size = 0;
+                total_size = total_size - file_size + new_file_size;
+                continue;
+            };
+
+            if let Some(split_if_n) = self.config.split_file_if_n_chunks_saved {
+                if file.chunks_saved_by_splitting() >= split_if_n {
+                    self.split_file(item.file_id)?;
+                }
+            }
+
+            // Update the total size with the difference
+            let new_file_size = self.calc_scope_data_size(&scope, item.file_id);
+            total_size = total_size - file_size + new_file_size;
+        }
+
+        if total_size > size {
+            tracing::error!(
+                "Storage is still over the {} of {} after evicting all unprotected parts",
+                scope,
+                size
+            );
+        }
+
+        for file_id in evicted_files {
+            let parts = evicted_parts.remove(&file_id).unwrap_or_default();
+            let status_message = if self.files.contains_key(&file_id) {
+                tracing::warn!(
+                    "Evicted {} parts of file {} to reach the {} of {}",
+                    parts.len(),
+                    file_id,
+                    scope,
+                    size
+                );
+
+                StatusMessage::PartsEvicted(PartsEvicted {
+                    file_id,
+                    ranges: part_ranges(parts),
+                })
+            } else {
+                tracing::warn!(
+                    "Evicted file {} to reach the {} of {}",
+                    file_id,
+                    scope,
+                    size
+                );
+
+                StatusMessage::FileRemoved(FileRemoved {
+                    file_id,
+                    reason: FileRemovedReason::Evicted,
+                })
+            };
+            self.status_messages.push_back(status_message);
+        }
+
+        Ok(())
+    }
+
+    pub fn add_file_from_path(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.add_file_from_path_with_options(path, NewFileOptions::default())
+    }
+
+    pub fn add_file_from_path_with_options(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: NewFileOptions,
+    ) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        if options.catalog_only {
+            return self.catalog_file(path, options).map(|_| ());
+        }
+
+        let chunk_size = self.chunk_size_for(&options)?;
+
+        // Compressed files are stored and sent compressed. The original is removed once the
+        // compressed copy was added.
+        let compressed_path = match options.compression {
+            FileCompression::None => None,
+            FileCompression::Zstd => Some(compress_file_zstd(path)?),
+        };
+
+        let data_path = compressed_path.as_deref().unwrap_or(path);
+        let result = self.add_file_data(path, data_path, chunk_size, options);
+
+        if let Some(compressed_path) = &compressed_path {
+            match &result {
+                Ok(()) => std::fs::remove_file(path)?,
+                Err(_) => std::fs::remove_file(compressed_path).unwrap_or_default(),
+            }
+        }
+
+        result
+    }
+
+    /// Move a file into the catalog instead of queuing it, and announce its entry on the
+    /// downlink. Returns the id of the entry.
+    pub fn catalog_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: NewFileOptions,
+    ) -> anyhow::Result<Uuid> {
+        // Checked now, rather than when the ground requests the file
+        self.chunk_size_for(&options)?;
+
+        let catalog = self
+            .catalog
+            .as_mut()
+            .context("File is catalog-only, but there's no catalog")?;
+        let now = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let entry = catalog.add(path.as_ref(), options, now)?.entry();
+        let entry_id = entry.entry_id;
+
+        // Announcements that weren't sent yet are coalesced into pages, like a listing
+        match self.status_messages.back_mut() {
+            Some(StatusMessage::CatalogEntries(announced)) if announced.has_room_for(&entry) => {
+                announced.entries.push(entry);
+            }
+            _ => {
+                self.status_messages
+                    .push_back(StatusMessage::CatalogEntries(CatalogEntries {
+                        entries: vec![entry],
+                    }));
+            }
+        }
+
+        Ok(entry_id)
+    }
+
+    /// Add a file with its data at `data_path`, which differs from `path` if the file was
+    /// compressed. The name and date are always taken from the original file.
+    fn add_file_data(
+        &mut self,
+        path: &Path,
+        data_path: &Path,
+        chunk_size: u32,
+        options: NewFileOptions,
+    ) -> anyhow::Result<()> {
+        let mut header = generate_file_header_from_path(data_path, chunk_size)?;
+        header.name = match options.name {
+            Some(name) => name,
+            None => path
+                .file_name()
+                .context("File path has no file name")?
+                .to_string_lossy()
+                .to_string(),
+        };
+        header.date = file_created_date(path)?;
+        header.metadata = options.metadata;
+        header.compression = options.compression;
+        header.kind = options.kind;
+        anyhow::ensure!(header.is_valid(), "File metadata is too large");
+        let content_hash = header
+            .sha256
+            .context("New file header is missing its digest")?
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        let expires_at = options
+            .ttl
+            .map(|ttl| header.date.saturating_add(ttl.as_nanos() as i64));
+
+        let duplicate_of = self.content_index.get(&content_hash).copied();
+        if let Some(existing_id) = duplicate_of {
+            if self.config.duplicate_policy != DuplicateFilePolicy::Keep {
+                return self.handle_duplicate_file(
+                    data_path,
+                    existing_id,
+                    options.source,
+                    expires_at,
+                );
+            }
+
+            tracing::info!(
+                "Keeping duplicate of managed file {} as a separate file: {:?}",
+                existing_id,
+                path
+            );
+        }
+
+        self.make_room_for_file(options.source.as_deref(), header.size)?;
+
+        let info = ManagedFileInfo {
+            source: options.source,
+            expires_at,
+            content_hash: Some(content_hash),
+            group: options.group,
+            ..Default::default()
+        };
+
+        self.create_file(header, data_path, info, options.priority)
+    }
+
+    /// Evict parts until a new file of `size` bytes fits within its source's quota and the
+    /// maximum folder size.
+    fn make_room_for_file(&mut self, source: Option<&str>, size: u64) -> anyhow::Result<()> {
+        // Make room within the source's quota first, which may already make room in the folder
+        let quota =
+            source.and_then(|source| Some((source, *self.config.source_quotas.get(source)?)));
+        if let Some((source, quota)) = quota {
+            if quota < size {
+                anyhow::bail!(
+                    "File size {} exceeds quota {} of source {}",
+                    size,
+                    quota,
+                    source
+                );
+            }
+
+            self.delete_source_parts_until_quota_reached(source, quota - size)?;
+        }
+
+        if let Some(max_folder_size) = self.config.max_folder_size {
+            if max_folder_size < size {
+                anyhow::bail!(
+                    "File size {} exceeds maximum folder size {}",
+                    size,
+                    max_folder_size
+                );
+            }
+
+            let remaining_size = max_folder_size - size;
+
+            self.delete_parts_until_max_size_reached(remaining_size)?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a managed file in the storage folder, moving in its data from `data_path`.
+    fn create_file(
+        &mut self,
+        header: HeaderChunk,
+        data_path: &Path,
+        info: ManagedFileInfo,
+        priority: Option<i16>,
+    ) -> anyhow::Result<()> {
+        let destination_path = self.path.join(header.id.to_string());
+        let content_hash = info.content_hash.clone();
+
+        self.journal.begin(JournalEntry::Add {
+            file_id: header.id,
+            priority,
+        })?;
+        let mut file = ManagedSendingFile::create_new_from_header(
+            self.backend.clone(),
+            destination_path,
+            data_path,
+            header,
+            info,
+        )?;
+        if let Some(priority) = priority {
+            file.set_all_parts_priorities(priority)?;
+        }
+        self.journal.finish()?;
+
+        if let Some(content_hash) = content_hash {
+            self.content_index.insert(content_hash, file.header().id);
+        }
+        self.files.insert(file.header().id, file);
+
+        self.rebalance_tiers()
+    }
+
+    fn chunk_size_for(&self, options: &NewFileOptions) -> anyhow::Result<u32> {
+        let chunk_size = options
+            .chunk_size
+            .unwrap_or(self.config.new_file_chunk_size);
+        anyhow::ensure!(
+            chunk_size > 0 && chunk_size as usize <= DataChunk::MAX_CHUNK_LENGTH,
+            "Invalid chunk size {}",
+            chunk_size
+        );
+
+        Ok(chunk_size)
+    }
+
+    /// Add an open file for a source that's still growing, e.g. a log that's being written to.
+    /// Nothing is read from the source here, its data is added with
+    /// [`Self::append_to_open_file`] and [`Self::seal_open_file`]. Returns the id of the file.
+    pub fn add_open_file(
+        &mut self,
+        source_path: &Path,
+        options: NewFileOptions,
+    ) -> anyhow::Result<Uuid> {
+        let chunk_size = self.chunk_size_for(&options)?;
+        anyhow::ensure!(
+            options.compression == FileCompression::None && options.kind == FileKind::Regular,
+            "Open files can't be compressed or bundled"
+        );
+
+        let name = match options.name {
+            Some(name) => name,
+            None => source_path
+                .file_name()
+                .context("File path has no file name")?
+                .to_string_lossy()
+                .to_string(),
+        };
+        let header = HeaderChunk {
+            id: Uuid::new_v4(),
+            name,
+            date: file_created_date(source_path)?,
+            part_count: 0,
+            size: 0,
+            file_part_size: chunk_size,
+            sha256: None,
+            metadata: options.metadata,
+            compression: FileCompression::None,
+            kind: FileKind::Regular,
+            open: true,
+        };
+        anyhow::ensure!(header.is_valid(), "File metadata is too large");
+
+        let info = ManagedFileInfo {
+            source: options.source,
+            expires_at: options
+                .ttl
+                .map(|ttl| header.date.saturating_add(ttl.as_nanos() as i64)),
+            content_hash: None,
+            group: options.group,
+            open_source_path: Some(source_path.to_path_buf()),
+            append_priority: options.priority.unwrap_or(0),
+            ..Default::default()
+        };
+
+        let destination_path = self.path.join(header.id.to_string());
+        self.journal.begin(JournalEntry::Add {
+            file_id: header.id,
+            priority: options.priority,
+        })?;
+        let mut file =
+            ManagedSendingFile::create_open(self.backend.clone(), destination_path, header, info)?;
+        if let Some(priority) = options.priority {
+            file.set_all_parts_priorities(priority)?;
+        }
+        self.journal.finish()?;
+
+        let file_id = file.header().id;
+        self.files.insert(file_id, file);
+
+        Ok(file_id)
+    }
+
+    /// Find the open file that's being appended from a source.
+    pub fn find_open_file(&self, source_path: &Path) -> Option<&ManagedSendingFile> {
+        self.files.values().find(|file| {
+            file.header().open && file.info().open_source_path.as_deref() == Some(source_path)
+        })
+    }
+
+    /// Append data to an open file. Only full parts can be appended before the file is sealed,
+    /// so the length of the data must be a multiple of the file's part size.
+    pub fn append_to_open_file(&mut self, file_id: Uuid, data: &[u8]) -> anyhow::Result<()> {
+        let file = self
+            .files
+            .get(&file_id)
+            .with_context(|| format!("Open file {} isn't managed", file_id))?;
+        let part_size = file.header().file_part_size as usize;
+        anyhow::ensure!(
+            data.len().is_multiple_of(part_size),
+            "Appended data must be a multiple of the part size {}, got {} bytes",
+            part_size,
+            data.len()
+        );
+
+        self.append_parts(file_id, data)
+    }
+
+    /// Append the rest of an open file's data, which may end with a short part, and seal it.
+    /// The digest is of the whole file, if it's known.
+    pub fn seal_open_file(
+        &mut self,
+        file_id: Uuid,
+        data: &[u8],
+        sha256: Option<[u8; 32]>,
+    ) -> anyhow::Result<()> {
+        self.append_parts(file_id, data)?;
+
+        let file = self
+            .files
+            .get_mut(&file_id)
+            .with_context(|| format!("Open file {} isn't managed", file_id))?;
+        file.seal(sha256)?;
+
+        // The header that was sent while the file was open may still be in flight
+        let header_range = FilePartIdRangeInclusive::new_single(FilePartId::Header);
+        self.in_flight.remove_acked(file_id, &header_range);
+        self.resealed_headers.insert(file_id);
+
+        Ok(())
+    }
+
+    fn append_parts(&mut self, file_id: Uuid, data: &[u8]) -> anyhow::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let appended_size = data.len() as u64;
+        let source = self
+            .files
+            .get(&file_id)
+            .and_then(|file| file.info().source.clone());
+        let quota = source
+            .as_ref()
+            .and_then(|source| Some((source, *self.config.source_quotas.get(source)?)));
+        if let Some((source, quota)) = quota {
+            if self.calc_source_data_size(source) + appended_size > quota {
+                self.delete_source_parts_until_quota_reached(
+                    source,
+                    quota.saturating_sub(appended_size),
+                )?;
+            }
+        }
+
+        if let Some(max_folder_size) = self.config.max_folder_size {
+            let folder_size = self.calc_tier_data_size(StorageTier::Cold);
+            if folder_size + appended_size > max_folder_size {
+                self.delete_parts_until_max_size_reached(
+                    max_folder_size.saturating_sub(appended_size),
+                )?;
+            }
+        }
+
+        let file = self
+            .files
+            .get_mut(&file_id)
+            .with_context(|| format!("Open file {} isn't managed", file_id))?;
+        for part in data.chunks(file.header().file_part_size as usize) {
+            file.append_part(part)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_duplicate_file(
+        &mut self,
+        path: &Path,
+        existing_id: Uuid,
+        source: Option<String>,
+        expires_at: Option<i64>,
+    ) -> anyhow::Result<()> {
+        let existing = self
+            .files
+            .get_mut(&existing_id)
+            .context("Content index refers to a file that isn't managed")?;
+
+        if self.config.duplicate_policy == DuplicateFilePolicy::Merge {
+            let mut info = existing.info().clone();
+            info.source = info.source.or(source);
+            info.expires_at = match (info.expires_at, expires_at) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                _ => None,
+            };
+            existing.set_info(info)?;
+
+            tracing::info!(
+                "Merged duplicate into managed file {}: {:?}",
+                existing_id,
+                path
+            );
+        } else {
+            tracing::info!(
+                "Dropped duplicate of managed file {}: {:?}",
+                existing_id,
+                path
+            );
+        }
+
+        std::fs::remove_file(path)?;
+
+        Ok(())
+    }
+
+    /// Confirms that a file has been sent, decreasing its priority. Not to be confused
+    /// with acknowleding files, which deletes their parts. The part is tracked as in flight
+    /// until it's acknowledged.
+    pub fn confirm_file_sent(
+        &mut self,
+        file_id: uuid::Uuid,
+        part_id: FilePartId,
+    ) -> anyhow::Result<()> {
+        let file = self.files.get_mut(&file_id);
+
+        let Some(file) = file else {
+            tracing::info!(
+                "Received file send confirmation for non-existent file: {}",
+                file_id
+            );
+            return Ok(());
+        };
+
+        if part_id == FilePartId::Header {
+            self.resealed_headers.remove(&file_id);
+        }
+
+        let part = file.remaining_parts().iter().find(|p| p.part == part_id);
+        let newly_in_flight = part.is_some() && !self.in_flight.is_in_flight(file_id, part_id);
+        if let Some(part) = part {
+            let now = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+            self.in_flight
+                .record_sent(file_id, part_id, part.priority, now);
+        }
+
+        match self.config.state_flush {
+            StateFlushPolicy::Immediate => {
+                // The original priority must be persisted before the lowered one is
+                if newly_in_flight {
+                    self.in_flight.append_record(file_id, part_id)?;
+                }
+                file.decrease_part_priority(part_id)?;
+            }
+            StateFlushPolicy::Batched { .. } => file.decrease_part_priority_deferred(part_id),
+        }
+
+        Ok(())
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+/// The total size of the files in a folder, e.g. the quarantine folder. A missing folder is empty.
+fn folder_size(backend: &dyn StorageBackend, folder: &Path) -> anyhow::Result<u64> {
+    if !backend.is_dir(folder) {
+        return Ok(0);
+    }
+
+    let mut size = 0;
+    for path in backend.read_dir(folder)? {
+        size += backend.file_len(&path)?;
+    }
+
+    Ok(size)
+}
+
+/// Finish or roll back the interrupted change of a file's whole folder, before the files are
+/// loaded. Otherwise a half-deleted or half-created file would look corrupted when it's loaded.
+fn recover_file_folder(
+    backend: &dyn StorageBackend,
+    storage_path: &Path,
+    hot_tier: Option<(&dyn StorageBackend, &Path)>,
+    entry: &JournalEntry,
+) -> anyhow::Result<()> {
+    let tier_folder = |tier: StorageTier, file_id: &Uuid| match (tier, hot_tier) {
+        (StorageTier::Hot, Some((hot_backend, hot_path))) => {
+            Some((hot_backend, hot_path.join(file_id.to_string())))
+        }
+        (StorageTier::Hot, None) => None,
+        (StorageTier::Cold, _) => Some((backend, storage_path.join(file_id.to_string()))),
+    };
+
+    match entry {
+        JournalEntry::Delete { file_id } => {
+            for tier in [StorageTier::Cold, StorageTier::Hot] {
+                let Some((backend, folder)) = tier_folder(tier, file_id) else {
+                    continue;
+                };
+                if backend.is_dir(&folder) {
+                    backend.remove_dir_all(&folder)?;
+                }
+            }
+        }
+        JournalEntry::Add { file_id, .. } => {
+            // The state is written after the data folder of open files, and the data of other
+            // files is moved in after the state
+            let folder = storage_path.join(file_id.to_string());
+            let complete = backend.is_file(&folder.join("state.bin"))
+                && (backend.is_file(&folder.join("data.bin"))
+                    || backend.is_dir(&folder.join("data")));
+            if !complete && backend.is_dir(&folder) {
+                tracing::warn!("Removing file that wasn't completely added: {}", file_id);
+                backend.remove_dir_all(&folder)?;
+            }
+        }
+        JournalEntry::Move { file_id, to } => {
+            let from = match to {
+                StorageTier::Hot => StorageTier::Cold,
+                StorageTier::Cold => StorageTier::Hot,
+            };
+            let (Some((to_backend, to_folder)), Some((from_backend, from_folder))) =
+                (tier_folder(*to, file_id), tier_folder(from, file_id))
+            else {
+                tracing::warn!("Can't recover move of file {} without a hot tier", file_id);
+                return Ok(());
+            };
+
+            match to {
+                // The state is copied last, so the staged copy is complete if it has one
+                StorageTier::Hot => {
+                    if !to_backend.is_file(&to_folder.join("state.bin"))
+                        && to_backend.is_dir(&to_folder)
+                    {
+                        tracing::warn!("Removing file that wasn't completely staged: {}", file_id);
+                        to_backend.remove_dir_all(&to_folder)?;
+                    }
+                }
+                // Write the state back again, unless the staged copy is already gone
+                StorageTier::Cold => {
+                    if from_backend.is_file(&from_folder.join("state.bin")) {
+                        for name in ["info.json", "state.bin"] {
+                            let path = from_folder.join(name);
+                            if from_backend.is_file(&path) {
+                                let data = from_backend.read(&path)?;
+                                to_backend.write_atomic(&to_folder.join(name), &data)?;
+                            }
+                        }
+                    }
+                    if from_backend.is_dir(&from_folder) {
+                        from_backend.remove_dir_all(&from_folder)?;
+                    }
+                }
+            }
+        }
+        JournalEntry::Acknowledge { .. } | JournalEntry::Split { .. } => {}
+    }
+
+    Ok(())
+}
+
+/// Load the managed files in the folders inside a storage folder
+fn load_file_folders(
+    backend: &Arc<dyn StorageBackend>,
+    path: &Path,
+) -> anyhow::Result<HashMap<Uuid, ManagedSendingFile>> {
+    let mut files = HashMap::new();
+    for path in backend.read_dir(path)? {
+        let is_quarantine = path.file_name() == Some(QUARANTINE_FOLDER.as_ref());
+        if !backend.is_dir(&path) || is_quarantine {
+            continue;
+        }
+
+        let file = ManagedSendingFile::try_read_from_path(backend.clone(), &path)?;
+        let Some(file) = file else {
+            // File was likely ended, but not cleaned up.
+            continue;
+        };
+        files.insert(file.header().id, file);
+    }
+
+    Ok(files)
+}
+
+/// The files that are evicted from to stay within a limit
+enum EvictionScope<'a> {
+    /// The files in the storage folder, for its maximum size
+    StorageFolder,
+
+    /// The files from an input source, for its quota
+    Source(&'a str),
+}
+
+impl std::fmt::Display for EvictionScope<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvictionScope::StorageFolder => write!(f, "maximum folder size"),
+            EvictionScope::Source(source) => write!(f, "quota of source {}", source),
+        }
+    }
+}
+
+/// Merge parts into the fewest inclusive ranges that cover them
+fn part_ranges(mut parts: Vec<FilePartId>) -> Vec<FilePartIdRangeInclusive> {
+    parts.sort_unstable();
+
+    let mut ranges = Vec::<FilePartIdRangeInclusive>::new();
+    for part in parts {
+        match ranges.last_mut() {
+            Some(range) if range.to.to_index() + 1 >= part.to_index() => range.to = part,
+            _ => ranges.push(FilePartIdRangeInclusive::new_single(part)),
+        }
+    }
+
+    ranges
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageFilePart {
+    pub file_id: Uuid,
+
+    pub part_id: FilePartId,
+    pub priority: i16,
+}
+
+#[allow(clippy::comparison_chain)]
+pub fn cmp_file_storage_part_normal(
+    a: &StorageFilePart,
+    b: &StorageFilePart,
+) -> std::cmp::Ordering {
+    // Sort by priority first. Higher priority gets higher precedence.
+    if a.priority > b.priority {
+        return std::cmp::Ordering::Greater;
+    } else if a.priority < b.priority {
+        return std::cmp::Ordering::Less;
+    }
+
+    // Sort by header parts next. Headers always get highest priority.
+    let a_header = a.part_id == FilePartId::Header;
+    let b_header = b.part_id == FilePartId::Header;
+    if a_header && !b_header {
+        return std::cmp::Ordering::Greater;
+    } else if !a_header && b_header {
+        return std::cmp::Ordering::Less;
+    }
+
+    // Then, sort by part number. Higher part numbers get higher precedence.
+    let a_part = match a.part_id {
+        FilePartId::Part(part) => part,
+        FilePartId::Header => 0,
+    };
+    let b_part = match b.part_id {
+        FilePartId::Part(part) => part,
+        FilePartId::Header => 0,
+    };
+    a_part.cmp(&b_part)
+}
+
+#[allow(clippy::comparison_chain)]
+/// Same as the normal cmp_file_storage_part, but with the priority for part index reversed.
+pub fn cmp_file_storage_part_for_deletion(
+    a: &StorageFilePart,
+    b: &StorageFilePart,
+) -> std::cmp::Ordering {
+    // Sort by priority first. Higher priority gets higher precedence.
+    if a.priority > b.priority {
+        return std::cmp::Ordering::Greater;
+    } else if a.priority < b.priority {
+        return std::cmp::Ordering::Less;
+    }
+
+    // Sort by header parts next. Headers always get highest priority.
+    let a_header = a.part_id == FilePartId::Header;
+    let b_header = b.part_id == FilePartId::Header;
+    if a_header && !b_header {
+        return std::cmp::Ordering::Greater;
+    } else if !a_header && b_header {
+        return std::cmp::Ordering::Less;
+    }
+
+    // Then, sort by part number. Lower part numbers get higher precedence. Headers are still highest.
+    let a_part = match a.part_id {
+        FilePartId::Part(part) => part,
+        FilePartId::Header => u32::MAX,
+    };
+    let b_part = match b.part_id {
+        FilePartId::Part(part) => part,
+        FilePartId::Header => u32::MAX,
+    };
+    b_part.cmp(&a_part)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::{
+        chunks::Chunk,
+        control::{
+            ConfirmPart, DeleteFile, ListCatalog, RequestCatalogedFile, RequestFileResend,
+            RequeueArchivedFile, SetFilePriority,
+        },
+        file_sending::storage_backend::MemoryBackend,
+        tempdir::{TempDir, TempDirProvider},
+    };
+
+    struct DummyFile {
+        _folder: TempDir,
+        path: PathBuf,
+    }
+
+    fn make_dummy_file(size: u64) -> anyhow::Result<DummyFile> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let path = folder.path().join("data.bin");
+
+        let file = File::create(&path)?;
+        file.set_len(size)?;
+
+        Ok(DummyFile {
+            _folder: folder,
+            path,
+        })
+    }
+
+    fn get_remaining_data_part_count(storage_manager: &SendingStorageManager) -> usize {
+        storage_manager
+            .iter_remaining_storage_file_parts()
+            .filter(|part| part.part_id != FilePartId::Header)
+            .count()
+    }
+
+    #[test]
+    fn test_shrinking_storage_data() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+
+        let mut storage_manager = SendingStorageManager::new(
+            folder.path().clone(),
+            SendingStorageManagerConfig {
+                split_file_if_n_chunks_saved: Some(5),
+                max_folder_size: Some(15),
+                new_file_chunk_size: 1,
+                ..Default::default()
+            },
+        )?;
+
+        let file = make_dummy_file(10)?;
+        storage_manager.add_file_from_path(&file.path)?;
+
+        assert_eq!(get_remaining_data_part_count(&storage_manager), 10);
+
+        let file = make_dummy_file(10)?;
+        storage_manager.add_file_from_path(&file.path)?;
+
+        assert_eq!(
+            get_remaining_data_part_count(&storage_manager),
+            15 // Would be 20, but we expect it to shrink to 15 as that's the max
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shrinking_data_lowest_priority() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+
+        let mut storage_manager = SendingStorageManager::new(
+            folder.path().clone(),
+            SendingStorageManagerConfig {
+                split_file_if_n_chunks_saved: None,
+                max_folder_size: Some(15),
+                new_file_chunk_size: 1,
+                ..Default::default()
+            },
+        )?;
+
+        let file = make_dummy_file(5)?;
+        storage_manager.add_file_from_path(&file.path)?;
+
+        let file_id = storage_manager.iter_files().next().unwrap().header().id;
+
+        // Decrease priority of all file parts in this file
+        let parts = storage_manager
+            .iter_remaining_storage_file_parts()
+            .collect::<Vec<_>>();
+        for part in parts {
+            if part.file_id == file_id {
+                storage_manager
+                    .confirm_file_sent(file_id, part.part_id)
+                    .unwrap();
+            }
+        }
+
+        // Add 2 more files, expecting the first file to still be present with 6 total parts
+        let file = make_dummy_file(5)?;
+        storage_manager.add_file_from_path(&file.path)?;
+
+        let file = make_dummy_file(5)?;
+        storage_manager.add_file_from_path(&file.path)?;
+
+        let old_file = storage_manager
+            .iter_files()
+            .find(|file| file.header().id == file_id)
+            .unwrap();
+
+        assert_eq!(old_file.remaining_parts().len(), 6);
+        assert_eq!(get_remaining_data_part_count(&storage_manager), 15);
+
+        // Add another file, expect the first file should only have the header left
+        let file = make_dummy_file(5)?;
+        storage_manager.add_file_from_path(&file.path)?;
+
+        assert_eq!(get_remaining_data_part_count(&storage_manager), 15);
+
+        let old_file = storage_manager
+            .iter_files()
+            .find(|file| file.header().id == file_id)
+            .unwrap();
 
-#[allow(clippy::comparison_chain)]
-pub fn cmp_file_storage_part_normal(
-    a: &StorageFilePart,
-    b: &StorageFilePart,
-) -> std::cmp::Ordering {
-    // Sort by priority first. Higher priority gets higher precedence.
-    if a.priority > b.priority {
-        return std::cmp::Ordering::Greater;
-    } else if a.priority < b.priority {
-        return std::cmp::Ordering::Less;
+        assert_eq!(old_file.remaining_parts().len(), 1);
+        assert_eq!(old_file.remaining_parts()[0].part, FilePartId::Header);
+
+        Ok(())
     }
 
-    // Sort by header parts next. Headers always get highest priority.
-    let a_header = a.part_id == FilePartId::Header;
-    let b_header = b.part_id == FilePartId::Header;
-    if a_header && !b_header {
-        return std::cmp::Ordering::Greater;
-    } else if !a_header && b_header {
-        return std::cmp::Ordering::Less;
+    #[test]
+    fn test_eviction_policies() -> anyhow::Result<()> {
+        use crate::file_sending::eviction::{EvictionOrder, ProtectedClass};
+
+        let folder = TempDirProvider::new_for_test().create()?;
+
+        let mut storage_manager = SendingStorageManager::new(
+            folder.path().clone(),
+            SendingStorageManagerConfig {
+                split_file_if_n_chunks_saved: None,
+                max_folder_size: None,
+                new_file_chunk_size: 1,
+                eviction: EvictionConfig {
+                    order: EvictionOrder::LargestFileFirst,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )?;
+
+        // The files are created apart, so that their creation dates differ
+        let mut file_ids = Vec::new();
+        for (size, group) in [(3, None), (6, None), (4, Some("keep"))] {
+            let file = make_dummy_file(size)?;
+            storage_manager.add_file_from_path_with_options(
+                &file.path,
+                NewFileOptions {
+                    group: group.map(String::from),
+                    ..Default::default()
+                },
+            )?;
+
+            let file = storage_manager
+                .iter_files()
+                .find(|file| file.header().size == size)
+                .unwrap();
+            file_ids.push(file.header().id);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        // Only the last parts of the largest file are evicted
+        storage_manager.delete_parts_until_max_size_reached(10)?;
+        assert_eq!(get_remaining_data_part_count(&storage_manager), 10);
+        assert_eq!(
+            storage_manager.pop_status_message(),
+            Some(StatusMessage::PartsEvicted(PartsEvicted {
+                file_id: file_ids[1],
+                ranges: vec![FilePartIdRangeInclusive::new(
+                    FilePartId::Part(3),
+                    FilePartId::Part(5)
+                )],
+            }))
+        );
+        assert!(storage_manager.pop_status_message().is_none());
+
+        // Whole files are evicted from the oldest, and the protected file is kept
+        storage_manager.set_eviction_policy(
+            EvictionConfig {
+                order: EvictionOrder::OldestFileFirst,
+                whole_files_only: true,
+                protected: vec![ProtectedClass::Group("keep".to_string())],
+            }
+            .build(),
+        );
+        storage_manager.delete_parts_until_max_size_reached(5)?;
+        assert!(storage_manager.get_file(file_ids[0]).is_none());
+        assert!(storage_manager.get_file(file_ids[1]).is_none());
+        for file_id in &file_ids[..2] {
+            assert_eq!(
+                storage_manager.pop_status_message(),
+                Some(StatusMessage::FileRemoved(FileRemoved {
+                    file_id: *file_id,
+                    reason: FileRemovedReason::Evicted,
+                }))
+            );
+        }
+
+        storage_manager.delete_parts_until_max_size_reached(0)?;
+        assert_eq!(get_remaining_data_part_count(&storage_manager), 4);
+        assert!(storage_manager.pop_status_message().is_none());
+
+        Ok(())
     }
 
-    // Then, sort by part number. Higher part numbers get higher precedence.
-    let a_part = match a.part_id {
-        FilePartId::Part(part) => part,
-        FilePartId::Header => 0,
-    };
-    let b_part = match b.part_id {
-        FilePartId::Part(part) => part,
-        FilePartId::Header => 0,
-    };
-    a_part.cmp(&b_part)
-}
+    #[test]
+    fn test_source_quotas() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
 
-#[allow(clippy::comparison_chain)]
-/// Same as the normal cmp_file_storage_part, but with the priority for part index reversed.
-pub fn cmp_file_storage_part_for_deletion(
-    a: &StorageFilePart,
-    b: &StorageFilePart,
-) -> std::cmp::Ordering {
-    // Sort by priority first. Higher priority gets higher precedence.
-    if a.priority > b.priority {
-        return std::cmp::Ordering::Greater;
-    } else if a.priority < b.priority {
-        return std::cmp::Ordering::Less;
+        let mut storage_manager = SendingStorageManager::new(
+            folder.path().clone(),
+            SendingStorageManagerConfig {
+                split_file_if_n_chunks_saved: None,
+                max_folder_size: Some(100),
+                source_quotas: HashMap::from([("camera".to_string(), 5)]),
+                new_file_chunk_size: 1,
+                ..Default::default()
+            },
+        )?;
+
+        let add_file = |storage_manager: &mut SendingStorageManager, size, source: &str| {
+            let file = make_dummy_file(size)?;
+            storage_manager.add_file_from_path_with_options(
+                &file.path,
+                NewFileOptions {
+                    source: Some(source.to_string()),
+                    ..Default::default()
+                },
+            )
+        };
+
+        add_file(&mut storage_manager, 3, "camera")?;
+        let camera_id = storage_manager.iter_files().next().unwrap().header().id;
+        add_file(&mut storage_manager, 4, "spectrometer")?;
+
+        // Only the files from the same source are evicted to stay within the quota
+        add_file(&mut storage_manager, 4, "camera")?;
+        assert_eq!(storage_manager.calc_source_data_size("camera"), 5);
+        assert_eq!(storage_manager.calc_source_data_size("spectrometer"), 4);
+        assert_eq!(
+            storage_manager.pop_status_message(),
+            Some(StatusMessage::PartsEvicted(PartsEvicted {
+                file_id: camera_id,
+                ranges: vec![FilePartIdRangeInclusive::new(
+                    FilePartId::Part(1),
+                    FilePartId::Part(2)
+                )],
+            }))
+        );
+
+        assert!(add_file(&mut storage_manager, 6, "camera").is_err());
+        assert_eq!(storage_manager.iter_files().count(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hot_tier() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let hot_folder = TempDirProvider::new_for_test().create()?;
+        let config = SendingStorageManagerConfig {
+            split_file_if_n_chunks_saved: None,
+            max_folder_size: None,
+            new_file_chunk_size: 1,
+            hot_tier: Some(HotTierConfig {
+                path: hot_folder.path().clone(),
+                max_size: 5,
+                min_priority: 1,
+            }),
+            ..Default::default()
+        };
+        let mut storage_manager =
+            SendingStorageManager::new(folder.path().clone(), config.clone())?;
+
+        let mut file_ids = Vec::new();
+        for (size, priority) in [(3, 0), (4, 5), (3, 10)] {
+            let file = make_dummy_file(size)?;
+            storage_manager.add_file_from_path_with_options(
+                &file.path,
+                NewFileOptions {
+                    priority: Some(priority),
+                    ..Default::default()
+                },
+            )?;
+
+            let file = storage_manager
+                .iter_files()
+                .find(|file| file.remaining_parts()[0].priority == priority)
+                .unwrap();
+            file_ids.push(file.header().id);
+        }
+
+        // The low priority file isn't staged, and the highest priority file took the place of
+        // the other one, as they don't both fit
+        let tiers = |storage_manager: &SendingStorageManager| {
+            file_ids
+                .iter()
+                .map(|file_id| storage_manager.file_tier(*file_id).unwrap())
+                .collect::<Vec<_>>()
+        };
+        use FilePartId::Part;
+        use StorageTier::{Cold, Hot};
+        assert_eq!(tiers(&storage_manager), [Cold, Cold, Hot]);
+        assert_eq!(storage_manager.calc_tier_data_size(Hot), 3);
+        assert!(hot_folder.path().join(file_ids[2].to_string()).is_dir());
+        assert!(folder.path().join(file_ids[2].to_string()).is_dir());
+        assert_eq!(storage_manager.calc_tier_data_size(Cold), 10);
+        let file = storage_manager.get_file(file_ids[2]).unwrap();
+        assert!(file.get_file_part(FilePartId::Part(2))?.is_some());
+
+        // Files move between the tiers as their priority changes, and the parts that were
+        // acknowledged while a file was staged stay acknowledged
+        ack(&mut storage_manager, file_ids[2], Part(2), Part(2))?;
+
+        // The copy in the storage folder keeps the acknowledged data until it's unstaged
+        assert_eq!(storage_manager.calc_tier_data_size(Hot), 2);
+        assert_eq!(storage_manager.calc_tier_data_size(Cold), 10);
+
+        storage_manager.process_control(ControlMessage::SetFilePriority(SetFilePriority {
+            file_id: file_ids[2],
+            priority: 0,
+        }))?;
+        assert_eq!(tiers(&storage_manager), [Cold, Hot, Cold]);
+        assert_eq!(storage_manager.calc_tier_data_size(Cold), 9);
+        assert!(!hot_folder.path().join(file_ids[2].to_string()).exists());
+
+        let remaining = |storage_manager: &SendingStorageManager, file_id| {
+            let file = storage_manager.get_file(file_id).unwrap();
+            file.remaining_parts().len()
+        };
+        let mut storage_manager =
+            SendingStorageManager::new(folder.path().clone(), config.clone())?;
+        assert_eq!(tiers(&storage_manager), [Cold, Hot, Cold]);
+        assert_eq!(storage_manager.iter_files().count(), 3);
+        assert_eq!(remaining(&storage_manager, file_ids[2]), 3);
+
+        // Losing the hot tier only loses the progress since the file was staged
+        ack(&mut storage_manager, file_ids[1], Part(0), Part(1))?;
+        assert_eq!(remaining(&storage_manager, file_ids[1]), 3);
+        drop(storage_manager);
+        std::fs::remove_dir_all(hot_folder.path())?;
+
+        let storage_manager = SendingStorageManager::new(folder.path().clone(), config)?;
+        assert_eq!(tiers(&storage_manager), [Cold, Hot, Cold]);
+        assert_eq!(remaining(&storage_manager, file_ids[1]), 5);
+        let file = storage_manager.get_file(file_ids[1]).unwrap();
+        assert!(file.get_file_part(Part(0))?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_tagged_commands() -> anyhow::Result<()> {
+        use crate::control::{ListTimeTaggedCommands, TimeTaggedCommand};
+
+        let folder = TempDirProvider::new_for_test().create()?;
+        let config = SendingStorageManagerConfig {
+            split_file_if_n_chunks_saved: None,
+            max_folder_size: None,
+            new_file_chunk_size: 1,
+            ..Default::default()
+        };
+
+        let mut storage_manager =
+            SendingStorageManager::new(folder.path().clone(), config.clone())?;
+
+        let file = make_dummy_file(5)?;
+        storage_manager.add_file_from_path(&file.path)?;
+        let file_id = storage_manager.iter_files().next().unwrap().header().id;
+
+        storage_manager.process_control(ControlMessage::TimeTagged(TimeTaggedCommand {
+            command_id: 7,
+            execute_at: 1000,
+            message: Box::new(ControlMessage::SetFilePriority(SetFilePriority {
+                file_id,
+                priority: 10,
+            })),
+        }))?;
+
+        // The command should survive a restart
+        drop(storage_manager);
+        let mut storage_manager = SendingStorageManager::new(folder.path().clone(), config)?;
+
+        storage_manager.process_control(ControlMessage::ListTimeTagged(ListTimeTaggedCommands))?;
+        let status = storage_manager.pop_status_message();
+        let Some(StatusMessage::TimeTaggedCommandList(list)) = status else {
+            panic!("Expected a time-tagged command list, got {:?}", status);
+        };
+        assert!(storage_manager.pop_status_message().is_none());
+        assert_eq!(list.commands.len(), 1);
+
+        storage_manager.execute_due_time_tagged_commands(999)?;
+        let file = storage_manager.get_file(file_id).unwrap();
+        assert!(file.remaining_parts().iter().all(|p| p.priority == 0));
+
+        storage_manager.execute_due_time_tagged_commands(1000)?;
+        let file = storage_manager.get_file(file_id).unwrap();
+        assert!(file.remaining_parts().iter().all(|p| p.priority == 10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_operations_by_filter() -> anyhow::Result<()> {
+        use crate::control::{DeleteFilesByFilter, SetPriorityByFilter};
+
+        let folder = TempDirProvider::new_for_test().create()?;
+
+        let mut storage_manager = SendingStorageManager::new(
+            folder.path().clone(),
+            SendingStorageManagerConfig {
+                split_file_if_n_chunks_saved: None,
+                max_folder_size: None,
+                new_file_chunk_size: 1,
+                ..Default::default()
+            },
+        )?;
+
+        for (size, source) in [(5, "camera"), (10, "camera"), (5, "telemetry")] {
+            let file = make_dummy_file(size)?;
+            storage_manager.add_file_from_path_with_options(
+                &file.path,
+                NewFileOptions {
+                    source: Some(source.to_string()),
+                    ..Default::default()
+                },
+            )?;
+        }
+
+        storage_manager.process_control(ControlMessage::SetPriorityByFilter(
+            SetPriorityByFilter {
+                filter: FileFilter {
+                    source: Some("camera".to_string()),
+                    ..Default::default()
+                },
+                priority: 3,
+            },
+        ))?;
+
+        for file in storage_manager.iter_files() {
+            let expected = if file.info().source.as_deref() == Some("camera") {
+                3
+            } else {
+                0
+            };
+            assert!(file
+                .remaining_parts()
+                .iter()
+                .all(|p| p.priority == expected));
+        }
+
+        storage_manager.process_control(ControlMessage::DeleteFilesByFilter(
+            DeleteFilesByFilter {
+                filter: FileFilter {
+                    name_glob: Some("data.*".to_string()),
+                    size_range: Some(0..=5),
+                    ..Default::default()
+                },
+            },
+        ))?;
+
+        let remaining = storage_manager.iter_files().collect::<Vec<_>>();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].header().size, 10);
+
+        // An empty filter would match every file
+        let result = storage_manager.process_control(ControlMessage::DeleteFilesByFilter(
+            DeleteFilesByFilter {
+                filter: FileFilter::default(),
+            },
+        ));
+        assert!(result.is_err());
+        assert_eq!(storage_manager.iter_files().count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_flight_parts_restored_after_timeout() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+
+        let mut storage_manager = SendingStorageManager::new(
+            folder.path().clone(),
+            SendingStorageManagerConfig {
+                split_file_if_n_chunks_saved: None,
+                max_folder_size: None,
+                new_file_chunk_size: 1,
+                in_flight_timeout: InFlightTimeout {
+                    sessions: Some(2),
+                    duration: None,
+                },
+                ..Default::default()
+            },
+        )?;
+
+        let file = make_dummy_file(3)?;
+        storage_manager.add_file_from_path(&file.path)?;
+        let file_id = storage_manager.iter_files().next().unwrap().header().id;
+
+        let part_priority = |storage_manager: &SendingStorageManager, part_id| {
+            let file = storage_manager.get_file(file_id).unwrap();
+            let part = file.remaining_parts().iter().find(|p| p.part == part_id);
+            part.unwrap().priority
+        };
+
+        storage_manager.begin_downlink_session();
+        storage_manager.confirm_file_sent(file_id, FilePartId::Part(0))?;
+        storage_manager.confirm_file_sent(file_id, FilePartId::Part(0))?;
+        storage_manager.confirm_file_sent(file_id, FilePartId::Part(1))?;
+        storage_manager.process_control(ControlMessage::ConfirmPart(ConfirmPart {
+            file_id,
+            part_range: FilePartIdRangeInclusive::new_single(FilePartId::Part(1)),
+        }))?;
+        storage_manager.end_downlink_session()?;
+
+        assert_eq!(part_priority(&storage_manager, FilePartId::Part(0)), -2);
+        assert!(storage_manager
+            .in_flight()
+            .is_in_flight(file_id, FilePartId::Part(0)));
+        assert!(!storage_manager
+            .in_flight()
+            .is_in_flight(file_id, FilePartId::Part(1)));
+
+        // Not timed out yet after one more session
+        storage_manager.begin_downlink_session();
+        storage_manager.restore_timed_out_in_flight_parts(0)?;
+        assert_eq!(part_priority(&storage_manager, FilePartId::Part(0)), -2);
+
+        storage_manager.begin_downlink_session();
+        storage_manager.restore_timed_out_in_flight_parts(0)?;
+        assert_eq!(part_priority(&storage_manager, FilePartId::Part(0)), 0);
+        assert!(!storage_manager
+            .in_flight()
+            .is_in_flight(file_id, FilePartId::Part(0)));
+
+        // A lowered priority that survives a crash mid-session can still be restored
+        storage_manager.confirm_file_sent(file_id, FilePartId::Part(2))?;
+        let config = storage_manager.config.clone();
+        drop(storage_manager);
+        let mut storage_manager = SendingStorageManager::new(folder.path().clone(), config)?;
+        assert_eq!(part_priority(&storage_manager, FilePartId::Part(2)), -1);
+        assert!(storage_manager
+            .in_flight()
+            .is_in_flight(file_id, FilePartId::Part(2)));
+
+        storage_manager.begin_downlink_session();
+        storage_manager.begin_downlink_session();
+        storage_manager.restore_timed_out_in_flight_parts(0)?;
+        assert_eq!(part_priority(&storage_manager, FilePartId::Part(2)), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_state_flush() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let config = SendingStorageManagerConfig {
+            split_file_if_n_chunks_saved: None,
+            max_folder_size: None,
+            new_file_chunk_size: 1,
+            state_flush: StateFlushPolicy::Batched {
+                interval: Duration::from_secs(3600),
+            },
+            ..Default::default()
+        };
+        let mut storage_manager =
+            SendingStorageManager::new(folder.path().clone(), config.clone())?;
+
+        let file = make_dummy_file(3)?;
+        storage_manager.add_file_from_path(&file.path)?;
+        let file_id = storage_manager.iter_files().next().unwrap().header().id;
+
+        let stored_priorities = || -> anyhow::Result<Vec<i16>> {
+            let storage_manager =
+                SendingStorageManager::new(folder.path().clone(), config.clone())?;
+            let file = storage_manager.get_file(file_id).unwrap();
+            Ok(file.remaining_parts().iter().map(|p| p.priority).collect())
+        };
+
+        storage_manager.begin_downlink_session();
+        storage_manager.confirm_file_sent(file_id, FilePartId::Part(0))?;
+        storage_manager.confirm_file_sent(file_id, FilePartId::Part(0))?;
+        storage_manager.confirm_file_sent(file_id, FilePartId::Part(1))?;
+        storage_manager.process_control(ControlMessage::ConfirmPart(ConfirmPart {
+            file_id,
+            part_range: FilePartIdRangeInclusive::new_single(FilePartId::Part(2)),
+        }))?;
+
+        // Acknowledgements are written right away, the decrements only once they're flushed
+        storage_manager.flush_state_if_due()?;
+        assert_eq!(stored_priorities()?, vec![0, 0, 0]);
+
+        storage_manager.end_downlink_session()?;
+        assert_eq!(stored_priorities()?, vec![0, -2, -1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expired_files_deleted() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+
+        let mut storage_manager = SendingStorageManager::new(
+            folder.path().clone(),
+            SendingStorageManagerConfig {
+                split_file_if_n_chunks_saved: None,
+                max_folder_size: None,
+                new_file_chunk_size: 1,
+                announce_expired_files: true,
+                ..Default::default()
+            },
+        )?;
+
+        let expiring = make_dummy_file(3)?;
+        storage_manager.add_file_from_path_with_options(
+            &expiring.path,
+            NewFileOptions {
+                ttl: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+        )?;
+        let kept = make_dummy_file(3)?;
+        storage_manager.add_file_from_path(&kept.path)?;
+
+        let expiring_file = storage_manager
+            .iter_files()
+            .find(|file| file.info().expires_at.is_some())
+            .unwrap();
+        let file_id = expiring_file.header().id;
+        let expires_at = expiring_file.info().expires_at.unwrap();
+        assert_eq!(expires_at, expiring_file.header().date + 60 * 1_000_000_000);
+
+        storage_manager.delete_expired_files(expires_at - 1)?;
+        assert_eq!(storage_manager.iter_files().count(), 2);
+        assert!(storage_manager.pop_status_message().is_none());
+
+        storage_manager.delete_expired_files(expires_at)?;
+        assert_eq!(storage_manager.iter_files().count(), 1);
+        assert!(storage_manager.get_file(file_id).is_none());
+        assert_eq!(
+            storage_manager.pop_status_message(),
+            Some(StatusMessage::FileRemoved(FileRemoved {
+                file_id,
+                reason: FileRemovedReason::Expired,
+            }))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let archive_folder = TempDirProvider::new_for_test().create()?;
+
+        let mut storage_manager = SendingStorageManager::new(
+            folder.path().clone(),
+            SendingStorageManagerConfig {
+                split_file_if_n_chunks_saved: None,
+                max_folder_size: None,
+                new_file_chunk_size: 3,
+                archive: Some(ArchiveConfig {
+                    path: archive_folder.path().clone(),
+                    max_size: Some(20),
+                    retention: Duration::from_secs(60),
+                }),
+                ..Default::default()
+            },
+        )?;
+
+        let data = (0..10).collect::<Vec<u8>>();
+        let add_file = |storage_manager: &mut SendingStorageManager| {
+            let file = make_dummy_file(0)?;
+            std::fs::write(&file.path, &data)?;
+            storage_manager.add_file_from_path(&file.path)?;
+
+            let file_id = storage_manager
+                .iter_files()
+                .map(|file| file.header().id)
+                .find(|file_id| storage_manager.archive().unwrap().get(*file_id).is_none())
+                .unwrap();
+            anyhow::Ok(file_id)
+        };
+
+        // The file is reassembled from its parts as they're acknowledged, in any order
+        let file_id = add_file(&mut storage_manager)?;
+        ack(
+            &mut storage_manager,
+            file_id,
+            FilePartId::Part(2),
+            FilePartId::Part(3),
+        )?;
+        assert!(storage_manager.archive().unwrap().get(file_id).is_none());
+        ack(
+            &mut storage_manager,
+            file_id,
+            FilePartId::Header,
+            FilePartId::Part(1),
+        )?;
+        assert!(storage_manager.get_file(file_id).is_none());
+
+        let archive = storage_manager.archive().unwrap();
+        let archived_at = archive.get(file_id).unwrap().archived_at;
+        assert_eq!(std::fs::read(archive.data_path(file_id))?, data);
+
+        // Files that are deleted before they're fully acknowledged aren't archived
+        let deleted_id = add_file(&mut storage_manager)?;
+        ack(
+            &mut storage_manager,
+            deleted_id,
+            FilePartId::Header,
+            FilePartId::Part(1),
+        )?;
+        storage_manager.process_control(ControlMessage::DeleteFile(DeleteFile {
+            file_id: deleted_id,
+        }))?;
+        assert!(storage_manager.archive().unwrap().get(deleted_id).is_none());
+
+        // The archive is kept across restarts
+        let config = storage_manager.config.clone();
+        drop(storage_manager);
+        let mut storage_manager = SendingStorageManager::new(folder.path().clone(), config)?;
+        assert_eq!(storage_manager.archive().unwrap().iter_files().count(), 1);
+
+        // Files that the ground lost are resent from the archive under the same id
+        storage_manager.process_control(ControlMessage::RequestFileResend(RequestFileResend {
+            file_id,
+        }))?;
+        let resent = storage_manager.get_file(file_id).unwrap();
+        assert_eq!(resent.remaining_parts().len(), 5);
+        ack(
+            &mut storage_manager,
+            file_id,
+            FilePartId::Header,
+            FilePartId::Part(3),
+        )?;
+        assert!(storage_manager.get_file(file_id).is_none());
+        assert_eq!(storage_manager.archive().unwrap().iter_files().count(), 1);
+
+        // Requeued files are sent again under a new id
+        storage_manager.process_control(ControlMessage::RequeueArchivedFile(
+            RequeueArchivedFile {
+                file_id,
+                priority: 7,
+            },
+        ))?;
+        let requeued = storage_manager.iter_files().next().unwrap();
+        assert_ne!(requeued.header().id, file_id);
+        assert_eq!(requeued.header().sha256, {
+            let archive = storage_manager.archive().unwrap();
+            archive.get(file_id).unwrap().header.sha256
+        });
+        assert!(requeued
+            .remaining_parts()
+            .iter()
+            .all(|part| part.priority == 7));
+        let Some(Chunk::Data(chunk)) = requeued.get_file_part(FilePartId::Part(3))? else {
+            panic!("Requeued file is missing its last part");
+        };
+        assert_eq!(chunk.data, vec![9]);
+
+        // Only the oldest archived files are kept within the maximum size
+        let requeued_id = requeued.header().id;
+        ack(
+            &mut storage_manager,
+            requeued_id,
+            FilePartId::Header,
+            FilePartId::Part(3),
+        )?;
+        let second_id = add_file(&mut storage_manager)?;
+        ack(
+            &mut storage_manager,
+            second_id,
+            FilePartId::Header,
+            FilePartId::Part(3),
+        )?;
+        let archive = storage_manager.archive().unwrap();
+        assert!(archive.get(file_id).is_none());
+        assert_eq!(archive.calc_total_size(), 20);
+
+        storage_manager.delete_expired_files(archived_at + 60 * 1_000_000_000 - 1)?;
+        assert_eq!(storage_manager.archive().unwrap().iter_files().count(), 2);
+        storage_manager.delete_expired_files(i64::MAX)?;
+        assert_eq!(storage_manager.archive().unwrap().iter_files().count(), 0);
+
+        // Files that failed verification on the ground have their header unconfirmed, and
+        // are completed in the archive and resent from it
+        let failed_id = add_file(&mut storage_manager)?;
+        ack(
+            &mut storage_manager,
+            failed_id,
+            FilePartId::Part(0),
+            FilePartId::Part(3),
+        )?;
+        storage_manager.process_control(ControlMessage::RequestFileResend(RequestFileResend {
+            file_id: failed_id,
+        }))?;
+        let resent = storage_manager.get_file(failed_id).unwrap();
+        assert_eq!(resent.remaining_parts().len(), 5);
+        let Some(Chunk::Data(chunk)) = resent.get_file_part(FilePartId::Part(3))? else {
+            panic!("Resent file is missing its last part");
+        };
+        assert_eq!(chunk.data, vec![9]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resend_without_archive() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut storage_manager = SendingStorageManager::new(
+            folder.path().clone(),
+            SendingStorageManagerConfig {
+                split_file_if_n_chunks_saved: None,
+                new_file_chunk_size: 3,
+                ..Default::default()
+            },
+        )?;
+
+        let file = make_dummy_file(10)?;
+        storage_manager.add_file_from_path(&file.path)?;
+        let file_id = storage_manager.iter_files().next().unwrap().header().id;
+
+        // The receiver doesn't confirm any parts of a file that failed verification, so all of
+        // them are sent again with their original priority
+        storage_manager.begin_downlink_session();
+        for part_id in [FilePartId::Header, FilePartId::Part(0), FilePartId::Part(3)] {
+            storage_manager.confirm_file_sent(file_id, part_id)?;
+        }
+        storage_manager.process_control(ControlMessage::RequestFileResend(RequestFileResend {
+            file_id,
+        }))?;
+        let resent = storage_manager.get_file(file_id).unwrap();
+        assert_eq!(resent.remaining_parts().len(), 5);
+        assert!(resent
+            .remaining_parts()
+            .iter()
+            .all(|part| part.priority == resent.remaining_parts()[0].priority));
+        assert!(storage_manager.pop_status_message().is_none());
+
+        // A file that's missing some of its parts can't be resent, so it's deleted and the
+        // ground is told
+        storage_manager.process_control(ControlMessage::ConfirmPart(ConfirmPart {
+            file_id,
+            part_range: FilePartIdRangeInclusive::new_single(FilePartId::Part(1)),
+        }))?;
+        storage_manager.process_control(ControlMessage::RequestFileResend(RequestFileResend {
+            file_id,
+        }))?;
+        assert!(storage_manager.get_file(file_id).is_none());
+        let removed = StatusMessage::FileRemoved(FileRemoved {
+            file_id,
+            reason: FileRemovedReason::NotResendable,
+        });
+        assert_eq!(storage_manager.pop_status_message(), Some(removed.clone()));
+
+        // So is a file that isn't stored anymore
+        storage_manager.process_control(ControlMessage::RequestFileResend(RequestFileResend {
+            file_id,
+        }))?;
+        assert_eq!(storage_manager.pop_status_message(), Some(removed));
+        assert!(storage_manager.pop_status_message().is_none());
+
+        Ok(())
     }
 
-    // Sort by header parts next. Headers always get highest priority.
-    let a_header = a.part_id == FilePartId::Header;
-    let b_header = b.part_id == FilePartId::Header;
-    if a_header && !b_header {
-        return std::cmp::Ordering::Greater;
-    } else if !a_header && b_header {
-        return std::cmp::Ordering::Less;
-    }
+    #[test]
+    fn test_catalog() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let catalog_folder = TempDirProvider::new_for_test().create()?;
+
+        let mut storage_manager = SendingStorageManager::new(
+            folder.path().clone(),
+            SendingStorageManagerConfig {
+                split_file_if_n_chunks_saved: None,
+                max_folder_size: None,
+                new_file_chunk_size: 3,
+                catalog: Some(CatalogConfig {
+                    path: catalog_folder.path().clone(),
+                    max_size: Some(25),
+                    retention: Duration::from_secs(3600),
+                }),
+                ..Default::default()
+            },
+        )?;
+
+        // Catalog-only files aren't queued, only their entry is sent
+        let data = (0..10).collect::<Vec<u8>>();
+        let file = make_dummy_file(0)?;
+        std::fs::write(&file.path, &data)?;
+        storage_manager.add_file_from_path_with_options(
+            &file.path,
+            NewFileOptions {
+                name: Some("camera/img_001.raw".to_string()),
+                metadata: BTreeMap::from([("exposure_ms".to_string(), "20".to_string())]),
+                group: Some("survey".to_string()),
+                catalog_only: true,
+                ..Default::default()
+            },
+        )?;
+        assert!(!file.path.exists());
+        assert_eq!(storage_manager.iter_files().count(), 0);
 
-    // Then, sort by part number. Lower part numbers get higher precedence. Headers are still highest.
-    let a_part = match a.part_id {
-        FilePartId::Part(part) => part,
-        FilePartId::Header => u32::MAX,
-    };
-    let b_part = match b.part_id {
-        FilePartId::Part(part) => part,
-        FilePartId::Header => u32::MAX,
-    };
-    b_part.cmp(&a_part)
-}
+        let Some(StatusMessage::CatalogEntries(announced)) = storage_manager.pop_status_message()
+        else {
+            panic!("Catalogued file wasn't announced");
+        };
+        let [entry] = announced.entries.as_slice() else {
+            panic!("Expected a single catalog entry");
+        };
+        assert_eq!(entry.name, "camera/img_001.raw");
+        assert_eq!(entry.size, 10);
+        assert_eq!(entry.metadata["exposure_ms"], "20");
+
+        // The catalog is kept across restarts, and can be listed again
+        let config = storage_manager.config.clone();
+        drop(storage_manager);
+        let mut storage_manager = SendingStorageManager::new(folder.path().clone(), config)?;
+        storage_manager.process_control(ControlMessage::ListCatalog(ListCatalog))?;
+        let Some(StatusMessage::CatalogEntries(listed)) = storage_manager.pop_status_message()
+        else {
+            panic!("Catalog wasn't listed");
+        };
+        assert_eq!(listed, announced);
 
-#[cfg(test)]
-mod tests {
-    use std::fs::File;
+        // Requested files are added with the requested priority and their original options
+        storage_manager.process_control(ControlMessage::RequestCatalogedFile(
+            RequestCatalogedFile {
+                entry_id: entry.entry_id,
+                priority: 5,
+            },
+        ))?;
+        let requested = storage_manager.iter_files().next().unwrap();
+        assert_eq!(requested.header().name, "camera/img_001.raw");
+        assert_eq!(requested.header().size, 10);
+        assert_eq!(requested.header().metadata, entry.metadata);
+        assert_eq!(requested.info().group.as_deref(), Some("survey"));
+        assert!(requested
+            .remaining_parts()
+            .iter()
+            .all(|part| part.priority == 5));
+
+        let catalog = storage_manager.catalog().unwrap();
+        assert!(catalog.get(entry.entry_id).is_none());
+        assert!(!catalog.data_path(entry.entry_id).exists());
+
+        // Announcements are coalesced, and the oldest files make room for new ones
+        let mut entry_ids = Vec::new();
+        for _ in 0..3 {
+            let file = make_dummy_file(10)?;
+            let options = NewFileOptions {
+                catalog_only: true,
+                ..Default::default()
+            };
+            entry_ids.push(storage_manager.catalog_file(&file.path, options)?);
+        }
+        let Some(StatusMessage::CatalogEntries(announced)) = storage_manager.pop_status_message()
+        else {
+            panic!("Catalogued files weren't announced");
+        };
+        let announced_ids = announced
+            .entries
+            .iter()
+            .map(|entry| entry.entry_id)
+            .collect::<Vec<_>>();
+        assert_eq!(announced_ids, entry_ids);
+        assert!(storage_manager.pop_status_message().is_none());
 
-    use super::*;
-    use crate::tempdir::{TempDir, TempDirProvider};
+        let catalog = storage_manager.catalog().unwrap();
+        assert!(catalog.get(entry_ids[0]).is_none());
+        assert_eq!(catalog.calc_total_size(), 20);
+        assert!(storage_manager
+            .catalog_file(make_dummy_file(26)?.path.clone(), Default::default())
+            .is_err());
 
-    struct DummyFile {
-        _folder: TempDir,
-        path: PathBuf,
+        // Files that weren't requested within the retention are removed
+        let now = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        storage_manager.delete_expired_files(now + 3600 * 1_000_000_000)?;
+        assert_eq!(storage_manager.catalog().unwrap().iter_files().count(), 0);
+
+        Ok(())
     }
 
-    fn make_dummy_file(size: u64) -> anyhow::Result<DummyFile> {
+    #[test]
+    fn test_file_metadata() -> anyhow::Result<()> {
         let folder = TempDirProvider::new_for_test().create()?;
-        let path = folder.path().join("data.bin");
+        let config = SendingStorageManagerConfig {
+            split_file_if_n_chunks_saved: None,
+            max_folder_size: None,
+            new_file_chunk_size: 1,
+            ..Default::default()
+        };
+        let mut storage_manager =
+            SendingStorageManager::new(folder.path().clone(), config.clone())?;
+
+        let metadata = BTreeMap::from([
+            ("instrument".to_string(), "cam-2".to_string()),
+            ("correlation_id".to_string(), "1234".to_string()),
+        ]);
+        let file = make_dummy_file(3)?;
+        storage_manager.add_file_from_path_with_options(
+            &file.path,
+            NewFileOptions {
+                name: Some("camera/2026-10-16/img_001.raw".to_string()),
+                metadata: metadata.clone(),
+                ..Default::default()
+            },
+        )?;
 
-        let file = File::create(&path)?;
-        file.set_len(size)?;
+        // Keys longer than 255 bytes can't be sent, so the file is rejected
+        let rejected = make_dummy_file(4)?;
+        let result = storage_manager.add_file_from_path_with_options(
+            &rejected.path,
+            NewFileOptions {
+                metadata: BTreeMap::from([("k".repeat(256), String::new())]),
+                ..Default::default()
+            },
+        );
+        assert!(result.is_err());
+        assert!(rejected.path.exists());
 
-        Ok(DummyFile {
-            _folder: folder,
-            path,
-        })
-    }
+        // The metadata is stored in the header, so it survives a restart
+        drop(storage_manager);
+        let storage_manager = SendingStorageManager::new(folder.path().clone(), config)?;
+        let files = storage_manager.iter_files().collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].header().name, "camera/2026-10-16/img_001.raw");
+        assert_eq!(files[0].header().metadata, metadata);
 
-    fn get_remaining_data_part_count(storage_manager: &SendingStorageManager) -> usize {
-        storage_manager
-            .iter_remaining_storage_file_parts()
-            .filter(|part| part.part_id != FilePartId::Header)
-            .count()
+        Ok(())
     }
 
     #[test]
-    fn test_shrinking_storage_data() -> anyhow::Result<()> {
+    fn test_new_file_options() -> anyhow::Result<()> {
         let folder = TempDirProvider::new_for_test().create()?;
-
         let mut storage_manager = SendingStorageManager::new(
             folder.path().clone(),
             SendingStorageManagerConfig {
-                split_file_if_n_chunks_saved: Some(5),
-                max_folder_size: Some(15),
+                split_file_if_n_chunks_saved: None,
+                max_folder_size: None,
                 new_file_chunk_size: 1,
+                ..Default::default()
             },
         )?;
 
-        let file = make_dummy_file(10)?;
-        storage_manager.add_file_from_path(&file.path)?;
+        let file = make_dummy_file(1000)?;
+        storage_manager.add_file_from_path_with_options(
+            &file.path,
+            NewFileOptions {
+                priority: Some(5),
+                chunk_size: Some(16),
+                compression: FileCompression::Zstd,
+                group: Some("capture-42".to_string()),
+                ..Default::default()
+            },
+        )?;
+        assert!(!file.path.exists());
+        assert_eq!(std::fs::read_dir(file.path.parent().unwrap())?.count(), 0);
+
+        let managed = storage_manager.iter_files().next().unwrap();
+        let header = managed.header().clone();
+        assert_eq!(header.name, "data.bin");
+        assert_eq!(header.compression, FileCompression::Zstd);
+        assert_eq!(header.file_part_size, 16);
+        assert!(header.size < 1000);
+        assert_eq!(managed.info().group.as_deref(), Some("capture-42"));
+        assert!(managed.remaining_parts().iter().all(|p| p.priority == 5));
+
+        // The stored data is the compressed file
+        let mut data = Vec::new();
+        for part in 0..header.part_count {
+            let chunk = storage_manager
+                .get_file(header.id)
+                .unwrap()
+                .get_file_part(FilePartId::Part(part))?;
+            let Some(Chunk::Data(chunk)) = chunk else {
+                panic!("Expected a data chunk");
+            };
+            data.extend(chunk.data);
+        }
+        assert_eq!(zstd::decode_all(&data[..])?, vec![0; 1000]);
+
+        let invalid = make_dummy_file(10)?;
+        let result = storage_manager.add_file_from_path_with_options(
+            &invalid.path,
+            NewFileOptions {
+                chunk_size: Some(0),
+                ..Default::default()
+            },
+        );
+        assert!(result.is_err());
+        assert!(invalid.path.exists());
 
-        assert_eq!(get_remaining_data_part_count(&storage_manager), 10);
+        Ok(())
+    }
 
-        let file = make_dummy_file(10)?;
-        storage_manager.add_file_from_path(&file.path)?;
+    #[test]
+    fn test_duplicate_files() -> anyhow::Result<()> {
+        for policy in [DuplicateFilePolicy::Drop, DuplicateFilePolicy::Merge] {
+            let folder = TempDirProvider::new_for_test().create()?;
+
+            let mut storage_manager = SendingStorageManager::new(
+                folder.path().clone(),
+                SendingStorageManagerConfig {
+                    split_file_if_n_chunks_saved: None,
+                    max_folder_size: None,
+                    new_file_chunk_size: 1,
+                    duplicate_policy: policy,
+                    ..Default::default()
+                },
+            )?;
+
+            let original = make_dummy_file(5)?;
+            storage_manager.add_file_from_path_with_options(
+                &original.path,
+                NewFileOptions {
+                    ttl: Some(Duration::from_secs(60)),
+                    ..Default::default()
+                },
+            )?;
+            let original_expiry = storage_manager
+                .iter_files()
+                .next()
+                .unwrap()
+                .info()
+                .expires_at
+                .unwrap();
+
+            // Same contents, so it's a duplicate
+            let duplicate = make_dummy_file(5)?;
+            storage_manager.add_file_from_path_with_options(
+                &duplicate.path,
+                NewFileOptions {
+                    source: Some("retry".to_string()),
+                    ttl: Some(Duration::from_secs(3600)),
+                    ..Default::default()
+                },
+            )?;
+            assert!(!duplicate.path.exists());
+
+            let files = storage_manager.iter_files().collect::<Vec<_>>();
+            assert_eq!(files.len(), 1);
+
+            let info = files[0].info();
+            if policy == DuplicateFilePolicy::Merge {
+                assert_eq!(info.source.as_deref(), Some("retry"));
+                assert!(info.expires_at.unwrap() > original_expiry);
+            } else {
+                assert_eq!(info.source, None);
+                assert_eq!(info.expires_at, Some(original_expiry));
+            }
+
+            // Different contents, so it's added separately
+            let different = make_dummy_file(6)?;
+            storage_manager.add_file_from_path(&different.path)?;
+            assert_eq!(storage_manager.iter_files().count(), 2);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupted_parts() -> anyhow::Result<()> {
+        use std::io::{Seek, Write};
+
+        use crate::file_sending::managed_sending_file::PartChecksumMismatch;
+
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut config = SendingStorageManagerConfig {
+            split_file_if_n_chunks_saved: None,
+            max_folder_size: None,
+            new_file_chunk_size: 1,
+            ..Default::default()
+        };
+
+        let mut storage_manager =
+            SendingStorageManager::new(folder.path().clone(), config.clone())?;
+        let dummy_file = make_dummy_file(4)?;
+        storage_manager.add_file_from_path(&dummy_file.path)?;
+        let file_id = storage_manager.iter_files().next().unwrap().header().id;
 
+        // Flip a byte of the stored data
+        let mut data_file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(folder.path().join(file_id.to_string()).join("data.bin"))?;
+        data_file.seek(std::io::SeekFrom::Start(2))?;
+        data_file.write_all(&[0xff])?;
+        drop(data_file);
+
+        let file = storage_manager.get_file(file_id).unwrap();
+        let err = file.get_file_part(FilePartId::Part(2)).unwrap_err();
         assert_eq!(
-            get_remaining_data_part_count(&storage_manager),
-            15 // Would be 20, but we expect it to shrink to 15 as that's the max
+            err.downcast_ref::<PartChecksumMismatch>(),
+            Some(&PartChecksumMismatch { file_id, part: 2 })
+        );
+        assert!(file.get_file_part(FilePartId::Part(1))?.is_some());
+
+        // Reported once, and dropped from the parts to send
+        storage_manager.scrub_next_file()?;
+        storage_manager.scrub_next_file()?;
+        assert_eq!(
+            storage_manager.pop_status_message(),
+            Some(StatusMessage::PartsCorrupted(PartsCorrupted {
+                file_id,
+                parts: vec![2],
+                quarantined: false,
+            }))
+        );
+        assert!(storage_manager.pop_status_message().is_none());
+        assert_eq!(get_remaining_data_part_count(&storage_manager), 3);
+        assert!(!folder.path().join(QUARANTINE_FOLDER).exists());
+        drop(storage_manager);
+
+        let corrupt_byte = |offset| {
+            let mut data_file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(folder.path().join(file_id.to_string()).join("data.bin"))?;
+            data_file.seek(std::io::SeekFrom::Start(offset))?;
+            data_file.write_all(&[0xff])?;
+            anyhow::Ok(())
+        };
+
+        // Quarantined parts are moved out of the storage, and survive a restart
+        corrupt_byte(1)?;
+        config.corrupt_part_action = CorruptPartAction::Quarantine { max_size: 1 };
+        let mut storage_manager =
+            SendingStorageManager::new(folder.path().clone(), config.clone())?;
+        storage_manager.scrub_next_file()?;
+        assert_eq!(
+            storage_manager.pop_status_message(),
+            Some(StatusMessage::PartsCorrupted(PartsCorrupted {
+                file_id,
+                parts: vec![1],
+                quarantined: true,
+            }))
+        );
+        assert_eq!(get_remaining_data_part_count(&storage_manager), 2);
+
+        let quarantine_path = folder
+            .path()
+            .join(QUARANTINE_FOLDER)
+            .join(format!("{}-1.bin", file_id));
+        assert_eq!(std::fs::read(quarantine_path)?, vec![0xff]);
+        drop(storage_manager);
+
+        // Once the quarantine is full, corrupted parts are only dropped
+        corrupt_byte(3)?;
+        let mut storage_manager =
+            SendingStorageManager::new(folder.path().clone(), config.clone())?;
+        assert_eq!(get_remaining_data_part_count(&storage_manager), 2);
+        storage_manager.scrub_next_file()?;
+        assert_eq!(
+            storage_manager.pop_status_message(),
+            Some(StatusMessage::PartsCorrupted(PartsCorrupted {
+                file_id,
+                parts: vec![3],
+                quarantined: false,
+            }))
+        );
+        assert_eq!(get_remaining_data_part_count(&storage_manager), 1);
+        assert_eq!(
+            std::fs::read_dir(folder.path().join(QUARANTINE_FOLDER))?.count(),
+            1
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_shrinking_data_lowest_priority() -> anyhow::Result<()> {
+    fn test_storage_backends() -> anyhow::Result<()> {
+        use crate::file_sending::storage_backend::{LogStructuredBackend, MemoryBackend};
+
+        let folder = TempDirProvider::new_for_test().create()?;
+        let log_path = folder.path().join("storage.log");
+        let config = SendingStorageManagerConfig {
+            split_file_if_n_chunks_saved: Some(1),
+            max_folder_size: None,
+            new_file_chunk_size: 1,
+            backend: StorageBackendConfig::LogStructured {
+                log_path: log_path.clone(),
+            },
+            ..Default::default()
+        };
+
+        let memory: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+        let log: Arc<dyn StorageBackend> = Arc::new(LogStructuredBackend::open(log_path)?);
+        for backend in [memory, log] {
+            let path = PathBuf::from("storage");
+            backend.create_dir_all(&path)?;
+
+            let mut storage_manager = SendingStorageManager::new_with_backend(
+                path.clone(),
+                config.clone(),
+                backend.clone(),
+            )?;
+            let dummy_file = make_dummy_file(5)?;
+            storage_manager.add_file_from_path(&dummy_file.path)?;
+            assert!(!dummy_file.path.exists());
+
+            let file_id = storage_manager.iter_files().next().unwrap().header().id;
+            storage_manager.process_control(ControlMessage::ConfirmPart(ConfirmPart {
+                file_id,
+                part_range: FilePartIdRangeInclusive::new_single(FilePartId::Part(1)),
+            }))?;
+            storage_manager
+                .get_file_mut(file_id)
+                .unwrap()
+                .trigger_file_split()?;
+
+            let storage_manager =
+                SendingStorageManager::new_with_backend(path, config.clone(), backend)?;
+            assert_eq!(get_remaining_data_part_count(&storage_manager), 4);
+            let file = storage_manager.get_file(file_id).unwrap();
+            assert!(file.get_file_part(FilePartId::Part(0))?.is_some());
+            assert!(file.get_file_part(FilePartId::Part(1))?.is_none());
+        }
+
+        // The configured log backend reads back the same log
+        let storage_manager = SendingStorageManager::new(PathBuf::from("storage"), config)?;
+        assert_eq!(get_remaining_data_part_count(&storage_manager), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hole_punching_fallback() -> anyhow::Result<()> {
+        use crate::file_sending::storage_backend::{LogStructuredBackend, MemoryBackend};
+
         let folder = TempDirProvider::new_for_test().create()?;
+        let config = SendingStorageManagerConfig {
+            split_file_if_n_chunks_saved: None,
+            max_folder_size: None,
+            new_file_chunk_size: 1,
+            punch_holes: true,
+            ..Default::default()
+        };
+
+        // The log can't punch holes, so the file is split instead
+        let memory: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+        let log: Arc<dyn StorageBackend> = Arc::new(LogStructuredBackend::open(
+            folder.path().join("storage.log"),
+        )?);
+        for (backend, punched) in [(memory, true), (log, false)] {
+            let path = PathBuf::from("storage");
+            backend.create_dir_all(&path)?;
+            let mut storage_manager = SendingStorageManager::new_with_backend(
+                path.clone(),
+                config.clone(),
+                backend.clone(),
+            )?;
+
+            let dummy_file = make_dummy_file(5)?;
+            storage_manager.add_file_from_path(&dummy_file.path)?;
+            let file_id = storage_manager.iter_files().next().unwrap().header().id;
+            storage_manager.process_control(ControlMessage::ConfirmPart(ConfirmPart {
+                file_id,
+                part_range: FilePartIdRangeInclusive::new_single(FilePartId::Part(1)),
+            }))?;
+
+            storage_manager.split_file(file_id)?;
+            let file = storage_manager.get_file(file_id).unwrap();
+            assert!(!file.is_contiguous());
+            assert_eq!(file.calc_remaining_data_size(), 4);
+            let data_path = path.join(file_id.to_string()).join("data.bin");
+            assert_eq!(backend.is_file(&data_path), punched);
+        }
+
+        Ok(())
+    }
 
+    #[test]
+    fn test_open_files() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
         let mut storage_manager = SendingStorageManager::new(
-            folder.path().clone(),
+            folder.path().join("storage"),
             SendingStorageManagerConfig {
                 split_file_if_n_chunks_saved: None,
-                max_folder_size: Some(15),
-                new_file_chunk_size: 1,
+                max_folder_size: None,
+                new_file_chunk_size: 4,
+                ..Default::default()
             },
         )?;
 
-        let file = make_dummy_file(5)?;
-        storage_manager.add_file_from_path(&file.path)?;
+        let source = make_dummy_file(0)?;
+        let file_id = storage_manager.add_open_file(
+            &source.path,
+            NewFileOptions {
+                name: Some("logs/payload.log".to_string()),
+                priority: Some(2),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(
+            storage_manager
+                .find_open_file(&source.path)
+                .unwrap()
+                .header()
+                .id,
+            file_id
+        );
+        assert!(storage_manager
+            .append_to_open_file(file_id, &[1; 6])
+            .is_err());
+        storage_manager.append_to_open_file(file_id, &[1; 8])?;
+        assert_eq!(get_remaining_data_part_count(&storage_manager), 2);
+        assert!(storage_manager
+            .iter_remaining_storage_file_parts()
+            .all(|part| part.priority == 2));
+
+        // The file is kept while it's open, even with all of its parts acknowledged
+        storage_manager.confirm_file_sent(file_id, FilePartId::Header)?;
+        storage_manager.process_control(ControlMessage::ConfirmPart(ConfirmPart {
+            file_id,
+            part_range: FilePartIdRangeInclusive::new(FilePartId::Header, FilePartId::Part(1)),
+        }))?;
+        assert!(storage_manager.get_file(file_id).is_some());
+
+        storage_manager.seal_open_file(file_id, &[2; 5], None)?;
+        assert!(storage_manager.find_open_file(&source.path).is_none());
+        let header = storage_manager.get_file(file_id).unwrap().header().clone();
+        assert!(!header.open);
+        assert_eq!((header.part_count, header.size), (4, 13));
+
+        // A late acknowledgement of the open header doesn't count
+        let ack_all = ControlMessage::ConfirmPart(ConfirmPart {
+            file_id,
+            part_range: FilePartIdRangeInclusive::new(FilePartId::Header, FilePartId::Part(3)),
+        });
+        storage_manager.process_control(ack_all.clone())?;
+        let file = storage_manager.get_file(file_id).unwrap();
+        assert_eq!(file.remaining_parts().len(), 1);
+        assert_eq!(file.remaining_parts()[0].part, FilePartId::Header);
 
-        let file_id = storage_manager.iter_files().next().unwrap().header().id;
+        storage_manager.confirm_file_sent(file_id, FilePartId::Header)?;
+        storage_manager.process_control(ack_all)?;
+        assert!(storage_manager.get_file(file_id).is_none());
 
-        // Decrease priority of all file parts in this file
-        let parts = storage_manager
-            .iter_remaining_storage_file_parts()
-            .collect::<Vec<_>>();
-        for part in parts {
-            if part.file_id == file_id {
-                storage_manager
-                    .confirm_file_sent(file_id, part.part_id)
-                    .unwrap();
+        Ok(())
+    }
+
+    /// Fails every storage change after the first `allowed_changes`, as if the power was cut
+    #[derive(Debug)]
+    struct CrashingBackend {
+        inner: Arc<dyn StorageBackend>,
+        allowed_changes: AtomicUsize,
+    }
+
+    impl CrashingBackend {
+        fn change(&self) -> io::Result<()> {
+            self.allowed_changes
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .map(|_| ())
+                .map_err(|_| io::Error::other("Injected crash"))
+        }
+    }
+
+    impl StorageBackend for CrashingBackend {
+        fn write_atomic(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+            self.change()?;
+            self.inner.write_atomic(path, data)
+        }
+
+        fn write_atomic_from(
+            &self,
+            path: &Path,
+            data: &mut dyn io::Read,
+            len: u64,
+        ) -> io::Result<()> {
+            self.change()?;
+            self.inner.write_atomic_from(path, data, len)
+        }
+
+        fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+            self.change()?;
+            self.inner.write_at(path, offset, data)
+        }
+
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            self.inner.read(path)
+        }
+
+        fn read_range(&self, path: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+            self.inner.read_range(path, offset, len)
+        }
+
+        fn file_len(&self, path: &Path) -> io::Result<u64> {
+            self.inner.file_len(path)
+        }
+
+        fn set_len(&self, path: &Path, len: u64) -> io::Result<()> {
+            self.change()?;
+            self.inner.set_len(path, len)
+        }
+
+        fn punch_hole(&self, path: &Path, offset: u64, len: u64) -> io::Result<()> {
+            self.change()?;
+            self.inner.punch_hole(path, offset, len)
+        }
+
+        fn is_file(&self, path: &Path) -> bool {
+            self.inner.is_file(path)
+        }
+
+        fn is_dir(&self, path: &Path) -> bool {
+            self.inner.is_dir(path)
+        }
+
+        fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+            self.change()?;
+            self.inner.create_dir_all(path)
+        }
+
+        fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+            self.inner.read_dir(path)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            self.change()?;
+            self.inner.remove_file(path)
+        }
+
+        fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+            self.change()?;
+            self.inner.remove_dir_all(path)
+        }
+
+        fn import_file(&self, source: &Path, path: &Path) -> io::Result<()> {
+            self.change()?;
+            self.inner.import_file(source, path)
+        }
+    }
+
+    /// Crash a change after each of its storage changes in turn, and check the storage that's
+    /// loaded afterwards. The journal is always the first change, so `check` is told whether
+    /// the change was journaled before the crash.
+    fn check_crash_consistency(
+        prepare: impl Fn(&mut SendingStorageManager) -> anyhow::Result<Uuid>,
+        change: impl Fn(&mut SendingStorageManager, Uuid) -> anyhow::Result<()>,
+        check: impl Fn(&SendingStorageManager, Uuid, bool),
+    ) -> anyhow::Result<()> {
+        check_crash_consistency_with_tiers(
+            false,
+            prepare,
+            change,
+            |storage_manager, file_id, _, changed| check(storage_manager, file_id, changed),
+        )
+    }
+
+    /// Like [`check_crash_consistency`], with a hot tier on the local filesystem if `hot_tier`
+    /// is set. Only the storage folder crashes.
+    fn check_crash_consistency_with_tiers(
+        hot_tier: bool,
+        prepare: impl Fn(&mut SendingStorageManager) -> anyhow::Result<Uuid>,
+        change: impl Fn(&mut SendingStorageManager, Uuid) -> anyhow::Result<()>,
+        check: impl Fn(&SendingStorageManager, Uuid, &Path, bool),
+    ) -> anyhow::Result<()> {
+        let mut config = SendingStorageManagerConfig {
+            split_file_if_n_chunks_saved: None,
+            max_folder_size: None,
+            new_file_chunk_size: 4,
+            ..Default::default()
+        };
+        let path = PathBuf::from("storage");
+
+        for allowed_changes in 0.. {
+            let hot_folder = TempDirProvider::new_for_test().create()?;
+            config.hot_tier = hot_tier.then(|| HotTierConfig {
+                path: hot_folder.path().clone(),
+                max_size: 1000,
+                min_priority: 1,
+            });
+
+            let inner: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+            inner.create_dir_all(&path)?;
+            let backend = Arc::new(CrashingBackend {
+                inner: inner.clone(),
+                allowed_changes: AtomicUsize::new(usize::MAX),
+            });
+
+            let mut storage_manager = SendingStorageManager::new_with_backend(
+                path.clone(),
+                config.clone(),
+                backend.clone(),
+            )?;
+            let file_id = prepare(&mut storage_manager)?;
+
+            backend
+                .allowed_changes
+                .store(allowed_changes, Ordering::SeqCst);
+            let crashed = change(&mut storage_manager, file_id).is_err();
+            drop(storage_manager);
+
+            let recovered =
+                SendingStorageManager::new_with_backend(path.clone(), config.clone(), inner)?;
+            assert!(recovered.journal.pending().is_none());
+            for file in recovered.iter_files() {
+                for part in file.remaining_parts() {
+                    assert!(file.get_file_part(part.part)?.is_some());
+                }
+            }
+            check(&recovered, file_id, hot_folder.path(), allowed_changes > 0);
+
+            if !crashed {
+                return Ok(());
             }
         }
 
-        // Add 2 more files, expecting the first file to still be present with 6 total parts
-        let file = make_dummy_file(5)?;
-        storage_manager.add_file_from_path(&file.path)?;
+        unreachable!()
+    }
 
-        let file = make_dummy_file(5)?;
-        storage_manager.add_file_from_path(&file.path)?;
+    fn add_test_file(storage_manager: &mut SendingStorageManager) -> anyhow::Result<Uuid> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let path = folder.path().join("data.bin");
+        std::fs::write(&path, (0..20).collect::<Vec<u8>>())?;
+        storage_manager.add_file_from_path(&path)?;
 
-        let old_file = storage_manager
-            .iter_files()
-            .find(|file| file.header().id == file_id)
-            .unwrap();
+        Ok(storage_manager.iter_files().next().unwrap().header().id)
+    }
 
-        assert_eq!(old_file.remaining_parts().len(), 6);
-        assert_eq!(get_remaining_data_part_count(&storage_manager), 15);
+    fn ack(
+        storage_manager: &mut SendingStorageManager,
+        file_id: Uuid,
+        from: FilePartId,
+        to: FilePartId,
+    ) -> anyhow::Result<()> {
+        storage_manager.process_control(ControlMessage::ConfirmPart(ConfirmPart {
+            file_id,
+            part_range: FilePartIdRangeInclusive::new(from, to),
+        }))
+    }
 
-        // Add another file, expect the first file should only have the header left
-        let file = make_dummy_file(5)?;
-        storage_manager.add_file_from_path(&file.path)?;
+    #[test]
+    fn test_crash_during_acknowledgement() -> anyhow::Result<()> {
+        check_crash_consistency(
+            add_test_file,
+            |storage_manager, file_id| {
+                ack(
+                    storage_manager,
+                    file_id,
+                    FilePartId::Part(3),
+                    FilePartId::Part(4),
+                )
+            },
+            |storage_manager, file_id, journaled| {
+                let file = storage_manager.get_file(file_id).unwrap();
+                assert_eq!(file.remaining_parts().len(), if journaled { 4 } else { 6 });
+            },
+        )?;
 
-        assert_eq!(get_remaining_data_part_count(&storage_manager), 15);
+        // Acknowledging a split file removes the parts' files
+        check_crash_consistency(
+            |storage_manager| {
+                let file_id = add_test_file(storage_manager)?;
+                storage_manager.split_file(file_id)?;
+                Ok(file_id)
+            },
+            |storage_manager, file_id| {
+                ack(
+                    storage_manager,
+                    file_id,
+                    FilePartId::Part(1),
+                    FilePartId::Part(2),
+                )
+            },
+            |storage_manager, file_id, journaled| {
+                let file = storage_manager.get_file(file_id).unwrap();
+                assert_eq!(file.remaining_parts().len(), if journaled { 4 } else { 6 });
+            },
+        )?;
 
-        let old_file = storage_manager
-            .iter_files()
-            .find(|file| file.header().id == file_id)
-            .unwrap();
+        // Acknowledging the last parts deletes the file
+        check_crash_consistency(
+            add_test_file,
+            |storage_manager, file_id| {
+                ack(
+                    storage_manager,
+                    file_id,
+                    FilePartId::Header,
+                    FilePartId::Part(4),
+                )
+            },
+            |storage_manager, file_id, journaled| {
+                assert_eq!(storage_manager.get_file(file_id).is_none(), journaled);
+            },
+        )
+    }
 
-        assert_eq!(old_file.remaining_parts().len(), 1);
-        assert_eq!(old_file.remaining_parts()[0].part, FilePartId::Header);
+    #[test]
+    fn test_crash_during_split() -> anyhow::Result<()> {
+        check_crash_consistency(
+            |storage_manager| {
+                let file_id = add_test_file(storage_manager)?;
+                ack(
+                    storage_manager,
+                    file_id,
+                    FilePartId::Part(1),
+                    FilePartId::Part(1),
+                )?;
+                Ok(file_id)
+            },
+            |storage_manager, file_id| storage_manager.split_file(file_id),
+            |storage_manager, file_id, journaled| {
+                let file = storage_manager.get_file(file_id).unwrap();
+                assert_eq!(file.remaining_parts().len(), 5);
+                if journaled {
+                    assert!(!file.is_contiguous());
+                }
+            },
+        )
+    }
 
-        Ok(())
+    #[test]
+    fn test_crash_during_delete() -> anyhow::Result<()> {
+        check_crash_consistency(
+            add_test_file,
+            |storage_manager, file_id| {
+                storage_manager.process_control(ControlMessage::DeleteFile(DeleteFile { file_id }))
+            },
+            |storage_manager, file_id, journaled| {
+                assert_eq!(storage_manager.get_file(file_id).is_none(), journaled);
+            },
+        )
+    }
+
+    #[test]
+    fn test_crash_during_add() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let source = folder.path().join("data.bin");
+
+        check_crash_consistency(
+            |_| Ok(Uuid::nil()),
+            |storage_manager, _| {
+                std::fs::write(&source, (0..20).collect::<Vec<u8>>())?;
+                storage_manager.add_file_from_path_with_options(
+                    &source,
+                    NewFileOptions {
+                        priority: Some(3),
+                        ..Default::default()
+                    },
+                )
+            },
+            |storage_manager, _, _| {
+                // Either the file was added completely, or the source is left to add it again
+                match storage_manager.iter_files().next() {
+                    Some(file) => {
+                        assert!(!source.exists());
+                        assert_eq!(file.remaining_parts().len(), 6);
+                        assert!(file.remaining_parts().iter().all(|p| p.priority == 3));
+                    }
+                    None => assert!(source.exists()),
+                }
+            },
+        )
+    }
+
+    #[test]
+    fn test_crash_during_move() -> anyhow::Result<()> {
+        check_crash_consistency_with_tiers(
+            true,
+            |storage_manager| {
+                let file_id = add_test_file(storage_manager)?;
+                storage_manager.process_control(ControlMessage::SetFilePriority(
+                    SetFilePriority {
+                        file_id,
+                        priority: 5,
+                    },
+                ))?;
+                assert_eq!(storage_manager.file_tier(file_id), Some(StorageTier::Hot));
+                Ok(file_id)
+            },
+            |storage_manager, file_id| {
+                storage_manager.process_control(ControlMessage::SetFilePriority(SetFilePriority {
+                    file_id,
+                    priority: 0,
+                }))
+            },
+            |storage_manager, file_id, hot_path, _| {
+                // The move is either rolled back and done again on startup, or finished
+                assert_eq!(storage_manager.file_tier(file_id), Some(StorageTier::Cold));
+                assert!(!hot_path.join(file_id.to_string()).exists());
+                let file = storage_manager.get_file(file_id).unwrap();
+                assert!(file.remaining_parts().iter().all(|p| p.priority == 0));
+            },
+        )
     }
 }
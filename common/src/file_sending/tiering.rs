@@ -0,0 +1,72 @@
+use std::{cmp::Reverse, collections::HashSet, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::managed_sending_file::ManagedSendingFile;
+
+/// The storage that a managed file is sent from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StorageTier {
+    /// The storage folder, e.g. on a large but slow flash. New files are added here.
+    Cold,
+
+    /// A small but fast folder, e.g. on a RAM disk, that a copy of the files which are sent
+    /// next is staged in
+    Hot,
+}
+
+/// A hot storage tier next to the storage folder. Files with the highest priority parts are
+/// copied into it, as long as they fit, and sent from there. Once other files take precedence,
+/// their state is written back into the storage folder and the copy is removed.
+///
+/// The storage folder keeps every file, so losing a RAM disk on power loss only loses the
+/// progress since the files were staged, and those parts are sent again.
+#[derive(Debug, Clone)]
+pub struct HotTierConfig {
+    /// The folder of the hot tier on the local filesystem
+    pub path: PathBuf,
+
+    /// Maximum size of the file data in the hot tier in bytes
+    pub max_size: u64,
+
+    /// Only files with a remaining part of at least this priority are staged
+    pub min_priority: i16,
+}
+
+/// The highest priority of the parts of a file that are left to send
+fn top_priority(file: &ManagedSendingFile) -> Option<i16> {
+    file.remaining_parts()
+        .iter()
+        .map(|part| part.priority)
+        .max()
+}
+
+/// Pick the files that should be in the hot tier: the ones with the highest priority parts
+/// first, and the oldest first for the same priority, skipping files that don't fit. Open files
+/// are never staged, as they're still growing in the storage folder.
+pub fn select_hot_files<'a>(
+    files: impl Iterator<Item = &'a ManagedSendingFile>,
+    config: &HotTierConfig,
+) -> HashSet<Uuid> {
+    let mut candidates = files
+        .filter(|file| !file.header().open)
+        .filter_map(|file| {
+            let priority = top_priority(file).filter(|p| *p >= config.min_priority)?;
+            Some((priority, file))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|(priority, file)| (Reverse(*priority), file.header().date));
+
+    let mut free_size = config.max_size;
+    let mut selected = HashSet::new();
+    for (_, file) in candidates {
+        let size = file.calc_remaining_data_size();
+        if size <= free_size {
+            free_size -= size;
+            selected.insert(file.header().id);
+        }
+    }
+
+    selected
+}
@@ -0,0 +1,182 @@
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+
+use crate::{
+    binary_serialize::BinarySerialize,
+    control::{ControlMessage, TimeTaggedCommand},
+    status::TimeTaggedCommandList,
+};
+
+use super::{managed_sending_file::write_file_atomic, storage_backend::StorageBackend};
+
+/// Persistent store of the time-tagged commands that are waiting to be executed. The whole
+/// list is stored in a single file, which is rewritten atomically on every change. The list
+/// is expected to stay small, as it's filled from the uplink.
+pub struct TimeTaggedCommandStore {
+    backend: Arc<dyn StorageBackend>,
+    path: PathBuf,
+    commands: BTreeMap<u32, TimeTaggedCommand>,
+}
+
+impl TimeTaggedCommandStore {
+    pub fn load_or_create(
+        backend: Arc<dyn StorageBackend>,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+
+        let mut commands = BTreeMap::new();
+        if backend.is_file(&path) {
+            let data = backend.read(&path)?;
+            let list = TimeTaggedCommandList::deserialize_from_stream(&mut &data[..])?;
+
+            for command in list.commands {
+                commands.insert(command.command_id, command);
+            }
+        }
+
+        Ok(Self {
+            backend,
+            path,
+            commands,
+        })
+    }
+
+    /// Add a command to the store. If a command with the same id is already pending, it gets replaced.
+    pub fn add(&mut self, command: TimeTaggedCommand) -> anyhow::Result<()> {
+        if self.commands.len() >= TimeTaggedCommandList::MAX_COMMANDS
+            && !self.commands.contains_key(&command.command_id)
+        {
+            anyhow::bail!(
+                "Too many pending time-tagged commands, the maximum is {}",
+                TimeTaggedCommandList::MAX_COMMANDS
+            );
+        }
+
+        let previous = self.commands.insert(command.command_id, command);
+        if let Some(previous) = previous {
+            tracing::warn!(
+                "Replaced pending time-tagged command {}",
+                previous.command_id
+            );
+        }
+
+        self.save()
+    }
+
+    /// Cancel a pending command. Returns false if the command wasn't pending.
+    pub fn cancel(&mut self, command_id: u32) -> anyhow::Result<bool> {
+        if self.commands.remove(&command_id).is_none() {
+            return Ok(false);
+        }
+
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Remove and return all the commands that should be executed at `now` (nanoseconds since the
+    /// unix epoch), in the order of their execution time.
+    pub fn take_due(&mut self, now: i64) -> anyhow::Result<Vec<ControlMessage>> {
+        let mut due = self
+            .commands
+            .values()
+            .filter(|command| command.execute_at <= now)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if due.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        due.sort_by_key(|command| (command.execute_at, command.command_id));
+        for command in due.iter() {
+            self.commands.remove(&command.command_id);
+        }
+
+        // The commands are removed before being executed, so a crash may skip them but never
+        // executes them twice.
+        self.save()?;
+
+        Ok(due.into_iter().map(|command| *command.message).collect())
+    }
+
+    pub fn list(&self) -> TimeTaggedCommandList {
+        let mut commands = self.commands.values().cloned().collect::<Vec<_>>();
+        commands.sort_by_key(|command| (command.execute_at, command.command_id));
+
+        TimeTaggedCommandList { commands }
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let list = self.list();
+        write_file_atomic(&*self.backend, &self.path, |file| {
+            Ok(list.serialize_to_stream(file)?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        control::DeleteFile, file_sending::storage_backend::PosixBackend, tempdir::TempDirProvider,
+    };
+
+    fn make_command(command_id: u32, execute_at: i64) -> TimeTaggedCommand {
+        TimeTaggedCommand {
+            command_id,
+            execute_at,
+            message: Box::new(ControlMessage::DeleteFile(DeleteFile {
+                file_id: Uuid::new_v4(),
+            })),
+        }
+    }
+
+    #[test]
+    fn test_persisting_commands() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let path = folder.path().join("time_tagged.bin");
+
+        let mut store = TimeTaggedCommandStore::load_or_create(Arc::new(PosixBackend), &path)?;
+        store.add(make_command(1, 300))?;
+        store.add(make_command(2, 100))?;
+        store.add(make_command(3, 200))?;
+        assert!(store.cancel(3)?);
+        assert!(!store.cancel(3)?);
+
+        let store = TimeTaggedCommandStore::load_or_create(Arc::new(PosixBackend), &path)?;
+        let ids = store
+            .list()
+            .commands
+            .iter()
+            .map(|c| c.command_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![2, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_taking_due_commands() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let path = folder.path().join("time_tagged.bin");
+
+        let mut store = TimeTaggedCommandStore::load_or_create(Arc::new(PosixBackend), &path)?;
+        let first = make_command(1, 100);
+        let second = make_command(2, 50);
+        store.add(first.clone())?;
+        store.add(second.clone())?;
+        store.add(make_command(3, 1000))?;
+
+        assert!(store.take_due(10)?.is_empty());
+
+        let due = store.take_due(100)?;
+        assert_eq!(due, vec![*second.message, *first.message]);
+
+        let store = TimeTaggedCommandStore::load_or_create(Arc::new(PosixBackend), &path)?;
+        assert_eq!(store.list().commands.len(), 1);
+
+        Ok(())
+    }
+}
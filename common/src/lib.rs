@@ -1,7 +1,9 @@
 pub mod binary_serialize;
+pub mod bundle;
 pub mod chunks;
 pub mod control;
 pub mod file_part_id;
+pub mod status;
 pub mod substream;
 pub mod tempdir;
 pub mod transport_packet;
@@ -0,0 +1,897 @@
+use std::collections::BTreeMap;
+
+use num_derive::{FromPrimitive, ToPrimitive};
+use uuid::Uuid;
+
+use crate::{
+    binary_serialize::BinarySerialize,
+    chunks::{is_valid_metadata, metadata_length, read_metadata_fields, write_metadata},
+    control::TimeTaggedCommand,
+    file_part_id::FilePartIdRangeInclusive,
+    transport_packet::TransportPacketInner,
+    validity::ValidityCheck,
+};
+
+/// Status messages are sent from the sender to the ground alongside the file chunks, to
+/// report on the state of the sender.
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StatusMessage {
+    TimeTaggedCommandList(TimeTaggedCommandList),
+    Heartbeat(Heartbeat),
+    FileRemoved(FileRemoved),
+    PartsCorrupted(PartsCorrupted),
+    PartsEvicted(PartsEvicted),
+    CatalogEntries(CatalogEntries),
+}
+
+impl StatusMessage {
+    /// The maximum serialized length of a status message, so that it fits into a transport
+    /// packet together with its type
+    pub const MAX_LEN: u32 = TransportPacketInner::MAX_DATA_LEN as u32 - 1;
+}
+
+/// Split the items of a list message into pages of at most `max_count` items, that each
+/// serialize to at most `max_len` bytes together with the count of the page. An item that's
+/// too large on its own gets a page of its own.
+pub(crate) fn split_into_pages<T: BinarySerialize>(
+    items: Vec<T>,
+    max_count: usize,
+    max_len: u32,
+) -> Vec<Vec<T>> {
+    const COUNT_LEN: u64 = 2;
+
+    let mut pages = Vec::new();
+    let mut page = Vec::new();
+    let mut page_len = COUNT_LEN;
+    for item in items {
+        let item_len = item.length_when_serialized() as u64;
+        if !page.is_empty() && (page.len() >= max_count || page_len + item_len > max_len as u64) {
+            pages.push(std::mem::take(&mut page));
+            page_len = COUNT_LEN;
+        }
+
+        page_len += item_len;
+        page.push(item);
+    }
+    if !page.is_empty() {
+        pages.push(page);
+    }
+
+    pages
+}
+
+impl std::fmt::Display for StatusMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatusMessage::TimeTaggedCommandList(msg) => write!(
+                f,
+                "StatusMessage::TimeTaggedCommandList {{ commands: {} }}",
+                msg.commands.len(),
+            ),
+            StatusMessage::Heartbeat(msg) => write!(
+                f,
+                "StatusMessage::Heartbeat {{ uptime_secs: {}, runner_state: {:?}, managed_files: {}, stored_bytes: {}, pending_files: {} }}",
+                msg.uptime_secs,
+                msg.runner_state,
+                msg.managed_file_count,
+                msg.stored_bytes,
+                msg.pending_file_count,
+            ),
+            StatusMessage::FileRemoved(msg) => write!(
+                f,
+                "StatusMessage::FileRemoved {{ file_id: {}, reason: {:?} }}",
+                msg.file_id, msg.reason,
+            ),
+            StatusMessage::PartsCorrupted(msg) => write!(
+                f,
+                "StatusMessage::PartsCorrupted {{ file_id: {}, parts: {:?}, quarantined: {} }}",
+                msg.file_id, msg.parts, msg.quarantined,
+            ),
+            StatusMessage::PartsEvicted(msg) => write!(
+                f,
+                "StatusMessage::PartsEvicted {{ file_id: {}, ranges: {} }}",
+                msg.file_id,
+                msg.ranges.len(),
+            ),
+            StatusMessage::CatalogEntries(msg) => write!(
+                f,
+                "StatusMessage::CatalogEntries {{ entries: {} }}",
+                msg.entries.len(),
+            ),
+        }
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The list of time-tagged commands that are pending on the sender
+pub struct TimeTaggedCommandList {
+    pub commands: Vec<TimeTaggedCommand>,
+}
+
+impl TimeTaggedCommandList {
+    pub const MAX_COMMANDS: usize = u16::MAX as usize;
+
+    /// Split the list into pages that each fit into a status message. An empty list is kept
+    /// as a single empty page.
+    pub fn into_pages(self) -> Vec<TimeTaggedCommandList> {
+        let pages = split_into_pages(self.commands, Self::MAX_COMMANDS, StatusMessage::MAX_LEN);
+        if pages.is_empty() {
+            return vec![TimeTaggedCommandList {
+                commands: Vec::new(),
+            }];
+        }
+
+        pages
+            .into_iter()
+            .map(|commands| TimeTaggedCommandList { commands })
+            .collect()
+    }
+}
+
+impl BinarySerialize for TimeTaggedCommandList {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        let count = self.commands.len().min(Self::MAX_COMMANDS) as u16;
+        writer.write_all(&count.to_le_bytes())?;
+
+        for command in self.commands.iter().take(count as usize) {
+            command.serialize_to_stream(writer)?;
+        }
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        2 // count
+        + self
+            .commands
+            .iter()
+            .take(Self::MAX_COMMANDS)
+            .map(|c| c.length_when_serialized())
+            .sum::<u32>()
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut count_bytes = [0u8; 2];
+        reader.read_exact(&mut count_bytes)?;
+        let count = u16::from_le_bytes(count_bytes);
+
+        let mut commands = Vec::new();
+        for _ in 0..count {
+            commands.push(TimeTaggedCommand::deserialize_from_stream(reader)?);
+        }
+
+        Ok(TimeTaggedCommandList { commands })
+    }
+}
+
+impl ValidityCheck for TimeTaggedCommandList {
+    fn is_valid(&self) -> bool {
+        self.commands.len() <= Self::MAX_COMMANDS && self.commands.iter().all(|c| c.is_valid())
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
+pub enum RunnerState {
+    Waiting = 0,
+    InSession = 1,
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Periodic housekeeping packet with the sender's health, so that operators can confirm that
+/// the service is alive even when no file data is flowing.
+pub struct Heartbeat {
+    pub uptime_secs: u64,
+    /// Whether the heartbeat was made during a downlink session, or while waiting for one
+    pub runner_state: RunnerState,
+    pub managed_file_count: u32,
+    /// Bytes of file data stored in the ready folder
+    pub stored_bytes: u64,
+    /// Files waiting in the pending folder to be added to the ready folder
+    pub pending_file_count: u32,
+    /// When the last control message was received, in nanoseconds since the unix epoch
+    pub last_control_time: Option<i64>,
+    pub control_error_count: u32,
+    pub add_file_error_count: u32,
+    pub downlink_error_count: u32,
+}
+
+impl BinarySerialize for Heartbeat {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        let runner_state = num_traits::ToPrimitive::to_u8(&self.runner_state).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid runner state")
+        })?;
+
+        writer.write_all(&self.uptime_secs.to_le_bytes())?;
+        writer.write_all(&[runner_state])?;
+        writer.write_all(&self.managed_file_count.to_le_bytes())?;
+        writer.write_all(&self.stored_bytes.to_le_bytes())?;
+        writer.write_all(&self.pending_file_count.to_le_bytes())?;
+
+        match self.last_control_time {
+            Some(time) => {
+                writer.write_all(&[1])?;
+                writer.write_all(&time.to_le_bytes())?;
+            }
+            None => {
+                writer.write_all(&[0])?;
+                writer.write_all(&0i64.to_le_bytes())?;
+            }
+        }
+
+        writer.write_all(&self.control_error_count.to_le_bytes())?;
+        writer.write_all(&self.add_file_error_count.to_le_bytes())?;
+        writer.write_all(&self.downlink_error_count.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        8 // uptime_secs
+        + 1 // runner_state
+        + 4 // managed_file_count
+        + 8 // stored_bytes
+        + 4 // pending_file_count
+        + 1 + 8 // last_control_time
+        + 4 // control_error_count
+        + 4 // add_file_error_count
+        + 4 // downlink_error_count
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut u64_bytes = [0u8; 8];
+        let mut u32_bytes = [0u8; 4];
+        let mut u8_bytes = [0u8; 1];
+
+        reader.read_exact(&mut u64_bytes)?;
+        let uptime_secs = u64::from_le_bytes(u64_bytes);
+
+        reader.read_exact(&mut u8_bytes)?;
+        let runner_state = num_traits::FromPrimitive::from_u8(u8_bytes[0]).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid runner state")
+        })?;
+
+        reader.read_exact(&mut u32_bytes)?;
+        let managed_file_count = u32::from_le_bytes(u32_bytes);
+
+        reader.read_exact(&mut u64_bytes)?;
+        let stored_bytes = u64::from_le_bytes(u64_bytes);
+
+        reader.read_exact(&mut u32_bytes)?;
+        let pending_file_count = u32::from_le_bytes(u32_bytes);
+
+        reader.read_exact(&mut u8_bytes)?;
+        let has_last_control_time = u8_bytes[0] != 0;
+        reader.read_exact(&mut u64_bytes)?;
+        let last_control_time = if has_last_control_time {
+            Some(i64::from_le_bytes(u64_bytes))
+        } else {
+            None
+        };
+
+        reader.read_exact(&mut u32_bytes)?;
+        let control_error_count = u32::from_le_bytes(u32_bytes);
+
+        reader.read_exact(&mut u32_bytes)?;
+        let add_file_error_count = u32::from_le_bytes(u32_bytes);
+
+        reader.read_exact(&mut u32_bytes)?;
+        let downlink_error_count = u32::from_le_bytes(u32_bytes);
+
+        Ok(Heartbeat {
+            uptime_secs,
+            runner_state,
+            managed_file_count,
+            stored_bytes,
+            pending_file_count,
+            last_control_time,
+            control_error_count,
+            add_file_error_count,
+            downlink_error_count,
+        })
+    }
+}
+
+impl ValidityCheck for Heartbeat {
+    fn is_valid(&self) -> bool {
+        true
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
+pub enum FileRemovedReason {
+    /// The file reached its time-to-live before being fully acknowledged
+    Expired = 0,
+
+    /// The file was evicted because the storage was over its maximum size
+    Evicted = 1,
+
+    /// The ground requested a resend of the file, but some of its parts were already freed
+    NotResendable = 2,
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Announces that the sender removed a file without it being fully acknowledged
+pub struct FileRemoved {
+    pub file_id: Uuid,
+    pub reason: FileRemovedReason,
+}
+
+impl BinarySerialize for FileRemoved {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        let reason = num_traits::ToPrimitive::to_u8(&self.reason).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid removal reason")
+        })?;
+
+        writer.write_all(self.file_id.as_bytes())?;
+        writer.write_all(&[reason])?;
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        16 // file_id
+        + 1 // reason
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut file_id_bytes = [0u8; 16];
+        reader.read_exact(&mut file_id_bytes)?;
+        let file_id = Uuid::from_bytes(file_id_bytes);
+
+        let mut reason_bytes = [0u8; 1];
+        reader.read_exact(&mut reason_bytes)?;
+        let reason = num_traits::FromPrimitive::from_u8(reason_bytes[0]).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid removal reason")
+        })?;
+
+        Ok(FileRemoved { file_id, reason })
+    }
+}
+
+impl ValidityCheck for FileRemoved {
+    fn is_valid(&self) -> bool {
+        true
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Reports stored parts of a file that failed their checksum on the sender. The parts are
+/// dropped from the storage, so they're never sent.
+pub struct PartsCorrupted {
+    pub file_id: Uuid,
+    pub parts: Vec<u32>,
+    /// Whether a copy of the parts was kept in the sender's quarantine folder
+    pub quarantined: bool,
+}
+
+impl PartsCorrupted {
+    pub const MAX_PARTS: usize = u16::MAX as usize;
+}
+
+impl BinarySerialize for PartsCorrupted {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        let count = self.parts.len().min(Self::MAX_PARTS) as u16;
+
+        writer.write_all(self.file_id.as_bytes())?;
+        writer.write_all(&[self.quarantined as u8])?;
+        writer.write_all(&count.to_le_bytes())?;
+        for part in self.parts.iter().take(count as usize) {
+            writer.write_all(&part.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        let count = self.parts.len().min(Self::MAX_PARTS) as u32;
+
+        16 // file_id
+        + 1 // quarantined
+        + 2 // count
+        + count * 4 // parts
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut file_id_bytes = [0u8; 16];
+        reader.read_exact(&mut file_id_bytes)?;
+        let file_id = Uuid::from_bytes(file_id_bytes);
+
+        let mut quarantined_bytes = [0u8; 1];
+        reader.read_exact(&mut quarantined_bytes)?;
+        let quarantined = quarantined_bytes[0] != 0;
+
+        let mut count_bytes = [0u8; 2];
+        reader.read_exact(&mut count_bytes)?;
+        let count = u16::from_le_bytes(count_bytes);
+
+        let mut parts = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let mut part_bytes = [0u8; 4];
+            reader.read_exact(&mut part_bytes)?;
+            parts.push(u32::from_le_bytes(part_bytes));
+        }
+
+        Ok(PartsCorrupted {
+            file_id,
+            parts,
+            quarantined,
+        })
+    }
+}
+
+impl ValidityCheck for PartsCorrupted {
+    fn is_valid(&self) -> bool {
+        self.parts.len() <= Self::MAX_PARTS
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Reports parts of a file that the sender evicted without them being acknowledged, because the
+/// storage was over its maximum size. Files that were evicted completely are announced with
+/// [`FileRemoved`] instead.
+pub struct PartsEvicted {
+    pub file_id: Uuid,
+    pub ranges: Vec<FilePartIdRangeInclusive>,
+}
+
+impl PartsEvicted {
+    pub const MAX_RANGES: usize = u16::MAX as usize;
+}
+
+impl BinarySerialize for PartsEvicted {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        let count = self.ranges.len().min(Self::MAX_RANGES) as u16;
+
+        writer.write_all(self.file_id.as_bytes())?;
+        writer.write_all(&count.to_le_bytes())?;
+        for range in self.ranges.iter().take(count as usize) {
+            range.serialize_to_stream(writer)?;
+        }
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        let ranges = self
+            .ranges
+            .iter()
+            .take(Self::MAX_RANGES)
+            .map(|range| range.length_when_serialized())
+            .sum::<u32>();
+
+        16 // file_id
+        + 2 // count
+        + ranges
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut file_id_bytes = [0u8; 16];
+        reader.read_exact(&mut file_id_bytes)?;
+        let file_id = Uuid::from_bytes(file_id_bytes);
+
+        let mut count_bytes = [0u8; 2];
+        reader.read_exact(&mut count_bytes)?;
+        let count = u16::from_le_bytes(count_bytes);
+
+        let mut ranges = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            ranges.push(FilePartIdRangeInclusive::deserialize_from_stream(reader)?);
+        }
+
+        Ok(PartsEvicted { file_id, ranges })
+    }
+}
+
+impl ValidityCheck for PartsEvicted {
+    fn is_valid(&self) -> bool {
+        self.ranges.len() <= Self::MAX_RANGES && self.ranges.iter().all(|range| range.is_valid())
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A file that the sender only catalogued, instead of queuing it. The ground requests it with
+/// [`crate::control::RequestCatalogedFile`].
+pub struct CatalogEntry {
+    pub entry_id: Uuid,
+    pub name: String,
+    /// When the file was created, in nanoseconds since the unix epoch
+    pub date: i64,
+    pub size: u64,
+    /// Summary fields of the file, e.g. the instrument settings
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl BinarySerialize for CatalogEntry {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        let name_bytes = self.name.as_bytes();
+
+        writer.write_all(self.entry_id.as_bytes())?;
+        writer.write_all(&(name_bytes.len() as u16).to_le_bytes())?;
+        writer.write_all(name_bytes)?;
+        writer.write_all(&self.date.to_le_bytes())?;
+        writer.write_all(&self.size.to_le_bytes())?;
+        write_metadata(writer, &self.metadata)?;
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        16 // entry_id
+        + 2 // name_len
+        + self.name.len() as u32 // name
+        + 8 // date
+        + 8 // size
+        + metadata_length(&self.metadata) // metadata
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut entry_id_bytes = [0u8; 16];
+        reader.read_exact(&mut entry_id_bytes)?;
+        let entry_id = Uuid::from_bytes(entry_id_bytes);
+
+        let mut name_len_bytes = [0u8; 2];
+        reader.read_exact(&mut name_len_bytes)?;
+        let mut name_bytes = vec![0; u16::from_le_bytes(name_len_bytes) as usize];
+        reader.read_exact(&mut name_bytes)?;
+        let name = String::from_utf8(name_bytes)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let mut date_bytes = [0u8; 8];
+        reader.read_exact(&mut date_bytes)?;
+        let date = i64::from_le_bytes(date_bytes);
+
+        let mut size_bytes = [0u8; 8];
+        reader.read_exact(&mut size_bytes)?;
+        let size = u64::from_le_bytes(size_bytes);
+
+        let mut field_count_bytes = [0u8; 2];
+        reader.read_exact(&mut field_count_bytes)?;
+        let metadata = read_metadata_fields(reader, u16::from_le_bytes(field_count_bytes))?;
+
+        Ok(CatalogEntry {
+            entry_id,
+            name,
+            date,
+            size,
+            metadata,
+        })
+    }
+}
+
+impl ValidityCheck for CatalogEntry {
+    fn is_valid(&self) -> bool {
+        self.name.len() <= u16::MAX as usize && is_valid_metadata(&self.metadata)
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Entries of the sender's catalog, either newly catalogued files, or a page of the whole
+/// catalog when it was requested with [`crate::control::ListCatalog`]
+pub struct CatalogEntries {
+    pub entries: Vec<CatalogEntry>,
+}
+
+impl CatalogEntries {
+    pub const MAX_ENTRIES: usize = u16::MAX as usize;
+
+    /// Split entries into pages that each fit into a status message
+    pub fn pages(entries: Vec<CatalogEntry>) -> Vec<CatalogEntries> {
+        split_into_pages(entries, Self::MAX_ENTRIES, StatusMessage::MAX_LEN)
+            .into_iter()
+            .map(|entries| CatalogEntries { entries })
+            .collect()
+    }
+
+    /// Whether another entry can be added, without the message getting too large
+    pub fn has_room_for(&self, entry: &CatalogEntry) -> bool {
+        self.entries.len() < Self::MAX_ENTRIES
+            && self.length_when_serialized() as u64 + entry.length_when_serialized() as u64
+                <= StatusMessage::MAX_LEN as u64
+    }
+}
+
+impl BinarySerialize for CatalogEntries {
+    fn serialize_to_stream(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        let count = self.entries.len().min(Self::MAX_ENTRIES) as u16;
+        writer.write_all(&count.to_le_bytes())?;
+
+        for entry in self.entries.iter().take(count as usize) {
+            entry.serialize_to_stream(writer)?;
+        }
+
+        Ok(())
+    }
+
+    fn length_when_serialized(&self) -> u32 {
+        2 // count
+        + self
+            .entries
+            .iter()
+            .take(Self::MAX_ENTRIES)
+            .map(|entry| entry.length_when_serialized())
+            .sum::<u32>()
+    }
+
+    fn deserialize_from_stream(reader: &mut impl std::io::Read) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut count_bytes = [0u8; 2];
+        reader.read_exact(&mut count_bytes)?;
+        let count = u16::from_le_bytes(count_bytes);
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            entries.push(CatalogEntry::deserialize_from_stream(reader)?);
+        }
+
+        Ok(CatalogEntries { entries })
+    }
+}
+
+impl ValidityCheck for CatalogEntries {
+    fn is_valid(&self) -> bool {
+        self.entries.len() <= Self::MAX_ENTRIES && self.entries.iter().all(|e| e.is_valid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        control::{ControlMessage, DeleteFile},
+        file_part_id::FilePartId,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_split_into_pages() {
+        let commands = (0..5)
+            .map(|command_id| TimeTaggedCommand {
+                command_id,
+                execute_at: 100,
+                message: Box::new(ControlMessage::DeleteFile(DeleteFile {
+                    file_id: Uuid::new_v4(),
+                })),
+            })
+            .collect::<Vec<_>>();
+        let command_len = commands[0].length_when_serialized();
+
+        let pages = split_into_pages(commands.clone(), 2, u32::MAX);
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), [2, 2, 1]);
+
+        let pages = split_into_pages(commands.clone(), usize::MAX, 2 + command_len * 3);
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), [3, 2]);
+        assert_eq!(pages.concat(), commands);
+
+        // Items that are too large on their own still get sent
+        let pages = split_into_pages(commands, usize::MAX, 1);
+        assert_eq!(pages.len(), 5);
+
+        // An empty command list is still listed
+        let list = TimeTaggedCommandList {
+            commands: Vec::new(),
+        };
+        assert_eq!(list.clone().into_pages(), vec![list]);
+    }
+
+    #[test]
+    fn test_oversized_time_tagged_command_list_clamped() {
+        let command = TimeTaggedCommand {
+            command_id: 1,
+            execute_at: 100,
+            message: Box::new(ControlMessage::DeleteFile(DeleteFile {
+                file_id: Uuid::new_v4(),
+            })),
+        };
+        let msg = TimeTaggedCommandList {
+            commands: vec![command; TimeTaggedCommandList::MAX_COMMANDS + 1],
+        };
+
+        let mut buf = Vec::new();
+        msg.serialize_to_stream(&mut buf).unwrap();
+        assert_eq!(buf.len() as u32, msg.length_when_serialized());
+
+        let pages = msg.into_pages();
+        assert_eq!(pages.len(), 2);
+        assert!(
+            pages
+                .iter()
+                .all(|page| page.is_valid()
+                    && page.length_when_serialized() <= StatusMessage::MAX_LEN)
+        );
+    }
+
+    #[test]
+    fn test_catalog_entries_paged_by_size() {
+        // Each entry has the largest name, so only some of them fit into a message
+        let entry = CatalogEntry {
+            entry_id: Uuid::new_v4(),
+            name: "n".repeat(u16::MAX as usize),
+            date: 0,
+            size: 0,
+            metadata: BTreeMap::new(),
+        };
+        let pages = CatalogEntries::pages(vec![entry.clone(); 256]);
+        assert!(pages.len() > 1);
+        assert_eq!(
+            pages.iter().map(|page| page.entries.len()).sum::<usize>(),
+            256
+        );
+        assert!(pages
+            .iter()
+            .all(|page| page.length_when_serialized() <= StatusMessage::MAX_LEN));
+        assert!(!pages[0].has_room_for(&entry));
+    }
+
+    #[test]
+    fn test_time_tagged_command_list_serialization() {
+        let msg = TimeTaggedCommandList {
+            commands: vec![
+                TimeTaggedCommand {
+                    command_id: 1,
+                    execute_at: 100,
+                    message: Box::new(ControlMessage::DeleteFile(DeleteFile {
+                        file_id: Uuid::new_v4(),
+                    })),
+                },
+                TimeTaggedCommand {
+                    command_id: 2,
+                    execute_at: 200,
+                    message: Box::new(ControlMessage::DeleteFile(DeleteFile {
+                        file_id: Uuid::new_v4(),
+                    })),
+                },
+            ],
+        };
+
+        let mut buf = Vec::new();
+        msg.serialize_to_stream(&mut buf).unwrap();
+        assert_eq!(buf.len() as u32, msg.length_when_serialized());
+
+        let mut cursor = Cursor::new(buf);
+        let deserialized_msg = TimeTaggedCommandList::deserialize_from_stream(&mut cursor).unwrap();
+
+        assert_eq!(msg, deserialized_msg);
+    }
+
+    #[test]
+    fn test_heartbeat_serialization() {
+        let msg = Heartbeat {
+            uptime_secs: 3600,
+            runner_state: RunnerState::InSession,
+            managed_file_count: 12,
+            stored_bytes: 123456789,
+            pending_file_count: 3,
+            last_control_time: Some(1_700_000_000_000_000_000),
+            control_error_count: 1,
+            add_file_error_count: 2,
+            downlink_error_count: 0,
+        };
+
+        let mut buf = Vec::new();
+        msg.serialize_to_stream(&mut buf).unwrap();
+        assert_eq!(buf.len() as u32, msg.length_when_serialized());
+
+        let mut cursor = Cursor::new(buf);
+        let deserialized_msg = Heartbeat::deserialize_from_stream(&mut cursor).unwrap();
+
+        assert_eq!(msg, deserialized_msg);
+    }
+
+    #[test]
+    fn test_file_removed_serialization() {
+        let msg = FileRemoved {
+            file_id: Uuid::new_v4(),
+            reason: FileRemovedReason::Expired,
+        };
+
+        let mut buf = Vec::new();
+        msg.serialize_to_stream(&mut buf).unwrap();
+        assert_eq!(buf.len() as u32, msg.length_when_serialized());
+
+        let mut cursor = Cursor::new(buf);
+        let deserialized_msg = FileRemoved::deserialize_from_stream(&mut cursor).unwrap();
+
+        assert_eq!(msg, deserialized_msg);
+    }
+
+    #[test]
+    fn test_parts_corrupted_serialization() {
+        let msg = PartsCorrupted {
+            file_id: Uuid::new_v4(),
+            parts: vec![0, 7, 123456],
+            quarantined: true,
+        };
+
+        let mut buf = Vec::new();
+        msg.serialize_to_stream(&mut buf).unwrap();
+        assert_eq!(buf.len() as u32, msg.length_when_serialized());
+
+        let mut cursor = Cursor::new(buf);
+        let deserialized_msg = PartsCorrupted::deserialize_from_stream(&mut cursor).unwrap();
+
+        assert_eq!(msg, deserialized_msg);
+    }
+
+    #[test]
+    fn test_parts_evicted_serialization() {
+        let msg = PartsEvicted {
+            file_id: Uuid::new_v4(),
+            ranges: vec![
+                FilePartIdRangeInclusive::new_single(FilePartId::Header),
+                FilePartIdRangeInclusive::new(FilePartId::Part(3), FilePartId::Part(9)),
+            ],
+        };
+
+        let mut buf = Vec::new();
+        msg.serialize_to_stream(&mut buf).unwrap();
+        assert_eq!(buf.len() as u32, msg.length_when_serialized());
+
+        let mut cursor = Cursor::new(buf);
+        let deserialized_msg = PartsEvicted::deserialize_from_stream(&mut cursor).unwrap();
+
+        assert_eq!(msg, deserialized_msg);
+    }
+
+    #[test]
+    fn test_catalog_entries_serialization() {
+        let msg = CatalogEntries {
+            entries: vec![
+                CatalogEntry {
+                    entry_id: Uuid::new_v4(),
+                    name: "camera/img_001.raw".to_string(),
+                    date: 1_700_000_000_000_000_000,
+                    size: 12_000_000,
+                    metadata: BTreeMap::from([("exposure_ms".to_string(), "20".to_string())]),
+                },
+                CatalogEntry {
+                    entry_id: Uuid::new_v4(),
+                    name: "log.txt".to_string(),
+                    date: 0,
+                    size: 0,
+                    metadata: BTreeMap::new(),
+                },
+            ],
+        };
+
+        let mut buf = Vec::new();
+        msg.serialize_to_stream(&mut buf).unwrap();
+        assert_eq!(buf.len() as u32, msg.length_when_serialized());
+
+        let mut cursor = Cursor::new(buf);
+        let deserialized_msg = CatalogEntries::deserialize_from_stream(&mut cursor).unwrap();
+
+        assert_eq!(msg, deserialized_msg);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
-    binary_serialize::BinarySerialize, chunks::Chunk, substream::SubstreamReader,
-    validity::ValidityCheck,
+    binary_serialize::BinarySerialize, chunks::Chunk, control::ControlMessage,
+    substream::SubstreamReader, validity::ValidityCheck,
 };
 
 use self::{
@@ -17,11 +17,16 @@ pub use tolerant_parser::parse_transport_packet_stream;
 #[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[derive(Clone, PartialEq, Debug)]
 pub enum TransportPacketData {
-    HeaderChunk(crate::chunks::HeaderChunk),            // 0
-    DataChunk(crate::chunks::DataChunk),                // 1
-    AcknowledgementPacket(crate::control::ConfirmPart), // 128
-    DeleteFile(crate::control::DeleteFile),             // 129
-    SetFilePriority(crate::control::SetFilePriority),   // 130
+    HeaderChunk(crate::chunks::HeaderChunk), // 0
+    DataChunk(crate::chunks::DataChunk),     // 1
+    TimeTaggedCommandList(crate::status::TimeTaggedCommandList), // 64
+    Heartbeat(crate::status::Heartbeat),     // 65
+    FileRemoved(crate::status::FileRemoved), // 66
+    PartsCorrupted(crate::status::PartsCorrupted), // 67
+    PartsEvicted(crate::status::PartsEvicted), // 68
+    CatalogEntries(crate::status::CatalogEntries), // 69
+    /// Messages from the ground, with the type ids from 128 on, see [`ControlMessage`]
+    Control(ControlMessage),
 }
 
 impl TransportPacketData {
@@ -33,14 +38,9 @@ impl TransportPacketData {
         }
     }
 
-    pub fn as_control_message(self) -> Option<crate::control::ControlMessage> {
+    pub fn as_control_message(self) -> Option<ControlMessage> {
         match self {
-            TransportPacketData::AcknowledgementPacket(ack) => {
-                Some(crate::control::ControlMessage::ConfirmPart(ack))
-            }
-            TransportPacketData::DeleteFile(delete_file) => {
-                Some(crate::control::ControlMessage::DeleteFile(delete_file))
-            }
+            TransportPacketData::Control(control_message) => Some(control_message),
             _ => None,
         }
     }
@@ -52,16 +52,53 @@ impl TransportPacketData {
         }
     }
 
-    pub fn from_control_message(control_message: crate::control::ControlMessage) -> Self {
-        match control_message {
-            crate::control::ControlMessage::ConfirmPart(ack) => {
-                TransportPacketData::AcknowledgementPacket(ack)
+    pub fn from_control_message(control_message: ControlMessage) -> Self {
+        TransportPacketData::Control(control_message)
+    }
+
+    pub fn as_status_message(self) -> Option<crate::status::StatusMessage> {
+        match self {
+            TransportPacketData::TimeTaggedCommandList(list) => {
+                Some(crate::status::StatusMessage::TimeTaggedCommandList(list))
             }
-            crate::control::ControlMessage::DeleteFile(delete_file) => {
-                TransportPacketData::DeleteFile(delete_file)
+            TransportPacketData::Heartbeat(heartbeat) => {
+                Some(crate::status::StatusMessage::Heartbeat(heartbeat))
             }
-            crate::control::ControlMessage::SetFilePriority(set_file_priority) => {
-                TransportPacketData::SetFilePriority(set_file_priority)
+            TransportPacketData::FileRemoved(removed) => {
+                Some(crate::status::StatusMessage::FileRemoved(removed))
+            }
+            TransportPacketData::PartsCorrupted(corrupted) => {
+                Some(crate::status::StatusMessage::PartsCorrupted(corrupted))
+            }
+            TransportPacketData::PartsEvicted(evicted) => {
+                Some(crate::status::StatusMessage::PartsEvicted(evicted))
+            }
+            TransportPacketData::CatalogEntries(entries) => {
+                Some(crate::status::StatusMessage::CatalogEntries(entries))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn from_status_message(status_message: crate::status::StatusMessage) -> Self {
+        match status_message {
+            crate::status::StatusMessage::TimeTaggedCommandList(list) => {
+                TransportPacketData::TimeTaggedCommandList(list)
+            }
+            crate::status::StatusMessage::Heartbeat(heartbeat) => {
+                TransportPacketData::Heartbeat(heartbeat)
+            }
+            crate::status::StatusMessage::FileRemoved(removed) => {
+                TransportPacketData::FileRemoved(removed)
+            }
+            crate::status::StatusMessage::PartsCorrupted(corrupted) => {
+                TransportPacketData::PartsCorrupted(corrupted)
+            }
+            crate::status::StatusMessage::PartsEvicted(evicted) => {
+                TransportPacketData::PartsEvicted(evicted)
+            }
+            crate::status::StatusMessage::CatalogEntries(entries) => {
+                TransportPacketData::CatalogEntries(entries)
             }
         }
     }
@@ -78,17 +115,33 @@ impl BinarySerialize for TransportPacketData {
                 writer.write_all(&[1])?;
                 data_chunk.serialize_to_stream(writer)
             }
-            TransportPacketData::AcknowledgementPacket(ack) => {
-                writer.write_all(&[128])?;
-                ack.serialize_to_stream(writer)
+            TransportPacketData::TimeTaggedCommandList(list) => {
+                writer.write_all(&[64])?;
+                list.serialize_to_stream(writer)
+            }
+            TransportPacketData::Heartbeat(heartbeat) => {
+                writer.write_all(&[65])?;
+                heartbeat.serialize_to_stream(writer)
+            }
+            TransportPacketData::FileRemoved(removed) => {
+                writer.write_all(&[66])?;
+                removed.serialize_to_stream(writer)
+            }
+            TransportPacketData::PartsCorrupted(corrupted) => {
+                writer.write_all(&[67])?;
+                corrupted.serialize_to_stream(writer)
             }
-            TransportPacketData::DeleteFile(delete_file) => {
-                writer.write_all(&[129])?;
-                delete_file.serialize_to_stream(writer)
+            TransportPacketData::PartsEvicted(evicted) => {
+                writer.write_all(&[68])?;
+                evicted.serialize_to_stream(writer)
             }
-            TransportPacketData::SetFilePriority(set_file_priority) => {
-                writer.write_all(&[130])?;
-                set_file_priority.serialize_to_stream(writer)
+            TransportPacketData::CatalogEntries(entries) => {
+                writer.write_all(&[69])?;
+                entries.serialize_to_stream(writer)
+            }
+            // Control messages write their own type id
+            TransportPacketData::Control(control_message) => {
+                control_message.serialize_to_stream(writer)
             }
         }
     }
@@ -97,10 +150,15 @@ impl BinarySerialize for TransportPacketData {
         let inner = match self {
             TransportPacketData::HeaderChunk(header_chunk) => header_chunk.length_when_serialized(),
             TransportPacketData::DataChunk(data_chunk) => data_chunk.length_when_serialized(),
-            TransportPacketData::AcknowledgementPacket(ack) => ack.length_when_serialized(),
-            TransportPacketData::DeleteFile(delete_file) => delete_file.length_when_serialized(),
-            TransportPacketData::SetFilePriority(set_file_priority) => {
-                set_file_priority.length_when_serialized()
+            TransportPacketData::TimeTaggedCommandList(list) => list.length_when_serialized(),
+            TransportPacketData::Heartbeat(heartbeat) => heartbeat.length_when_serialized(),
+            TransportPacketData::FileRemoved(removed) => removed.length_when_serialized(),
+            TransportPacketData::PartsCorrupted(corrupted) => corrupted.length_when_serialized(),
+            TransportPacketData::PartsEvicted(evicted) => evicted.length_when_serialized(),
+            TransportPacketData::CatalogEntries(entries) => entries.length_when_serialized(),
+            // Includes the type
+            TransportPacketData::Control(control_message) => {
+                return control_message.length_when_serialized()
             }
         };
 
@@ -123,15 +181,27 @@ impl BinarySerialize for TransportPacketData {
             1 => TransportPacketData::DataChunk(crate::chunks::DataChunk::deserialize_from_stream(
                 reader,
             )?),
-            128 => TransportPacketData::AcknowledgementPacket(
-                crate::control::ConfirmPart::deserialize_from_stream(reader)?,
+            64 => TransportPacketData::TimeTaggedCommandList(
+                crate::status::TimeTaggedCommandList::deserialize_from_stream(reader)?,
+            ),
+            65 => TransportPacketData::Heartbeat(
+                crate::status::Heartbeat::deserialize_from_stream(reader)?,
+            ),
+            66 => TransportPacketData::FileRemoved(
+                crate::status::FileRemoved::deserialize_from_stream(reader)?,
+            ),
+            67 => TransportPacketData::PartsCorrupted(
+                crate::status::PartsCorrupted::deserialize_from_stream(reader)?,
             ),
-            129 => TransportPacketData::DeleteFile(
-                crate::control::DeleteFile::deserialize_from_stream(reader)?,
+            68 => TransportPacketData::PartsEvicted(
+                crate::status::PartsEvicted::deserialize_from_stream(reader)?,
             ),
-            130 => TransportPacketData::SetFilePriority(
-                crate::control::SetFilePriority::deserialize_from_stream(reader)?,
+            69 => TransportPacketData::CatalogEntries(
+                crate::status::CatalogEntries::deserialize_from_stream(reader)?,
             ),
+            ControlMessage::MIN_TYPE_ID..=u8::MAX => {
+                TransportPacketData::Control(ControlMessage::deserialize_with_type(type_, reader)?)
+            }
             _ => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
@@ -174,16 +244,12 @@ impl TransportPacket {
         }
     }
 
-    pub fn data_as_control_message(self) -> Option<crate::control::ControlMessage> {
-        match self.data.data {
-            TransportPacketData::AcknowledgementPacket(ack) => {
-                Some(crate::control::ControlMessage::ConfirmPart(ack))
-            }
-            TransportPacketData::DeleteFile(delete_file) => {
-                Some(crate::control::ControlMessage::DeleteFile(delete_file))
-            }
-            _ => None,
-        }
+    pub fn data_as_status_message(self) -> Option<crate::status::StatusMessage> {
+        self.data.data.as_status_message()
+    }
+
+    pub fn data_as_control_message(self) -> Option<ControlMessage> {
+        self.data.data.as_control_message()
     }
 }
 
@@ -227,7 +293,7 @@ pub struct TransportPacketInner {
 }
 
 impl TransportPacketInner {
-    const MAX_DATA_LEN: usize = 8388608; // 8 MiB
+    pub const MAX_DATA_LEN: usize = 8388608; // 8 MiB
 
     pub fn new(data: TransportPacketData) -> Self {
         Self { data }
@@ -310,9 +376,13 @@ impl ValidityCheck for TransportPacketData {
         match self {
             TransportPacketData::HeaderChunk(header_chunk) => header_chunk.is_valid(),
             TransportPacketData::DataChunk(data_chunk) => data_chunk.is_valid(),
-            TransportPacketData::AcknowledgementPacket(ack) => ack.is_valid(),
-            TransportPacketData::DeleteFile(delete_file) => delete_file.is_valid(),
-            TransportPacketData::SetFilePriority(set_file_priority) => set_file_priority.is_valid(),
+            TransportPacketData::TimeTaggedCommandList(list) => list.is_valid(),
+            TransportPacketData::Heartbeat(heartbeat) => heartbeat.is_valid(),
+            TransportPacketData::FileRemoved(removed) => removed.is_valid(),
+            TransportPacketData::PartsCorrupted(corrupted) => corrupted.is_valid(),
+            TransportPacketData::PartsEvicted(evicted) => evicted.is_valid(),
+            TransportPacketData::CatalogEntries(entries) => entries.is_valid(),
+            TransportPacketData::Control(control_message) => control_message.is_valid(),
         }
     }
 }
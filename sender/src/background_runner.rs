@@ -1,7 +1,7 @@
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
@@ -9,12 +9,39 @@ use common::{
     chunks::Chunk,
     control::ControlMessage,
     file_part_id::FilePartId,
-    file_sending::storage_manager::{SendingStorageManager, SendingStorageManagerConfig},
+    file_sending::storage_manager::{NewFileOptions, SendingStorageManager},
+    status::{Heartbeat, RunnerState, StatusMessage},
+    transport_packet::TransportPacketData,
 };
-use crossbeam_channel::{Receiver, SendTimeoutError, Sender};
+use crossbeam_channel::{Receiver, RecvTimeoutError, SendTimeoutError, Sender};
 use uuid::Uuid;
 
-use crate::downlink_session::DownlinkSession;
+use crate::{
+    downlink_session::DownlinkSession, growing_files::GrowingFiles, health::RunnerHealth,
+    input_folder, sidecar, DownlinkServerConfig,
+};
+
+/// How often the time-tagged commands, in-flight timeouts and file expiry are checked. Each
+/// check goes through all the stored files or parts, so it's not done on every loop.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the waiting state blocks on new messages before it does its periodic work again
+const WAITING_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A single item sent on the downlink, either a file chunk or a status message.
+pub enum DownlinkPacket {
+    Chunk(Chunk),
+    Status(StatusMessage),
+}
+
+impl DownlinkPacket {
+    pub fn into_transport_packet_data(self) -> TransportPacketData {
+        match self {
+            DownlinkPacket::Chunk(chunk) => TransportPacketData::from_chunk(chunk),
+            DownlinkPacket::Status(status) => TransportPacketData::from_status_message(status),
+        }
+    }
+}
 
 pub enum DownlinkServerMessage {
     /// Process a control message received from outside
@@ -23,10 +50,10 @@ pub enum DownlinkServerMessage {
     /// which deletes it. This just decreases the part's priority.
     ConfirmChunkSent { file_id: Uuid, part_id: FilePartId },
     /// Inform the server that a new file can be added by the following path.
-    AddFile(PathBuf),
+    AddFile(PathBuf, NewFileOptions),
     /// Begin a new downlink session, sending chunks to the new chunk queue. The session
     /// will end when the receiver is dropped.
-    BeginDownlinkSession(Sender<Option<Chunk>>),
+    BeginDownlinkSession(Sender<Option<DownlinkPacket>>),
     /// End the downlink session, and transition to the waiting state. The sender gets
     /// dropped, so the session has successfully ended when the receiver disconnects.
     EndDownlinkSession,
@@ -40,25 +67,87 @@ pub enum DownlinkServerMessage {
 /// get collected to be processed when the session ends.
 struct BackgroundRunnerDownlinkSessionState {
     downlink_session: DownlinkSession,
-    sender: Sender<Option<Chunk>>,
-    pending_new_files: Vec<PathBuf>,
+    sender: Sender<Option<DownlinkPacket>>,
+    pending_new_files: Vec<(PathBuf, NewFileOptions)>,
+    shared: BackgroundRunnerShared,
+    /// A heartbeat is sent at the start of every session, and then every n packets.
+    heartbeat_due: bool,
+    packets_since_heartbeat: u32,
 }
 
 /// The background runner is waiting for a downlink session to start. It can't send
 /// chunks, but it can process control messages and add new files.
 struct BackgroundRunnerWaitingState {
     storage: SendingStorageManager,
+    shared: BackgroundRunnerShared,
+}
+
+/// State that's carried across both background runner states.
+struct BackgroundRunnerShared {
+    config: DownlinkServerConfig,
+    health: RunnerHealth,
+    last_scrub: Instant,
+    /// When the periodic checks of the stored files were last done, see [`HOUSEKEEPING_INTERVAL`]
+    last_housekeeping: Option<Instant>,
+    growing_files: GrowingFiles,
+    /// Where the heartbeats made while waiting go, see [`DownlinkServerConfig::waiting_heartbeat_interval`]
+    waiting_heartbeats: Sender<Heartbeat>,
+    last_waiting_heartbeat: Option<Instant>,
+}
+
+impl BackgroundRunnerShared {
+    /// Whether the periodic checks of the stored files are due. They're due right away after
+    /// startup.
+    fn take_housekeeping_due(&mut self) -> bool {
+        let is_due = self
+            .last_housekeeping
+            .is_none_or(|last| last.elapsed() >= HOUSEKEEPING_INTERVAL);
+        if is_due {
+            self.last_housekeeping = Some(Instant::now());
+        }
+
+        is_due
+    }
+
+    /// Whether a heartbeat is due while waiting. The first one is due right away.
+    fn take_waiting_heartbeat_due(&mut self) -> bool {
+        let Some(interval) = self.config.waiting_heartbeat_interval else {
+            return false;
+        };
+
+        let is_due = self
+            .last_waiting_heartbeat
+            .is_none_or(|last| last.elapsed() >= interval);
+        if is_due {
+            self.last_waiting_heartbeat = Some(Instant::now());
+        }
+
+        is_due
+    }
 }
 
 pub fn run_downlink_server_bg_runner(
     files_dir: PathBuf,
+    pending_dir: PathBuf,
     message_rcv: Receiver<DownlinkServerMessage>,
-    storage_config: SendingStorageManagerConfig,
+    waiting_heartbeats: Sender<Heartbeat>,
+    config: DownlinkServerConfig,
 ) -> anyhow::Result<JoinHandle<()>> {
-    let storage = SendingStorageManager::new(files_dir.clone(), storage_config)
+    let storage = SendingStorageManager::new(files_dir.clone(), config.storage.clone())
         .context("Failed to load storage when initializing downlink background runner")?;
 
-    let mut prev_waiting_state = BackgroundRunnerWaitingState { storage };
+    let mut prev_waiting_state = BackgroundRunnerWaitingState {
+        storage,
+        shared: BackgroundRunnerShared {
+            growing_files: GrowingFiles::new(config.growing_files.clone()),
+            config,
+            health: RunnerHealth::new(pending_dir),
+            last_scrub: Instant::now(),
+            last_housekeeping: None,
+            waiting_heartbeats,
+            last_waiting_heartbeat: None,
+        },
+    };
 
     // Run a thread that bounces between the two states until killed.
     Ok(thread::spawn(move || loop {
@@ -87,6 +176,58 @@ impl BackgroundRunnerDownlinkSessionState {
         // processing control events. This helps avoid deadlocks.
         let mut pending_chunk = None;
         loop {
+            if self.shared.take_housekeeping_due() {
+                let result = self
+                    .downlink_session
+                    .execute_due_time_tagged_commands(current_timestamp());
+                if let Err(err) = result {
+                    self.shared.health.control_error_count += 1;
+                    tracing::error!("Error executing time-tagged commands: {}", err);
+                }
+
+                let result = self
+                    .downlink_session
+                    .restore_timed_out_in_flight_parts(current_timestamp());
+                if let Err(err) = result {
+                    tracing::error!("Error restoring timed out in-flight parts: {}", err);
+                }
+
+                let result = self
+                    .downlink_session
+                    .delete_expired_files(current_timestamp());
+                if let Err(err) = result {
+                    tracing::error!("Error deleting expired files: {}", err);
+                }
+            }
+
+            self.shared.health.add_file_error_count += self
+                .shared
+                .growing_files
+                .poll(self.downlink_session.storage_mut());
+
+            let result = self.downlink_session.storage_mut().flush_state_if_due();
+            if let Err(err) = result {
+                tracing::error!("Error flushing file states: {}", err);
+            }
+
+            if pending_chunk.is_none() && self.heartbeat_due {
+                let heartbeat = self
+                    .shared
+                    .health
+                    .make_heartbeat(self.downlink_session.storage(), RunnerState::InSession);
+                pending_chunk = Some(Some(DownlinkPacket::Status(StatusMessage::Heartbeat(
+                    heartbeat,
+                ))));
+                self.heartbeat_due = false;
+                self.packets_since_heartbeat = 0;
+            }
+
+            if pending_chunk.is_none() {
+                if let Some(status) = self.downlink_session.next_status_message() {
+                    pending_chunk = Some(Some(DownlinkPacket::Status(status)));
+                }
+            }
+
             if pending_chunk.is_none() {
                 // Try to process the next chunk
                 let next_chunk = self.downlink_session.next_chunk();
@@ -98,11 +239,12 @@ impl BackgroundRunnerDownlinkSessionState {
 
                         Some(None)
                     }
-                    Ok(Some(chunk)) => Some(Some(chunk)),
+                    Ok(Some(chunk)) => Some(Some(DownlinkPacket::Chunk(chunk))),
                     Err(err) => {
                         // An unexpected error occurred when trying to get the next chunk.
                         // Sleep for 1000ms, to not spam the CPU or the logs.
                         std::thread::sleep(std::time::Duration::from_millis(1000));
+                        self.shared.health.downlink_error_count += 1;
                         tracing::error!("Error getting next chunk from downlink: {}", err);
 
                         None
@@ -133,6 +275,13 @@ impl BackgroundRunnerDownlinkSessionState {
                 } else {
                     // We sent the chunk, so we can clear it.
                     pending_chunk = None;
+
+                    self.packets_since_heartbeat += 1;
+                    if let Some(interval) = self.shared.config.heartbeat_interval_packets {
+                        if self.packets_since_heartbeat >= interval {
+                            self.heartbeat_due = true;
+                        }
+                    }
                 }
             }
 
@@ -140,8 +289,11 @@ impl BackgroundRunnerDownlinkSessionState {
             while let Ok(message) = message_rcv.try_recv() {
                 match message {
                     DownlinkServerMessage::Control(control) => {
+                        self.shared.health.last_control_time = Some(current_timestamp());
+
                         let process_result = self.downlink_session.process_control(control.clone());
                         if let Err(err) = process_result {
+                            self.shared.health.control_error_count += 1;
                             tracing::error!(
                                 "Error processing control message.\nMessage: {:?}\nError: {}",
                                 control,
@@ -153,20 +305,23 @@ impl BackgroundRunnerDownlinkSessionState {
                         let process_result =
                             self.downlink_session.confirm_file_sent(file_id, part_id);
                         if let Err(err) = process_result {
+                            self.shared.health.downlink_error_count += 1;
                             tracing::error!("Error marking file part as sent. File ID: {}\nPart ID: {}\nError: {}", file_id, part_id, err);
                         }
                     }
-                    DownlinkServerMessage::AddFile(path) => {
+                    DownlinkServerMessage::AddFile(path, options) => {
                         // We can't add new files during a downlink session, as that may require re-shuffing a lot of data.
                         // Instead, we queue them up and process them when the session ends.
-                        self.pending_new_files.push(path);
+                        self.pending_new_files.push((path, options));
                     }
                     DownlinkServerMessage::BeginDownlinkSession(_) => {
                         // We can't start a new downlink session while we're already in one.
                         tracing::error!("Received BeginDownlinkSession message while already in a downlink session");
                     }
                     DownlinkServerMessage::StopAndQuit => {
-                        // We're done here
+                        // We're done here. Ending the session persists what's only kept in
+                        // memory.
+                        self.downlink_session.into_storage_manager();
                         return None;
                     }
                     DownlinkServerMessage::EndDownlinkSession => {
@@ -179,12 +334,18 @@ impl BackgroundRunnerDownlinkSessionState {
         }
     }
 
-    fn transition_to_waiting_state(self) -> BackgroundRunnerWaitingState {
+    fn transition_to_waiting_state(mut self) -> BackgroundRunnerWaitingState {
         let mut current_storage_manager = self.downlink_session.into_storage_manager();
 
-        for new_file_path in self.pending_new_files {
-            let result = current_storage_manager.add_file_from_path(&new_file_path);
+        for (new_file_path, options) in self.pending_new_files {
+            let result = add_file_to_storage(
+                &mut current_storage_manager,
+                self.shared.health.pending_folder(),
+                &new_file_path,
+                options,
+            );
             if let Err(err) = result {
+                self.shared.health.add_file_error_count += 1;
                 tracing::error!(
                     "Error adding new file. Path: {:?}\nError: {}",
                     new_file_path,
@@ -195,6 +356,7 @@ impl BackgroundRunnerDownlinkSessionState {
 
         BackgroundRunnerWaitingState {
             storage: current_storage_manager,
+            shared: self.shared,
         }
     }
 }
@@ -205,12 +367,71 @@ impl BackgroundRunnerWaitingState {
         message_rcv: Receiver<DownlinkServerMessage>,
     ) -> Option<BackgroundRunnerDownlinkSessionState> {
         loop {
-            // Then, try to process the next message(s).
-            while let Ok(message) = message_rcv.try_recv() {
+            if self.shared.take_housekeeping_due() {
+                let result = self
+                    .storage
+                    .execute_due_time_tagged_commands(current_timestamp());
+                if let Err(err) = result {
+                    self.shared.health.control_error_count += 1;
+                    tracing::error!("Error executing time-tagged commands: {}", err);
+                }
+
+                let result = self
+                    .storage
+                    .restore_timed_out_in_flight_parts(current_timestamp());
+                if let Err(err) = result {
+                    tracing::error!("Error restoring timed out in-flight parts: {}", err);
+                }
+
+                let result = self.storage.delete_expired_files(current_timestamp());
+                if let Err(err) = result {
+                    tracing::error!("Error deleting expired files: {}", err);
+                }
+
+                if self.shared.take_waiting_heartbeat_due() {
+                    let heartbeat = self
+                        .shared
+                        .health
+                        .make_heartbeat(&self.storage, RunnerState::Waiting);
+                    // Dropped if nobody read the previous one
+                    self.shared.waiting_heartbeats.try_send(heartbeat).ok();
+                }
+            }
+
+            self.shared.health.add_file_error_count +=
+                self.shared.growing_files.poll(&mut self.storage);
+
+            let result = self.storage.flush_state_if_due();
+            if let Err(err) = result {
+                tracing::error!("Error flushing file states: {}", err);
+            }
+
+            if let Some(interval) = self.shared.config.scrub_interval {
+                if self.shared.last_scrub.elapsed() >= interval {
+                    self.shared.last_scrub = Instant::now();
+
+                    let result = self.storage.scrub_next_file();
+                    if let Err(err) = result {
+                        tracing::error!("Error scrubbing stored files: {}", err);
+                    }
+                }
+            }
+
+            // Then, wait for the next message(s). There's nothing else to do until then, other
+            // than the periodic work above.
+            let mut next_message = match message_rcv.recv_timeout(WAITING_POLL_INTERVAL) {
+                Ok(message) => Some(message),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => Some(DownlinkServerMessage::StopAndQuit),
+            };
+            while let Some(message) = next_message.take().or_else(|| message_rcv.try_recv().ok()) {
                 match message {
                     DownlinkServerMessage::Control(control) => {
+                        self.shared.health.last_control_time = Some(current_timestamp());
+
                         let process_result = self.storage.process_control(control.clone());
                         if let Err(err) = process_result {
+                            self.shared.health.control_error_count += 1;
                             tracing::error!(
                                 "Error processing control message.\nMessage: {:?}\nError: {}",
                                 control,
@@ -221,12 +442,19 @@ impl BackgroundRunnerWaitingState {
                     DownlinkServerMessage::ConfirmChunkSent { file_id, part_id } => {
                         let result = self.storage.confirm_file_sent(file_id, part_id);
                         if let Err(err) = result {
+                            self.shared.health.downlink_error_count += 1;
                             tracing::error!("Error marking file part as sent. File ID: {}\nPart ID: {}\nError: {}", file_id, part_id, err);
                         }
                     }
-                    DownlinkServerMessage::AddFile(path) => {
-                        let add_result = self.storage.add_file_from_path(&path);
+                    DownlinkServerMessage::AddFile(path, options) => {
+                        let add_result = add_file_to_storage(
+                            &mut self.storage,
+                            self.shared.health.pending_folder(),
+                            &path,
+                            options,
+                        );
                         if let Err(err) = add_result {
+                            self.shared.health.add_file_error_count += 1;
                             tracing::error!(
                                 "Error adding file from path. Path: {:?}\nError: {}",
                                 path,
@@ -240,6 +468,9 @@ impl BackgroundRunnerWaitingState {
                     }
                     DownlinkServerMessage::StopAndQuit => {
                         // We're done here
+                        if let Err(err) = self.storage.flush_state() {
+                            tracing::error!("Error flushing file states: {}", err);
+                        }
                         return None;
                     }
                     DownlinkServerMessage::EndDownlinkSession => {
@@ -253,13 +484,38 @@ impl BackgroundRunnerWaitingState {
 
     fn transition_to_downlink_session(
         self,
-        sender: Sender<Option<Chunk>>,
+        sender: Sender<Option<DownlinkPacket>>,
     ) -> BackgroundRunnerDownlinkSessionState {
-        let downlink_session = DownlinkSession::new(self.storage);
+        let downlink_session =
+            DownlinkSession::new(self.storage, self.shared.config.scheduling_policy.build());
         BackgroundRunnerDownlinkSessionState {
             downlink_session,
             sender,
             pending_new_files: Vec::new(),
+            shared: self.shared,
+            heartbeat_due: true,
+            packets_since_heartbeat: 0,
         }
     }
 }
+
+fn add_file_to_storage(
+    storage: &mut SendingStorageManager,
+    pending_folder: &Path,
+    path: &Path,
+    options: NewFileOptions,
+) -> anyhow::Result<()> {
+    storage.add_file_from_path_with_options(path, options)?;
+
+    // The sidecar stays in the pending folder until the file is added, so that the options
+    // survive a restart.
+    sidecar::remove_sidecar_for(path);
+    input_folder::remove_empty_parents(pending_folder, path);
+
+    Ok(())
+}
+
+/// The current time of the onboard clock, in nanoseconds since the unix epoch
+fn current_timestamp() -> i64 {
+    chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+}
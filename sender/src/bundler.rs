@@ -0,0 +1,335 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use common::{
+    bundle::{write_bundle, BundleEntry, BundleReader, BUNDLE_SUFFIX},
+    chunks::FileKind,
+    file_sending::managed_sending_file::file_created_date,
+};
+
+use crate::{input_folder, sidecar, BundlingConfig};
+
+/// Collects small files from the pending folder and packs them into bundles, so that they
+/// share a single managed file. The files stay in the pending folder until their bundle is
+/// complete, so nothing is lost on a restart. Files that are still in the pending folder
+/// although their bundle is complete are removed on startup, so they aren't sent twice.
+pub struct Bundler {
+    config: BundlingConfig,
+    pending_folder: PathBuf,
+    /// Bundles are written here first, and moved into the bundles folder once they're complete
+    bundling_folder: PathBuf,
+    /// Complete bundles wait here until they're added to the storage. It's outside of the
+    /// pending folder, so that no input file can be mistaken for a bundle.
+    bundles_folder: PathBuf,
+
+    files: Vec<(BundleEntry, PathBuf)>,
+    size: u64,
+    window_started: Option<Instant>,
+}
+
+impl Bundler {
+    pub fn new(
+        config: BundlingConfig,
+        pending_folder: PathBuf,
+        bundling_folder: PathBuf,
+        bundles_folder: PathBuf,
+    ) -> anyhow::Result<Self> {
+        // Leftovers of an interrupted bundle. Its files are still in the pending folder.
+        if bundling_folder.exists() {
+            std::fs::remove_dir_all(&bundling_folder)?;
+        }
+        std::fs::create_dir_all(&bundling_folder)?;
+        std::fs::create_dir_all(&bundles_folder)?;
+
+        let bundler = Self {
+            config,
+            pending_folder,
+            bundling_folder,
+            bundles_folder,
+            files: Vec::new(),
+            size: 0,
+            window_started: None,
+        };
+
+        // The files of a bundle are removed after it's complete, which may have been
+        // interrupted
+        for bundle_path in bundler.waiting_bundles()? {
+            if let Err(err) = bundler.remove_bundled_files(&bundle_path) {
+                tracing::error!(
+                    "Failed to remove the pending files of bundle {:?}: {}",
+                    bundle_path,
+                    err
+                );
+            }
+        }
+
+        Ok(bundler)
+    }
+
+    /// Remove the files in the pending folder that are in a complete bundle. Files are only
+    /// taken for the bundled ones if their size and date match, in case a new file with the
+    /// same name arrived since.
+    fn remove_bundled_files(&self, bundle_path: &Path) -> anyhow::Result<()> {
+        let reader = BundleReader::open(bundle_path)?;
+        for entry in reader.remaining_entries() {
+            let path = self.pending_folder.join(&entry.name);
+            let is_bundled = std::fs::metadata(&path)
+                .is_ok_and(|metadata| metadata.is_file() && metadata.len() == entry.size)
+                && file_created_date(&path).is_ok_and(|date| date == entry.date);
+            if is_bundled {
+                tracing::info!("Removing pending file {:?}, it's already bundled", path);
+                self.remove_pending_file(&path);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove_pending_file(&self, path: &Path) {
+        if let Err(err) = std::fs::remove_file(path) {
+            tracing::error!("Failed to remove bundled file {:?}: {}", path, err);
+        }
+        sidecar::remove_sidecar_for(path);
+        input_folder::remove_empty_parents(&self.pending_folder, path);
+    }
+
+    /// Add a file in the pending folder to the next bundle. Returns false if the file should be
+    /// added on its own, because it's too large or its sidecar has options besides metadata.
+    pub fn try_add(&mut self, path: &Path, name: &str) -> bool {
+        let Ok(size) = std::fs::metadata(path).map(|metadata| metadata.len()) else {
+            return false;
+        };
+        if size > self.config.max_file_size {
+            return false;
+        }
+
+        let file_sidecar = sidecar::read_sidecar_for(path).unwrap_or_default();
+        if !file_sidecar.is_metadata_only() {
+            return false;
+        }
+
+        let Ok(date) = file_created_date(path) else {
+            return false;
+        };
+
+        let entry = BundleEntry {
+            name: name.to_string(),
+            date,
+            size,
+            metadata: file_sidecar.metadata,
+        };
+        self.files.push((entry, path.to_path_buf()));
+        self.size += size;
+        self.window_started.get_or_insert_with(Instant::now);
+
+        true
+    }
+
+    /// The folder that complete bundles wait in, which their names are relative to
+    pub fn bundles_folder(&self) -> &Path {
+        &self.bundles_folder
+    }
+
+    /// The complete bundles that weren't added to the storage yet, e.g. before a restart
+    pub fn waiting_bundles(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(&self.bundles_folder)? {
+            let path = entry?.path();
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Once the window is over or the bundle is full, write the bundle into the bundles folder
+    /// and remove its files. Returns the paths that should be added to the storage with their
+    /// kind: the bundle, or the files on their own if the bundle couldn't be written.
+    pub fn take_due_bundle(&mut self) -> Vec<(PathBuf, FileKind)> {
+        let Some(window_started) = self.window_started else {
+            return Vec::new();
+        };
+        if window_started.elapsed() < self.config.window && self.size < self.config.max_bundle_size
+        {
+            return Vec::new();
+        }
+
+        let files = std::mem::take(&mut self.files);
+        self.size = 0;
+        self.window_started = None;
+
+        let bundle_name = format!("bundle-{}{}", uuid::Uuid::new_v4(), BUNDLE_SUFFIX);
+        let bundle_path = self.bundles_folder.join(&bundle_name);
+        if let Err(err) = self.write_bundle(&bundle_name, &bundle_path, &files) {
+            tracing::error!(
+                "Failed to write bundle of {} files, adding them on their own: {}",
+                files.len(),
+                err
+            );
+            return files
+                .into_iter()
+                .map(|(_, path)| (path, FileKind::Regular))
+                .collect();
+        }
+
+        for (_, path) in &files {
+            self.remove_pending_file(path);
+        }
+
+        tracing::info!("Bundled {} small files into {}", files.len(), bundle_name);
+
+        vec![(bundle_path, FileKind::Bundle)]
+    }
+
+    fn write_bundle(
+        &self,
+        bundle_name: &str,
+        bundle_path: &Path,
+        files: &[(BundleEntry, PathBuf)],
+    ) -> anyhow::Result<()> {
+        let tmp_path = self.bundling_folder.join(bundle_name);
+        let result =
+            write_bundle(&tmp_path, files).and_then(|()| std::fs::rename(&tmp_path, bundle_path));
+        if result.is_err() {
+            std::fs::remove_file(&tmp_path).ok();
+        }
+
+        Ok(result?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use common::tempdir::TempDirProvider;
+
+    use super::*;
+
+    #[test]
+    fn test_bundler() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let pending_folder = folder.path().join("pending");
+        std::fs::create_dir_all(pending_folder.join("camera"))?;
+
+        let config = BundlingConfig {
+            max_file_size: 100,
+            window: Duration::from_secs(3600),
+            max_bundle_size: 150,
+        };
+        let mut bundler = Bundler::new(
+            config,
+            pending_folder.clone(),
+            folder.path().join("b"),
+            folder.path().join("bundles"),
+        )?;
+
+        let small = pending_folder.join("camera/small.txt");
+        std::fs::write(&small, [1; 80])?;
+        let small_sidecar = sidecar::FileSidecar {
+            metadata: BTreeMap::from([("instrument".to_string(), "cam-2".to_string())]),
+            ..Default::default()
+        };
+        sidecar::write_sidecar_for(&small, &small_sidecar)?;
+        assert!(bundler.try_add(&small, "camera/small.txt"));
+
+        // Files with other options are added on their own
+        let prioritized = pending_folder.join("prioritized.txt");
+        std::fs::write(&prioritized, [2; 10])?;
+        let prioritized_sidecar = sidecar::FileSidecar {
+            priority: Some(3),
+            ..Default::default()
+        };
+        sidecar::write_sidecar_for(&prioritized, &prioritized_sidecar)?;
+        assert!(!bundler.try_add(&prioritized, "prioritized.txt"));
+
+        let large = pending_folder.join("large.bin");
+        std::fs::write(&large, [3; 101])?;
+        assert!(!bundler.try_add(&large, "large.bin"));
+
+        // The window isn't over, and the bundle isn't full yet
+        assert!(bundler.take_due_bundle().is_empty());
+
+        let other = pending_folder.join("other.txt");
+        std::fs::write(&other, [4; 80])?;
+        assert!(bundler.try_add(&other, "other.txt"));
+
+        let bundle_paths = bundler.take_due_bundle();
+        assert_eq!(bundle_paths.len(), 1);
+        let (bundle_path, kind) = &bundle_paths[0];
+        assert_eq!(*kind, FileKind::Bundle);
+        assert!(bundle_path.starts_with(bundler.bundles_folder()));
+        assert_eq!(bundler.waiting_bundles()?, vec![bundle_path.clone()]);
+        assert!(!small.exists());
+        assert!(!sidecar::sidecar_path_for(&small).exists());
+        assert!(!pending_folder.join("camera").exists());
+        assert!(!other.exists());
+
+        let mut reader = BundleReader::open(bundle_path)?;
+        let mut data = Vec::new();
+        let entry = reader.next_file(&mut data)?.unwrap();
+        assert_eq!(entry.name, "camera/small.txt");
+        assert_eq!(entry.metadata, small_sidecar.metadata);
+        assert_eq!(data, [1; 80]);
+        let entry = reader.next_file(&mut Vec::new())?.unwrap();
+        assert_eq!(entry.name, "other.txt");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted_bundle_cleanup() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let pending_folder = folder.path().join("pending");
+        let bundles_folder = folder.path().join("bundles");
+        std::fs::create_dir_all(pending_folder.join("camera"))?;
+        std::fs::create_dir_all(&bundles_folder)?;
+
+        // A bundle that was complete before a crash, but whose files weren't removed yet
+        let bundled = pending_folder.join("camera/bundled.txt");
+        std::fs::write(&bundled, [1; 10])?;
+        sidecar::write_sidecar_for(&bundled, &Default::default())?;
+        let replaced = pending_folder.join("replaced.txt");
+        std::fs::write(&replaced, [2; 10])?;
+
+        let entry = |name: &str, path: &Path| -> anyhow::Result<_> {
+            let entry = BundleEntry {
+                name: name.to_string(),
+                date: file_created_date(path)?,
+                size: 10,
+                metadata: Default::default(),
+            };
+            Ok((entry, path.to_path_buf()))
+        };
+        let files = vec![
+            entry("camera/bundled.txt", &bundled)?,
+            entry("replaced.txt", &replaced)?,
+        ];
+        let bundle_path = bundles_folder.join(format!("bundle{}", BUNDLE_SUFFIX));
+        write_bundle(&bundle_path, &files)?;
+
+        // A new file with the name of a bundled one arrived since
+        std::fs::write(&replaced, [3; 20])?;
+
+        let bundler = Bundler::new(
+            BundlingConfig {
+                max_file_size: 100,
+                window: Duration::from_secs(3600),
+                max_bundle_size: 150,
+            },
+            pending_folder.clone(),
+            folder.path().join("b"),
+            bundles_folder,
+        )?;
+        assert_eq!(bundler.waiting_bundles()?, vec![bundle_path]);
+        assert!(!bundled.exists());
+        assert!(!sidecar::sidecar_path_for(&bundled).exists());
+        assert!(!pending_folder.join("camera").exists());
+        assert!(replaced.exists());
+
+        Ok(())
+    }
+}
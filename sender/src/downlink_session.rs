@@ -2,68 +2,95 @@ use common::{
     chunks::Chunk,
     control::ControlMessage,
     file_part_id::FilePartId,
-    file_sending::storage_manager::{
-        cmp_file_storage_part_normal, SendingStorageManager, StorageFilePart,
+    file_sending::{
+        managed_sending_file::PartChecksumMismatch,
+        storage_manager::{SendingStorageManager, StorageFilePart},
     },
+    status::StatusMessage,
 };
 
-/// A single "downlink session". Sorts all chunks by priority and sends them all.
-/// Once all chunks are sent, it loops from the start. The downlink session can only
-/// reduce data used by the service, it can't add new files. Adding new files is
-/// handled by the StorageManager outside of downlink sessions.
+use crate::scheduling::SchedulingPolicy;
+
+/// A single "downlink session". Orders all chunks using the scheduling policy and sends
+/// them all. Once all chunks are sent, the remaining chunks are ordered again and it loops
+/// from the start. The downlink session can only reduce data used by the service, it can't
+/// add new files. Adding new files is handled by the StorageManager outside of downlink sessions.
+/// Open files can still grow, as appending parts doesn't move any stored data.
 pub struct DownlinkSession {
     storage: SendingStorageManager,
+    policy: Box<dyn SchedulingPolicy>,
     parts_queue: Vec<StorageFilePart>,
     parts_queue_index: usize,
 }
 
 impl DownlinkSession {
-    pub fn new(storage: SendingStorageManager) -> Self {
-        let mut parts_queue = storage
-            .iter_remaining_storage_file_parts()
-            .collect::<Vec<_>>();
-
-        // Sort from highest priority to lowest, we will index from the start and increment up.
-        parts_queue.sort_unstable_by(|a, b| cmp_file_storage_part_normal(b, a));
+    pub fn new(mut storage: SendingStorageManager, policy: Box<dyn SchedulingPolicy>) -> Self {
+        storage.begin_downlink_session();
 
-        Self {
+        let mut session = Self {
             storage,
-            parts_queue,
+            policy,
+            parts_queue: Vec::new(),
             parts_queue_index: 0,
-        }
+        };
+        session.rebuild_queue();
+
+        session
+    }
+
+    fn rebuild_queue(&mut self) {
+        let parts = self
+            .storage
+            .iter_remaining_storage_file_parts()
+            .collect::<Vec<_>>();
+
+        self.parts_queue = self.policy.order_parts(&self.storage, parts);
+        self.parts_queue_index = 0;
     }
 
     pub fn process_control(&mut self, control: ControlMessage) -> anyhow::Result<()> {
+        if let ControlMessage::ConfirmPart(confirm) = &control {
+            self.policy
+                .on_parts_acked(confirm.file_id, confirm.part_range.clone());
+        }
+
         self.storage.process_control(control)
     }
 
-    fn next_item(&mut self) -> Option<StorageFilePart> {
-        if self.parts_queue.is_empty() {
-            return None;
-        }
+    pub fn execute_due_time_tagged_commands(&mut self, now: i64) -> anyhow::Result<()> {
+        self.storage.execute_due_time_tagged_commands(now)
+    }
 
-        let item = self.parts_queue[self.parts_queue_index].clone();
-        self.parts_queue_index += 1;
-        if self.parts_queue_index >= self.parts_queue.len() {
-            self.parts_queue_index = 0;
-        }
+    pub fn restore_timed_out_in_flight_parts(&mut self, now: i64) -> anyhow::Result<()> {
+        self.storage.restore_timed_out_in_flight_parts(now)
+    }
+
+    pub fn delete_expired_files(&mut self, now: i64) -> anyhow::Result<()> {
+        self.storage.delete_expired_files(now)
+    }
 
-        Some(item)
+    /// Status messages take precedence over chunks, so they should be checked first.
+    pub fn next_status_message(&mut self) -> Option<StatusMessage> {
+        self.storage.pop_status_message()
     }
 
     pub fn next_chunk(&mut self) -> anyhow::Result<Option<Chunk>> {
-        let start_index = self.parts_queue_index;
+        let mut rebuilt_queue = false;
         loop {
-            let item = self.next_item();
-            let Some(item) = item else {
-                return Ok(None);
-            };
+            if self.parts_queue_index >= self.parts_queue.len() {
+                if rebuilt_queue {
+                    // We went through a fresh queue without finding anything, no more items
+                    return Ok(None);
+                }
 
-            if self.parts_queue_index == start_index {
-                // We looped around, no more items
-                return Ok(None);
+                self.rebuild_queue();
+                rebuilt_queue = true;
+                continue;
             }
 
+            let item = self.parts_queue[self.parts_queue_index].clone();
+            self.parts_queue_index += 1;
+
             let file = &mut self.storage.get_file(item.file_id);
             let Some(file) = file else {
                 // File likely deleted, due to acknowledgements
@@ -75,7 +102,12 @@ impl DownlinkSession {
                 Ok(None) => continue,
                 Ok(Some(chunk)) => chunk,
                 Err(err) => {
-                    tracing::warn!("Failed to get file part: {}", err);
+                    if let Some(mismatch) = err.downcast_ref::<PartChecksumMismatch>() {
+                        self.storage
+                            .handle_corrupted_parts(mismatch.file_id, vec![mismatch.part])?;
+                    } else {
+                        tracing::warn!("Failed to get file part: {}", err);
+                    }
                     continue;
                 }
             };
@@ -91,10 +123,24 @@ impl DownlinkSession {
         file_id: uuid::Uuid,
         part_id: FilePartId,
     ) -> anyhow::Result<()> {
+        self.policy.on_part_sent(file_id, part_id);
         self.storage.confirm_file_sent(file_id, part_id)
     }
 
-    pub fn into_storage_manager(self) -> SendingStorageManager {
+    pub fn storage(&self) -> &SendingStorageManager {
+        &self.storage
+    }
+
+    /// Appended parts are picked up the next time the queue is rebuilt
+    pub fn storage_mut(&mut self) -> &mut SendingStorageManager {
+        &mut self.storage
+    }
+
+    pub fn into_storage_manager(mut self) -> SendingStorageManager {
+        if let Err(err) = self.storage.end_downlink_session() {
+            tracing::error!("Failed to persist in-flight parts: {}", err);
+        }
+
         self.storage
     }
 }
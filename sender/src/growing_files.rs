@@ -0,0 +1,281 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    time::Instant,
+};
+
+use common::file_sending::{
+    managed_sending_file::file_created_date, storage_manager::SendingStorageManager,
+};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::GrowingFileConfig;
+
+/// At most this much data is appended to an open file per poll, so that the background runner
+/// stays responsive while a producer writes a lot at once
+const MAX_APPEND_SIZE: u64 = 1024 * 1024;
+
+/// Appends the data that producers write to growing files, e.g. logs, to open files in the
+/// storage, so that it can be downlinked before the file is complete. When a growing file is
+/// rotated, i.e. replaced by a new file at its path or truncated, the rest of the old file is
+/// appended and its open file is sealed. Removing the rotated file is left to the producer.
+pub struct GrowingFiles {
+    files: Vec<GrowingFile>,
+}
+
+struct GrowingFile {
+    config: GrowingFileConfig,
+    last_poll: Option<Instant>,
+    source: Option<OpenSource>,
+}
+
+/// A growing file that's being appended from. It's kept open, so that it can be read to the
+/// end after it was rotated.
+struct OpenSource {
+    file_id: Uuid,
+    file: File,
+    /// When the file was created, to tell it apart from the file that replaces it
+    created: i64,
+    /// How much of the file was appended
+    offset: u64,
+    /// The digest of the appended data
+    hasher: Sha256,
+}
+
+impl GrowingFiles {
+    pub fn new(configs: Vec<GrowingFileConfig>) -> Self {
+        let files = configs
+            .into_iter()
+            .map(|config| GrowingFile {
+                config,
+                last_poll: None,
+                source: None,
+            })
+            .collect();
+
+        Self { files }
+    }
+
+    /// Append the new data of the growing files that are due, and seal the ones that were
+    /// rotated. Returns how many files failed.
+    pub fn poll(&mut self, storage: &mut SendingStorageManager) -> u32 {
+        let mut error_count = 0;
+
+        for file in &mut self.files {
+            let due = file
+                .last_poll
+                .is_none_or(|last_poll| last_poll.elapsed() >= file.config.append_interval);
+            if !due {
+                continue;
+            }
+
+            match file.poll(storage) {
+                // A producer that writes faster than the limit is polled again right away
+                Ok(caught_up) => file.last_poll = caught_up.then(Instant::now),
+                Err(err) => {
+                    error_count += 1;
+                    file.last_poll = Some(Instant::now());
+                    tracing::error!(
+                        "Error appending growing file {:?}: {}",
+                        file.config.path,
+                        err
+                    );
+                }
+            }
+        }
+
+        error_count
+    }
+}
+
+impl GrowingFile {
+    /// Returns false if there's more data to append than the limit allows for a single poll.
+    fn poll(&mut self, storage: &mut SendingStorageManager) -> anyhow::Result<bool> {
+        let path = &self.config.path;
+        let current = match std::fs::metadata(path) {
+            Ok(metadata) => Some((file_created_date(path)?, metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        let source = match &mut self.source {
+            Some(source) => source,
+            None => {
+                // Nothing to do until the producer creates the file
+                let Some((created, _)) = current else {
+                    return Ok(true);
+                };
+                self.source.insert(self.open_source(storage, created)?)
+            }
+        };
+
+        let rotated = match current {
+            Some((created, len)) => created != source.created || len < source.offset,
+            None => true,
+        };
+        if rotated {
+            let data = source.read_from_offset(u64::MAX)?;
+            let mut hasher = source.hasher.clone();
+            hasher.update(&data);
+            storage.seal_open_file(source.file_id, &data, Some(hasher.finalize().into()))?;
+
+            tracing::info!(
+                "Growing file {:?} was rotated, sealed file {}",
+                path,
+                source.file_id
+            );
+
+            // The file that replaced it is picked up on the next poll
+            self.source = None;
+            return Ok(true);
+        }
+
+        // Only full parts can be appended before the file is sealed
+        let part_size = storage
+            .get_file(source.file_id)
+            .map(|file| file.header().file_part_size as u64)
+            .unwrap_or(MAX_APPEND_SIZE)
+            .min(MAX_APPEND_SIZE);
+        let max_len = MAX_APPEND_SIZE - MAX_APPEND_SIZE % part_size;
+        let mut data = source.read_from_offset(max_len)?;
+        data.truncate(data.len() - data.len() % part_size as usize);
+        if data.is_empty() {
+            return Ok(true);
+        }
+
+        storage.append_to_open_file(source.file_id, &data)?;
+        source.offset += data.len() as u64;
+        source.hasher.update(&data);
+
+        Ok((data.len() as u64) < max_len)
+    }
+
+    fn open_source(
+        &self,
+        storage: &mut SendingStorageManager,
+        created: i64,
+    ) -> anyhow::Result<OpenSource> {
+        let path = &self.config.path;
+        let mut file = File::open(path)?;
+
+        // Continue the open file from before a restart, if it's still the same file
+        if let Some(open_file) = storage.find_open_file(path) {
+            let file_id = open_file.header().id;
+            if open_file.header().date == created {
+                // The digest is of the whole file, so the appended data is hashed again
+                let offset = open_file.header().size;
+                let mut hasher = Sha256::new();
+                io::copy(&mut (&mut file).take(offset), &mut hasher)?;
+
+                return Ok(OpenSource {
+                    file_id,
+                    file,
+                    created,
+                    offset,
+                    hasher,
+                });
+            }
+
+            tracing::warn!(
+                "Growing file {:?} was rotated while the sender wasn't running, sealing file {} without the rest of its data",
+                path,
+                file_id
+            );
+            storage.seal_open_file(file_id, &[], None)?;
+        }
+
+        let file_id = storage.add_open_file(path, self.config.options.clone())?;
+
+        Ok(OpenSource {
+            file_id,
+            file,
+            created,
+            offset: 0,
+            hasher: Sha256::new(),
+        })
+    }
+}
+
+impl OpenSource {
+    /// Read up to `max_len` bytes of data that weren't appended yet.
+    fn read_from_offset(&mut self, max_len: u64) -> io::Result<Vec<u8>> {
+        self.file.seek(SeekFrom::Start(self.offset))?;
+
+        let mut data = Vec::new();
+        (&mut self.file).take(max_len).read_to_end(&mut data)?;
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, time::Duration};
+
+    use common::{
+        file_sending::storage_manager::{NewFileOptions, SendingStorageManagerConfig},
+        tempdir::TempDirProvider,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_growing_file() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let mut storage = SendingStorageManager::new(
+            folder.path().join("storage"),
+            SendingStorageManagerConfig {
+                new_file_chunk_size: 4,
+                max_folder_size: None,
+                split_file_if_n_chunks_saved: None,
+                ..Default::default()
+            },
+        )?;
+
+        let log_path = folder.path().join("payload.log");
+        let mut growing_files = GrowingFiles::new(vec![GrowingFileConfig {
+            path: log_path.clone(),
+            append_interval: Duration::ZERO,
+            options: NewFileOptions::default(),
+        }]);
+
+        // Nothing happens until the file exists
+        assert_eq!(growing_files.poll(&mut storage), 0);
+        assert_eq!(storage.iter_files().count(), 0);
+
+        let mut log = File::create(&log_path)?;
+        log.write_all(b"first line\n")?;
+        assert_eq!(growing_files.poll(&mut storage), 0);
+        let file = storage.find_open_file(&log_path).unwrap();
+        let file_id = file.header().id;
+        assert_eq!(file.header().size, 8);
+
+        // A restart continues the same open file
+        let mut growing_files = GrowingFiles::new(vec![growing_files.files.remove(0).config]);
+        log.write_all(b"second\n")?;
+        assert_eq!(growing_files.poll(&mut storage), 0);
+        assert_eq!(storage.get_file(file_id).unwrap().header().size, 16);
+
+        // Rotating the file seals it with the rest of its data
+        std::fs::rename(&log_path, folder.path().join("payload.log.1"))?;
+        log.write_all(b"end")?;
+        assert_eq!(growing_files.poll(&mut storage), 0);
+        let header = storage.get_file(file_id).unwrap().header();
+        assert!(!header.open);
+        assert_eq!(header.size, 21);
+        assert_eq!(
+            header.sha256,
+            Some(Sha256::digest(b"first line\nsecond\nend").into())
+        );
+
+        std::fs::write(&log_path, b"new log\n")?;
+        assert_eq!(growing_files.poll(&mut storage), 0);
+        assert_ne!(
+            storage.find_open_file(&log_path).unwrap().header().id,
+            file_id
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use common::{
+    file_sending::storage_manager::SendingStorageManager,
+    status::{Heartbeat, RunnerState},
+};
+
+use crate::input_folder;
+
+/// Health statistics of the background runner. They're carried between the runner states,
+/// and reported on the downlink through heartbeats.
+pub struct RunnerHealth {
+    started_at: Instant,
+    pending_folder: PathBuf,
+
+    /// When the last control message was received, in nanoseconds since the unix epoch
+    pub last_control_time: Option<i64>,
+    pub control_error_count: u32,
+    pub add_file_error_count: u32,
+    pub downlink_error_count: u32,
+}
+
+impl RunnerHealth {
+    pub fn new(pending_folder: PathBuf) -> Self {
+        Self {
+            started_at: Instant::now(),
+            pending_folder,
+            last_control_time: None,
+            control_error_count: 0,
+            add_file_error_count: 0,
+            downlink_error_count: 0,
+        }
+    }
+
+    pub fn make_heartbeat(
+        &self,
+        storage: &SendingStorageManager,
+        runner_state: RunnerState,
+    ) -> Heartbeat {
+        Heartbeat {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            runner_state,
+            managed_file_count: storage.iter_files().count() as u32,
+            stored_bytes: storage.calc_total_data_size(),
+            pending_file_count: self.count_pending_files(),
+            last_control_time: self.last_control_time,
+            control_error_count: self.control_error_count,
+            add_file_error_count: self.add_file_error_count,
+            downlink_error_count: self.downlink_error_count,
+        }
+    }
+
+    fn count_pending_files(&self) -> u32 {
+        match input_folder::find_files_recursive(&self.pending_folder) {
+            Ok(files) => files.len() as u32,
+            Err(err) => {
+                tracing::warn!("Failed to read pending folder for heartbeat: {}", err);
+                0
+            }
+        }
+    }
+
+    pub fn pending_folder(&self) -> &Path {
+        &self.pending_folder
+    }
+}
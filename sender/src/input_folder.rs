@@ -0,0 +1,97 @@
+use std::path::{Component, Path, PathBuf};
+
+use common::file_sending::managed_sending_file::COMPRESSING_SUFFIX;
+
+use crate::sidecar;
+
+/// Find all the files in a folder and its subfolders, without the sidecars and the leftovers of
+/// interrupted compression. Instruments write into subfolders, e.g. `camera/2026-10-16/img_001.raw`.
+pub fn find_files_recursive(folder: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut folders = vec![folder.to_path_buf()];
+
+    while let Some(folder) = folders.pop() {
+        for entry in std::fs::read_dir(&folder)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                folders.push(path);
+            } else if path.is_file()
+                && !sidecar::is_sidecar_path(&path)
+                && !path.to_string_lossy().ends_with(COMPRESSING_SUFFIX)
+            {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// The path of a file relative to the folder it was found in, with `/` as the separator.
+/// This is the name that's sent in the file header.
+pub fn relative_name(folder: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(folder).ok()?;
+
+    let components = relative
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    if components.is_empty() {
+        return None;
+    }
+
+    Some(components.join("/"))
+}
+
+/// Remove the folders between a file and the root folder that became empty, after the file
+/// was moved out. The root folder itself is kept.
+pub fn remove_empty_parents(root: &Path, path: &Path) {
+    for folder in path.ancestors().skip(1) {
+        if folder == root || !folder.starts_with(root) {
+            return;
+        }
+
+        // Fails if the folder isn't empty, which is where we stop
+        if std::fs::remove_dir(folder).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::tempdir::TempDirProvider;
+
+    use super::*;
+
+    #[test]
+    fn test_recursive_input_files() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let root = folder.path();
+
+        let nested = root.join("camera/2026-10-16/img_001.raw");
+        std::fs::create_dir_all(nested.parent().unwrap())?;
+        std::fs::write(&nested, [1, 2, 3])?;
+        std::fs::write(sidecar::sidecar_path_for(&nested), "{}")?;
+        std::fs::write(root.join("top.bin"), [4])?;
+
+        let mut names = find_files_recursive(root)?
+            .iter()
+            .map(|path| relative_name(root, path).unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["camera/2026-10-16/img_001.raw", "top.bin"]);
+
+        std::fs::remove_file(sidecar::sidecar_path_for(&nested))?;
+        std::fs::remove_file(&nested)?;
+        remove_empty_parents(root, &nested);
+        assert!(!root.join("camera").exists());
+        assert!(root.exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,463 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime},
+};
+
+use crate::{input_folder, sidecar, InputWatchConfig, InputWatchMode};
+
+/// Finds files in the input folder that the producer finished writing. With inotify, a file is
+/// finished once it's closed after writing or moved into the folder, along with the files of a
+/// folder that's moved in. Files that were already there on startup or when events were lost,
+/// or all files when polling, are finished once they stopped changing for the settle time.
+///
+/// A folder that's created is watched as soon as its event is read. Files can be created in it
+/// before that, e.g. with `mkdir -p camera/2026-10-16 && cp img ...`, and have no events, so
+/// the files that are already there have to settle. Folders that couldn't be watched, e.g.
+/// because the watch limit was reached, are scanned like when polling.
+pub struct InputWatcher {
+    input_folder: PathBuf,
+    config: InputWatchConfig,
+    #[cfg(target_os = "linux")]
+    inotify: Option<inotify_watch::InotifyWatch>,
+    #[cfg(target_os = "linux")]
+    unwatched_folders: HashSet<PathBuf>,
+    settling: SettlingFiles,
+}
+
+impl InputWatcher {
+    pub fn new(input_folder: PathBuf, config: InputWatchConfig) -> anyhow::Result<Self> {
+        #[cfg(target_os = "linux")]
+        let inotify = match config.mode {
+            InputWatchMode::Polling => None,
+            InputWatchMode::Inotify => Some(inotify_watch::InotifyWatch::new(&input_folder)?),
+            InputWatchMode::Auto => match inotify_watch::InotifyWatch::new(&input_folder) {
+                Ok(inotify) => Some(inotify),
+                Err(err) => {
+                    tracing::warn!("Failed to set up inotify, polling instead: {}", err);
+                    None
+                }
+            },
+        };
+
+        #[cfg(not(target_os = "linux"))]
+        if config.mode == InputWatchMode::Inotify {
+            anyhow::bail!("Inotify is only available on Linux");
+        }
+
+        let mut watcher = Self {
+            input_folder,
+            config,
+            #[cfg(target_os = "linux")]
+            inotify,
+            #[cfg(target_os = "linux")]
+            unwatched_folders: HashSet::new(),
+            settling: SettlingFiles::default(),
+        };
+
+        // Files that are already there may still be written to, and we'll get no event for
+        // them if they aren't
+        watcher.rescan();
+
+        Ok(watcher)
+    }
+
+    /// Wait up to the poll interval, and return the files that are ready to be ingested.
+    pub fn next_ready_files(&mut self) -> Vec<PathBuf> {
+        let mut ready = Vec::new();
+
+        #[cfg(target_os = "linux")]
+        if let Some(inotify) = &mut self.inotify {
+            let changes = inotify.read_changes();
+            if changes.overflowed {
+                tracing::warn!("Inotify event queue overflowed, rescanning input folder");
+                self.rescan();
+            } else {
+                self.settling.refresh();
+                for folder in changes.created_folders {
+                    self.settling.track_folder(&folder);
+                }
+            }
+
+            self.unwatched_folders.extend(changes.unwatched_folders);
+            self.unwatched_folders.retain(|folder| folder.is_dir());
+            for folder in &self.unwatched_folders {
+                self.settling.track_folder(folder);
+            }
+
+            for path in changes.finished_files {
+                self.settling.forget(&path);
+                ready.push(path);
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        let polling = self.inotify.is_none();
+        #[cfg(not(target_os = "linux"))]
+        let polling = true;
+
+        if polling {
+            self.rescan();
+        }
+
+        ready.extend(self.settling.take_settled(self.config.settle_time));
+        ready.retain(|path| !self.is_ignored(path));
+
+        if ready.is_empty() {
+            std::thread::sleep(self.config.poll_interval);
+        }
+
+        ready
+    }
+
+    fn rescan(&mut self) {
+        self.settling.track_folder(&self.input_folder);
+    }
+
+    fn is_ignored(&self, path: &Path) -> bool {
+        if sidecar::is_sidecar_path(path) {
+            return true;
+        }
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        self.config
+            .ignored_suffixes
+            .iter()
+            .any(|suffix| name.ends_with(suffix.as_str()))
+    }
+}
+
+/// Files that were seen, but aren't known to be finished yet
+#[derive(Default)]
+struct SettlingFiles {
+    files: HashMap<PathBuf, SettlingFile>,
+}
+
+struct SettlingFile {
+    len: u64,
+    modified: Option<SystemTime>,
+    unchanged_since: Instant,
+}
+
+impl SettlingFiles {
+    /// Track all the files in a folder and its subfolders, and drop the tracked files inside of
+    /// it that are gone.
+    fn track_folder(&mut self, folder: &Path) {
+        let files = match input_folder::find_files_recursive(folder) {
+            Ok(files) => files,
+            Err(err) => {
+                tracing::error!("Failed to read input folder {:?}: {}", folder, err);
+                return;
+            }
+        };
+
+        self.files
+            .retain(|path, _| !path.starts_with(folder) || files.contains(path));
+
+        for path in files {
+            self.update(path);
+        }
+    }
+
+    /// Check the tracked files for changes again, without looking for new ones.
+    fn refresh(&mut self) {
+        let paths = self.files.keys().cloned().collect::<Vec<_>>();
+        for path in paths {
+            self.update(path);
+        }
+    }
+
+    fn update(&mut self, path: PathBuf) {
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            self.files.remove(&path);
+            return;
+        };
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+
+        let now = Instant::now();
+        let file = self.files.entry(path).or_insert(SettlingFile {
+            len,
+            modified,
+            unchanged_since: now,
+        });
+        if file.len != len || file.modified != modified {
+            file.len = len;
+            file.modified = modified;
+            file.unchanged_since = now;
+        }
+    }
+
+    fn forget(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    fn take_settled(&mut self, settle_time: std::time::Duration) -> Vec<PathBuf> {
+        let settled = self
+            .files
+            .iter()
+            .filter(|(_, file)| file.unchanged_since.elapsed() >= settle_time)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+
+        for path in &settled {
+            self.files.remove(path);
+        }
+
+        settled
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify_watch {
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+    };
+
+    use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+    use crate::input_folder;
+
+    /// Watches a folder and all its subfolders
+    pub struct InotifyWatch {
+        inotify: Inotify,
+        folders: HashMap<WatchDescriptor, PathBuf>,
+        buffer: Vec<u8>,
+    }
+
+    #[derive(Default)]
+    pub struct InotifyChanges {
+        /// Files that were closed after writing, or moved into a watched folder, including the
+        /// files of folders that were moved in
+        pub finished_files: Vec<PathBuf>,
+        /// Folders that were created and are watched now. Their files may have been written
+        /// before that, so they have no events.
+        pub created_folders: Vec<PathBuf>,
+        /// Folders that couldn't be watched, so they have to be scanned for files
+        pub unwatched_folders: Vec<PathBuf>,
+        /// Events were lost, so the whole folder should be scanned again
+        pub overflowed: bool,
+    }
+
+    impl InotifyWatch {
+        pub fn new(folder: &Path) -> std::io::Result<Self> {
+            let mut watch = Self {
+                inotify: Inotify::init()?,
+                folders: HashMap::new(),
+                buffer: vec![0; 4096],
+            };
+            watch.watch_recursive(folder)?;
+
+            Ok(watch)
+        }
+
+        fn watch_recursive(&mut self, folder: &Path) -> std::io::Result<()> {
+            let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE;
+            let descriptor = self.inotify.watches().add(folder, mask)?;
+            self.folders.insert(descriptor, folder.to_path_buf());
+
+            for entry in std::fs::read_dir(folder)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    self.watch_recursive(&path)?;
+                }
+            }
+
+            Ok(())
+        }
+
+        /// Read the events that happened since the last call, without blocking.
+        pub fn read_changes(&mut self) -> InotifyChanges {
+            let mut changes = InotifyChanges::default();
+            let mut new_folders = Vec::new();
+
+            loop {
+                let events = match self.inotify.read_events(&mut self.buffer) {
+                    Ok(events) => events,
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        tracing::error!("Failed to read inotify events: {}", err);
+                        changes.overflowed = true;
+                        break;
+                    }
+                };
+
+                for event in events {
+                    if event.mask.contains(EventMask::Q_OVERFLOW) {
+                        changes.overflowed = true;
+                        continue;
+                    }
+                    if event.mask.contains(EventMask::IGNORED) {
+                        self.folders.remove(&event.wd);
+                        continue;
+                    }
+
+                    let (Some(folder), Some(name)) = (self.folders.get(&event.wd), event.name)
+                    else {
+                        continue;
+                    };
+                    let path = folder.join(name);
+
+                    if event.mask.contains(EventMask::ISDIR) {
+                        let moved_in = event.mask.contains(EventMask::MOVED_TO);
+                        new_folders.push((path, moved_in));
+                    } else if event
+                        .mask
+                        .intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO)
+                    {
+                        changes.finished_files.push(path);
+                    }
+                }
+            }
+
+            for (folder, moved_in) in new_folders {
+                if let Err(err) = self.watch_recursive(&folder) {
+                    tracing::error!("Failed to watch new input folder {:?}: {}", folder, err);
+                    changes.unwatched_folders.push(folder.clone());
+                }
+
+                // A folder is moved in once its files are written. The files of a created
+                // folder may still be written to, and those that are closed from now on get
+                // their own events.
+                if moved_in {
+                    match input_folder::find_files_recursive(&folder) {
+                        Ok(files) => changes.finished_files.extend(files),
+                        Err(err) => {
+                            tracing::error!("Failed to read input folder {:?}: {}", folder, err)
+                        }
+                    }
+                } else {
+                    changes.created_folders.push(folder);
+                }
+            }
+
+            // A file can be closed more than once, and may be gone by now
+            changes.finished_files.retain(|path| path.is_file());
+            changes.finished_files.sort();
+            changes.finished_files.dedup();
+
+            changes
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use common::tempdir::TempDirProvider;
+
+    use super::*;
+
+    /// Move the ready files out of the input folder, like the file poller does
+    fn ingest(watcher: &mut InputWatcher, ingested: &mut Vec<PathBuf>) -> std::io::Result<()> {
+        for path in watcher.next_ready_files() {
+            std::fs::remove_file(&path)?;
+            ingested.push(path);
+        }
+
+        Ok(())
+    }
+
+    fn check_watcher(mode: InputWatchMode) -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let root = folder.path().clone();
+
+        let config = InputWatchConfig {
+            mode,
+            poll_interval: Duration::from_millis(10),
+            settle_time: Duration::from_millis(200),
+            ignored_suffixes: vec![".part".to_string()],
+        };
+
+        std::fs::write(root.join("existing.bin"), [1])?;
+        let mut watcher = InputWatcher::new(root.clone(), config)?;
+
+        // Files that are still being written to are never ready
+        std::fs::create_dir_all(root.join("camera"))?;
+        let mut writing = std::fs::File::create(root.join("camera/img.raw"))?;
+        std::fs::write(root.join("camera/img.raw.part"), [4])?;
+
+        let mut ready = Vec::new();
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_millis(400) {
+            std::io::Write::write_all(&mut writing, &[1, 2, 3])?;
+            ingest(&mut watcher, &mut ready)?;
+        }
+        assert_eq!(ready, vec![root.join("existing.bin")]);
+
+        drop(writing);
+
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_millis(400) {
+            ingest(&mut watcher, &mut ready)?;
+        }
+        assert_eq!(
+            ready,
+            vec![root.join("existing.bin"), root.join("camera/img.raw")]
+        );
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_inotify_new_folders() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let root = folder.path().join("input");
+        std::fs::create_dir_all(&root)?;
+
+        let config = InputWatchConfig {
+            mode: InputWatchMode::Inotify,
+            poll_interval: Duration::from_millis(10),
+            settle_time: Duration::from_millis(100),
+            ignored_suffixes: Vec::new(),
+        };
+        let mut watcher = InputWatcher::new(root.clone(), config)?;
+
+        // Files in a created folder aren't ready while they're open, even if they stop changing
+        std::fs::create_dir_all(root.join("created"))?;
+        let mut ready = Vec::new();
+        ingest(&mut watcher, &mut ready)?;
+        let mut writing = std::fs::File::create(root.join("created/img.raw"))?;
+        std::io::Write::write_all(&mut writing, &[1, 2, 3])?;
+
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_millis(300) {
+            ingest(&mut watcher, &mut ready)?;
+        }
+        assert!(ready.is_empty());
+
+        drop(writing);
+        ingest(&mut watcher, &mut ready)?;
+        assert_eq!(ready, vec![root.join("created/img.raw")]);
+
+        // The files of a folder that's moved in are ready right away
+        std::fs::create_dir_all(folder.path().join("moved/nested"))?;
+        std::fs::write(folder.path().join("moved/nested/log.txt"), [1])?;
+        std::fs::rename(folder.path().join("moved"), root.join("moved"))?;
+        ingest(&mut watcher, &mut ready)?;
+        assert_eq!(ready[1..], [root.join("moved/nested/log.txt")]);
+
+        // Files that were written before their created folder was watched settle instead
+        std::fs::create_dir_all(root.join("camera/2026-10-16"))?;
+        std::fs::write(root.join("camera/2026-10-16/img.raw"), [1])?;
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_millis(300) {
+            ingest(&mut watcher, &mut ready)?;
+        }
+        assert_eq!(ready[2..], [root.join("camera/2026-10-16/img.raw")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_watcher() -> anyhow::Result<()> {
+        #[cfg(target_os = "linux")]
+        check_watcher(InputWatchMode::Inotify)?;
+        check_watcher(InputWatchMode::Polling)?;
+
+        Ok(())
+    }
+}
@@ -1,52 +1,379 @@
-use std::{io::Read, path::PathBuf, sync::Mutex, thread::JoinHandle};
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread::JoinHandle,
+    time::Duration,
+};
 
 use anyhow::Context;
 use common::{
-    chunks::Chunk,
-    file_sending::storage_manager::SendingStorageManagerConfig,
-    transport_packet::{parse_transport_packet_stream, TransportPacket, TransportPacketData},
+    chunks::FileKind,
+    file_sending::storage_manager::{NewFileOptions, SendingStorageManagerConfig},
+    status::Heartbeat,
+    transport_packet::{parse_transport_packet_stream, TransportPacket},
 };
 use crossbeam_channel::{Receiver, Sender};
 
-use self::background_runner::{run_downlink_server_bg_runner, DownlinkServerMessage};
+use self::{
+    background_runner::{run_downlink_server_bg_runner, DownlinkPacket, DownlinkServerMessage},
+    bundler::Bundler,
+    input_watcher::InputWatcher,
+    scheduling::SchedulingPolicyConfig,
+};
 
 mod background_runner;
+mod bundler;
 mod downlink_session;
+mod growing_files;
+mod health;
+mod input_folder;
+mod input_watcher;
+pub mod scheduling;
+mod sidecar;
+
+#[derive(Debug, Clone)]
+pub struct DownlinkServerConfig {
+    pub storage: SendingStorageManagerConfig,
+
+    /// Send a heartbeat every n packets during a downlink session. A heartbeat is always
+    /// sent at the start of every session.
+    pub heartbeat_interval_packets: Option<u32>,
+
+    /// Make a heartbeat every interval while waiting for a downlink session, so that the
+    /// runner can be seen alive between sessions, see [`DownlinkServer::waiting_heartbeats`].
+    /// It's checked with the other periodic work, so at most once a second.
+    pub waiting_heartbeat_interval: Option<Duration>,
+
+    /// The order in which file parts are sent during a downlink session
+    pub scheduling_policy: SchedulingPolicyConfig,
+
+    /// The time-to-live of new files. Can be overridden per input source with its policy, and
+    /// per file with a sidecar.
+    pub file_ttl: FileTtlConfig,
+
+    /// Verify the checksums of one stored file every interval, while waiting for a downlink
+    /// session. Corrupted parts are handled according to the storage config.
+    pub scrub_interval: Option<Duration>,
+
+    /// How the input folder is watched for new files
+    pub input_watch: InputWatchConfig,
+
+    /// Pack small files into bundles, instead of sending each of them as its own file
+    pub bundling: Option<BundlingConfig>,
+
+    /// Files that are downlinked while they're still being written to, e.g. logs
+    pub growing_files: Vec<GrowingFileConfig>,
+}
+
+/// A folder that a producer, e.g. an instrument, writes its files into
+#[derive(Debug, Clone)]
+pub struct InputSourceConfig {
+    /// Recorded on the files from this source, so they can be selected by source in bulk
+    /// control messages. It's also the name of the source's folder in the pending folder.
+    pub id: String,
+
+    pub folder: PathBuf,
+
+    pub policy: SourcePolicy,
+}
+
+/// How the files from an input source are stored. Sidecars take precedence over the policy.
+#[derive(Debug, Clone, Default)]
+pub struct SourcePolicy {
+    /// The size of the file parts, instead of the storage's `new_file_chunk_size`
+    pub chunk_size: Option<u32>,
+
+    /// The initial priority of all the parts of the files
+    pub priority: Option<i16>,
+
+    /// The time-to-live of the files, instead of the `default_ttl` from the `file_ttl` config
+    pub ttl: Option<Duration>,
+
+    /// Maximum size of the stored data of the source's files in bytes. The source's own files
+    /// are evicted to stay within it.
+    pub quota: Option<u64>,
+
+    /// Only catalog the files, and send them once the ground requests them. The files aren't
+    /// bundled, so that they can be requested one by one.
+    pub catalog_only: bool,
+}
+
+impl SourcePolicy {
+    /// Fill in the options of a file that weren't set otherwise
+    pub fn apply_to(&self, options: &mut NewFileOptions) {
+        options.chunk_size = options.chunk_size.or(self.chunk_size);
+        options.priority = options.priority.or(self.priority);
+        options.ttl = options.ttl.or(self.ttl);
+        options.catalog_only |= self.catalog_only;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GrowingFileConfig {
+    /// The path that the producer keeps writing to, e.g. `/var/log/payload.log`. Once it's
+    /// rotated, the old file is sealed and a new open file is started for the new one.
+    pub path: PathBuf,
+
+    /// How often new data is appended. Only full parts are appended until the file is sealed.
+    pub append_interval: Duration,
+
+    /// The options of the open files. Open files can't be compressed or bundled.
+    pub options: NewFileOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct BundlingConfig {
+    /// Files up to this size are bundled. Files with a sidecar that sets anything besides
+    /// metadata are never bundled.
+    pub max_file_size: u64,
+
+    /// How long to collect files for, after the first file of a bundle arrived
+    pub window: Duration,
+
+    /// A bundle is closed before the window is over once its files add up to this size
+    pub max_bundle_size: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputWatchMode {
+    /// Use inotify where it's available, and poll otherwise
+    #[default]
+    Auto,
+    /// Fail to start if inotify isn't available
+    Inotify,
+    Polling,
+}
+
+#[derive(Debug, Clone)]
+pub struct InputWatchConfig {
+    pub mode: InputWatchMode,
+
+    /// How often to check for changes
+    pub poll_interval: Duration,
+
+    /// When polling, files are ingested once their size and modification time haven't changed
+    /// for this long. With inotify this only applies to files that were there on startup, or
+    /// when events were lost because the event queue overflowed.
+    pub settle_time: Duration,
+
+    /// Files ending with any of these are never ingested, e.g. the temporary files of a
+    /// producer that renames them once they're written.
+    pub ignored_suffixes: Vec<String>,
+}
+
+impl Default for InputWatchConfig {
+    fn default() -> Self {
+        Self {
+            mode: InputWatchMode::Auto,
+            poll_interval: Duration::from_millis(100),
+            settle_time: Duration::from_secs(2),
+            ignored_suffixes: vec![".part".to_string(), ".tmp".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileTtlConfig {
+    /// The time-to-live of files that neither their sidecar nor the policy of their input
+    /// source sets one for
+    pub default_ttl: Option<Duration>,
+}
 
 pub struct DownlinkServer {
+    pending_folder: PathBuf,
+    sources: HashMap<String, InputSourceConfig>,
     background_runner_messages: Sender<DownlinkServerMessage>,
+    waiting_heartbeats: Receiver<Heartbeat>,
     join_handles: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl DownlinkServer {
     pub fn spawn(
-        input_folder: PathBuf,
+        sources: Vec<InputSourceConfig>,
         workdir: PathBuf,
-        storage_config: SendingStorageManagerConfig,
+        mut config: DownlinkServerConfig,
     ) -> anyhow::Result<Self> {
         let (message_snd, message_rcv) = crossbeam_channel::unbounded();
+        let (heartbeat_snd, heartbeat_rcv) = crossbeam_channel::bounded(1);
 
         let pending_folder = workdir.join("pending");
         let ready_folder = workdir.join("ready");
 
+        let mut source_ids = HashMap::new();
+        for source in &sources {
+            let is_folder_name = !source.id.is_empty()
+                && !source.id.contains(['/', '\\'])
+                && source.id != "."
+                && source.id != "..";
+            anyhow::ensure!(is_folder_name, "Invalid input source id: {:?}", source.id);
+            anyhow::ensure!(
+                source_ids
+                    .insert(source.id.clone(), source.clone())
+                    .is_none(),
+                "Duplicate input source id: {}",
+                source.id
+            );
+
+            // The quotas are enforced by the storage, when files are added
+            if let Some(quota) = source.policy.quota {
+                config
+                    .storage
+                    .source_quotas
+                    .insert(source.id.clone(), quota);
+            }
+        }
+
         // Create the folders
-        std::fs::create_dir_all(&input_folder)?;
         std::fs::create_dir_all(&pending_folder)?;
         std::fs::create_dir_all(&ready_folder)?;
 
-        let poller_join_handle =
-            spawn_file_poller(input_folder, pending_folder, message_snd.clone())
-                .context("Failed to spawn file poller")?;
+        // Files that were added directly are in the pending folder itself, rather than in the
+        // folder of a source
+        for path in input_folder::find_files_recursive(&pending_folder)? {
+            let in_source_folder = sources
+                .iter()
+                .any(|source| path.starts_with(pending_folder.join(&source.id)));
+            if in_source_folder {
+                continue;
+            }
 
-        let server_join_handle =
-            run_downlink_server_bg_runner(ready_folder, message_rcv, storage_config)?;
+            let options = pending_file_options(
+                &pending_folder,
+                &path,
+                None,
+                &config.file_ttl,
+                FileKind::Regular,
+            );
+            message_snd
+                .send(DownlinkServerMessage::AddFile(path, options))
+                .context("Failed to add pending file to queue")?;
+        }
+
+        let mut join_handles = Vec::new();
+        for source in sources {
+            let source_pending_folder = pending_folder.join(&source.id);
+            std::fs::create_dir_all(&source.folder)?;
+            std::fs::create_dir_all(&source_pending_folder)?;
+
+            let bundler = match &config.bundling {
+                Some(bundling) if !source.policy.catalog_only => Some(
+                    Bundler::new(
+                        bundling.clone(),
+                        source_pending_folder.clone(),
+                        workdir.join("bundling").join(&source.id),
+                        workdir.join("bundles").join(&source.id),
+                    )
+                    .context("Failed to create bundling folder")?,
+                ),
+                _ => None,
+            };
+
+            let source_id = source.id.clone();
+            let poller_join_handle = spawn_file_poller(
+                source,
+                source_pending_folder,
+                message_snd.clone(),
+                config.file_ttl.clone(),
+                config.input_watch.clone(),
+                bundler,
+            )
+            .with_context(|| format!("Failed to spawn file poller of source {}", source_id))?;
+            join_handles.push(poller_join_handle);
+        }
+
+        let server_join_handle = run_downlink_server_bg_runner(
+            ready_folder,
+            pending_folder.clone(),
+            message_rcv,
+            heartbeat_snd,
+            config,
+        )?;
+        join_handles.insert(0, server_join_handle);
 
         Ok(Self {
+            pending_folder,
+            sources: source_ids,
             background_runner_messages: message_snd,
-            join_handles: Mutex::new(vec![server_join_handle, poller_join_handle]),
+            waiting_heartbeats: heartbeat_rcv,
+            join_handles: Mutex::new(join_handles),
         })
     }
 
+    /// Add a file directly, instead of through an input folder. The file is moved into the
+    /// pending folder, which must be on the same filesystem. The options are kept in a sidecar
+    /// until the file is added to the storage. A name with subfolders, e.g.
+    /// `camera/img_001.raw`, is kept by placing the file in the same subfolder of the pending
+    /// folder.
+    ///
+    /// If the source is one of the input sources, then its policy applies, and the file is
+    /// placed in the source's pending folder. The source of other files is lost on a restart,
+    /// so their names can't start with the id of an input source.
+    pub fn add_file(
+        &self,
+        path: impl AsRef<Path>,
+        mut options: NewFileOptions,
+    ) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let name = match options.name.take() {
+            Some(name) => name,
+            None => path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .context("File path has no UTF-8 file name")?
+                .to_string(),
+        };
+
+        let source = options
+            .source
+            .as_ref()
+            .and_then(|source| self.sources.get(source));
+        let pending_folder = match source {
+            Some(source) => {
+                source.policy.apply_to(&mut options);
+                self.pending_folder.join(&source.id)
+            }
+            None => self.pending_folder.clone(),
+        };
+
+        let pending_path = pending_folder.join(&name);
+        let name = input_folder::relative_name(&pending_folder, &pending_path)
+            .context("File name must be a relative path without `..`")?;
+        if source.is_none() {
+            // On restart, files in the folder of a source are loaded as files of that source
+            let first_component = name.split('/').next().unwrap();
+            anyhow::ensure!(
+                !self.sources.contains_key(first_component),
+                "File name {name} is in the pending folder of input source {first_component}"
+            );
+        }
+        std::fs::create_dir_all(pending_path.parent().unwrap())?;
+        options.name = Some(name);
+
+        let file_sidecar = sidecar::FileSidecar {
+            ttl_secs: options.ttl.map(|ttl| ttl.as_secs()),
+            metadata: options.metadata.clone(),
+            priority: options.priority,
+            chunk_size: options.chunk_size,
+            compression: options.compression,
+            group: options.group.clone(),
+            catalog_only: options.catalog_only,
+        };
+        sidecar::write_sidecar_for(&pending_path, &file_sidecar)
+            .context("Failed to write sidecar to pending folder")?;
+        if let Err(err) = std::fs::rename(path, &pending_path) {
+            sidecar::remove_sidecar_for(&pending_path);
+            return Err(err).context("Failed to move file to pending folder");
+        }
+
+        self.background_runner_messages
+            .send(DownlinkServerMessage::AddFile(pending_path, options))
+            .context("Failed to add file. The background runner is probably dead.")?;
+
+        Ok(())
+    }
+
     pub fn add_control_message_reader(&self, reader: impl 'static + Read + Send) {
         let handle = spawn_control_reader(reader, self.background_runner_messages.clone());
         self.join_handles.lock().unwrap().push(handle);
@@ -64,6 +391,12 @@ impl DownlinkServer {
         })
     }
 
+    /// The heartbeats made while waiting for a downlink session, e.g. to be sent on a
+    /// housekeeping link. New heartbeats are dropped until the previous one is read.
+    pub fn waiting_heartbeats(&self) -> Receiver<Heartbeat> {
+        self.waiting_heartbeats.clone()
+    }
+
     pub fn join(self) {
         self.background_runner_messages
             .send(DownlinkServerMessage::StopAndQuit)
@@ -85,11 +418,11 @@ impl Drop for DownlinkServer {
     }
 }
 
-/// The downlink reader. Provides a stream of chunks. The session ends
+/// The downlink reader. Provides a stream of chunks and status messages. The session ends
 /// when the reader is dropped.
 pub struct DownlinkReader {
     background_runner_messages: Sender<DownlinkServerMessage>,
-    chunks: Receiver<Option<Chunk>>,
+    chunks: Receiver<Option<DownlinkPacket>>,
 }
 
 impl DownlinkReader {
@@ -103,17 +436,19 @@ impl DownlinkReader {
             return Ok(None);
         };
 
-        let ack = DownlinkServerMessage::ConfirmChunkSent {
-            file_id: next_chunk.file_id(),
-            part_id: next_chunk.part_index(),
-        };
-        self.background_runner_messages
-            .send(ack)
-            .context("Failed to send ack. The background server is probably dead.")?;
+        if let DownlinkPacket::Chunk(chunk) = &next_chunk {
+            let ack = DownlinkServerMessage::ConfirmChunkSent {
+                file_id: chunk.file_id(),
+                part_id: chunk.part_index(),
+            };
+            self.background_runner_messages
+                .send(ack)
+                .context("Failed to send ack. The background server is probably dead.")?;
+        }
 
-        Ok(Some(TransportPacket::new(TransportPacketData::from_chunk(
-            next_chunk,
-        ))))
+        Ok(Some(TransportPacket::new(
+            next_chunk.into_transport_packet_data(),
+        )))
     }
 }
 
@@ -161,39 +496,83 @@ fn spawn_control_reader(
     })
 }
 
+/// Watch the input folder of a source, and move the files there into the source's pending
+/// folder, with its own options.
 pub fn spawn_file_poller(
-    input_folder: PathBuf,
+    source: InputSourceConfig,
     pending_folder: PathBuf,
     new_file_snd: Sender<DownlinkServerMessage>,
+    ttl_config: FileTtlConfig,
+    watch_config: InputWatchConfig,
+    mut bundler: Option<Bundler>,
 ) -> anyhow::Result<JoinHandle<()>> {
-    // Add all the files that are already in the pending folder to the queue
-    for entry in std::fs::read_dir(&pending_folder)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file() {
+    let input_folder = source.folder.clone();
+
+    // Add all the files that are already in the pending folder to the queue. The pending folder
+    // has the same structure as the input folder, so the relative paths are kept.
+    for path in input_folder::find_files_recursive(&pending_folder)? {
+        let name = input_folder::relative_name(&pending_folder, &path);
+        if let (Some(bundler), Some(name)) = (&mut bundler, &name) {
+            if bundler.try_add(&path, name) {
+                continue;
+            }
+        }
+
+        let options = pending_file_options(
+            &pending_folder,
+            &path,
+            Some(&source),
+            &ttl_config,
+            FileKind::Regular,
+        );
+        new_file_snd
+            .send(DownlinkServerMessage::AddFile(path, options))
+            .context("Failed to add file to queue when spawning poller")?;
+    }
+
+    // Bundles that were complete, but not added to the storage yet
+    if let Some(bundler) = &bundler {
+        for path in bundler.waiting_bundles()? {
+            let options = pending_file_options(
+                bundler.bundles_folder(),
+                &path,
+                Some(&source),
+                &ttl_config,
+                FileKind::Bundle,
+            );
             new_file_snd
-                .send(DownlinkServerMessage::AddFile(path))
-                .context("Failed to add file to queue when spawning poller")?;
+                .send(DownlinkServerMessage::AddFile(path, options))
+                .context("Failed to add bundle to queue when spawning poller")?;
         }
     }
 
-    // Spawn a thread that polls the new folder, and moves each file there into the pending folder and
-    // notifies the pending queue
+    let mut watcher = InputWatcher::new(input_folder.clone(), watch_config)
+        .context("Failed to watch input folder")?;
+
+    // Spawn a thread that watches the input folder, and moves each finished file there into the
+    // pending folder and notifies the pending queue
     let join = std::thread::spawn(move || loop {
-        // Find all the new foles in the new folder
-        let mut new_files = Vec::new();
-        for entry in std::fs::read_dir(&input_folder).unwrap() {
-            // Ensure that the file no longer has open file handles
-
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_file() {
-                new_files.push(path);
+        // The subfolders are left in place, as instruments may still write into them
+        for path in watcher.next_ready_files() {
+            let Some(name) = input_folder::relative_name(&input_folder, &path) else {
+                tracing::error!("Skipping input file with a non UTF-8 path: {:?}", path);
+                continue;
+            };
+
+            let pending_path = pending_folder.join(&name);
+            if let Err(err) = std::fs::create_dir_all(pending_path.parent().unwrap()) {
+                tracing::error!("Failed to create folder in pending folder: {}", err);
+                continue;
             }
-        }
 
-        for path in new_files {
-            let pending_path = pending_folder.join(path.file_name().unwrap());
+            // Move the sidecar first, so that it's in place when the file gets added
+            let sidecar_path = sidecar::sidecar_path_for(&path);
+            if sidecar_path.is_file() {
+                let pending_sidecar_path = sidecar::sidecar_path_for(&pending_path);
+                if let Err(err) = std::fs::rename(&sidecar_path, &pending_sidecar_path) {
+                    tracing::error!("Failed to move sidecar to pending folder: {}", err);
+                }
+            }
 
             let rename_result = std::fs::rename(&path, &pending_path);
             if rename_result.is_err() {
@@ -201,15 +580,215 @@ pub fn spawn_file_poller(
                 continue;
             }
 
-            let snd_result = new_file_snd.send(DownlinkServerMessage::AddFile(pending_path));
+            if let Some(bundler) = &mut bundler {
+                if bundler.try_add(&pending_path, &name) {
+                    continue;
+                }
+            }
+
+            let options = pending_file_options(
+                &pending_folder,
+                &pending_path,
+                Some(&source),
+                &ttl_config,
+                FileKind::Regular,
+            );
+            let snd_result =
+                new_file_snd.send(DownlinkServerMessage::AddFile(pending_path, options));
             if snd_result.is_err() {
                 return; // The queue has been removed
             }
         }
 
-        // Sleep to not spam the disk too much
-        std::thread::sleep(std::time::Duration::from_millis(100));
+        let Some(bundler) = &mut bundler else {
+            continue;
+        };
+        for (path, kind) in bundler.take_due_bundle() {
+            let folder = match kind {
+                FileKind::Bundle => bundler.bundles_folder(),
+                FileKind::Regular => &pending_folder,
+            };
+            let options = pending_file_options(folder, &path, Some(&source), &ttl_config, kind);
+            let snd_result = new_file_snd.send(DownlinkServerMessage::AddFile(path, options));
+            if snd_result.is_err() {
+                return; // The queue has been removed
+            }
+        }
     });
 
     Ok(join)
 }
+
+/// The options of a file in the pending folder, or of a bundle in the bundles folder, from its
+/// path, sidecar and input source
+fn pending_file_options(
+    pending_folder: &Path,
+    path: &Path,
+    source: Option<&InputSourceConfig>,
+    ttl_config: &FileTtlConfig,
+    kind: FileKind,
+) -> NewFileOptions {
+    let source_id = source.map(|source| source.id.clone());
+    let policy = source
+        .map(|source| source.policy.clone())
+        .unwrap_or_default();
+    let mut options = sidecar::new_file_options_for(path, source_id, &policy, ttl_config);
+    options.name = input_folder::relative_name(pending_folder, path);
+    options.kind = kind;
+
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use common::{
+        file_sending::storage_manager::SendingStorageManager, status::RunnerState,
+        tempdir::TempDirProvider,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_input_sources() -> anyhow::Result<()> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let workdir = folder.path().join("work");
+        let camera_folder = folder.path().join("camera");
+        let pending_folder = workdir.join("pending");
+        std::fs::create_dir_all(pending_folder.join("camera"))?;
+        std::fs::create_dir_all(&camera_folder)?;
+
+        let sources = vec![
+            InputSourceConfig {
+                id: "camera".to_string(),
+                folder: camera_folder.clone(),
+                policy: SourcePolicy {
+                    chunk_size: Some(4),
+                    priority: Some(3),
+                    ttl: Some(Duration::from_secs(3600)),
+                    ..Default::default()
+                },
+            },
+            InputSourceConfig {
+                id: "logs".to_string(),
+                folder: folder.path().join("logs"),
+                policy: SourcePolicy::default(),
+            },
+        ];
+        let config = DownlinkServerConfig {
+            storage: SendingStorageManagerConfig {
+                new_file_chunk_size: 1024,
+                ..Default::default()
+            },
+            heartbeat_interval_packets: None,
+            waiting_heartbeat_interval: Some(Duration::ZERO),
+            scheduling_policy: SchedulingPolicyConfig::default(),
+            file_ttl: FileTtlConfig::default(),
+            scrub_interval: None,
+            input_watch: InputWatchConfig {
+                poll_interval: Duration::from_millis(10),
+                settle_time: Duration::from_millis(50),
+                ..Default::default()
+            },
+            bundling: None,
+            growing_files: Vec::new(),
+        };
+
+        // Files left in the pending folders by a previous run
+        std::fs::write(pending_folder.join("camera").join("left.bin"), [1; 10])?;
+        let overridden = pending_folder.join("camera").join("overridden.bin");
+        std::fs::write(&overridden, [2; 10])?;
+        sidecar::write_sidecar_for(
+            &overridden,
+            &sidecar::FileSidecar {
+                priority: Some(7),
+                ..Default::default()
+            },
+        )?;
+        std::fs::write(pending_folder.join("direct.bin"), [3; 10])?;
+
+        let server = DownlinkServer::spawn(sources, workdir.clone(), config.clone())?;
+
+        // A file moved into the input folder of the source
+        let moved = folder.path().join("moved.bin");
+        std::fs::write(&moved, [4; 10])?;
+        std::fs::rename(&moved, camera_folder.join("moved.bin"))?;
+
+        // A file added directly, as a file of the source
+        let added = folder.path().join("added.bin");
+        std::fs::write(&added, [5; 10])?;
+        server.add_file(
+            &added,
+            NewFileOptions {
+                source: Some("camera".to_string()),
+                ..Default::default()
+            },
+        )?;
+
+        // Without a source, it would be taken for a file of the source on restart
+        let mistaken = folder.path().join("mistaken.bin");
+        std::fs::write(&mistaken, [6; 10])?;
+        let options = NewFileOptions {
+            name: Some("camera/mistaken.bin".to_string()),
+            ..Default::default()
+        };
+        assert!(server.add_file(&mistaken, options).is_err());
+        assert!(mistaken.exists());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let pending = input_folder::find_files_recursive(&pending_folder)?;
+            let input = input_folder::find_files_recursive(&camera_folder)?;
+            if pending.is_empty() && input.is_empty() {
+                break;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Files weren't added: {pending:?} {input:?}"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let heartbeat = server
+            .waiting_heartbeats()
+            .recv_timeout(Duration::from_secs(10))?;
+        assert_eq!(heartbeat.runner_state, RunnerState::Waiting);
+
+        // Stop the background runner, so the storage can be loaded here
+        let runner = server.join_handles.lock().unwrap().remove(0);
+        drop(server);
+        runner.join().unwrap();
+
+        let storage = SendingStorageManager::new(workdir.join("ready"), config.storage)?;
+        let mut files = storage
+            .iter_files()
+            .map(|file| (file.header().name.clone(), file))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(files.len(), 5);
+
+        let now = chrono::Utc::now().timestamp();
+        for name in ["left.bin", "overridden.bin", "moved.bin", "added.bin"] {
+            let file = files.remove(name).unwrap();
+            assert_eq!(file.header().file_part_size, 4, "{name}");
+            assert_eq!(file.header().part_count, 3, "{name}");
+            assert_eq!(file.info().source.as_deref(), Some("camera"), "{name}");
+            assert!(file.info().expires_at.unwrap() > now, "{name}");
+
+            let priority = if name == "overridden.bin" { 7 } else { 3 };
+            assert!(
+                file.remaining_parts()
+                    .iter()
+                    .all(|part| part.priority == priority),
+                "{name}"
+            );
+        }
+
+        let direct = files.remove("direct.bin").unwrap();
+        assert_eq!(direct.header().file_part_size, 1024);
+        assert_eq!(direct.info().source, None);
+        assert_eq!(direct.info().expires_at, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+
+use common::{
+    file_part_id::{FilePartId, FilePartIdRangeInclusive},
+    file_sending::storage_manager::{
+        cmp_file_storage_part_normal, SendingStorageManager, StorageFilePart,
+    },
+};
+use uuid::Uuid;
+
+/// Decides in which order the file parts are sent during a downlink session. The downlink
+/// session asks the policy to order the remaining parts at the start of every pass over the
+/// queue, and notifies it of sent and acknowledged parts in between.
+pub trait SchedulingPolicy: Send {
+    /// Order the parts for the next pass. The first part in the returned list is sent first.
+    fn order_parts(
+        &mut self,
+        storage: &SendingStorageManager,
+        parts: Vec<StorageFilePart>,
+    ) -> Vec<StorageFilePart>;
+
+    /// Called when a part was handed over to the downlink.
+    fn on_part_sent(&mut self, _file_id: Uuid, _part_id: FilePartId) {}
+
+    /// Called when the ground acknowledged a range of parts.
+    fn on_parts_acked(&mut self, _file_id: Uuid, _parts: FilePartIdRangeInclusive) {}
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum SchedulingPolicyConfig {
+    /// Send the highest priority parts first, across all files.
+    #[default]
+    StrictPriority,
+
+    /// Interleave the files, giving every file a share of the downlink proportional to its
+    /// priority.
+    WeightedFairShare,
+
+    /// Send the files with the earliest deadline first. The deadline of a file is when it
+    /// expires, files that don't expire are due after, in the order they were created.
+    EarliestDeadlineFirst,
+
+    /// Send the files with the least remaining parts first, so that nearly complete files
+    /// get finished before starting new ones.
+    NearlyCompleteFirst,
+}
+
+impl SchedulingPolicyConfig {
+    pub fn build(&self) -> Box<dyn SchedulingPolicy> {
+        match self {
+            SchedulingPolicyConfig::StrictPriority => Box::new(StrictPriority),
+            SchedulingPolicyConfig::WeightedFairShare => Box::new(WeightedFairShare::default()),
+            SchedulingPolicyConfig::EarliestDeadlineFirst => Box::new(EarliestDeadlineFirst),
+            SchedulingPolicyConfig::NearlyCompleteFirst => Box::new(NearlyCompleteFirst),
+        }
+    }
+}
+
+pub struct StrictPriority;
+
+impl SchedulingPolicy for StrictPriority {
+    fn order_parts(
+        &mut self,
+        _storage: &SendingStorageManager,
+        mut parts: Vec<StorageFilePart>,
+    ) -> Vec<StorageFilePart> {
+        parts.sort_unstable_by(|a, b| cmp_file_storage_part_normal(b, a));
+        parts
+    }
+}
+
+#[derive(Default)]
+pub struct WeightedFairShare {
+    /// Parts sent per file during this session, used to keep the shares fair across passes
+    sent_counts: HashMap<Uuid, u64>,
+}
+
+impl SchedulingPolicy for WeightedFairShare {
+    fn order_parts(
+        &mut self,
+        _storage: &SendingStorageManager,
+        parts: Vec<StorageFilePart>,
+    ) -> Vec<StorageFilePart> {
+        let mut parts_by_file = HashMap::<Uuid, Vec<StorageFilePart>>::new();
+        for part in parts {
+            parts_by_file.entry(part.file_id).or_default().push(part);
+        }
+
+        // The weight of a file is based on its highest part priority, relative to the lowest
+        // priority file, so that every file gets at least some share.
+        let file_priority =
+            |parts: &[StorageFilePart]| parts.iter().map(|p| p.priority as i32).max().unwrap_or(0);
+        let min_priority = parts_by_file
+            .values()
+            .map(|parts| file_priority(parts))
+            .min()
+            .unwrap_or(0);
+
+        // Each part gets a virtual finish time, the file's nth part finishes at n / weight.
+        let mut scheduled = Vec::new();
+        for (file_id, mut parts) in parts_by_file {
+            let weight = (file_priority(&parts) - min_priority + 1) as f64;
+            let already_sent = self.sent_counts.get(&file_id).copied().unwrap_or(0);
+
+            parts.sort_unstable_by(|a, b| cmp_file_storage_part_normal(b, a));
+            for (i, part) in parts.into_iter().enumerate() {
+                let finish_time = (already_sent + i as u64 + 1) as f64 / weight;
+                scheduled.push((finish_time, part));
+            }
+        }
+
+        scheduled.sort_by(|(a_time, a), (b_time, b)| {
+            a_time
+                .total_cmp(b_time)
+                .then_with(|| a.file_id.cmp(&b.file_id))
+        });
+
+        scheduled.into_iter().map(|(_, part)| part).collect()
+    }
+
+    fn on_part_sent(&mut self, file_id: Uuid, _part_id: FilePartId) {
+        *self.sent_counts.entry(file_id).or_default() += 1;
+    }
+}
+
+pub struct EarliestDeadlineFirst;
+
+impl EarliestDeadlineFirst {
+    fn file_deadline(storage: &SendingStorageManager, file_id: Uuid) -> (i64, i64) {
+        let Some(file) = storage.get_file(file_id) else {
+            return (i64::MAX, i64::MAX);
+        };
+
+        let expires_at = file.info().expires_at.unwrap_or(i64::MAX);
+        (expires_at, file.header().date)
+    }
+}
+
+impl SchedulingPolicy for EarliestDeadlineFirst {
+    fn order_parts(
+        &mut self,
+        storage: &SendingStorageManager,
+        mut parts: Vec<StorageFilePart>,
+    ) -> Vec<StorageFilePart> {
+        let deadlines = parts
+            .iter()
+            .map(|part| part.file_id)
+            .map(|file_id| (file_id, Self::file_deadline(storage, file_id)))
+            .collect::<HashMap<_, _>>();
+
+        parts.sort_unstable_by(|a, b| {
+            deadlines[&a.file_id]
+                .cmp(&deadlines[&b.file_id])
+                .then_with(|| a.file_id.cmp(&b.file_id))
+                .then_with(|| cmp_file_storage_part_normal(b, a))
+        });
+        parts
+    }
+}
+
+pub struct NearlyCompleteFirst;
+
+impl SchedulingPolicy for NearlyCompleteFirst {
+    fn order_parts(
+        &mut self,
+        storage: &SendingStorageManager,
+        mut parts: Vec<StorageFilePart>,
+    ) -> Vec<StorageFilePart> {
+        let remaining_counts = parts
+            .iter()
+            .map(|part| part.file_id)
+            .map(|file_id| {
+                let remaining = storage
+                    .get_file(file_id)
+                    .map(|file| file.remaining_parts().len())
+                    .unwrap_or(usize::MAX);
+                (file_id, remaining)
+            })
+            .collect::<HashMap<_, _>>();
+
+        parts.sort_unstable_by(|a, b| {
+            remaining_counts[&a.file_id]
+                .cmp(&remaining_counts[&b.file_id])
+                .then_with(|| a.file_id.cmp(&b.file_id))
+                .then_with(|| cmp_file_storage_part_normal(b, a))
+        });
+        parts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use common::{
+        control::{ConfirmPart, ControlMessage},
+        file_sending::storage_manager::{NewFileOptions, SendingStorageManagerConfig},
+        tempdir::{TempDir, TempDirProvider},
+    };
+
+    use crate::downlink_session::DownlinkSession;
+
+    use super::*;
+
+    fn make_storage(file_sizes: &[usize]) -> anyhow::Result<(TempDir, SendingStorageManager)> {
+        let files = file_sizes
+            .iter()
+            .map(|size| (*size, NewFileOptions::default()))
+            .collect::<Vec<_>>();
+        make_storage_with_options(&files)
+    }
+
+    /// The files are named `file0`, `file1`, ... in the order they're given
+    fn make_storage_with_options(
+        files: &[(usize, NewFileOptions)],
+    ) -> anyhow::Result<(TempDir, SendingStorageManager)> {
+        let folder = TempDirProvider::new_for_test().create()?;
+        let storage_path = folder.path().join("storage");
+        std::fs::create_dir_all(&storage_path)?;
+
+        let mut storage = SendingStorageManager::new(
+            storage_path,
+            SendingStorageManagerConfig {
+                new_file_chunk_size: 10,
+                max_folder_size: None,
+                split_file_if_n_chunks_saved: None,
+                ..Default::default()
+            },
+        )?;
+
+        for (i, (size, options)) in files.iter().enumerate() {
+            let name = format!("file{}", i);
+            let mut file = folder.make_file(&name)?;
+            file.write_all(&vec![i as u8; *size])?;
+            drop(file);
+
+            storage.add_file_from_path_with_options(folder.path().join(&name), options.clone())?;
+        }
+
+        Ok((folder, storage))
+    }
+
+    fn file_id(storage: &SendingStorageManager, name: &str) -> Uuid {
+        storage
+            .iter_files()
+            .find(|file| file.header().name == name)
+            .unwrap()
+            .header()
+            .id
+    }
+
+    fn with_priority(priority: i16) -> NewFileOptions {
+        NewFileOptions {
+            priority: Some(priority),
+            ..Default::default()
+        }
+    }
+
+    fn file_order(parts: &[StorageFilePart]) -> Vec<Uuid> {
+        let mut order = Vec::new();
+        for part in parts {
+            if order.last() != Some(&part.file_id) {
+                order.push(part.file_id);
+            }
+        }
+        order
+    }
+
+    #[test]
+    fn test_weighted_fair_share_interleaves_files() -> anyhow::Result<()> {
+        let (_folder, storage) = make_storage(&[50, 50])?;
+        let parts = storage
+            .iter_remaining_storage_file_parts()
+            .collect::<Vec<_>>();
+
+        let ordered = WeightedFairShare::default().order_parts(&storage, parts.clone());
+        assert_eq!(ordered.len(), parts.len());
+
+        // Equal priority files alternate
+        let order = file_order(&ordered);
+        assert_eq!(order.len(), ordered.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_priority() -> anyhow::Result<()> {
+        let (_folder, storage) =
+            make_storage_with_options(&[(30, with_priority(1)), (30, with_priority(5))])?;
+        let parts = storage
+            .iter_remaining_storage_file_parts()
+            .collect::<Vec<_>>();
+
+        // All the parts of the higher priority file come first
+        let ordered = StrictPriority.order_parts(&storage, parts);
+        assert_eq!(
+            file_order(&ordered),
+            vec![file_id(&storage, "file1"), file_id(&storage, "file0")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_weighted_fair_share_by_priority() -> anyhow::Result<()> {
+        let (_folder, storage) =
+            make_storage_with_options(&[(50, with_priority(2)), (50, with_priority(1))])?;
+        let high_id = file_id(&storage, "file0");
+        let parts = storage
+            .iter_remaining_storage_file_parts()
+            .collect::<Vec<_>>();
+
+        // The higher priority file has twice the weight, so it gets twice the share
+        let ordered = WeightedFairShare::default().order_parts(&storage, parts);
+        let high_count = ordered[..6]
+            .iter()
+            .filter(|part| part.file_id == high_id)
+            .count();
+        assert_eq!(high_count, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_weighted_fair_share_carries_over_sent_parts() -> anyhow::Result<()> {
+        let (_folder, storage) = make_storage(&[50, 50])?;
+        let sent_id = file_id(&storage, "file0");
+        let parts = storage
+            .iter_remaining_storage_file_parts()
+            .collect::<Vec<_>>();
+
+        // The file that was sent more of in an earlier pass waits for the other to catch up
+        let mut policy = WeightedFairShare::default();
+        for part_id in 0..3 {
+            policy.on_part_sent(sent_id, FilePartId::Part(part_id));
+        }
+        let ordered = policy.order_parts(&storage, parts);
+        assert!(ordered[..3].iter().all(|part| part.file_id != sent_id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_earliest_deadline_first() -> anyhow::Result<()> {
+        let with_ttl = |secs| NewFileOptions {
+            ttl: Some(Duration::from_secs(secs)),
+            ..Default::default()
+        };
+        let (_folder, storage) = make_storage_with_options(&[
+            (20, NewFileOptions::default()),
+            (20, with_ttl(7200)),
+            (20, with_ttl(3600)),
+            (20, NewFileOptions::default()),
+        ])?;
+        let parts = storage
+            .iter_remaining_storage_file_parts()
+            .collect::<Vec<_>>();
+
+        // The files that expire go first, the others follow in the order they were created
+        let ordered = EarliestDeadlineFirst.order_parts(&storage, parts);
+        let expected = ["file2", "file1", "file0", "file3"]
+            .map(|name| file_id(&storage, name))
+            .to_vec();
+        assert_eq!(file_order(&ordered), expected);
+
+        Ok(())
+    }
+
+    /// Records the notifications it gets, and keeps the order of the parts
+    #[derive(Clone, Default)]
+    struct RecordingPolicy {
+        sent: Arc<Mutex<Vec<(Uuid, FilePartId)>>>,
+        acked: Arc<Mutex<Vec<(Uuid, FilePartIdRangeInclusive)>>>,
+    }
+
+    impl SchedulingPolicy for RecordingPolicy {
+        fn order_parts(
+            &mut self,
+            _storage: &SendingStorageManager,
+            parts: Vec<StorageFilePart>,
+        ) -> Vec<StorageFilePart> {
+            parts
+        }
+
+        fn on_part_sent(&mut self, file_id: Uuid, part_id: FilePartId) {
+            self.sent.lock().unwrap().push((file_id, part_id));
+        }
+
+        fn on_parts_acked(&mut self, file_id: Uuid, parts: FilePartIdRangeInclusive) {
+            self.acked.lock().unwrap().push((file_id, parts));
+        }
+    }
+
+    #[test]
+    fn test_policy_notified_by_session() -> anyhow::Result<()> {
+        let (_folder, storage) = make_storage(&[30])?;
+        let file_id = file_id(&storage, "file0");
+
+        let policy = RecordingPolicy::default();
+        let mut session = DownlinkSession::new(storage, Box::new(policy.clone()));
+
+        session.confirm_file_sent(file_id, FilePartId::Part(1))?;
+        assert_eq!(
+            *policy.sent.lock().unwrap(),
+            vec![(file_id, FilePartId::Part(1))]
+        );
+
+        let part_range = FilePartIdRangeInclusive::new(FilePartId::Part(0), FilePartId::Part(1));
+        session.process_control(ControlMessage::ConfirmPart(ConfirmPart {
+            file_id,
+            part_range: part_range.clone(),
+        }))?;
+        assert_eq!(*policy.acked.lock().unwrap(), vec![(file_id, part_range)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nearly_complete_first() -> anyhow::Result<()> {
+        let (_folder, storage) = make_storage(&[100, 20])?;
+        let parts = storage
+            .iter_remaining_storage_file_parts()
+            .collect::<Vec<_>>();
+
+        let ordered = NearlyCompleteFirst.order_parts(&storage, parts);
+        let order = file_order(&ordered);
+        assert_eq!(order.len(), 2);
+
+        let first_file = storage.get_file(order[0]).unwrap();
+        assert_eq!(first_file.header().size, 20);
+        assert_eq!(ordered[0].part_id, FilePartId::Header);
+
+        Ok(())
+    }
+}
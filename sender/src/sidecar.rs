@@ -0,0 +1,132 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use common::{chunks::FileCompression, file_sending::storage_manager::NewFileOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::{FileTtlConfig, SourcePolicy};
+
+const SIDECAR_SUFFIX: &str = ".leoftp.json";
+
+/// Optional per-file options, provided by placing a `<file name>.leoftp.json` file next to the
+/// file in the input folder. Files are picked up as soon as they appear, so the sidecar must be
+/// written before the file itself.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileSidecar {
+    /// Overrides the configured time-to-live of the file
+    pub ttl_secs: Option<u64>,
+
+    /// Custom fields that are sent in the file header, and written out next to the file
+    /// by the receiver
+    pub metadata: BTreeMap<String, String>,
+
+    /// The initial priority of all the parts of the file
+    pub priority: Option<i16>,
+
+    /// The size of the file parts, instead of the configured one
+    pub chunk_size: Option<u32>,
+
+    /// How the file is compressed before it's stored and sent, e.g. `"zstd"`
+    pub compression: FileCompression,
+
+    /// A group that the file belongs to, for filtering in bulk control messages
+    pub group: Option<String>,
+
+    /// Only catalog the file, and send it once the ground requests it
+    pub catalog_only: bool,
+}
+
+impl FileSidecar {
+    /// Whether the sidecar only attaches metadata, and doesn't change how the file is handled
+    pub fn is_metadata_only(&self) -> bool {
+        self.ttl_secs.is_none()
+            && self.priority.is_none()
+            && self.chunk_size.is_none()
+            && self.compression == FileCompression::None
+            && self.group.is_none()
+            && !self.catalog_only
+    }
+}
+
+pub fn is_sidecar_path(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(SIDECAR_SUFFIX))
+}
+
+pub fn sidecar_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(SIDECAR_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Read the sidecar of a file, if there is one. Invalid sidecars are logged and ignored, as
+/// the file should still be sent.
+pub fn read_sidecar_for(path: &Path) -> Option<FileSidecar> {
+    let sidecar_path = sidecar_path_for(path);
+    if !sidecar_path.is_file() {
+        return None;
+    }
+
+    let result = std::fs::read(&sidecar_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|data| Ok(serde_json::from_slice::<FileSidecar>(&data)?));
+
+    match result {
+        Ok(sidecar) => Some(sidecar),
+        Err(err) => {
+            tracing::error!("Failed to read sidecar {:?}: {}", sidecar_path, err);
+            None
+        }
+    }
+}
+
+/// Write the sidecar of a file, so that its options survive a restart while it's pending.
+/// Must be called before the file itself is moved into place.
+pub fn write_sidecar_for(path: &Path, sidecar: &FileSidecar) -> anyhow::Result<()> {
+    std::fs::write(sidecar_path_for(path), serde_json::to_vec_pretty(sidecar)?)?;
+    Ok(())
+}
+
+/// Remove the sidecar of a file once the file was added to the storage.
+pub fn remove_sidecar_for(path: &Path) {
+    let sidecar_path = sidecar_path_for(path);
+    if !sidecar_path.is_file() {
+        return;
+    }
+
+    if let Err(err) = std::fs::remove_file(&sidecar_path) {
+        tracing::error!("Failed to remove sidecar {:?}: {}", sidecar_path, err);
+    }
+}
+
+/// Build the options for a new file in the pending folder, from its sidecar, the policy of its
+/// input source and the config, in that order.
+pub fn new_file_options_for(
+    path: &Path,
+    source: Option<String>,
+    policy: &SourcePolicy,
+    ttl_config: &FileTtlConfig,
+) -> NewFileOptions {
+    let sidecar = read_sidecar_for(path).unwrap_or_default();
+
+    let mut options = NewFileOptions {
+        name: None,
+        source,
+        ttl: sidecar.ttl_secs.map(Duration::from_secs),
+        metadata: sidecar.metadata,
+        priority: sidecar.priority,
+        chunk_size: sidecar.chunk_size,
+        compression: sidecar.compression,
+        group: sidecar.group,
+        kind: Default::default(),
+        catalog_only: sidecar.catalog_only,
+    };
+    policy.apply_to(&mut options);
+    options.ttl = options.ttl.or(ttl_config.default_ttl);
+
+    options
+}
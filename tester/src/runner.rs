@@ -2,14 +2,18 @@ use std::{
     path::{Path, PathBuf},
     sync::{atomic::AtomicBool, Arc},
     thread::JoinHandle,
+    time::Duration,
 };
 
 use common::{
     binary_serialize::BinarySerialize,
-    file_sending::storage_manager::SendingStorageManagerConfig,
+    file_sending::{in_flight::InFlightTimeout, storage_manager::SendingStorageManagerConfig},
     transport_packet::{parse_transport_packet_stream, TransportPacket, TransportPacketData},
 };
-use sender::DownlinkServer;
+use sender::{
+    scheduling::SchedulingPolicyConfig, DownlinkServer, DownlinkServerConfig, FileTtlConfig,
+    InputSourceConfig, InputWatchConfig,
+};
 
 use crate::byte_pipe::make_corrupt_pipe;
 
@@ -30,21 +34,45 @@ impl TestRunner {
 
         let rcv_pending_folder = rcv_folder.join("pending");
         let rcv_finished_folder = rcv_folder.join("finished");
+        let rcv_quarantine_folder = rcv_folder.join("quarantine");
 
         let mut downlink = DownlinkServer::spawn(
-            snd_input_folder.clone(),
+            vec![InputSourceConfig {
+                id: "input".to_string(),
+                folder: snd_input_folder.clone(),
+                policy: Default::default(),
+            }],
             snd_workdir_folder,
-            SendingStorageManagerConfig {
-                new_file_chunk_size: 1024 * 64,
-                max_folder_size: None,
-                split_file_if_n_chunks_saved: None,
+            DownlinkServerConfig {
+                storage: SendingStorageManagerConfig {
+                    new_file_chunk_size: 1024 * 64,
+                    max_folder_size: None,
+                    split_file_if_n_chunks_saved: None,
+                    in_flight_timeout: InFlightTimeout {
+                        sessions: Some(3),
+                        duration: Some(Duration::from_secs(60)),
+                    },
+                    announce_expired_files: true,
+                    ..Default::default()
+                },
+                heartbeat_interval_packets: Some(50),
+                waiting_heartbeat_interval: None,
+                scheduling_policy: SchedulingPolicyConfig::StrictPriority,
+                file_ttl: FileTtlConfig::default(),
+                scrub_interval: Some(Duration::from_secs(10)),
+                input_watch: InputWatchConfig::default(),
+                bundling: None,
+                growing_files: Vec::new(),
             },
         )
         .unwrap();
 
-        let mut rcv_file_server =
-            receiver::ReceivingStoreManager::new(rcv_pending_folder, rcv_finished_folder.clone())
-                .unwrap();
+        let mut rcv_file_server = receiver::ReceivingStoreManager::new(
+            rcv_pending_folder,
+            rcv_finished_folder.clone(),
+            rcv_quarantine_folder,
+        )
+        .unwrap();
 
         let kill_flag = Arc::new(AtomicBool::new(false));
 
@@ -67,7 +95,13 @@ impl TestRunner {
                         break 'outer;
                     };
 
-                    let chunk = chunk.unwrap().as_chunk().expect("Expected chunk");
+                    let packet = chunk.unwrap();
+                    if let Some(status) = packet.clone().as_status_message() {
+                        tracing::info!("Received status message: {}", status);
+                        continue;
+                    }
+
+                    let chunk = packet.as_chunk().expect("Expected chunk");
 
                     tracing::info!("Received chunk: {}", chunk);
 